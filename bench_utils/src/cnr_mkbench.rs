@@ -680,28 +680,11 @@ where
         let stuck = Arc::new([ATOMIC_INIT; MAX_REPLICAS_PER_LOG]);
         let nlogs = self.log.len();
 
-        use core::sync::atomic::AtomicBool;
         for i in 0..nlogs {
             let stuck = stuck.clone();
-            let func = move |rid: &[AtomicBool; MAX_REPLICAS_PER_LOG], idx: usize| {
-                for replia in 0..MAX_REPLICAS_PER_LOG {
-                    if rid[replia].compare_exchange_weak(
-                        true,
-                        false,
-                        Ordering::Relaxed,
-                        Ordering::Relaxed,
-                    ) == Ok(true)
-                    {
-                        stuck[replia].compare_exchange_weak(
-                            0,
-                            idx,
-                            Ordering::Release,
-                            Ordering::Relaxed,
-                        );
-                    }
-                }
-            };
-            unsafe { Arc::get_mut_unchecked(&mut self.log[i]).update_closure(func) };
+            self.log[i].on_lagging_replica(move |rid, idx| {
+                stuck[rid].compare_exchange_weak(0, idx, Ordering::Release, Ordering::Relaxed);
+            });
         }
 
         let thread_num = self.threads();
@@ -0,0 +1,38 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Procedural macros for node-replication.
+//!
+//! Don't depend on this crate directly, the macros are re-exported by
+//! `node-replication` (with the `macros` feature, enabled by default) and the
+//! generated code refers to items of `node_replication`.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+
+mod log_mapper;
+mod replicated;
+
+/// Implements `node_replication::nr::Dispatch` for the type of an inherent
+/// `impl` block.
+///
+/// See the documentation of `node_replication::replicated` for details.
+#[proc_macro_attribute]
+pub fn replicated(attr: TokenStream, item: TokenStream) -> TokenStream {
+    replicated::expand(attr.into(), item.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Implements `node_replication::cnr::LogMapper` for an operation from
+/// `#[log(...)]` attributes.
+///
+/// See the documentation of `node_replication::cnr::LogMapper` for details.
+#[proc_macro_derive(LogMapper, attributes(log))]
+pub fn derive_log_mapper(item: TokenStream) -> TokenStream {
+    syn::parse(item)
+        .and_then(log_mapper::expand)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,126 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Implementation of `#[derive(LogMapper)]`.
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{Attribute, Data, DeriveInput, Error, Fields, LitInt, Member, Result};
+
+/// How an operation is mapped to logs (the argument of `#[log(...)]`).
+enum Mapping {
+    /// `#[log(key = field)]`: The log is determined by `field`.
+    Key(Member),
+    /// `#[log(all)]`: The operation goes to every log.
+    All,
+    /// `#[log(fixed = n)]`: The operation goes to log `n`.
+    Fixed(usize),
+}
+
+impl Mapping {
+    /// Parses the `#[log(...)]` attribute in `attrs` (if any).
+    fn parse(attrs: &[Attribute]) -> Result<Option<Self>> {
+        let mut mapping = None;
+        for attr in attrs.iter().filter(|a| a.path().is_ident("log")) {
+            attr.parse_nested_meta(|meta| {
+                if mapping.is_some() {
+                    return Err(meta.error("an operation can only have one log mapping"));
+                }
+                if meta.path.is_ident("all") {
+                    mapping = Some(Mapping::All);
+                } else if meta.path.is_ident("key") {
+                    mapping = Some(Mapping::Key(meta.value()?.parse()?));
+                } else if meta.path.is_ident("fixed") {
+                    let log: LitInt = meta.value()?.parse()?;
+                    mapping = Some(Mapping::Fixed(log.base10_parse()?));
+                } else {
+                    return Err(meta.error("expected `key = <field>`, `all` or `fixed = <log>`"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(mapping)
+    }
+
+    /// Returns the match arm for the variant (or struct) `path` with `fields`.
+    fn arm(&self, path: TokenStream, fields: &Fields, span: Span) -> Result<TokenStream> {
+        Ok(match self {
+            Mapping::All => quote!(#path { .. } => logs.extend(0..nlogs)),
+            Mapping::Fixed(log) => quote!(#path { .. } => logs.push(#log % nlogs)),
+            Mapping::Key(member) => {
+                let exists = fields.iter().enumerate().any(|(i, field)| match member {
+                    Member::Named(name) => field.ident.as_ref() == Some(name),
+                    Member::Unnamed(index) => field.ident.is_none() && index.index as usize == i,
+                });
+                if !exists {
+                    return Err(Error::new(
+                        span,
+                        format!("no field `{}` to use as key", quote!(#member)),
+                    ));
+                }
+                quote! {
+                    #path { #member: key, .. } => {
+                        logs.push(::node_replication::cnr::log_for_key(key, nlogs))
+                    }
+                }
+            }
+        })
+    }
+}
+
+pub(crate) fn expand(input: DeriveInput) -> Result<TokenStream> {
+    let name = &input.ident;
+    // A mapping on an enum is the default for its variants.
+    let default = Mapping::parse(&input.attrs)?;
+
+    let arms = match &input.data {
+        Data::Struct(data) => {
+            let mapping = default.ok_or_else(|| {
+                Error::new(
+                    Span::call_site(),
+                    "missing `#[log(...)]` attribute to map the operation to logs",
+                )
+            })?;
+            vec![mapping.arm(quote!(Self), &data.fields, input.span())?]
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::with_capacity(data.variants.len());
+            for variant in &data.variants {
+                let mapping = Mapping::parse(&variant.attrs)?;
+                let mapping = mapping.as_ref().or(default.as_ref()).ok_or_else(|| {
+                    Error::new(
+                        variant.span(),
+                        "missing `#[log(...)]` attribute to map the operation to logs",
+                    )
+                })?;
+                let ident = &variant.ident;
+                arms.push(mapping.arm(quote!(Self::#ident), &variant.fields, variant.span())?);
+            }
+            arms
+        }
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span(),
+                "LogMapper can't be derived for unions",
+            ))
+        }
+    };
+
+    let body = if arms.is_empty() {
+        quote! {
+            let _ = (nlogs, logs);
+            match *self {}
+        }
+    } else {
+        quote!(match self { #(#arms,)* })
+    };
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::node_replication::cnr::LogMapper for #name #ty_generics #where_clause {
+            fn hash(&self, nlogs: usize, logs: &mut ::node_replication::cnr::Vec<usize>) {
+                #body
+            }
+        }
+    })
+}
@@ -0,0 +1,406 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Implementation of `#[replicated]`.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    Attribute, Error, FnArg, Ident, ImplItem, ImplItemFn, ItemImpl, Lifetime, Pat, Result,
+    ReturnType, Type, TypeReference, Visibility,
+};
+
+/// A method of the annotated `impl` block that becomes an operation.
+struct Operation {
+    /// Name of the method.
+    name: Ident,
+    /// Name of the enum variants generated for the method.
+    variant: Ident,
+    /// Visibility of the method (the handle method gets the same).
+    vis: Visibility,
+    /// Doc comments of the method (forwarded to the handle method).
+    docs: Vec<Attribute>,
+    /// Argument names and types (without the receiver), as declared.
+    args: Vec<(Ident, Type)>,
+    /// Argument types as stored in the operation enum.
+    fields: Vec<Type>,
+    /// Return type of the method.
+    output: Type,
+}
+
+impl Operation {
+    fn parse(method: &ImplItemFn, read: bool) -> Result<Self> {
+        let sig = &method.sig;
+        if sig.asyncness.is_some() {
+            return Err(Error::new(
+                sig.asyncness.span(),
+                "#[replicated] doesn't support async methods",
+            ));
+        }
+        if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+            return Err(Error::new(
+                sig.generics.span(),
+                "#[replicated] doesn't support generic methods",
+            ));
+        }
+        if let Some(variadic) = &sig.variadic {
+            return Err(Error::new(
+                variadic.span(),
+                "variadic methods can't be replicated",
+            ));
+        }
+
+        let mut args = Vec::new();
+        let mut fields = Vec::new();
+        for arg in sig.inputs.iter().skip(1) {
+            let arg = match arg {
+                FnArg::Typed(arg) => arg,
+                FnArg::Receiver(r) => return Err(Error::new(r.span(), "unexpected receiver")),
+            };
+            let name = match &*arg.pat {
+                Pat::Ident(pat) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                    pat.ident.clone()
+                }
+                pat => {
+                    return Err(Error::new(
+                        pat.span(),
+                        "#[replicated] only supports plain identifiers as argument patterns",
+                    ))
+                }
+            };
+
+            let mut field = (*arg.ty).clone();
+            if read {
+                // Read operations are executed right away, so they can borrow
+                // from the caller (for the lifetime of `ReadOperation<'rop>`).
+                ElidedToRop.visit_type_mut(&mut field);
+            } else if let Some(span) = borrowed(&field) {
+                return Err(Error::new(
+                    span,
+                    "arguments of mutable methods must be owned (they are stored in the log)",
+                ));
+            }
+            args.push((name, (*arg.ty).clone()));
+            fields.push(field);
+        }
+
+        let output = match &sig.output {
+            ReturnType::Default => syn::parse_quote!(()),
+            ReturnType::Type(_, ty) => (**ty).clone(),
+        };
+        if let Some(span) = borrowed(&output) {
+            return Err(Error::new(
+                span,
+                "replicated methods must return owned values",
+            ));
+        }
+
+        Ok(Operation {
+            name: sig.ident.clone(),
+            variant: variant_name(&sig.ident),
+            vis: method.vis.clone(),
+            docs: method
+                .attrs
+                .iter()
+                .filter(|a| a.path().is_ident("doc"))
+                .cloned()
+                .collect(),
+            args,
+            fields,
+            output,
+        })
+    }
+
+    /// The variant of an operation enum, binding its fields to `binds`.
+    fn pattern(&self, enum_name: &Ident, binds: &[Ident]) -> TokenStream {
+        let variant = &self.variant;
+        if binds.is_empty() {
+            quote!(#enum_name::#variant)
+        } else {
+            quote!(#enum_name::#variant(#(#binds),*))
+        }
+    }
+
+    /// Variable names for the fields of the operation.
+    fn binds(&self) -> Vec<Ident> {
+        (0..self.args.len())
+            .map(|i| format_ident!("__arg{}", i))
+            .collect()
+    }
+
+    /// The declaration of the variant of an operation enum.
+    fn declaration(&self) -> TokenStream {
+        let variant = &self.variant;
+        let fields = &self.fields;
+        if fields.is_empty() {
+            quote!(#variant)
+        } else {
+            quote!(#variant(#(#fields),*))
+        }
+    }
+}
+
+/// Replaces elided lifetimes of references with `'rop`.
+struct ElidedToRop;
+
+impl VisitMut for ElidedToRop {
+    fn visit_type_reference_mut(&mut self, ty: &mut TypeReference) {
+        if ty.lifetime.is_none() {
+            ty.lifetime = Some(Lifetime::new("'rop", ty.and_token.span));
+        }
+        visit_mut::visit_type_reference_mut(self, ty);
+    }
+
+    fn visit_lifetime_mut(&mut self, lt: &mut Lifetime) {
+        if lt.ident == "_" {
+            *lt = Lifetime::new("'rop", lt.span());
+        }
+    }
+}
+
+/// Finds references and lifetimes (other than `'static`) in a type.
+#[derive(Default)]
+struct Borrows(Option<Span>);
+
+impl<'ast> Visit<'ast> for Borrows {
+    fn visit_type_reference(&mut self, ty: &'ast TypeReference) {
+        match &ty.lifetime {
+            Some(lt) if lt.ident == "static" => visit::visit_type_reference(self, ty),
+            _ => self.0 = self.0.or_else(|| Some(ty.span())),
+        }
+    }
+
+    fn visit_lifetime(&mut self, lt: &'ast Lifetime) {
+        if lt.ident != "static" {
+            self.0 = self.0.or_else(|| Some(lt.span()));
+        }
+    }
+}
+
+/// Returns the location of the first borrow in `ty` (if any).
+fn borrowed(ty: &Type) -> Option<Span> {
+    let mut borrows = Borrows::default();
+    borrows.visit_type(ty);
+    borrows.0
+}
+
+/// Converts a method name (`snake_case`) to a variant name (`CamelCase`).
+fn variant_name(method: &Ident) -> Ident {
+    let name: String = method
+        .to_string()
+        .trim_start_matches("r#")
+        .split('_')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let mut chars = s.chars();
+            chars
+                .next()
+                .map(|c| c.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect();
+    Ident::new(&name, method.span())
+}
+
+pub(crate) fn expand(attr: TokenStream, item: TokenStream) -> Result<TokenStream> {
+    let vis: Visibility = syn::parse2(attr)?;
+    let block: ItemImpl = syn::parse2(item)?;
+
+    if let Some((_, path, _)) = &block.trait_ {
+        return Err(Error::new(
+            path.span(),
+            "#[replicated] expects an inherent impl block",
+        ));
+    }
+    if !block.generics.params.is_empty() {
+        return Err(Error::new(
+            block.generics.span(),
+            "#[replicated] doesn't support generic impl blocks",
+        ));
+    }
+    let ty_name = match &*block.self_ty {
+        Type::Path(path) if path.qself.is_none() => {
+            path.path.segments.last().map(|s| s.ident.clone())
+        }
+        _ => None,
+    }
+    .ok_or_else(|| Error::new(block.self_ty.span(), "expected the name of a type"))?;
+
+    let mut reads = Vec::new();
+    let mut writes = Vec::new();
+    for item in &block.items {
+        let method = match item {
+            ImplItem::Fn(method) => method,
+            _ => continue,
+        };
+        // Associated functions (e.g., constructors) aren't operations.
+        let receiver = match method.sig.receiver() {
+            Some(receiver) => receiver,
+            None => continue,
+        };
+        if receiver.reference.is_none() || receiver.colon_token.is_some() {
+            return Err(Error::new(
+                receiver.span(),
+                "replicated methods must take `&self` or `&mut self`",
+            ));
+        }
+        // The handle has methods of the same name that aren't operations.
+        if method.sig.ident == "new" || method.sig.ident == "token" {
+            return Err(Error::new(
+                method.sig.ident.span(),
+                format!(
+                    "`{}` clashes with a method of the generated handle, rename it",
+                    method.sig.ident
+                ),
+            ));
+        }
+        if receiver.mutability.is_some() {
+            writes.push(Operation::parse(method, false)?);
+        } else {
+            reads.push(Operation::parse(method, true)?);
+        }
+    }
+
+    let self_ty = &block.self_ty;
+    let read_op = format_ident!("{}ReadOp", ty_name);
+    let write_op = format_ident!("{}WriteOp", ty_name);
+    let response = format_ident!("{}Response", ty_name);
+    let handle = format_ident!("{}Handle", ty_name);
+
+    let read_variants = reads.iter().map(Operation::declaration);
+    let write_variants = writes.iter().map(Operation::declaration);
+    let response_variants = reads.iter().chain(writes.iter()).map(|op| {
+        let variant = &op.variant;
+        let output = &op.output;
+        quote!(#variant(#output))
+    });
+
+    let dispatch_arms = |ops: &[Operation], op_enum: &Ident| {
+        ops.iter()
+            .map(|op| {
+                let binds = op.binds();
+                let pattern = op.pattern(op_enum, &binds);
+                let (name, variant) = (&op.name, &op.variant);
+                quote!(#pattern => #response::#variant(Self::#name(self, #(#binds),*)))
+            })
+            .collect::<Vec<_>>()
+    };
+    let read_arms = dispatch_arms(&reads, &read_op);
+    let write_arms = dispatch_arms(&writes, &write_op);
+
+    let handle_methods = |ops: &[Operation], op_enum: &Ident, execute: TokenStream| {
+        ops.iter()
+            .map(|op| {
+                let binds: Vec<Ident> = op.args.iter().map(|(name, _)| name.clone()).collect();
+                let pattern = op.pattern(op_enum, &binds);
+                let types = op.args.iter().map(|(_, ty)| ty);
+                let (vis, docs, name, variant, output) =
+                    (&op.vis, &op.docs, &op.name, &op.variant, &op.output);
+                let msg = format!("Response of `{}` doesn't match the operation", name);
+                quote! {
+                    #(#docs)*
+                    #[allow(unreachable_patterns)]
+                    #vis fn #name(&self, #(#binds: #types),*) -> #output {
+                        match self.nr.#execute(#pattern, self.tkn) {
+                            #response::#variant(r) => r,
+                            _ => unreachable!(#msg),
+                        }
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let read_methods = handle_methods(&reads, &read_op, quote!(execute));
+    let write_methods = handle_methods(&writes, &write_op, quote!(execute_mut));
+
+    let read_doc = format!(
+        "Read-only operations of [`{}`] (generated by `#[replicated]`).",
+        ty_name
+    );
+    let write_doc = format!(
+        "Mutable operations of [`{}`] (generated by `#[replicated]`).",
+        ty_name
+    );
+    let response_doc = format!(
+        "Responses of the operations of [`{}`] (generated by `#[replicated]`).",
+        ty_name
+    );
+    let handle_doc = format!(
+        "Calls the methods of [`{}`] on a `NodeReplicated` instance, on behalf \
+         of a registered thread (generated by `#[replicated]`).",
+        ty_name
+    );
+
+    Ok(quote! {
+        #block
+
+        #[doc = #read_doc]
+        #vis enum #read_op<'rop> {
+            #(#read_variants,)*
+            #[doc(hidden)]
+            __Lifetime(
+                ::core::marker::PhantomData<&'rop ()>,
+                ::core::convert::Infallible,
+            ),
+        }
+
+        #[doc = #write_doc]
+        #[derive(Clone, PartialEq)]
+        #vis enum #write_op {
+            #(#write_variants,)*
+        }
+
+        #[doc = #response_doc]
+        #[derive(Clone)]
+        #vis enum #response {
+            #(#response_variants,)*
+        }
+
+        impl ::node_replication::nr::Dispatch for #self_ty {
+            type ReadOperation<'rop> = #read_op<'rop>;
+            type WriteOperation = #write_op;
+            type Response = #response;
+
+            fn dispatch<'rop>(&self, op: Self::ReadOperation<'rop>) -> Self::Response {
+                match op {
+                    #(#read_arms,)*
+                    #read_op::__Lifetime(_, never) => match never {},
+                }
+            }
+
+            fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+                match op {
+                    #(#write_arms,)*
+                }
+            }
+        }
+
+        #[doc = #handle_doc]
+        #vis struct #handle<N> {
+            nr: N,
+            tkn: ::node_replication::nr::ThreadToken,
+        }
+
+        impl<N> #handle<N>
+        where
+            N: ::core::ops::Deref<Target = ::node_replication::nr::NodeReplicated<#self_ty>>,
+        {
+            /// Creates a handle that executes operations on `nr` with `tkn`
+            /// (see `NodeReplicated::register`).
+            #vis fn new(nr: N, tkn: ::node_replication::nr::ThreadToken) -> Self {
+                Self { nr, tkn }
+            }
+
+            /// Returns the token the handle executes operations with.
+            #vis fn token(&self) -> ::node_replication::nr::ThreadToken {
+                self.tkn
+            }
+
+            #(#read_methods)*
+            #(#write_methods)*
+        }
+    })
+}
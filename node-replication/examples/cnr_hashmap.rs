@@ -4,9 +4,10 @@
 
 //! A minimal example that implements a replicated hashmap
 use chashmap::CHashMap as HashMap;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 
-use node_replication::cnr::{Dispatch, Log, LogMapper, LogMetaData, Replica};
+use node_replication::cnr::{Dispatch, LogMapper, NodeReplicated};
 
 /// The node-replicated hashmap uses a std hashmap internally.
 #[derive(Default)]
@@ -21,8 +22,10 @@ enum Modify {
 }
 
 impl LogMapper for Modify {
-    fn hash(&self, _nlogs: usize, logs: &mut Vec<usize>) {
-        logs.push(0);
+    fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+        match self {
+            Modify::Put(key, _value) => logs.push(*key as usize % nlogs),
+        }
     }
 }
 
@@ -33,8 +36,10 @@ enum Access {
 }
 
 impl LogMapper for Access {
-    fn hash(&self, _nlogs: usize, logs: &mut Vec<usize>) {
-        logs.push(0);
+    fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+        match self {
+            Access::Get(key) => logs.push(*key as usize % nlogs),
+        }
     }
 }
 
@@ -61,29 +66,23 @@ impl Dispatch for NrHashMap {
     }
 }
 
-/// We initialize a log, and two replicas for a hashmap, register with the replica
-/// and then execute operations.
+/// We create two replicas of the hashmap (with two logs), register threads
+/// with the replicas and then execute operations.
 fn main() {
-    // The operation log for storing `WriteOperation`, it has a size of 2 MiB:
-    let log = Arc::new(
-        Log::<<NrHashMap as Dispatch>::WriteOperation>::new_with_bytes(
-            2 * 1024 * 1024,
-            LogMetaData::new(1),
-        ),
-    );
-
-    // Next, we create two replicas of the hashmap
-    let replica1 = Replica::<NrHashMap>::new(vec![log.clone()]);
-    let replica2 = Replica::<NrHashMap>::new(vec![log.clone()]);
+    // Next, we create two replicas of the hashmap, the operations are
+    // distributed over two logs (by their key, see the `LogMapper` impls):
+    let replicas = NonZeroUsize::new(2).unwrap();
+    let logs = NonZeroUsize::new(2).unwrap();
+    let nrht = Arc::new(NodeReplicated::<NrHashMap>::new(replicas, logs, |_rid| 0).unwrap());
 
     // The replica executes a Modify or Access operations by calling
     // `execute_mut` and `execute`. Eventually they end up in the `Dispatch` trait.
-    let thread_loop = |replica: &Arc<Replica<NrHashMap>>, ridx| {
+    let thread_loop = |replica: &NodeReplicated<NrHashMap>, ttkn| {
         for i in 0..2048 {
             let _r = match i % 2 {
-                0 => replica.execute_mut(Modify::Put(i, i + 1), ridx),
+                0 => replica.execute_mut(Modify::Put(i, i + 1), ttkn),
                 1 => {
-                    let response = replica.execute(Access::Get(i - 1), ridx);
+                    let response = replica.execute(Access::Get(i - 1), ttkn);
                     assert_eq!(response, Some(i));
                     response
                 }
@@ -93,24 +92,15 @@ fn main() {
     };
 
     // Finally, we spawn three threads that issue operations, thread 1 and 2
-    // will use replica1 and thread 3 will use replica 2:
+    // will use replica 0 and thread 3 will use replica 1:
     let mut threads = Vec::with_capacity(3);
-    let replica11 = replica1.clone();
-    threads.push(std::thread::spawn(move || {
-        let ridx = replica11.register().expect("Unable to register with log");
-        thread_loop(&replica11, ridx);
-    }));
-
-    let replica12 = replica1.clone();
-    threads.push(std::thread::spawn(move || {
-        let ridx = replica12.register().expect("Unable to register with log");
-        thread_loop(&replica12, ridx);
-    }));
-
-    threads.push(std::thread::spawn(move || {
-        let ridx = replica2.register().expect("Unable to register with log");
-        thread_loop(&replica2, ridx);
-    }));
+    for rid in [0, 0, 1] {
+        let nrht = nrht.clone();
+        threads.push(std::thread::spawn(move || {
+            let ttkn = nrht.register(rid).expect("Unable to register with replica");
+            thread_loop(&nrht, ttkn);
+        }));
+    }
 
     // Wait for all the threads to finish
     for thread in threads {
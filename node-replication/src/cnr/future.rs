@@ -0,0 +1,198 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Futures returned by the async interface of [`Replica`].
+//!
+//! Instead of spinning until a response is available, the futures enqueue
+//! their operation in the thread's context, try to combine once and then
+//! return [`Poll::Pending`]. The combiner of the log wakes them up once it has
+//! written their response (or, for reads and operations that couldn't be
+//! enqueued yet, once it releases the combiner lock).
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::replica::unwrap_validated;
+use super::{Dispatch, Replica, ReplicaToken};
+
+/// Future for [`Replica::async_execute_mut`] and the scan operations that go
+/// through the thread's context.
+pub(crate) struct ExecuteMutFuture<'a, D>
+where
+    D: Dispatch + Sized + Sync,
+{
+    replica: &'a Replica<D>,
+    idx: ReplicaToken,
+    /// The operation, until it is enqueued in the thread context.
+    op: Option<<D as Dispatch>::WriteOperation>,
+    /// The log the operation maps to (the first log for scans).
+    hash: usize,
+    is_scan: bool,
+    is_read_op: bool,
+    /// Slot of the operation in the thread context, once it is enqueued.
+    slot: Option<usize>,
+}
+
+impl<'a, D> ExecuteMutFuture<'a, D>
+where
+    D: Dispatch + Sized + Sync,
+{
+    pub(crate) fn new(
+        replica: &'a Replica<D>,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        hash: usize,
+        is_scan: bool,
+        is_read_op: bool,
+    ) -> Self {
+        Self {
+            replica,
+            idx,
+            op: Some(op),
+            hash,
+            is_scan,
+            is_read_op,
+            slot: None,
+        }
+    }
+}
+
+/// We never hand out pinned references to any of the fields.
+impl<D> Unpin for ExecuteMutFuture<'_, D> where D: Dispatch + Sized + Sync {}
+
+impl<D> Future for ExecuteMutFuture<'_, D>
+where
+    D: Dispatch + Sized + Sync,
+{
+    type Output = <D as Dispatch>::Response;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let replica = this.replica;
+
+        let slot = match this.slot {
+            Some(slot) => slot,
+            None => {
+                let op = this.op.take().expect("Polled after completion");
+                match replica.poll_enqueue(
+                    op,
+                    this.idx,
+                    this.hash,
+                    this.is_scan,
+                    this.is_read_op,
+                    cx.waker(),
+                ) {
+                    Ok(slot) => {
+                        this.slot = Some(slot);
+                        slot
+                    }
+                    Err(op) => {
+                        // Another operation of this thread is still waiting
+                        // for a combiner (or the context is full with
+                        // responses), we're woken up once it made progress.
+                        this.op = Some(op);
+                        return Poll::Pending;
+                    }
+                }
+            }
+        };
+
+        if let Some(resp) = replica.poll_response(this.idx, slot, cx.waker()) {
+            this.slot = None;
+            return Poll::Ready(unwrap_validated::<D>(resp));
+        }
+
+        replica.try_combine(this.idx.0, this.hash);
+        match replica.poll_response(this.idx, slot, cx.waker()) {
+            Some(resp) => {
+                this.slot = None;
+                Poll::Ready(unwrap_validated::<D>(resp))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<D> Drop for ExecuteMutFuture<'_, D>
+where
+    D: Dispatch + Sized + Sync,
+{
+    /// An operation can't be retracted once it is enqueued. If the future is
+    /// dropped before it completed, its response is discarded once it
+    /// arrives.
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            self.replica.abandon(self.idx, slot);
+        }
+    }
+}
+
+/// Future for [`Replica::async_execute`] (and [`Replica::async_execute_scan`]
+/// with a single log).
+pub(crate) struct ExecuteFuture<'a, D, F>
+where
+    D: Dispatch + Sized + Sync,
+{
+    replica: &'a Replica<D>,
+    idx: ReplicaToken,
+    /// The log the operation maps to.
+    hash: usize,
+    /// Executes the operation, until the replica is synced with the log.
+    read: Option<F>,
+    /// The completed tail of the log at the time we were first polled, the
+    /// replica has to be synced up to this point before we can read.
+    ctail: Option<usize>,
+}
+
+impl<'a, D, F> ExecuteFuture<'a, D, F>
+where
+    D: Dispatch + Sized + Sync,
+    F: FnOnce(&D) -> <D as Dispatch>::Response,
+{
+    pub(crate) fn new(replica: &'a Replica<D>, idx: ReplicaToken, hash: usize, read: F) -> Self {
+        Self {
+            replica,
+            idx,
+            hash,
+            read: Some(read),
+            ctail: None,
+        }
+    }
+}
+
+/// We never hand out pinned references to any of the fields.
+impl<D, F> Unpin for ExecuteFuture<'_, D, F> where D: Dispatch + Sized + Sync {}
+
+impl<D, F> Future for ExecuteFuture<'_, D, F>
+where
+    D: Dispatch + Sized + Sync,
+    F: FnOnce(&D) -> <D as Dispatch>::Response,
+{
+    type Output = <D as Dispatch>::Response;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let replica = this.replica;
+        let hash = this.hash;
+        let ctail = *this.ctail.get_or_insert_with(|| replica.get_ctail(hash));
+
+        let read = this.read.take().expect("Polled after completion");
+        let read = match replica.try_execute_synced(hash, ctail, read) {
+            Ok(resp) => return Poll::Ready(resp),
+            Err(read) => read,
+        };
+
+        replica.register_reader(cx.waker());
+        replica.try_combine(this.idx.0, hash);
+        match replica.try_execute_synced(hash, ctail, read) {
+            Ok(resp) => Poll::Ready(resp),
+            Err(read) => {
+                // Another thread is the combiner and is busy applying the log
+                // to our replica, it wakes us up once it's done.
+                this.read = Some(read);
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -1,14 +1,13 @@
 // Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use core::default::Default;
 use core::hint::spin_loop;
-use core::ops::FnMut;
+use core::ops::{Fn, FnMut};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crossbeam_utils::CachePadded;
@@ -19,10 +18,15 @@ pub use crate::log::MAX_REPLICAS_PER_LOG;
 pub use crate::log::LogToken;
 pub use crate::log::GC_FROM_HEAD;
 pub use crate::log::WARN_THRESHOLD;
+use crate::replica::{token_mismatch, ReplicaId};
 
-/// Callback function which indicates which replicas need to be advanced for GC
-/// to make progress.
-type CallbackFn = dyn FnMut(&[AtomicBool; MAX_REPLICAS_PER_LOG], usize);
+/// A global unique id for each log, the `idx` the log's [`LogMetaData`] is
+/// created with.
+pub type LogId = usize;
+
+/// Callback which is notified about a replica that needs to be advanced for GC
+/// to make progress (see [`Log::on_lagging_replica`]).
+type LaggingReplicaFn = dyn Fn(ReplicaId, LogId) + Send + Sync;
 
 /// The meta-data we need to store in the log entries to make scan (multi-log)
 /// operations works.
@@ -48,43 +52,60 @@ pub struct EntryMetaData {
 /// The additional meta-data we need to store in the log for CNR operations.
 pub struct LogMetaData {
     /// A global unique id for each log.
-    pub(crate) idx: usize,
+    pub(crate) idx: LogId,
 
     /// Use to append scan op atomically to all the logs.
     pub(crate) scanlock: CachePadded<AtomicUsize>,
 
-    /// The application can provide a callback function to the log. The function is invoked
-    /// when one or more replicas lag and stop the log to garbage collected the entries.
-    /// One example of a callback function is to notify the application with a lagging
-    /// replica number for this log. The application can then take action to start the log
-    /// consumption on that replica. If the application is proactively taking measures to
-    /// consume the log on all the replicas, then the variable can be initialized to default.
-    gc: UnsafeCell<Box<CallbackFn>>,
+    /// The callback registered with [`Log::on_lagging_replica`] (if any). It is
+    /// invoked when one or more replicas lag and stop the log from garbage
+    /// collecting entries.
+    ///
+    /// Only accessed while holding `on_lagging_lock`.
+    on_lagging: UnsafeCell<Option<Arc<LaggingReplicaFn>>>,
+
+    /// Protects `on_lagging`, so the callback can be replaced while the log is
+    /// in use.
+    on_lagging_lock: AtomicBool,
 
     /// Check if the log can issue a GC callback; reset
     /// after the GC is done in `advance_head` function.
     notify_replicas: CachePadded<AtomicBool>,
 
-    /// Use this array in GC callback function to notify other replicas to make progress.
-    /// Assumes that the callback handler clears the replica-ids which need to do GC.
+    /// The replicas which lag behind, set before the GC callback is invoked
+    /// and cleared once a replica was reported to the callback.
     dormant_replicas: [AtomicBool; MAX_REPLICAS_PER_LOG],
 }
 
 impl LogMetaData {
-    pub fn new(idx: usize) -> Self {
+    pub fn new(idx: LogId) -> Self {
         #[allow(clippy::declare_interior_mutable_const)]
         const DORMANT_DEFAULT: AtomicBool = AtomicBool::new(false);
 
         Self {
             idx,
             scanlock: CachePadded::new(AtomicUsize::new(0)),
-            gc: UnsafeCell::new(Box::new(
-                |_rid: &[AtomicBool; MAX_REPLICAS_PER_LOG], _lid: usize| {},
-            )),
+            on_lagging: UnsafeCell::new(None),
+            on_lagging_lock: AtomicBool::new(false),
             notify_replicas: CachePadded::new(AtomicBool::new(true)),
             dormant_replicas: [DORMANT_DEFAULT; MAX_REPLICAS_PER_LOG],
         }
     }
+
+    /// Runs `f` on the GC callback while holding `on_lagging_lock`.
+    fn with_on_lagging<R>(&self, f: impl FnOnce(&mut Option<Arc<LaggingReplicaFn>>) -> R) -> R {
+        while self
+            .on_lagging_lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        // Safety: We hold the lock, so we have exclusive access to the callback.
+        let r = f(unsafe { &mut *self.on_lagging.get() });
+        self.on_lagging_lock.store(false, Ordering::Release);
+        r
+    }
 }
 
 impl Default for LogMetaData {
@@ -112,6 +133,7 @@ where
         idx: &LogToken,
         mut s: F,
     ) {
+        self.check_token(idx).unwrap_or_else(|e| token_mismatch(e));
         let nops = ops.len();
         let mut iteration = 1;
         let mut waitgc = 1;
@@ -159,12 +181,7 @@ where
                         Ordering::Relaxed,
                     ) == Ok(true)
                 {
-                    unsafe {
-                        (*self.metadata.gc.get())(
-                            &self.metadata.dormant_replicas,
-                            self.metadata.idx,
-                        )
-                    };
+                    self.notify_lagging_replicas();
                 }
             }
 
@@ -485,16 +502,34 @@ where
         }
     }
 
-    /// The application calls this function to update the callback function.
-    /// The application does not need to call this function if it knows that all
-    /// the replicas are active for this log and no replica will lag behind.
+    /// Registers a callback which is notified about replicas that lag behind
+    /// on this log.
+    ///
+    /// Entries can only be garbage collected once every replica consumed
+    /// them. A replica whose threads are idle (or busy with other logs) never
+    /// consumes this log, so it eventually runs full and appends on every
+    /// other replica wait for it forever. To prevent that, an appending thread
+    /// which finds replicas more than a third of the log behind invokes `f`
+    /// once for each of them with the [`ReplicaId`] of the replica (its index
+    /// in this log, in the order replicas registered, starting at 0) and the
+    /// [`LogId`] of this log. The callback is then expected to make the
+    /// replica consume the log, e.g., with
+    /// [`Replica::sync_log`](super::Replica::sync_log).
+    ///
+    /// `f` is invoked on the appending thread, while it holds the combiner
+    /// lock of its own replica, and isn't invoked again until the head of the
+    /// log advanced. [`Replica::sync_on_lagging`](super::Replica::sync_on_lagging)
+    /// registers a callback which does this for a set of replicas.
+    ///
+    /// Registering a callback replaces the previous one. This is safe to do
+    /// while the log is in use; an append which is already notifying replicas
+    /// might still invoke the previous callback.
     ///
     /// # Example
     ///
     /// ```
     /// use node_replication::cnr::Log;
     /// use node_replication::cnr::LogMetaData;
-    /// use core::sync::atomic::AtomicBool;
     ///
     /// // Operation type that will go onto the log.
     /// #[derive(Clone)]
@@ -505,19 +540,34 @@ where
     /// }
     ///
     /// // Creates a 1 Mega Byte sized log.
-    /// let mut l = Log::<Operation>::new_with_bytes(1 * 1024 * 1024, LogMetaData::new(1));
+    /// let l = Log::<Operation>::new_with_bytes(1 * 1024 * 1024, LogMetaData::new(1));
     ///
     /// // Update the callback function for the log.
-    /// let callback_func = |rid: &[AtomicBool; 16], idx: usize| {
-    ///     // Take action on log `idx` and replicas in `rid`.
-    /// };
-    /// l.update_closure(callback_func)
+    /// l.on_lagging_replica(|rid, log_id| {
+    ///     // Make replica `rid` consume log `log_id`.
+    /// });
     /// ```
-    pub fn update_closure(
-        &mut self,
-        gc: impl FnMut(&[AtomicBool; MAX_REPLICAS_PER_LOG], usize) + 'static,
-    ) {
-        unsafe { *self.metadata.gc.get() = Box::new(gc) };
+    pub fn on_lagging_replica(&self, f: impl Fn(ReplicaId, LogId) + Send + Sync + 'static) {
+        let f: Arc<LaggingReplicaFn> = Arc::new(f);
+        // Drop the previous callback outside of the lock.
+        let _prev = self.metadata.with_on_lagging(|cb| cb.replace(f));
+    }
+
+    /// Reports the replicas marked as dormant to the callback registered with
+    /// [`Log::on_lagging_replica`].
+    fn notify_lagging_replicas(&self) {
+        // Don't hold the lock while calling out, the callback might take a
+        // while and it's fine to call a callback which was just replaced.
+        let f = match self.metadata.with_on_lagging(|cb| cb.clone()) {
+            Some(f) => f,
+            None => return,
+        };
+
+        for (rid, dormant) in self.metadata.dormant_replicas.iter().enumerate() {
+            if dormant.swap(false, Ordering::Relaxed) {
+                f(rid, self.metadata.idx);
+            }
+        }
     }
 }
 
@@ -916,7 +966,7 @@ mod tests {
         assert_eq!(l.slog.len(), total_entries);
 
         // Intentionally not using `register()`, (will fail the test due to GC).
-        let tkn = LogToken(1);
+        let tkn = LogToken(1, l.instance);
 
         let o1 = [(Arc::new(Operation::Read), 1, false)];
         let o2 = [(Arc::new(Operation::Read), 1, false)];
@@ -946,6 +996,31 @@ mod tests {
         assert_eq!(Arc::strong_count(&o2[0].0), total_entries + 1);
     }
 
+    // Tests that the callback registered with on_lagging_replica() is notified
+    // (once) about a replica which doesn't consume the log.
+    #[test]
+    fn test_log_on_lagging_replica() {
+        let l = Log::<Operation>::new_with_entries(16384, LogMetaData::new(7));
+        let one = l.register().unwrap();
+        let two = l.register().unwrap();
+        let three = l.register().unwrap();
+
+        let lagging = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let lagging2 = lagging.clone();
+        l.on_lagging_replica(move |rid, log_id| lagging2.lock().unwrap().push((rid, log_id)));
+
+        let o = [(Operation::Read, 1, false)];
+        for _ in 0..l.slog.len() / 2 {
+            l.append(&o, &one, |_o: Operation, _i: usize, _, _, _, _| -> bool {
+                true
+            });
+            l.exec(&one, &mut |_o: Operation, _i: usize, _, _, _, _| true);
+            l.exec(&three, &mut |_o: Operation, _i: usize, _, _, _, _| true);
+        }
+
+        assert_eq!(*lagging.lock().unwrap(), [(two.0 - 1, 7)]);
+    }
+
     // Tests that is_replica_synced_for_read() works correctly; it returns
     // false when a replica is not synced up and true when it is.
     #[test]
@@ -955,8 +1030,8 @@ mod tests {
         let one = l.register().unwrap();
         let two = l.register().unwrap();
 
-        assert_eq!(one, LogToken(1));
-        assert_eq!(two, LogToken(2));
+        assert_eq!(one, LogToken(1, l.instance));
+        assert_eq!(two, LogToken(2, l.instance));
 
         let o = [(Operation::Read, 1, false)];
         let mut f = |op: Operation, i: usize, _, _, _, _| -> bool {
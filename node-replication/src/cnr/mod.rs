@@ -96,16 +96,33 @@
 //! ```
 
 mod context;
+#[cfg(feature = "async")]
+mod future;
 mod log;
 mod replica;
 
 pub use crate::log::MAX_REPLICAS_PER_LOG;
+pub use crate::nr::{AffinityChange, NodeReplicatedError, ReplicaId, ThreadToken};
 pub use crate::replica::ReplicaToken;
-pub use log::{EntryMetaData, Log, LogMetaData};
+pub use log::{EntryMetaData, Log, LogId, LogMetaData};
 pub use replica::{Replica, MAX_THREADS_PER_REPLICA};
 
-use alloc::vec::Vec;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+// Public for the code generated by `#[derive(LogMapper)]`:
+#[doc(hidden)]
+pub use alloc::vec::Vec;
 use core::fmt::Debug;
+use core::hash::{Hash, Hasher};
+use core::num::NonZeroUsize;
+
+#[cfg(feature = "async")]
+use crate::nr::reusable_box::ReusableBoxFuture;
+use crate::nr::AffinityManager;
+use crate::replica::{token_mismatch, InstanceId, TokenMismatch};
+
+#[cfg(feature = "macros")]
+pub use node_replication_macros::LogMapper;
 
 /// Every data structure must implement [`LogMapper`] trait for
 /// [`Dispatch::ReadOperation`] and [`Dispatch::WriteOperation`].
@@ -121,11 +138,112 @@ use core::fmt::Debug;
 ///
 /// When the replica calls `hash`, the implementor can assume that the capacity
 /// of `logs` >= `nlogs` and that `logs` is empty.
+///
+/// # Deriving
+/// With the `macros` feature, `#[derive(LogMapper)]` implements the trait for
+/// operations from a `#[log(...)]` attribute on each variant of an enum (or on
+/// a struct):
+///
+/// - `#[log(key = field)]`: Maps the operation to the log of `field` (a name, or
+///   an index for tuple variants), see [`log_for_key`].
+/// - `#[log(all)]`: Maps the operation to every log (e.g., for scans).
+/// - `#[log(fixed = n)]`: Maps the operation to log `n` (modulo the number of
+///   logs).
+///
+/// A `#[log(...)]` attribute on an enum applies to all variants which don't
+/// have their own.
+///
+/// ```rust
+/// use node_replication::cnr::LogMapper;
+///
+/// #[derive(Debug, PartialEq, Clone, LogMapper)]
+/// pub enum Modify {
+///     #[log(key = 0)]
+///     Put(usize, usize),
+///     #[log(key = key)]
+///     Remove { key: usize },
+///     #[log(all)]
+///     Clear,
+///     #[log(fixed = 1)]
+///     Compact,
+/// }
+///
+/// let mut logs = Vec::with_capacity(4);
+/// Modify::Put(6, 1).hash(4, &mut logs);
+/// Modify::Remove { key: 7 }.hash(4, &mut logs);
+/// Modify::Clear.hash(4, &mut logs);
+/// Modify::Compact.hash(4, &mut logs);
+/// assert_eq!(logs, vec![2, 3, 0, 1, 2, 3, 1]);
+/// ```
 pub trait LogMapper {
     /// Method to convert the operation and it's arguments to a log number.
     fn hash(&self, nlogs: usize, logs: &mut Vec<usize>);
 }
 
+/// Maps `key` to one of `nlogs` logs (used for `#[log(key = ...)]` by
+/// `#[derive(LogMapper)]`).
+///
+/// Integer keys are mapped to `key % nlogs` (as hand-written [`LogMapper`]
+/// implementations usually do), other keys are hashed first. The result is the
+/// same for equal keys on all replicas.
+///
+/// # Example
+/// ```rust
+/// use node_replication::cnr::log_for_key;
+///
+/// assert_eq!(log_for_key(&7usize, 4), 3);
+/// assert_eq!(log_for_key("a", 4), log_for_key(&String::from("a"), 4));
+/// ```
+pub fn log_for_key<K: Hash + ?Sized>(key: &K, nlogs: usize) -> usize {
+    let mut hasher = KeyHasher(0);
+    key.hash(&mut hasher);
+    (hasher.finish() % nlogs as u64) as usize
+}
+
+/// A deterministic hasher (FNV-1a for bytes) which keeps the value of a single
+/// integer written to it (see [`log_for_key`]).
+struct KeyHasher(u64);
+
+impl KeyHasher {
+    const PRIME: u64 = 0x100000001b3;
+
+    fn write_int(&mut self, i: u64) {
+        self.0 = self.0.wrapping_mul(Self::PRIME) ^ i;
+    }
+}
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write_int(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write_int(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write_int(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write_int(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_int(i as u64);
+    }
+}
+
 /// Trait that a data structure must implement to be usable with this library.
 ///
 /// When this library executes a read-only operation against the data structure,
@@ -137,6 +255,9 @@ pub trait Dispatch {
     /// A read-only operation. When executed against the data structure, an
     /// operation of this type must not mutate the data structure in anyway.
     /// Otherwise, the assumptions made by this library no longer hold.
+    ///
+    /// # For feature `async`
+    /// - `async_execute` needs it to be [`Send`]
     type ReadOperation<'a>: Sized + LogMapper;
 
     /// A write operation. When executed against the data structure, an
@@ -148,6 +269,13 @@ pub trait Dispatch {
     /// `ReadOperation` or a `WriteOperation` successfully executes against it.
     type Response: Sized + Clone;
 
+    /// The error returned for a `WriteOperation` that was rejected by
+    /// [`Dispatch::validate`].
+    ///
+    /// Defaults to [`Infallible`](core::convert::Infallible) for data
+    /// structures which don't validate their operations.
+    type Error: Sized + Clone = core::convert::Infallible;
+
     /// Method on the data structure that allows a read-only operation to be
     /// executed against it.
     fn dispatch(&self, op: Self::ReadOperation<'_>) -> Self::Response;
@@ -155,4 +283,736 @@ pub trait Dispatch {
     /// Method on the data structure that allows a write operation to be
     /// executed against it.
     fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response;
+
+    /// Checks whether a write operation should be executed at all.
+    ///
+    /// The combiner of a log calls this on its local replica for every
+    /// (non read-only) operation it collects, before appending the operations
+    /// to the log. A rejected operation never reaches the log and its error is
+    /// returned to the caller of [`Replica::execute_mut_checked`] or
+    /// [`Replica::execute_mut_scan_checked`]; the other `execute*` methods
+    /// panic if their operation is rejected.
+    ///
+    /// # Note
+    /// The local replica isn't necessarily up-to-date with the logs, so the
+    /// check should only rely on state that can't be invalidated by concurrent
+    /// operations (or be fine with a stale view).
+    ///
+    /// The default implementation accepts every operation.
+    fn validate(&self, _op: &Self::WriteOperation) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The "main" type of CNR which users interact with, the equivalent of
+/// [`crate::nr::NodeReplicated`].
+///
+/// It wraps a concurrent data-structure that implements [`Dispatch`]. It
+/// allocates a configurable number of [`Log`]s and [`Replica`]s, hands out
+/// [`ThreadToken`]s for threads that want to interact with the data-structure
+/// and routes their operations to the correct replica. It handles liveness by
+/// advancing replicas which fall behind on any of the logs (and therefore
+/// prevent garbage collection of the log) automatically.
+///
+/// # Note
+/// Every replica reserves one of its [`MAX_THREADS_PER_REPLICA`] thread slots
+/// to advance it when it falls behind.
+///
+/// Unlike [`crate::nr::NodeReplicated`], the capacity limits are not
+/// configurable: CNR always uses [`MAX_REPLICAS_PER_LOG`] replicas per log and
+/// [`MAX_THREADS_PER_REPLICA`] threads per replica.
+///
+/// # Example
+///
+/// ```
+/// #![feature(generic_associated_types)]
+/// use core::num::NonZeroUsize;
+/// use core::sync::atomic::{AtomicUsize, Ordering};
+/// use node_replication::cnr::{Dispatch, LogMapper, NodeReplicated};
+///
+/// #[derive(Default)]
+/// struct Counters([AtomicUsize; 4]);
+///
+/// #[derive(Debug, PartialEq, Clone)]
+/// enum Op {
+///     Inc(usize),
+///     Sum,
+/// }
+///
+/// #[derive(Debug, PartialEq, Clone)]
+/// struct Get(usize);
+///
+/// impl LogMapper for Get {
+///     fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+///         logs.push(self.0 % nlogs);
+///     }
+/// }
+///
+/// impl LogMapper for Op {
+///     fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+///         match self {
+///             Op::Inc(c) => logs.push(*c % nlogs),
+///             Op::Sum => logs.extend(0..nlogs),
+///         }
+///     }
+/// }
+///
+/// impl Dispatch for Counters {
+///     type ReadOperation<'rop> = Get;
+///     type WriteOperation = Op;
+///     type Response = usize;
+///
+///     fn dispatch<'rop>(&self, op: Self::ReadOperation<'rop>) -> Self::Response {
+///         self.0[op.0].load(Ordering::Relaxed)
+///     }
+///
+///     fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+///         match op {
+///             Op::Inc(c) => self.0[c].fetch_add(1, Ordering::Relaxed) + 1,
+///             Op::Sum => self.0.iter().map(|c| c.load(Ordering::Relaxed)).sum(),
+///         }
+///     }
+/// }
+///
+/// let replicas = NonZeroUsize::new(2).unwrap();
+/// let logs = NonZeroUsize::new(4).unwrap();
+/// let cnr = NodeReplicated::<Counters>::new(replicas, logs, |_| 0).unwrap();
+///
+/// let ttkn = cnr.register(0).unwrap();
+/// assert_eq!(cnr.execute_mut(Op::Inc(1), ttkn), 1);
+/// assert_eq!(cnr.execute_mut(Op::Inc(2), ttkn), 1);
+///
+/// let ttkn = cnr.register(1).unwrap();
+/// assert_eq!(cnr.execute(Get(1), ttkn), 1);
+/// assert_eq!(cnr.execute_scan(Op::Sum, ttkn), 2);
+/// ```
+pub struct NodeReplicated<D: Dispatch + Sync> {
+    /// The logs, operations are mapped to them with [`LogMapper`].
+    logs: Vec<Arc<Log<D::WriteOperation>>>,
+    /// The replicas, indexed by [`ReplicaId`].
+    replicas: Vec<Arc<Replica<D>>>,
+    /// Unique id of the instance, embedded in the [`ThreadToken`]s it issues.
+    instance: InstanceId,
+}
+
+impl<D> NodeReplicated<D>
+where
+    D: Default + Dispatch + Sized + Sync + 'static,
+    Replica<D>: Send,
+{
+    /// Creates a new, replicated data-structure from a concurrent
+    /// data-structure that implements [`Dispatch`]. It uses the [`Default`]
+    /// constructor to create a initial data-structure for `D` on all replicas.
+    ///
+    /// # Arguments
+    /// - `num_replicas`: How many replicas you want to create. Typically the
+    ///   number of NUMA nodes in your system.
+    /// - `num_logs`: How many logs the operations are distributed over (see
+    ///   [`LogMapper`]).
+    /// - `chg_mem_affinity`: A user-provided function that is called whenever
+    ///   the code operates on a [`Replica`] that is not local to the thread
+    ///   (see [`crate::nr::NodeReplicated::new`] and [`AffinityChange`]).
+    ///
+    /// # Errors
+    /// - [`NodeReplicatedError::TooManyReplicas`] if `num_replicas` exceeds
+    ///   [`MAX_REPLICAS_PER_LOG`].
+    /// - [`NodeReplicatedError::OutOfMemory`] if allocating the replicas
+    ///   fails.
+    pub fn new(
+        num_replicas: NonZeroUsize,
+        num_logs: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+    ) -> Result<Self, NodeReplicatedError> {
+        Self::with_log_size(
+            num_replicas,
+            num_logs,
+            chg_mem_affinity,
+            crate::log::DEFAULT_LOG_BYTES,
+        )
+    }
+
+    /// Same as [`NodeReplicated::new`], but in addition use a non-default size
+    /// (provided in bytes) for each [`Log`].
+    pub fn with_log_size(
+        num_replicas: NonZeroUsize,
+        num_logs: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        log_size: usize,
+    ) -> Result<Self, NodeReplicatedError> {
+        if num_replicas.get() > MAX_REPLICAS_PER_LOG {
+            return Err(NodeReplicatedError::TooManyReplicas);
+        }
+        let affinity_mngr = Arc::new(AffinityManager::new(Box::try_new(chg_mem_affinity)?));
+
+        let mut logs = Vec::new();
+        logs.try_reserve(num_logs.get())?;
+        for log_id in 1..=num_logs.get() {
+            logs.push(Arc::try_new(Log::new_with_bytes(
+                log_size,
+                LogMetaData::new(log_id),
+            ))?);
+        }
+
+        let mut replicas = Vec::new();
+        replicas.try_reserve(num_replicas.get())?;
+        for replica_id in 0..num_replicas.get() {
+            let replica = {
+                // Allocate the replica (and its data) on the proper NUMA node
+                let _aff_tkn = affinity_mngr.switch(replica_id);
+                Replica::<D>::new(logs.clone())
+                // aff_tkn is dropped here
+            };
+            replicas.push(replica);
+        }
+
+        // Make replicas that went idle consume the logs, on their NUMA node.
+        Replica::sync_on_lagging_with(&replicas, move |replica_id, sync| {
+            let _aff_tkn = affinity_mngr.switch(replica_id);
+            sync()
+        })
+        .expect("A new replica has free thread slots");
+
+        Ok(NodeReplicated {
+            logs,
+            replicas,
+            instance: InstanceId::next(),
+        })
+    }
+}
+
+impl<D> NodeReplicated<D>
+where
+    D: Dispatch + Sized + Sync,
+{
+    /// Registers a thread with a given replica in the [`NodeReplicated`]
+    /// data-structure. Returns an Option containing a [`ThreadToken`] if the
+    /// registration was successful. None if the registration failed.
+    ///
+    /// The [`ThreadToken`] is used to identify the thread to issue the
+    /// operation for subsequent [`NodeReplicated::execute`] and
+    /// [`NodeReplicated::execute_mut`] calls.
+    ///
+    /// # Arguments
+    /// - `replica_id`: Which replica the thread should be registered with.
+    ///   This should be less than the `num_replicas` argument provided in the
+    ///   constructor (see [`NodeReplicated::new`]). In most cases,
+    ///   `replica_id` will correspond to the NUMA node that the thread is
+    ///   running on.
+    pub fn register(&self, replica_id: ReplicaId) -> Option<ThreadToken> {
+        let rtkn = self.replicas.get(replica_id)?.register()?;
+        Some(ThreadToken {
+            rid: replica_id,
+            rtkn,
+            nr: self.instance,
+        })
+    }
+
+    /// Returns the number of logs operations are distributed over.
+    pub fn num_logs(&self) -> usize {
+        self.logs.len()
+    }
+
+    /// Returns the unique id of this instance, which is embedded in the
+    /// [`ThreadToken`]s it issues.
+    pub fn instance_id(&self) -> InstanceId {
+        self.instance
+    }
+
+    /// Checks that `tkn` was issued by this instance.
+    ///
+    /// Passing a token to another instance is a bug. It's detected in debug
+    /// builds (and with the `token-checks` feature) and the methods that take
+    /// a [`ThreadToken`] panic, use this method to handle the error instead.
+    pub fn check_token(&self, tkn: ThreadToken) -> Result<(), TokenMismatch> {
+        tkn.nr.check(self.instance)
+    }
+
+    /// Panics if `tkn` wasn't issued by this instance.
+    #[inline(always)]
+    fn check(&self, tkn: ThreadToken) {
+        self.check_token(tkn).unwrap_or_else(|e| token_mismatch(e));
+    }
+
+    /// Executes a mutable operation against the data-structure (on the log
+    /// [`LogMapper::hash`] maps it to).
+    ///
+    /// # Panics
+    /// - If the operation is rejected by [`Dispatch::validate`] (see
+    ///   [`NodeReplicated::execute_mut_checked`]).
+    /// - If `tkn` was issued by another instance (see
+    ///   [`NodeReplicated::check_token`]).
+    pub fn execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> <D as Dispatch>::Response {
+        self.check(tkn);
+        self.replicas[tkn.rid].execute_mut(op, tkn.rtkn)
+    }
+
+    /// Same as [`NodeReplicated::execute_mut`], but returns the error if the
+    /// operation was rejected by [`Dispatch::validate`].
+    ///
+    /// # Panics
+    /// If `tkn` was issued by another instance (see
+    /// [`NodeReplicated::check_token`]).
+    pub fn execute_mut_checked(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> Result<<D as Dispatch>::Response, <D as Dispatch>::Error> {
+        self.check(tkn);
+        self.replicas[tkn.rid].execute_mut_checked(op, tkn.rtkn)
+    }
+
+    /// Executes a mutable operation which depends on all logs (e.g., it
+    /// touches every partition of the data-structure) against the
+    /// data-structure.
+    ///
+    /// # Panics
+    /// - If the operation is rejected by [`Dispatch::validate`] (see
+    ///   [`NodeReplicated::execute_mut_scan_checked`]).
+    /// - If `tkn` was issued by another instance (see
+    ///   [`NodeReplicated::check_token`]).
+    pub fn execute_mut_scan(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> <D as Dispatch>::Response {
+        self.check(tkn);
+        self.replicas[tkn.rid].execute_mut_scan(op, tkn.rtkn)
+    }
+
+    /// Same as [`NodeReplicated::execute_mut_scan`], but returns the error if
+    /// the operation was rejected by [`Dispatch::validate`].
+    ///
+    /// # Panics
+    /// If `tkn` was issued by another instance (see
+    /// [`NodeReplicated::check_token`]).
+    pub fn execute_mut_scan_checked(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> Result<<D as Dispatch>::Response, <D as Dispatch>::Error> {
+        self.check(tkn);
+        self.replicas[tkn.rid].execute_mut_scan_checked(op, tkn.rtkn)
+    }
+
+    /// Executes a read-only operation against the data-structure, once the
+    /// thread's replica is up-to-date with the log the operation maps to.
+    ///
+    /// # Panics
+    /// If `tkn` was issued by another instance (see
+    /// [`NodeReplicated::check_token`]).
+    pub fn execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation<'_>,
+        tkn: ThreadToken,
+    ) -> <D as Dispatch>::Response {
+        self.check(tkn);
+        self.replicas[tkn.rid].execute(op, tkn.rtkn)
+    }
+
+    /// Executes a read-only operation which depends on all logs against the
+    /// data-structure. Like [`Replica::execute_scan`], the operation is passed
+    /// to [`Dispatch::dispatch_mut`] (and must not modify the data-structure).
+    ///
+    /// # Panics
+    /// If `tkn` was issued by another instance (see
+    /// [`NodeReplicated::check_token`]).
+    pub fn execute_scan(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> <D as Dispatch>::Response {
+        self.check(tkn);
+        self.replicas[tkn.rid].execute_scan(op, tkn.rtkn)
+    }
+
+    /// Executes a mutable operation asynchronously against the data-structure,
+    /// and returns the response in `resp`.
+    ///
+    /// See [`Replica::async_execute_mut`].
+    ///
+    /// # Panics
+    /// If `tkn` was issued by another instance (see
+    /// [`NodeReplicated::check_token`]).
+    #[cfg(feature = "async")]
+    pub async fn async_execute_mut<'a>(
+        &'a self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) {
+        self.check(tkn);
+        self.replicas[tkn.rid]
+            .async_execute_mut(op, tkn.rtkn, resp)
+            .await
+    }
+
+    /// Executes a mutable operation which depends on all logs asynchronously
+    /// against the data-structure, and returns the response in `resp`.
+    ///
+    /// See [`Replica::async_execute_mut_scan`].
+    ///
+    /// # Panics
+    /// If `tkn` was issued by another instance (see
+    /// [`NodeReplicated::check_token`]).
+    #[cfg(feature = "async")]
+    pub async fn async_execute_mut_scan<'a>(
+        &'a self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) {
+        self.check(tkn);
+        self.replicas[tkn.rid]
+            .async_execute_mut_scan(op, tkn.rtkn, resp)
+            .await
+    }
+
+    /// Executes a read-only operation asynchronously against the
+    /// data-structure, and returns the response in `resp`.
+    ///
+    /// See [`Replica::async_execute`].
+    ///
+    /// # Panics
+    /// If `tkn` was issued by another instance (see
+    /// [`NodeReplicated::check_token`]).
+    #[cfg(feature = "async")]
+    pub fn async_execute<'a, 'rop: 'a>(
+        &'a self,
+        op: <D as Dispatch>::ReadOperation<'rop>,
+        tkn: ThreadToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) where
+        <D as Dispatch>::ReadOperation<'rop>: Send,
+    {
+        self.check(tkn);
+        self.replicas[tkn.rid].async_execute(op, tkn.rtkn, resp)
+    }
+
+    /// Executes a read-only operation which depends on all logs
+    /// asynchronously against the data-structure, and returns the response in
+    /// `resp`.
+    ///
+    /// See [`Replica::async_execute_scan`].
+    ///
+    /// # Panics
+    /// If `tkn` was issued by another instance (see
+    /// [`NodeReplicated::check_token`]).
+    #[cfg(feature = "async")]
+    pub fn async_execute_scan<'a>(
+        &'a self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) {
+        self.check(tkn);
+        self.replicas[tkn.rid].async_execute_scan(op, tkn.rtkn, resp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// One counter per log, so we can tell which log an operation went to.
+    #[derive(Default)]
+    struct Counters([AtomicUsize; 4]);
+
+    #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+    enum OpWr {
+        Inc(usize),
+        Sum,
+    }
+
+    impl LogMapper for OpWr {
+        fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+            match self {
+                OpWr::Inc(c) => logs.push(*c % nlogs),
+                OpWr::Sum => logs.extend(0..nlogs),
+            }
+        }
+    }
+
+    #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+    struct OpRd(usize);
+
+    impl LogMapper for OpRd {
+        fn hash(&self, nlogs: usize, logs: &mut Vec<usize>) {
+            logs.push(self.0 % nlogs);
+        }
+    }
+
+    impl Dispatch for Counters {
+        type ReadOperation<'rop> = OpRd;
+        type WriteOperation = OpWr;
+        type Response = usize;
+
+        fn dispatch<'rop>(&self, op: Self::ReadOperation<'rop>) -> Self::Response {
+            self.0[op.0].load(Ordering::Relaxed)
+        }
+
+        fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+            match op {
+                OpWr::Inc(c) => self.0[c].fetch_add(1, Ordering::Relaxed) + 1,
+                OpWr::Sum => self.0.iter().map(|c| c.load(Ordering::Relaxed)).sum(),
+            }
+        }
+    }
+
+    fn nz(n: usize) -> NonZeroUsize {
+        NonZeroUsize::new(n).unwrap()
+    }
+
+    /// Operations on different logs and replicas.
+    #[test]
+    fn test_execute() {
+        const NTHREADS: usize = 4;
+        const NOPS: usize = 1000;
+
+        let cnr = Arc::new(NodeReplicated::<Counters>::new(nz(2), nz(4), |_| 0).unwrap());
+        assert_eq!(cnr.num_logs(), 4);
+
+        let mut threads = std::vec::Vec::with_capacity(NTHREADS);
+        for t in 0..NTHREADS {
+            let cnr = cnr.clone();
+            threads.push(thread::spawn(move || {
+                let ttkn = cnr.register(t % 2).unwrap();
+                for i in 0..NOPS {
+                    assert!(cnr.execute_mut(OpWr::Inc(t), ttkn) > i);
+                    assert!(cnr.execute(OpRd(t), ttkn) > i);
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        for rid in 0..2 {
+            let ttkn = cnr.register(rid).unwrap();
+            for c in 0..4 {
+                assert_eq!(cnr.execute(OpRd(c), ttkn), NOPS);
+            }
+            assert_eq!(cnr.execute_scan(OpWr::Sum, ttkn), NTHREADS * NOPS);
+            assert_eq!(cnr.execute_mut_scan(OpWr::Sum, ttkn), NTHREADS * NOPS);
+        }
+    }
+
+    /// A replica without any active threads doesn't prevent the others from
+    /// making progress.
+    #[test]
+    fn test_idle_replica() {
+        const NOPS: usize = 50_000;
+
+        let cnr = NodeReplicated::<Counters>::with_log_size(nz(2), nz(2), |_| 0, 8 * 1024).unwrap();
+        let ttkn = cnr.register(0).unwrap();
+        for i in 0..NOPS {
+            assert_eq!(cnr.execute_mut(OpWr::Inc(i % 2), ttkn), i / 2 + 1);
+        }
+
+        let ttkn = cnr.register(1).unwrap();
+        assert_eq!(cnr.execute(OpRd(0), ttkn), NOPS / 2);
+        assert_eq!(cnr.execute(OpRd(1), ttkn), NOPS / 2);
+    }
+
+    /// A replica which only lags behind on a later log is synced on that log,
+    /// even if the logs don't have unique ids.
+    #[test]
+    fn test_sync_on_lagging_later_log() {
+        const NOPS: usize = 50_000;
+
+        let logs: Vec<_> = (0..3)
+            .map(|_| {
+                Arc::new(Log::<OpWr>::new_with_bytes(
+                    8 * 1024,
+                    LogMetaData::default(),
+                ))
+            })
+            .collect();
+        let replicas = std::vec![
+            Replica::<Counters>::new(logs.clone()),
+            Replica::<Counters>::new(logs),
+        ];
+        Replica::sync_on_lagging(&replicas).unwrap();
+
+        let idx = replicas[0].register().unwrap();
+        for i in 0..NOPS {
+            assert_eq!(replicas[0].execute_mut(OpWr::Inc(2), idx), i + 1);
+        }
+        let idx = replicas[1].register().unwrap();
+        assert_eq!(replicas[1].execute(OpRd(2), idx), NOPS);
+    }
+
+    /// Replicas are created with the memory affinity of the replica.
+    #[test]
+    fn test_affinity() {
+        static SWITCHES: AtomicUsize = AtomicUsize::new(0);
+        static REVERTS: AtomicUsize = AtomicUsize::new(0);
+
+        let _cnr = NodeReplicated::<Counters>::new(nz(3), nz(2), |change| {
+            match change {
+                AffinityChange::Replica(_) => SWITCHES.fetch_add(1, Ordering::Relaxed),
+                AffinityChange::Revert(_) => REVERTS.fetch_add(1, Ordering::Relaxed),
+            };
+            0
+        })
+        .unwrap();
+        assert_eq!(SWITCHES.load(Ordering::Relaxed), 3);
+        assert_eq!(REVERTS.load(Ordering::Relaxed), 3);
+    }
+
+    /// Many async operations of one thread (on different logs) can be
+    /// in-flight at the same time and each future gets the response to its
+    /// own operation.
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_execute() {
+        use crate::nr::reusable_box::ReusableBoxFuture;
+
+        const NFUTURES: usize = 8;
+        const NROUNDS: usize = 100;
+
+        let cnr = NodeReplicated::<Counters>::new(nz(2), nz(4), |_| 0).unwrap();
+        let ttkn = cnr.register(0).unwrap();
+
+        let mut futures: std::vec::Vec<ReusableBoxFuture<usize>> = (0..NFUTURES)
+            .map(|_| ReusableBoxFuture::new(async { 0 }))
+            .collect();
+        for round in 0..NROUNDS {
+            for (c, resp) in futures.iter_mut().enumerate() {
+                cnr.async_execute_mut(OpWr::Inc(c % 4), ttkn, resp).await;
+            }
+            let resps = futures::future::join_all(&mut futures).await;
+            for c in 0..4 {
+                // Each counter was incremented twice in this round.
+                let mut incs: std::vec::Vec<usize> =
+                    resps.iter().skip(c).step_by(4).cloned().collect();
+                incs.sort_unstable();
+                assert_eq!(incs, [2 * round + 1, 2 * round + 2]);
+            }
+        }
+
+        for (c, resp) in futures.iter_mut().enumerate().take(4) {
+            cnr.async_execute(OpRd(c), ttkn, resp);
+        }
+        for resp in futures.iter_mut().take(4) {
+            assert_eq!(resp.await, 2 * NROUNDS);
+        }
+
+        let ttkn = cnr.register(1).unwrap();
+        let mut resp = ReusableBoxFuture::new(async { 0 });
+        cnr.async_execute_scan(OpWr::Sum, ttkn, &mut resp);
+        assert_eq!((&mut resp).await, NFUTURES * NROUNDS);
+        cnr.async_execute_mut_scan(OpWr::Sum, ttkn, &mut resp).await;
+        assert_eq!((&mut resp).await, NFUTURES * NROUNDS);
+        cnr.async_execute(OpRd(3), ttkn, &mut resp);
+        assert_eq!(resp.await, 2 * NROUNDS);
+    }
+
+    /// Async scans with a single log.
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_scan_single_log() {
+        use crate::nr::reusable_box::ReusableBoxFuture;
+
+        let cnr = NodeReplicated::<Counters>::new(nz(2), nz(1), |_| 0).unwrap();
+        let ttkn = cnr.register(0).unwrap();
+        let mut resp = ReusableBoxFuture::new(async { 0 });
+
+        cnr.async_execute_mut(OpWr::Inc(1), ttkn, &mut resp).await;
+        assert_eq!((&mut resp).await, 1);
+        cnr.async_execute_mut_scan(OpWr::Inc(2), ttkn, &mut resp)
+            .await;
+        assert_eq!((&mut resp).await, 1);
+
+        let ttkn = cnr.register(1).unwrap();
+        cnr.async_execute_scan(OpWr::Sum, ttkn, &mut resp);
+        assert_eq!(resp.await, 2);
+    }
+
+    /// Async operations from many threads and replicas.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_threads() {
+        use crate::nr::reusable_box::ReusableBoxFuture;
+        use futures::executor::block_on;
+
+        const NTHREADS: usize = 4;
+        const NFUTURES: usize = 4;
+        const NROUNDS: usize = 500;
+
+        let cnr = Arc::new(NodeReplicated::<Counters>::new(nz(2), nz(4), |_| 0).unwrap());
+        let mut threads = std::vec::Vec::with_capacity(NTHREADS);
+        for t in 0..NTHREADS {
+            let cnr = cnr.clone();
+            threads.push(thread::spawn(move || {
+                let ttkn = cnr.register(t % 2).unwrap();
+                let mut futures: std::vec::Vec<ReusableBoxFuture<usize>> = (0..NFUTURES)
+                    .map(|_| ReusableBoxFuture::new(async { 0 }))
+                    .collect();
+                block_on(async {
+                    for _round in 0..NROUNDS {
+                        for (c, resp) in futures.iter_mut().enumerate() {
+                            cnr.async_execute_mut(OpWr::Inc(c), ttkn, resp).await;
+                        }
+                        futures::future::join_all(&mut futures).await;
+                    }
+                });
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        for rid in 0..2 {
+            let ttkn = cnr.register(rid).unwrap();
+            for c in 0..NFUTURES {
+                assert_eq!(cnr.execute(OpRd(c), ttkn), NTHREADS * NROUNDS);
+            }
+        }
+    }
+
+    /// Too many replicas are rejected.
+    #[test]
+    fn test_too_many_replicas() {
+        assert!(matches!(
+            NodeReplicated::<Counters>::new(nz(MAX_REPLICAS_PER_LOG + 1), nz(1), |_| 0),
+            Err(NodeReplicatedError::TooManyReplicas)
+        ));
+    }
+
+    /// Tokens of another instance are rejected.
+    #[test]
+    #[cfg(any(debug_assertions, feature = "token-checks"))]
+    #[should_panic]
+    fn test_token_mismatch() {
+        let cnr1 = NodeReplicated::<Counters>::new(nz(1), nz(1), |_| 0).unwrap();
+        let cnr2 = NodeReplicated::<Counters>::new(nz(1), nz(1), |_| 0).unwrap();
+        let ttkn = cnr1.register(0).unwrap();
+        cnr2.execute_mut(OpWr::Inc(0), ttkn);
+    }
+
+    /// Rejected tokens can be detected without panicking.
+    #[test]
+    #[cfg(any(debug_assertions, feature = "token-checks"))]
+    fn test_check_token() {
+        let cnr1 = NodeReplicated::<Counters>::new(nz(1), nz(1), |_| 0).unwrap();
+        let cnr2 = NodeReplicated::<Counters>::new(nz(1), nz(1), |_| 0).unwrap();
+        let ttkn = cnr1.register(0).unwrap();
+        assert_eq!(cnr1.check_token(ttkn), Ok(()));
+        assert_eq!(
+            cnr2.check_token(ttkn),
+            Err(TokenMismatch {
+                expected: cnr2.instance_id(),
+                found: cnr1.instance_id(),
+            })
+        );
+    }
 }
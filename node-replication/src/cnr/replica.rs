@@ -7,24 +7,47 @@ use core::cell::RefCell;
 use core::hint::spin_loop;
 use core::intrinsics::unlikely;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "async")]
+use core::{sync::atomic::fence, task::Waker};
 
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 
 use crossbeam_utils::CachePadded;
 
 use super::context::Context;
-use super::log::Log;
+#[cfg(feature = "async")]
+use super::future::{ExecuteFuture, ExecuteMutFuture};
+use super::log::{Log, MAX_REPLICAS_PER_LOG};
 use super::Dispatch;
 use super::LogMapper;
+#[cfg(feature = "async")]
+use crate::context::WakerList;
 
 use crate::log::LogToken;
-use crate::replica::ReplicaToken;
+#[cfg(feature = "async")]
+use crate::nr::reusable_box::ReusableBoxFuture;
 pub use crate::replica::MAX_THREADS_PER_REPLICA;
+use crate::replica::{token_mismatch, FreeList, InstanceId, ReplicaToken};
 
 /// Type that has meta-data about either scan or write op while it's in the log.
 type OperationState<D> = (<D as Dispatch>::WriteOperation, usize, bool);
 
+/// The outcome of a write operation as it is handed back to the issuing thread:
+/// either the operation's response, or the error it was rejected with by
+/// [`Dispatch::validate`].
+pub(crate) type Validated<D> = Result<<D as Dispatch>::Response, <D as Dispatch>::Error>;
+
+/// Returns the response of a write operation, panics if it was rejected.
+#[inline(always)]
+pub(crate) fn unwrap_validated<D: Dispatch>(resp: Validated<D>) -> <D as Dispatch>::Response {
+    match resp {
+        Ok(resp) => resp,
+        Err(_) => panic!("Operation rejected by `Dispatch::validate`, use a `*_checked` method"),
+    }
+}
+
 /// An instance of per log state maintained by each replica.
 pub(self) struct LogState<D>
 where
@@ -107,9 +130,16 @@ pub struct Replica<D>
 where
     D: Sized + Dispatch + Sync,
 {
+    /// Identifies the replica in the [`ReplicaToken`]s it issues.
+    instance: InstanceId,
+
     /// Idx that will be handed out to the next thread that registers with the replica.
     next: CachePadded<AtomicUsize>,
 
+    /// Idxs given back with [`Replica::deregister()`], these are handed out
+    /// again before `next` is incremented.
+    free: FreeList,
+
     /// The underlying replicated data structure. Shared between threads registered
     /// with this replica. Each replica maintains its own copy of the data structure.
     data: CachePadded<D>,
@@ -118,7 +148,7 @@ where
     /// cannot perform flat combining (because another thread might be doing so).
     ///
     /// The vector is initialized with `MAX_THREADS_PER_REPLICA` elements.
-    contexts: Vec<CachePadded<Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>>>,
+    contexts: Vec<CachePadded<Context<<D as Dispatch>::WriteOperation, Validated<D>>>>,
 
     /// It is used to store the log offsets in various logs for scan operations.
     offsets: Vec<RefCell<Vec<usize>>>,
@@ -128,6 +158,11 @@ where
 
     /// An instance of per log state maintained by each replica.
     logstate: Vec<CachePadded<LogState<D>>>,
+
+    /// Tasks waiting for the replica to catch up with a log before they can
+    /// read, woken up whenever a combiner lock is released.
+    #[cfg(feature = "async")]
+    readers: WakerList,
 }
 
 /// The Replica is Sync. Member variables are protected by a CAS on `combiner`.
@@ -254,12 +289,16 @@ where
             let uninit_ptr = Arc::get_mut_unchecked(&mut uninit_replica).as_mut_ptr();
 
             uninit_ptr.write(Replica {
+                instance: InstanceId::next(),
                 next: CachePadded::new(AtomicUsize::new(1)),
+                free: FreeList::new(MAX_THREADS_PER_REPLICA),
                 data: CachePadded::new(d),
                 logstate: Vec::with_capacity(logs.len()),
                 contexts: Vec::with_capacity(MAX_THREADS_PER_REPLICA),
                 offsets: Vec::with_capacity(MAX_THREADS_PER_REPLICA),
                 hash: Vec::with_capacity(MAX_THREADS_PER_REPLICA),
+                #[cfg(feature = "async")]
+                readers: WakerList::default(),
             });
 
             let mut replica = uninit_replica.assume_init();
@@ -362,6 +401,11 @@ where
     /// let idx = replica.register().expect("Failed to register with replica.");
     /// ```
     pub fn register(&self) -> Option<ReplicaToken> {
+        // Reuse the idx of a thread that deregistered, if there is one.
+        if let Some(idx) = self.free.pop() {
+            return Some(ReplicaToken(idx, self.instance));
+        }
+
         // Loop until we either run out of identifiers or we manage to increment `next`.
         loop {
             let idx = self.next.load(Ordering::SeqCst);
@@ -378,8 +422,42 @@ where
                 continue;
             };
 
-            return Some(ReplicaToken(idx));
+            return Some(ReplicaToken(idx, self.instance));
+        }
+    }
+
+    /// Returns the id of the replica, which is embedded in the
+    /// [`ReplicaToken`]s it issues.
+    pub fn instance_id(&self) -> InstanceId {
+        self.instance
+    }
+
+    /// Panics if `idx` wasn't issued by this replica.
+    #[inline(always)]
+    fn check(&self, idx: ReplicaToken) {
+        idx.1
+            .check(self.instance)
+            .unwrap_or_else(|e| token_mismatch(e));
+    }
+
+    /// Deregisters a thread from this replica, `idx` must not be used anymore
+    /// afterwards.
+    ///
+    /// Operations of the thread that are still pending are appended to the
+    /// logs first (responses which weren't retrieved are dropped). Then the
+    /// thread's context is handed out again by a later [`Replica::register`].
+    pub fn deregister(&self, idx: ReplicaToken) {
+        self.check(idx);
+        let context = &self.contexts[idx.0 - 1];
+        while context.has_uncombined() {
+            for hashidx in 0..self.logstate.len() {
+                self.try_combine(idx.0, hashidx);
+            }
+            spin_loop();
         }
+
+        context.discard_responses();
+        self.free.push(idx.0);
     }
 
     /// Executes an mutable operation against this replica and returns a response.
@@ -452,11 +530,30 @@ where
     /// // execute_mut() can be used to write to the replicated data structure.
     /// let res = replica.execute_mut(OpWr(100), idx);
     /// assert_eq!(None, res);
+    /// ```
+    ///
+    /// # Panics
+    /// If the operation is rejected by [`Dispatch::validate`] (see
+    /// [`Replica::execute_mut_checked`]).
     pub fn execute_mut(
         &self,
         op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
+        unwrap_validated::<D>(self.execute_mut_checked(op, idx))
+    }
+
+    /// Same as [`Replica::execute_mut`], but returns the error if the
+    /// operation was rejected by [`Dispatch::validate`].
+    ///
+    /// A rejected operation is not appended to the log and therefore never
+    /// executed on any replica.
+    pub fn execute_mut_checked(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, <D as Dispatch>::Error> {
+        self.check(idx);
         let mut hash_vec = self.hash[idx.0 - 1].borrow_mut();
         hash_vec.clear();
         // Calculate the hash of the operation to map the operation to a log.
@@ -547,17 +644,33 @@ where
     /// // through all the logs.
     /// let res = replica.execute_mut_scan(OpWr(100), idx);
     /// assert_eq!(Some(100), res);
+    /// ```
+    ///
+    /// # Panics
+    /// If the operation is rejected by [`Dispatch::validate`] (see
+    /// [`Replica::execute_mut_scan_checked`]).
     pub fn execute_mut_scan(
         &self,
         op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
+        unwrap_validated::<D>(self.execute_mut_scan_checked(op, idx))
+    }
+
+    /// Same as [`Replica::execute_mut_scan`], but returns the error if the
+    /// operation was rejected by [`Dispatch::validate`].
+    pub fn execute_mut_scan_checked(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, <D as Dispatch>::Error> {
+        self.check(idx);
         let nlogs = self.logstate.len();
 
         // If there is only one log in the system, then execute
         // scan operation as a mutable operations.
         if nlogs == 1 {
-            return self.execute_mut(op, idx);
+            return self.execute_mut_checked(op, idx);
         }
 
         let hash = 0; /* Fake hash; scan op is appended to each log.*/
@@ -572,12 +685,13 @@ where
     }
 
     fn append_scan(&self, op: (<D as Dispatch>::WriteOperation, usize, bool), thread_id: usize) {
-        let mut hash_vec = self.hash[op.1 - 1].borrow_mut();
         let mut entries = self.offsets[thread_id - 1].borrow_mut();
         entries.clear();
 
+        // Not the buffer of the issuer (`op.1`): With the async interface, it
+        // may be computing the logs of its next operation concurrently.
         let nlogs = self.logstate.len();
-        hash_vec.clear();
+        let mut hash_vec = Vec::with_capacity(nlogs);
         op.0.hash(nlogs, &mut hash_vec);
         debug_assert_eq!(hash_vec.len(), nlogs, "Append scan ops to all the logs");
         let root_log = hash_vec[0];
@@ -598,7 +712,7 @@ where
                     } else {
                         let resp = self.data.dispatch_mut(o);
                         if rid == self.logstate[*logidx].idx.0 {
-                            self.contexts[tid - 1].enqueue_resp(resp);
+                            self.contexts[tid - 1].enqueue_resp(Ok(resp));
                         }
                         true
                     }
@@ -706,6 +820,7 @@ where
         op: <D as Dispatch>::ReadOperation<'_>,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
+        self.check(idx);
         let mut hash_vec = self.hash[idx.0 - 1].borrow_mut();
         hash_vec.clear();
         // Calculate the hash of the operation to map the operation to a log.
@@ -805,6 +920,7 @@ where
         op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
     ) -> <D as Dispatch>::Response {
+        self.check(idx);
         let nlogs = self.logstate.len();
 
         // If there is only one log in the system, then execute
@@ -832,13 +948,14 @@ where
         // A thread becomes combiner for operations with hash same as its own operation.
         self.try_combine(idx.0, hash);
 
-        // Return the response to the caller function.
-        self.get_response(idx.0, hash)
+        // Return the response to the caller function, read-only operations
+        // aren't validated.
+        unwrap_validated::<D>(self.get_response(idx.0, hash))
     }
 
     /// Busy waits until a response is available within the thread's context.
     /// `idx` identifies this thread.
-    fn get_response(&self, idx: usize, hash: usize) -> <D as Dispatch>::Response {
+    fn get_response(&self, idx: usize, hash: usize) -> Validated<D> {
         let mut iter = 0;
         let interval = 1 << 29;
 
@@ -904,6 +1021,7 @@ where
     /// in the log and won't be able perform garbage collection because of the inactive
     /// replica. So, this method syncs up the replica against the underlying log.
     pub fn sync(&self, idx: ReplicaToken) {
+        self.check(idx);
         let nlogs = self.logstate.len();
         for i in 0..nlogs {
             let ctail = self.logstate[i].slog.get_ctail();
@@ -923,6 +1041,7 @@ where
     /// No need to run in a loop because the replica will
     /// be synced for log_id if there is an active combiner.
     pub fn sync_log(&self, idx: ReplicaToken, log_id: usize) {
+        self.check(idx);
         let ctail = self.logstate[log_id - 1].slog.get_ctail();
         if !self.logstate[log_id - 1]
             .slog
@@ -954,7 +1073,7 @@ where
 
     /// Appends an operation to the log and attempts to perform flat combining.
     /// Accepts a thread `tid` as an argument. Required to acquire the combiner lock.
-    fn try_combine(&self, tid: usize, hashidx: usize) {
+    pub(crate) fn try_combine(&self, tid: usize, hashidx: usize) {
         // First, check if there already is a flat combiner. If there is no active flat combiner
         // then try to acquire the combiner lock. If there is, then just return.
         for _i in 0..4 {
@@ -988,6 +1107,8 @@ where
         // At this point, we've dropped all mutable references to thread contexts and to
         // the staging buffer as well.
         self.logstate[hashidx].combiner.store(0, Ordering::Release);
+        #[cfg(feature = "async")]
+        self.wake_uncollected();
     }
 
     /// Performs one round of flat combining. Collects, appends and executes operations.
@@ -1017,6 +1138,15 @@ where
                 let ctxt_iter = self.contexts[tid - 1].iter();
                 for (op, (hash, is_scan, is_read_only)) in ctxt_iter {
                     if hash == hashidx {
+                        // Rejected operations never make it to the log, their
+                        // issuer gets the error right away.
+                        if !is_read_only {
+                            if let Err(e) = self.data.validate(&op) {
+                                self.contexts[tid - 1].enqueue_resp(Err(e));
+                                continue;
+                            }
+                        }
+
                         if is_scan {
                             scan_buffer.push((op, tid, is_read_only));
                         } else {
@@ -1041,7 +1171,7 @@ where
                     false => {
                         let resp = self.data.dispatch_mut(o);
                         if rid == self.logstate[hashidx].idx.0 {
-                            self.contexts[tid - 1].enqueue_resp(resp);
+                            self.contexts[tid - 1].enqueue_resp(Ok(resp));
                         }
                         true
                     }
@@ -1075,7 +1205,7 @@ where
                 } else {
                     let resp = self.data.dispatch_mut(o);
                     if rid == self.logstate[hashidx].idx.0 {
-                        self.contexts[tid - 1].enqueue_resp(resp);
+                        self.contexts[tid - 1].enqueue_resp(Ok(resp));
                     };
                     true
                 }
@@ -1126,7 +1256,7 @@ where
             if self.is_replica_sync_for_logs(1, self.logstate.len(), depends_on) {
                 let resp = self.data.dispatch_mut(op);
                 if issuer_rid == self.logstate[hashidx].idx.0 {
-                    self.contexts[issuer_tid - 1].enqueue_resp(resp);
+                    self.contexts[issuer_tid - 1].enqueue_resp(Ok(resp));
                 };
                 true
             } else {
@@ -1171,6 +1301,351 @@ where
     }
 }
 
+impl<D> Replica<D>
+where
+    D: Sized + Dispatch + Sync + 'static,
+    Replica<D>: Send,
+{
+    /// Makes sure `replicas` don't hold back the logs they share when their
+    /// threads go idle.
+    ///
+    /// A replica only consumes a log while one of its threads executes
+    /// operations on it, so an idle replica eventually wedges the writers on
+    /// every other replica. This registers a callback with
+    /// [`Log::on_lagging_replica`] on all logs of `replicas` (which have to be
+    /// the same for all of them) that advances a lagging replica with
+    /// [`Replica::sync_log`].
+    ///
+    /// Registers a thread with each replica to consume the logs. Returns None
+    /// if a replica doesn't have a free thread slot left, the callbacks aren't
+    /// registered in this case.
+    pub fn sync_on_lagging(replicas: &[Arc<Replica<D>>]) -> Option<()> {
+        Self::sync_on_lagging_with(replicas, |_i, sync| sync())
+    }
+
+    /// Same as [`Replica::sync_on_lagging`], but calls `around(i, sync)` to
+    /// sync the replica `replicas[i]` (e.g., to change the memory affinity
+    /// around the call).
+    pub(crate) fn sync_on_lagging_with(
+        replicas: &[Arc<Replica<D>>],
+        around: impl Fn(usize, &dyn Fn()) + Send + Sync + 'static,
+    ) -> Option<()> {
+        let mut tokens = Vec::with_capacity(replicas.len());
+        for replica in replicas.iter() {
+            match replica.register() {
+                Some(rtkn) => tokens.push(rtkn),
+                None => {
+                    for (replica, rtkn) in replicas.iter().zip(tokens) {
+                        replica.deregister(rtkn);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        let around = Arc::new(around);
+        let nlogs = replicas.first().map_or(0, |r| r.logstate.len());
+        for hash in 0..nlogs {
+            let log = &replicas[0].logstate[hash].slog;
+            // The replicas (and their index in `replicas`) by their index in
+            // the log, that's what the log reports lagging replicas with.
+            let mut lagging = vec![None; MAX_REPLICAS_PER_LOG];
+            for (i, (replica, rtkn)) in replicas.iter().zip(tokens.iter()).enumerate() {
+                let logstate = &replica.logstate[hash];
+                debug_assert!(
+                    Arc::ptr_eq(&logstate.slog, log),
+                    "Replicas use different logs"
+                );
+                lagging[logstate.idx.0 - 1] = Some((Arc::downgrade(replica), rtkn.0, i));
+            }
+
+            let around = around.clone();
+            // Sync the log by its index in the replicas rather than the
+            // reported `LogId`, which is up to whoever created the logs.
+            let log_id = hash + 1;
+            log.on_lagging_replica(move |rid, _log_id| {
+                if let Some((replica, tid, i)) = &lagging[rid] {
+                    if let Some(replica) = replica.upgrade() {
+                        // Whichever thread appends to the log syncs the
+                        // replica, so the token can't stay on one thread.
+                        let rtkn = ReplicaToken(*tid, replica.instance);
+                        around(*i, &|| replica.sync_log(rtkn, log_id));
+                    }
+                }
+            });
+        }
+
+        Some(())
+    }
+}
+
+/// The async interface of the replica, and the non-blocking building blocks
+/// for the futures it returns.
+///
+/// Instead of spinning until a response is available, the futures enqueue
+/// their operation in the thread's context, try to combine once and then
+/// return [`core::task::Poll::Pending`]. The combiner of the log wakes them up
+/// once it has written their response.
+///
+/// # Note
+/// Combiners write the responses in the order of the operations in a context,
+/// but only collect the operations for their own log. So (unlike NR) every
+/// thread can have at most one operation waiting for a combiner at a time:
+/// The futures of a thread may be in-flight at the same time and complete in
+/// any order, but they're appended to the logs one after the other.
+#[cfg(feature = "async")]
+impl<D> Replica<D>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Executes a mutable operation asynchronously against this replica, and
+    /// returns the response in `resp`.
+    ///
+    /// When `resp` is polled, the operation is enqueued in the thread's
+    /// context and we try to combine once. If another thread is the combiner
+    /// of the log the operation maps to, the future returns
+    /// [`core::task::Poll::Pending`] and is woken up by the combiner once the
+    /// response is ready.
+    ///
+    /// # Note
+    /// - Futures for the same `idx` may complete in any order, but they must
+    ///   not be polled concurrently from different threads.
+    /// - Don't mix these futures with the blocking [`Replica::execute_mut`]
+    ///   (or the other blocking methods) on the same `idx` while they're
+    ///   in-flight.
+    /// - Once polled, an operation can't be retracted: if the future is
+    ///   dropped before it completes, the operation is still executed and its
+    ///   response is discarded once it arrives.
+    ///
+    /// # Panics
+    /// If the operation is rejected by [`Dispatch::validate`] (when `resp` is
+    /// polled).
+    pub async fn async_execute_mut<'a>(
+        &'a self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) {
+        self.check(idx);
+        let hash = self.log_of(&op, idx);
+        resp.set(ExecuteMutFuture::new(self, op, idx, hash, false, false));
+    }
+
+    /// Executes a mutable operation which depends on all logs asynchronously
+    /// against this replica, and returns the response in `resp`.
+    ///
+    /// See [`Replica::async_execute_mut`].
+    pub async fn async_execute_mut_scan<'a>(
+        &'a self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) {
+        self.check(idx);
+        // If there is only one log in the system, then execute
+        // scan operation as a mutable operations.
+        let fut = if self.logstate.len() == 1 {
+            ExecuteMutFuture::new(self, op, idx, 0, false, false)
+        } else {
+            ExecuteMutFuture::new(self, op, idx, 0, true, false)
+        };
+        resp.set(fut);
+    }
+
+    /// Executes an immutable operation asynchronously against this replica,
+    /// and returns the response in `resp`.
+    ///
+    /// When `resp` is polled and the replica isn't up-to-date with the log
+    /// the operation maps to yet, we try to combine once. If another thread
+    /// is the combiner, the future yields ([`core::task::Poll::Pending`])
+    /// instead of spinning and is woken up once the combiner releases its
+    /// lock.
+    ///
+    /// The future is [`Send`], so this requires the operation to be [`Send`]
+    /// too.
+    pub fn async_execute<'a, 'rop: 'a>(
+        &'a self,
+        op: <D as Dispatch>::ReadOperation<'rop>,
+        idx: ReplicaToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) where
+        <D as Dispatch>::ReadOperation<'rop>: Send,
+    {
+        self.check(idx);
+        let hash = self.log_of(&op, idx);
+        resp.set(ExecuteFuture::new(self, idx, hash, move |data: &D| {
+            data.dispatch(op)
+        }));
+    }
+
+    /// Executes a read-only operation which depends on all logs
+    /// asynchronously against this replica, and returns the response in
+    /// `resp`.
+    ///
+    /// Like [`Replica::execute_scan`], the operation is passed to
+    /// [`Dispatch::dispatch_mut`]. With more than one log it goes through the
+    /// thread's context (see [`Replica::async_execute_mut`]), otherwise it
+    /// behaves like [`Replica::async_execute`].
+    pub fn async_execute_scan<'a>(
+        &'a self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) {
+        self.check(idx);
+        if self.logstate.len() == 1 {
+            resp.set(ExecuteFuture::new(self, idx, 0, move |data: &D| {
+                data.dispatch_mut(op)
+            }));
+        } else {
+            resp.set(ExecuteMutFuture::new(self, op, idx, 0, true, true));
+        }
+    }
+
+    /// Returns the log `op` maps to, for operations that go to exactly one
+    /// log.
+    fn log_of(&self, op: &impl LogMapper, idx: ReplicaToken) -> usize {
+        let mut hash_vec = self.hash[idx.0 - 1].borrow_mut();
+        hash_vec.clear();
+        op.hash(self.logstate.len(), &mut hash_vec);
+        assert_eq!(hash_vec.len(), 1);
+        hash_vec[0]
+    }
+
+    /// Enqueues `op` in the context of thread `idx` for the combiner of log
+    /// `hash`.
+    ///
+    /// Returns the slot of the operation within the context which can be
+    /// passed to [`Replica::poll_response`]. Hands back `op` if the thread
+    /// has another operation which wasn't collected by a combiner yet, or if
+    /// the context is full.
+    pub(crate) fn enqueue(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        hash: usize,
+        is_scan: bool,
+        is_read_op: bool,
+    ) -> Result<usize, <D as Dispatch>::WriteOperation> {
+        let ctxt = &self.contexts[idx.0 - 1];
+        if ctxt.has_uncombined() || ctxt.free_slots() == 0 {
+            return Err(op);
+        }
+
+        let slot = ctxt
+            .enqueue_slot(op, (hash, is_scan, is_read_op))
+            .expect("Context has a free slot");
+        self.logstate[hash].pending[idx.0 - 1].store(true, Ordering::Release);
+        Ok(slot)
+    }
+
+    /// Returns the response for the operation in `slot` of thread `idx` if a
+    /// combiner already executed it, otherwise `waker` is registered to be
+    /// woken up once it arrives.
+    pub(crate) fn poll_response(
+        &self,
+        idx: ReplicaToken,
+        slot: usize,
+        waker: &Waker,
+    ) -> Option<Validated<D>> {
+        let ctxt = &self.contexts[idx.0 - 1];
+        if let Some(resp) = ctxt.res_at(slot) {
+            return Some(resp);
+        }
+
+        ctxt.register_waker(slot, waker);
+        // Pairs with the fence in `wake_uncollected`: either the current
+        // combiner sees our operation (and wakes us) when it releases the
+        // lock, or we see the released lock in the next `try_combine`.
+        fence(Ordering::SeqCst);
+        ctxt.res_at(slot)
+    }
+
+    /// Same as [`Replica::enqueue`], but if `op` can't be enqueued yet `waker`
+    /// is registered to be woken up once it might be possible.
+    pub(crate) fn poll_enqueue(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        hash: usize,
+        is_scan: bool,
+        is_read_op: bool,
+        waker: &Waker,
+    ) -> Result<usize, <D as Dispatch>::WriteOperation> {
+        let op = match self.enqueue(op, idx, hash, is_scan, is_read_op) {
+            Ok(slot) => return Ok(slot),
+            Err(op) => op,
+        };
+
+        let ctxt = &self.contexts[idx.0 - 1];
+        ctxt.register_room_waker(waker);
+        // Pairs with the fence in `wake_uncollected`.
+        fence(Ordering::SeqCst);
+        // Nobody might be waiting for the operation in our way (e.g., if its
+        // future was dropped), so we help to get it combined.
+        if let Some((hash, _is_scan, _is_read_op)) = ctxt.uncombined_meta() {
+            self.try_combine(idx.0, hash);
+        }
+        self.enqueue(op, idx, hash, is_scan, is_read_op)
+    }
+
+    /// Drops the response for the operation in `slot` of thread `idx` once a
+    /// combiner executed it.
+    pub(crate) fn abandon(&self, idx: ReplicaToken, slot: usize) {
+        self.contexts[idx.0 - 1].abandon(slot)
+    }
+
+    /// Registers `waker` to be woken up the next time a combiner lock is
+    /// released, i.e., once the replica might have caught up with a log.
+    ///
+    /// The caller has to check again afterwards (and try to combine, in case
+    /// the combiner lock was released before we registered).
+    pub(crate) fn register_reader(&self, waker: &Waker) {
+        self.readers.register(waker);
+        // Pairs with the fence in `wake_uncollected`.
+        fence(Ordering::SeqCst);
+    }
+
+    /// Returns the completed tail of log `hash`.
+    pub(crate) fn get_ctail(&self, hash: usize) -> usize {
+        self.logstate[hash].slog.get_ctail()
+    }
+
+    /// Calls `read` on the data-structure if the replica has applied log
+    /// `hash` up to `ctail`, otherwise hands back `read` to the caller.
+    pub(crate) fn try_execute_synced<F>(
+        &self,
+        hash: usize,
+        ctail: usize,
+        read: F,
+    ) -> Result<<D as Dispatch>::Response, F>
+    where
+        F: FnOnce(&D) -> <D as Dispatch>::Response,
+    {
+        if self.logstate[hash]
+            .slog
+            .is_replica_synced_for_reads(&self.logstate[hash].idx, ctail)
+        {
+            Ok(read(&self.data))
+        } else {
+            Err(read)
+        }
+    }
+
+    /// Wakes up tasks with operations that have not been collected by a
+    /// combiner yet (or whose response is still outstanding), as well as
+    /// tasks waiting for a free slot in their context or for the replica to
+    /// catch up. Called whenever a combiner lock is released.
+    fn wake_uncollected(&self) {
+        fence(Ordering::SeqCst);
+        let num_registered_threads = self.next.load(Ordering::Relaxed);
+        for ctxt in &self.contexts[..num_registered_threads - 1] {
+            ctxt.wake_uncollected();
+        }
+        self.readers.wake_all();
+    }
+}
+
 #[cfg(test)]
 mod test {
     extern crate std;
@@ -1247,10 +1722,10 @@ mod test {
             LogMetaData::new(1),
         ));
         let repl = Replica::<Data>::new(vec![slog]);
-        assert_eq!(repl.register(), Some(ReplicaToken(1)));
+        assert_eq!(repl.register(), Some(ReplicaToken(1, repl.instance)));
         assert_eq!(repl.next.load(Ordering::SeqCst), 2);
         repl.next.store(17, Ordering::SeqCst);
-        assert_eq!(repl.register(), Some(ReplicaToken(17)));
+        assert_eq!(repl.register(), Some(ReplicaToken(17, repl.instance)));
         assert_eq!(repl.next.load(Ordering::SeqCst), 18);
     }
 
@@ -1267,6 +1742,25 @@ mod test {
         assert!(repl.register().is_none());
     }
 
+    // Tests that a deregistered thread's pending operation is executed and that
+    // its slot is reused, even once the replica is full.
+    #[test]
+    fn test_replica_deregister() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Data>::new(vec![slog]);
+
+        let idx = repl.register().unwrap();
+        repl.next
+            .store(MAX_THREADS_PER_REPLICA + 1, Ordering::SeqCst);
+        assert!(repl.make_pending(OpWr(121), idx.0, 0, false, false));
+        repl.deregister(idx);
+        assert_eq!(repl.data.junk.load(Ordering::Relaxed), 1);
+        assert_eq!(repl.contexts[idx.0 - 1].res(), None);
+
+        assert_eq!(repl.register(), Some(idx));
+        assert!(repl.register().is_none());
+    }
+
     // Tests that we can successfully allow operations to go pending on this replica.
     #[test]
     fn test_replica_make_pending() {
@@ -1300,7 +1794,7 @@ mod test {
 
         assert_eq!(repl.logstate[0].combiner.load(Ordering::SeqCst), 0);
         assert_eq!(repl.data.junk.load(Ordering::Relaxed), 1);
-        assert_eq!(repl.contexts[0].res(), Some(Ok(107)));
+        assert_eq!(repl.contexts[0].res(), Some(Ok(Ok(107))));
     }
 
     // Tests whether try_combine() also applies pending operations on other threads to the log.
@@ -1314,7 +1808,7 @@ mod test {
         repl.try_combine(1, 0);
 
         assert_eq!(repl.data.junk.load(Ordering::Relaxed), 1);
-        assert_eq!(repl.contexts[7].res(), Some(Ok(107)));
+        assert_eq!(repl.contexts[7].res(), Some(Ok(Ok(107))));
     }
 
     // Tests whether try_combine() fails if someone else is currently flat combining.
@@ -1335,6 +1829,45 @@ mod test {
         assert_eq!(repl.contexts[0].res(), None);
     }
 
+    // Tests that operations rejected by `validate()` are neither logged nor executed.
+    #[test]
+    fn test_replica_validate_rejects() {
+        #[derive(Default)]
+        struct Checked(AtomicUsize);
+
+        impl Dispatch for Checked {
+            type ReadOperation<'rop> = OpRd;
+            type WriteOperation = OpWr;
+            type Response = usize;
+            type Error = usize;
+
+            fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+                self.0.load(Ordering::Relaxed)
+            }
+
+            fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+                self.0.fetch_add(op.0, Ordering::Relaxed)
+            }
+
+            fn validate(&self, op: &Self::WriteOperation) -> Result<(), Self::Error> {
+                match op.0 {
+                    0 => Err(0),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let slog = Arc::new(Log::<<Checked as Dispatch>::WriteOperation>::default());
+        let repl = Replica::<Checked>::new(vec![slog.clone()]);
+        let idx = repl.register().unwrap();
+
+        assert_eq!(repl.execute_mut_checked(OpWr(0), idx), Err(0));
+        assert_eq!(slog.get_ctail(), 0);
+        assert_eq!(repl.execute_mut_checked(OpWr(5), idx), Ok(0));
+        assert_eq!(slog.get_ctail(), 1);
+        assert_eq!(repl.execute(OpRd(0), idx), 5);
+    }
+
     // Tests whether we can execute an operation against the log using execute_mut().
     #[test]
     fn test_replica_execute_combine() {
@@ -1360,7 +1893,7 @@ mod test {
         let hash = logs[0];
         repl.make_pending(op, 1, hash, false, false);
 
-        assert_eq!(repl.get_response(1, hash), Ok(107));
+        assert_eq!(repl.get_response(1, hash), Ok(Ok(107)));
     }
 
     // Tests whether we can issue a read-only operation against the replica.
@@ -1498,7 +2031,7 @@ mod test {
 
         assert_eq!(repl.data.junk.load(Ordering::Relaxed), 4);
         for i in 0..logs.len() {
-            assert_eq!(repl.contexts[i].res(), Some(Ok(107)));
+            assert_eq!(repl.contexts[i].res(), Some(Ok(Ok(107))));
         }
     }
 
@@ -1724,6 +2257,163 @@ mod test {
                 &ltails,
             )
         );
-        assert_eq!(Ok(0), repl.get_response(idx.tid(), hash));
+        assert_eq!(Ok(Ok(0)), repl.get_response(idx.tid(), hash));
+    }
+
+    // Tests that an idle replica doesn't stop another replica from appending
+    // to the log once they sync on lagging.
+    #[test]
+    fn test_replica_sync_on_lagging() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new_with_bytes(
+            8 * 1024,
+            LogMetaData::new(1),
+        ));
+        let replicas = vec![
+            Replica::<Data>::new(vec![slog.clone()]),
+            Replica::<Data>::new(vec![slog.clone()]),
+        ];
+        assert_eq!(Replica::sync_on_lagging(&replicas), Some(()));
+
+        let idx = replicas[0].register().unwrap();
+        for _ in 0..10 * slog.slog.len() {
+            assert_eq!(replicas[0].execute_mut(OpWr(1), idx), Ok(107));
+        }
+        assert!(replicas[1].data.junk.load(Ordering::Relaxed) > 0);
+    }
+
+    // Tests that sync_on_lagging() gives back the thread slots it took when a
+    // replica is full.
+    #[test]
+    fn test_replica_sync_on_lagging_full() {
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::default());
+        let replicas = vec![
+            Replica::<Data>::new(vec![slog.clone()]),
+            Replica::<Data>::new(vec![slog]),
+        ];
+        replicas[1]
+            .next
+            .store(MAX_THREADS_PER_REPLICA + 1, Ordering::SeqCst);
+        assert_eq!(Replica::sync_on_lagging(&replicas), None);
+        assert_eq!(
+            replicas[0].register(),
+            Some(ReplicaToken(1, replicas[0].instance))
+        );
+    }
+
+    /// An async operation is parked while another thread is the combiner and
+    /// woken up once the combiner wrote its response.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_woken_by_combiner() {
+        use crate::cnr::future::ExecuteMutFuture;
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::task::{Context, Poll};
+        use futures::task::{waker, ArcWake};
+
+        struct Flag(AtomicBool);
+        impl ArcWake for Flag {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new_with_bytes(
+            1024,
+            LogMetaData::new(1),
+        ));
+        let repl = Replica::<Data>::new(vec![slog]);
+        let idx = repl.register().unwrap();
+        let idx2 = repl.register().unwrap();
+
+        // Pretend someone else is the combiner, so our future can't finish.
+        repl.logstate[0]
+            .combiner
+            .store(MAX_THREADS_PER_REPLICA + 2, Ordering::Release);
+        let mut fut = ExecuteMutFuture::new(&repl, OpWr(1), idx, 0, false, false);
+        let woken = Arc::new(Flag(AtomicBool::new(false)));
+        let waker1 = waker(woken.clone());
+        let mut cx = Context::from_waker(&waker1);
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+        assert!(!woken.0.load(Ordering::SeqCst));
+
+        // The next operation of the thread waits until the first one was
+        // collected by a combiner.
+        let mut fut2 = ExecuteMutFuture::new(&repl, OpWr(2), idx, 0, false, false);
+        let woken2 = Arc::new(Flag(AtomicBool::new(false)));
+        let waker2 = waker(woken2.clone());
+        let mut cx2 = Context::from_waker(&waker2);
+        assert!(Pin::new(&mut fut2).poll(&mut cx2).is_pending());
+
+        // The combiner picks up our operation and wakes us up.
+        repl.logstate[0].combiner.store(0, Ordering::Release);
+        repl.try_combine(idx2.tid(), 0);
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert!(woken2.0.load(Ordering::SeqCst));
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Ok(107)));
+        assert_eq!(Pin::new(&mut fut2).poll(&mut cx2), Poll::Ready(Ok(107)));
+        assert_eq!(repl.execute(OpRd(0), idx2), Ok(2));
+    }
+
+    /// Dropping a pending operation doesn't wait for its response, and a
+    /// pending read is woken up once the combiner lock is released.
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_drop_and_read() {
+        use crate::cnr::future::{ExecuteFuture, ExecuteMutFuture};
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::task::{Context, Poll};
+        use futures::task::{waker, ArcWake};
+
+        struct Flag(AtomicBool);
+        impl ArcWake for Flag {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let slog = Arc::new(Log::<<Data as Dispatch>::WriteOperation>::new_with_bytes(
+            1024,
+            LogMetaData::new(1),
+        ));
+        let repl = Replica::<Data>::new(vec![slog.clone()]);
+        let repl2 = Replica::<Data>::new(vec![slog]);
+        let idx = repl.register().unwrap();
+        let idx2 = repl.register().unwrap();
+        let ridx = repl2.register().unwrap();
+        let ridx2 = repl2.register().unwrap();
+
+        let woken = Arc::new(Flag(AtomicBool::new(false)));
+        let waker1 = waker(woken.clone());
+        let mut cx = Context::from_waker(&waker1);
+
+        // Pretend someone else is the combiner, so our future can't finish.
+        repl.logstate[0]
+            .combiner
+            .store(MAX_THREADS_PER_REPLICA + 2, Ordering::Release);
+        let mut fut = ExecuteMutFuture::new(&repl, OpWr(1), idx, 0, false, false);
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+        drop(fut);
+
+        // The next operation of the thread gets the dropped one combined.
+        repl.logstate[0].combiner.store(0, Ordering::Release);
+        let mut fut = ExecuteMutFuture::new(&repl, OpWr(2), idx, 0, false, false);
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Ok(107)));
+        assert_eq!(repl.execute(OpRd(0), idx2), Ok(2));
+
+        // `repl2` is behind and someone else is its combiner.
+        repl2.logstate[0]
+            .combiner
+            .store(MAX_THREADS_PER_REPLICA + 2, Ordering::Release);
+        woken.0.store(false, Ordering::SeqCst);
+        let mut rfut = ExecuteFuture::new(&repl2, ridx, 0, |d: &Data| d.dispatch(OpRd(0)));
+        assert!(Pin::new(&mut rfut).poll(&mut cx).is_pending());
+        assert!(!woken.0.load(Ordering::SeqCst));
+
+        repl2.logstate[0].combiner.store(0, Ordering::Release);
+        repl2.try_combine(ridx2.tid(), 0);
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert_eq!(Pin::new(&mut rfut).poll(&mut cx), Poll::Ready(Ok(2)));
     }
 }
@@ -1,12 +1,21 @@
 use core::cell::{Cell, UnsafeCell};
 use core::default::Default;
 use core::iter::{ExactSizeIterator, Iterator};
-use core::sync::atomic::{AtomicUsize, Ordering};
-
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(feature = "async")]
+use alloc::vec::Vec;
+#[cfg(feature = "async")]
+use atomic_waker::AtomicWaker;
+#[cfg(feature = "async")]
+use core::hint::spin_loop;
+#[cfg(feature = "async")]
+use core::task::Waker;
 use crossbeam_utils::CachePadded;
 use static_assertions::const_assert;
 
-/// The maximum number of operations that can be batched inside this context.
+/// The default for the maximum number of operations that can be batched inside
+/// a context.
 #[cfg(not(loom))]
 pub const MAX_PENDING_OPS: usize = 32;
 #[cfg(loom)]
@@ -23,6 +32,13 @@ pub struct PendingOperation<T, R, M> {
     pub(crate) op: UnsafeCell<Option<T>>,
     pub(crate) resp: Cell<Option<R>>,
     pub(crate) meta: UnsafeCell<M>,
+    /// Set if nobody is going to retrieve `resp` (see [`Context::abandon`]),
+    /// it is dropped once the head of the batch reaches it.
+    pub(crate) abandoned: AtomicBool,
+    /// Task waiting for `resp` (if any), woken by the combiner once the
+    /// response has been written.
+    #[cfg(feature = "async")]
+    pub(crate) waker: AtomicWaker,
 }
 
 impl<T, R, M> Default for PendingOperation<T, R, M>
@@ -34,7 +50,70 @@ where
             op: UnsafeCell::new(None),
             resp: Cell::new(None),
             meta: Default::default(),
+            abandoned: AtomicBool::new(false),
+            #[cfg(feature = "async")]
+            waker: AtomicWaker::new(),
+        }
+    }
+}
+
+/// A list of tasks waiting for the same event (e.g., a free slot in a
+/// [`Context`]), all of them are woken up at once.
+#[cfg(feature = "async")]
+#[derive(Default)]
+pub(crate) struct WakerList {
+    /// Protects `wakers`, held only briefly to push or take them.
+    locked: AtomicBool,
+    wakers: UnsafeCell<Vec<Waker>>,
+    /// Set while `wakers` isn't empty, so waking an empty list is cheap.
+    waiting: AtomicBool,
+}
+
+// `wakers` is only accessed with `locked` held.
+#[cfg(feature = "async")]
+unsafe impl Sync for WakerList {}
+
+#[cfg(feature = "async")]
+impl WakerList {
+    /// Adds `waker` to the list.
+    ///
+    /// Callers have to check the condition they wait for again afterwards,
+    /// with a `SeqCst` fence in between that pairs with the one that
+    /// precedes [`WakerList::wake_all`].
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.with(|wakers| {
+            if !wakers.iter().any(|w| w.will_wake(waker)) {
+                wakers.push(waker.clone());
+            }
+            self.waiting.store(true, Ordering::Relaxed);
+        });
+    }
+
+    /// Wakes up (and removes) all registered tasks.
+    pub(crate) fn wake_all(&self) {
+        if !self.waiting.load(Ordering::Relaxed) {
+            return;
+        }
+        let wakers = self.with(|wakers| {
+            self.waiting.store(false, Ordering::Relaxed);
+            core::mem::take(wakers)
+        });
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    fn with<F: FnOnce(&mut Vec<Waker>) -> U, U>(&self, f: F) -> U {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
         }
+        let r = f(unsafe { &mut *self.wakers.get() });
+        self.locked.store(false, Ordering::Release);
+        r
     }
 }
 
@@ -55,15 +134,18 @@ where
 /// against the replica.
 ///
 /// - `M` is the type of meta-data that is associated with each operation.
+///
+/// - `P` is the maximum number of operations that can be pending in the
+/// context (must be a power of two).
 #[repr(align(64))]
-pub struct Context<T, R, M>
+pub struct Context<T, R, M, const P: usize = MAX_PENDING_OPS>
 where
     T: Sized + Clone,
     R: Sized + Clone,
 {
     /// Array that will hold all pending operations to be appended to the shared
     /// log as well as the results obtained on executing them against a replica.
-    pub(crate) batch: [CachePadded<PendingOperation<T, R, M>>; MAX_PENDING_OPS],
+    pub(crate) batch: [CachePadded<PendingOperation<T, R, M>>; P],
 
     /// Logical array index at which new operations will be enqueued into the
     /// batch. This variable is updated by the thread that owns this context,
@@ -83,9 +165,14 @@ where
     /// Identifies the context number within a replica. It also maps to the
     /// thread-id because the partitioned nature of the contexts in the replica.
     pub _idx: usize,
+
+    /// Tasks waiting for a free slot in the batch, woken up whenever the head
+    /// advances or the combiner lock of the replica is released.
+    #[cfg(feature = "async")]
+    pub(crate) room: WakerList,
 }
 
-impl<T, R, M> Default for Context<T, R, M>
+impl<T, R, M, const P: usize> Default for Context<T, R, M, P>
 where
     T: Sized + Clone,
     R: Sized + Clone,
@@ -93,7 +180,9 @@ where
 {
     /// Default constructor for the context.
     fn default() -> Self {
-        let mut batch: [CachePadded<PendingOperation<T, R, M>>; MAX_PENDING_OPS] =
+        // Required for `index()` to work.
+        assert!(P.is_power_of_two(), "Context size must be a power of two");
+        let mut batch: [CachePadded<PendingOperation<T, R, M>>; P] =
             unsafe { ::core::mem::MaybeUninit::zeroed().assume_init() };
         for elem in &mut batch[..] {
             *elem = CachePadded::new(Default::default());
@@ -105,11 +194,13 @@ where
             head: CachePadded::new(AtomicUsize::new(0)),
             comb: CachePadded::new(AtomicUsize::new(0)),
             _idx: 0,
+            #[cfg(feature = "async")]
+            room: WakerList::default(),
         }
     }
 }
 
-impl<T, R, M> Context<T, R, M>
+impl<T, R, M, const P: usize> Context<T, R, M, P>
 where
     T: Sized + Clone,
     R: Sized + Clone,
@@ -123,7 +214,7 @@ where
     }
 }
 
-impl<T, R, M> Context<T, R, M>
+impl<T, R, M, const P: usize> Context<T, R, M, P>
 where
     T: Sized + Clone,
     R: Sized + Clone,
@@ -134,13 +225,50 @@ where
     /// otherwise.
     #[inline(always)]
     pub fn enqueue(&self, op: T, meta: M) -> bool {
+        self.enqueue_slot(op, meta).is_some()
+    }
+
+    /// Enqueues all `ops` (with meta-data `meta(i)` for `ops[i]`) at once, the
+    /// combiner either collects all of them or none. Returns false if they
+    /// don't fit into the batch (then none is enqueued).
+    #[inline(always)]
+    pub(crate) fn enqueue_all(&self, ops: &[T], meta: impl Fn(usize) -> M) -> bool {
         let t = self.tail.load(Ordering::Acquire);
-        let h = self.head.load(Ordering::Relaxed);
+        if P - (t - self.reclaimed_head(t)) < ops.len() {
+            return false;
+        }
+
+        for (i, op) in ops.iter().enumerate() {
+            let e = &self.batch[self.index(t + i)];
+            unsafe { *e.op.get() = Some(op.clone()) };
+            unsafe { *e.meta.get() = meta(i) };
+        }
+
+        self.tail.store(t + ops.len(), Ordering::Release);
+        true
+    }
+
+    /// Returns the number of operations that can still be enqueued before the
+    /// batch is full.
+    #[inline(always)]
+    pub(crate) fn free_slots(&self) -> usize {
+        let t = self.tail.load(Ordering::Relaxed);
+        P - (t - self.reclaimed_head(t))
+    }
+
+    /// Same as [`Context::enqueue`], but returns the logical index (slot) at
+    /// which the operation was enqueued. The slot can be used with
+    /// [`Context::res_at`] to retrieve the response of this particular
+    /// operation.
+    #[inline(always)]
+    pub(crate) fn enqueue_slot(&self, op: T, meta: M) -> Option<usize> {
+        let t = self.tail.load(Ordering::Acquire);
+        let h = self.reclaimed_head(t);
 
         // Check if we have space in the batch to hold this operation. If we
-        // don't, then return false to the caller thread.
-        if t - h == MAX_PENDING_OPS {
-            return false;
+        // don't, then return None to the caller thread.
+        if t - h == P {
+            return None;
         }
 
         // Add in the operation to the batch. Once added, update the tail so
@@ -153,7 +281,7 @@ where
         unsafe { *me = meta };
 
         self.tail.store(t + 1, Ordering::Release);
-        true
+        Some(t)
     }
 
     /// Enqueues a batch of responses onto this context. This is invoked by the combiner
@@ -179,18 +307,35 @@ where
     /// Enqueues a response onto this context. This is invoked by the combiner
     /// after it has executed operations (obtained through a call to ops()) against the
     /// replica this thread is registered against.
+    ///
+    /// With the `async` feature, a task waiting for the response of the
+    /// operation is woken up.
     #[inline(always)]
     pub fn enqueue_resp(&self, response: R) {
         let h = self.comb.load(Ordering::Relaxed);
-        self.batch[self.index(h)].resp.replace(Some(response));
-        self.comb.store(h + 1, Ordering::Relaxed);
+        let e = &self.batch[self.index(h)];
+        e.resp.replace(Some(response));
+        self.comb.store(h + 1, Ordering::Release);
+        #[cfg(feature = "async")]
+        e.waker.wake();
+    }
+
+    /// Same as [`Context::enqueue_resp`], but also updates the meta-data of
+    /// the operation with `update` (e.g., with information from the
+    /// combiner).
+    #[inline(always)]
+    pub(crate) fn enqueue_resp_with_meta(&self, response: R, update: impl FnOnce(&mut M)) {
+        let h = self.comb.load(Ordering::Relaxed);
+        update(unsafe { &mut *self.batch[self.index(h)].meta.get() });
+        self.enqueue_resp(response);
     }
 
     /// Returns a single response if available. Otherwise, returns None.
     #[inline(always)]
     pub fn res(&self) -> Option<R> {
-        let s = self.head.load(Ordering::Relaxed);
         let f = self.comb.load(Ordering::Relaxed);
+        self.reclaim(f);
+        let s = self.head.load(Ordering::Relaxed);
 
         // No responses ready yet; return to the caller.
         if s == f {
@@ -201,14 +346,199 @@ where
             panic!("Head of thread-local batch has advanced beyond combiner of.store!");
         }
 
-        self.head.store(s + 1, Ordering::Relaxed);
+        self.advance_head(s + 1);
         self.batch[self.index(s)].resp.take()
     }
 
+    /// Same as [`Context::res`], but also returns the meta-data of the
+    /// operation.
+    #[inline(always)]
+    pub(crate) fn res_with_meta(&self) -> Option<(R, M)>
+    where
+        M: Copy,
+    {
+        self.reclaim(self.comb.load(Ordering::Relaxed));
+        let s = self.head.load(Ordering::Relaxed);
+        let resp = self.res()?;
+        // Only the owner of the context enqueues, so the slot wasn't reused.
+        Some((resp, unsafe { *self.batch[self.index(s)].meta.get() }))
+    }
+
+    /// Returns the response of the operation enqueued at `slot` (see
+    /// [`Context::enqueue_slot`]) if available. Otherwise, returns None.
+    ///
+    /// Unlike [`Context::res`], responses can be retrieved in any order. The
+    /// head only advances once all responses before it have been retrieved.
+    /// The two methods should not be mixed while operations are outstanding.
+    #[inline(always)]
+    pub(crate) fn res_at(&self, slot: usize) -> Option<R> {
+        let f = self.comb.load(Ordering::Acquire);
+        if slot >= f {
+            return None;
+        }
+
+        let r = self.batch[self.index(slot)].resp.take();
+        debug_assert!(r.is_some(), "Response for slot {} was already taken", slot);
+
+        // Move the head past every response that was already taken out.
+        self.reclaim(f);
+
+        r
+    }
+
+    /// Gives up on the response of the operation enqueued at `slot` (see
+    /// [`Context::enqueue_slot`]). The operation is still executed, but its
+    /// response is dropped and the slot can be reused afterwards.
+    pub(crate) fn abandon(&self, slot: usize) {
+        // The slot was reclaimed already, e.g., by `discard_responses`.
+        if slot < self.head.load(Ordering::Relaxed) {
+            return;
+        }
+        self.batch[self.index(slot)]
+            .abandoned
+            .store(true, Ordering::Relaxed);
+        self.reclaim(self.comb.load(Ordering::Acquire));
+    }
+
+    /// Moves the head past all responses (up to `f`, the combiner's head)
+    /// that were already retrieved or abandoned. Responses of abandoned
+    /// operations are dropped.
+    fn reclaim(&self, f: usize) {
+        let mut h = self.head.load(Ordering::Relaxed);
+        while h < f {
+            let e = &self.batch[self.index(h)];
+            if e.abandoned.swap(false, Ordering::Relaxed) {
+                e.resp.take();
+            } else if let Some(pending) = e.resp.take() {
+                e.resp.set(Some(pending));
+                break;
+            }
+            h += 1;
+        }
+        self.advance_head(h);
+    }
+
+    /// Sets the head to `h`, tasks waiting for a free slot are woken up if
+    /// it moved.
+    #[inline(always)]
+    fn advance_head(&self, h: usize) {
+        #[cfg(feature = "async")]
+        let moved = self.head.load(Ordering::Relaxed) != h;
+        self.head.store(h, Ordering::Relaxed);
+        #[cfg(feature = "async")]
+        if moved {
+            self.room.wake_all();
+        }
+    }
+
+    /// Returns the head of the batch (with tail `t`), slots are reclaimed
+    /// first if the batch is full (see [`Context::reclaim`]).
+    #[inline(always)]
+    fn reclaimed_head(&self, t: usize) -> usize {
+        let h = self.head.load(Ordering::Relaxed);
+        if t - h < P {
+            return h;
+        }
+        self.reclaim(self.comb.load(Ordering::Acquire));
+        self.head.load(Ordering::Relaxed)
+    }
+
+    /// Registers `waker` to be woken up once the response for the operation
+    /// at `slot` is enqueued (or once the operation should be re-polled, see
+    /// [`Context::wake_uncollected`]).
+    #[cfg(feature = "async")]
+    #[inline(always)]
+    pub(crate) fn register_waker(&self, slot: usize, waker: &Waker) {
+        self.batch[self.index(slot)].waker.register(waker);
+    }
+
+    /// Wakes up all tasks which have operations in this context that haven't
+    /// been picked up by a combiner yet.
+    ///
+    /// Called after the combiner lock is released so an operation that was
+    /// enqueued too late for the last combine round isn't left behind
+    /// without anyone polling it.
+    #[cfg(feature = "async")]
+    pub(crate) fn wake_uncollected(&self) {
+        let c = self.comb.load(Ordering::Relaxed);
+        let t = self.tail.load(Ordering::Acquire);
+        for i in c..t {
+            self.batch[self.index(i)].waker.wake();
+        }
+        // The combiner may have written responses of abandoned operations
+        // (see `Context::abandon`), which frees up their slots.
+        self.room.wake_all();
+    }
+
+    /// Registers `waker` to be woken up once a slot in the batch might have
+    /// become free (see [`Context::wake_uncollected`]).
+    #[cfg(feature = "async")]
+    #[inline(always)]
+    pub(crate) fn register_room_waker(&self, waker: &Waker) {
+        self.room.register(waker);
+    }
+
+    /// Returns true if there are operations in this context that haven't been
+    /// picked up by a combiner yet.
+    #[inline(always)]
+    pub(crate) fn has_uncombined(&self) -> bool {
+        self.comb.load(Ordering::Acquire) < self.tail.load(Ordering::Relaxed)
+    }
+
+    /// Returns the meta-data of the next operation a combiner will pick up,
+    /// if there is one.
+    ///
+    /// Only the owner of the context writes meta-data, so this should only
+    /// be called by the owner.
+    #[cfg(feature = "async")]
+    pub(crate) fn uncombined_meta(&self) -> Option<M>
+    where
+        M: Copy,
+    {
+        let c = self.comb.load(Ordering::Acquire);
+        if c == self.tail.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(unsafe { *self.batch[self.index(c)].meta.get() })
+    }
+
+    /// Drops the available responses at the head of the batch as long as
+    /// `discard` returns true for the meta-data of their operation.
+    pub(crate) fn discard_responses_while(&self, discard: impl Fn(&M) -> bool) {
+        let f = self.comb.load(Ordering::Acquire);
+        let mut h = self.head.load(Ordering::Relaxed);
+        while h < f {
+            let e = &self.batch[self.index(h)];
+            if !e.abandoned.swap(false, Ordering::Relaxed) && !discard(unsafe { &*e.meta.get() }) {
+                break;
+            }
+            e.resp.take();
+            h += 1;
+        }
+        self.advance_head(h);
+    }
+
+    /// Drops all responses that weren't retrieved, leaving an empty context
+    /// behind that can be handed to another thread.
+    ///
+    /// All operations must have been combined already (see
+    /// [`Context::has_uncombined`]).
+    pub(crate) fn discard_responses(&self) {
+        let f = self.comb.load(Ordering::Acquire);
+        debug_assert_eq!(f, self.tail.load(Ordering::Relaxed));
+
+        for i in self.head.load(Ordering::Relaxed)..f {
+            let e = &self.batch[self.index(i)];
+            e.abandoned.store(false, Ordering::Relaxed);
+            e.resp.take();
+        }
+        self.advance_head(f);
+    }
+
     /// Adds any pending operations on this context to a passed in buffer.
     /// Returns the the number of such operations that were added in.
     #[inline(always)]
-    pub(crate) fn iter(&self) -> ContextIterator<T, R, M>
+    pub(crate) fn iter(&self) -> ContextIterator<T, R, M, P>
     where
         M: Copy,
     {
@@ -229,13 +559,13 @@ where
     /// Returns the maximum number of operations that will go pending on this context.
     #[inline(always)]
     pub fn batch_size() -> usize {
-        MAX_PENDING_OPS
+        P
     }
 
     /// Given a logical address, returns an index into the batch at which it falls.
     #[inline(always)]
     pub fn index(&self, logical: usize) -> usize {
-        logical & (MAX_PENDING_OPS - 1)
+        logical & (P - 1)
     }
 }
 
@@ -247,7 +577,7 @@ where
 /// and tail changes. Worst case will just panic due to unwrap on a None or give
 /// incorrect results, but needs some thought: If worse things are possible, we
 /// should instead make [`Context::iter`] unsafe.
-pub(crate) struct ContextIterator<'s, T, R, M>
+pub(crate) struct ContextIterator<'s, T, R, M, const P: usize>
 where
     T: Sized + Clone,
     R: Sized + Clone,
@@ -255,7 +585,7 @@ where
 {
     // A reference to the per-thread context with the window we're iterating
     // over.
-    ctxt: &'s Context<T, R, M>,
+    ctxt: &'s Context<T, R, M, P>,
     /// Current index into the iteration window (h..t).
     cur: usize,
     /// The head (start) of the currently available context buffer window.
@@ -266,7 +596,7 @@ where
 
 /// We know the exact window size for the [`ContextIterator`], it's the range
 /// from head to tail.
-impl<'s, T, R, M> ExactSizeIterator for ContextIterator<'s, T, R, M>
+impl<'s, T, R, M, const P: usize> ExactSizeIterator for ContextIterator<'s, T, R, M, P>
 where
     T: Sized + Clone,
     R: Sized + Clone,
@@ -279,7 +609,7 @@ where
 
 /// We can iterator over the active window by calling `.next()` until it returns
 /// None.
-impl<'s, T, R, M> Iterator for ContextIterator<'s, T, R, M>
+impl<'s, T, R, M, const P: usize> Iterator for ContextIterator<'s, T, R, M, P>
 where
     T: Sized + Clone,
     R: Sized + Clone,
@@ -433,6 +763,50 @@ mod test {
         assert_eq!(c.res(), None);
     }
 
+    // Tests that responses can be retrieved out of order with res_at() and that
+    // the head only moves once all earlier responses were taken.
+    #[test]
+    fn test_context_res_at() {
+        let c = Context::<u64, Result<u64, ()>, ()>::default();
+        assert_eq!(c.enqueue_slot(1, ()), Some(0));
+        assert_eq!(c.enqueue_slot(2, ()), Some(1));
+        assert_eq!(c.enqueue_slot(3, ()), Some(2));
+        assert_eq!(c.res_at(0), None);
+
+        c.enqueue_resps(&[Ok(11), Ok(12), Ok(13)]);
+
+        assert_eq!(c.res_at(1), Some(Ok(12)));
+        assert_eq!(c.head.load(Ordering::Relaxed), 0);
+        assert_eq!(c.res_at(0), Some(Ok(11)));
+        assert_eq!(c.head.load(Ordering::Relaxed), 2);
+        assert_eq!(c.res_at(2), Some(Ok(13)));
+        assert_eq!(c.head.load(Ordering::Relaxed), 3);
+    }
+
+    // Tests that abandoned responses are dropped once they arrive and that
+    // their slots are reused.
+    #[test]
+    fn test_context_abandon() {
+        let c = Context::<u64, Result<u64, ()>, ()>::default();
+        assert_eq!(c.enqueue_slot(1, ()), Some(0));
+        assert_eq!(c.enqueue_slot(2, ()), Some(1));
+        c.abandon(0);
+        assert_eq!(c.head.load(Ordering::Relaxed), 0);
+
+        c.enqueue_resps(&[Ok(11), Ok(12)]);
+        assert_eq!(c.res(), Some(Ok(12)));
+        assert_eq!(c.head.load(Ordering::Relaxed), 2);
+
+        for i in 0..MAX_PENDING_OPS as u64 {
+            assert!(c.enqueue(i, ()));
+            c.abandon(c.tail.load(Ordering::Relaxed) - 1);
+        }
+        assert!(!c.enqueue(0, ()));
+        c.enqueue_resps(&[Ok(0); MAX_PENDING_OPS]);
+        assert_eq!(c.free_slots(), MAX_PENDING_OPS);
+        assert!(c.enqueue(0, ()));
+    }
+
     // Tests that batch_size() works correctly.
     #[test]
     fn test_context_batch_size() {
@@ -445,4 +819,4 @@ mod test {
         let c = Context::<u64, Result<u64, ()>, ()>::default();
         assert_eq!(c.index(100), 100 % MAX_PENDING_OPS);
     }
-}
\ No newline at end of file
+}
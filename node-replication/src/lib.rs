@@ -15,9 +15,10 @@
     nonnull_slice_from_raw_parts,
     doc_auto_cfg,
     core_intrinsics,
-    new_zeroed_alloc
+    new_zeroed_alloc,
+    associated_type_defaults
 )]
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 extern crate std;
 
 extern crate alloc;
@@ -31,10 +32,90 @@ extern crate logging;
 pub mod context;
 pub mod log;
 pub mod replica;
+pub mod stats;
+pub mod wait;
+pub mod wal;
 
 pub mod cnr;
 pub mod nr;
 
+/// Implements [`nr::Dispatch`] for a data structure from the methods of an
+/// inherent `impl` block.
+///
+/// Methods taking `&self` become read-only operations, methods taking `&mut
+/// self` become mutable operations (associated functions without a receiver
+/// are left alone). For `impl Stack`, the macro keeps the `impl` block as is
+/// and generates:
+///
+/// - `StackReadOp<'rop>` and `StackWriteOp`: enums with a variant per method
+///   (the method name in `CamelCase`) holding its arguments.
+/// - `StackResponse`: an enum with a variant per method holding its return
+///   value.
+/// - The [`nr::Dispatch`] implementation which calls the methods.
+/// - `StackHandle<N>`: a handle (created from anything that dereferences to a
+///   [`nr::NodeReplicated`], e.g., a reference or an `Arc`, and a
+///   [`nr::ThreadToken`]) with the same methods as `Stack`, except that all of
+///   them take `&self` and are executed on the replicated data structure.
+///
+/// The generated items have the visibility passed to the macro (e.g.,
+/// `#[replicated(pub)]`), they're private by default.
+///
+/// # Restrictions
+/// - The `impl` block and its methods can't be generic or `async`.
+/// - Arguments of mutable methods are stored in the log, so they must be
+///   owned (and implement [`Clone`] and [`PartialEq`]). Read-only methods can
+///   take references.
+/// - Methods must return owned values which implement [`Clone`].
+/// - Methods can't be called `new` or `token` (the handle has methods with
+///   these names).
+/// - The crate using the macro needs `#![feature(generic_associated_types)]`.
+///
+/// # Example
+/// ```
+/// #![feature(generic_associated_types)]
+/// use core::num::NonZeroUsize;
+/// use node_replication::nr::NodeReplicated;
+/// use node_replication::replicated;
+///
+/// #[derive(Default)]
+/// struct Stack {
+///     storage: Vec<u64>,
+/// }
+///
+/// #[replicated]
+/// impl Stack {
+///     fn push(&mut self, v: u64) {
+///         self.storage.push(v);
+///     }
+///
+///     fn pop(&mut self) -> Option<u64> {
+///         self.storage.pop()
+///     }
+///
+///     fn contains(&self, v: &u64) -> bool {
+///         self.storage.contains(v)
+///     }
+/// }
+///
+/// let replicas = NonZeroUsize::new(2).unwrap();
+/// let nr = NodeReplicated::<Stack>::new(replicas, |_| 0).unwrap();
+/// let stack = StackHandle::new(&nr, nr.register(0).unwrap());
+///
+/// stack.push(1);
+/// stack.push(2);
+/// assert_eq!(stack.pop(), Some(2));
+/// assert!(stack.contains(&1));
+///
+/// // The generated operations can be used directly too:
+/// let ttkn = nr.register(1).unwrap();
+/// match nr.execute(StackReadOp::Contains(&2), ttkn) {
+///     StackResponse::Contains(found) => assert!(!found),
+///     _ => unreachable!(),
+/// }
+/// ```
+#[cfg(feature = "macros")]
+pub use node_replication_macros::replicated;
+
 #[cfg(doctest)]
 mod test_readme {
     macro_rules! external_doc_test {
@@ -5,6 +5,7 @@
 //! circular-buffer.
 
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use core::cell::Cell;
@@ -20,16 +21,27 @@ pub use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use static_assertions::const_assert;
 
 use crate::context::MAX_PENDING_OPS;
-use crate::replica::MAX_THREADS_PER_REPLICA;
+use crate::replica::{token_mismatch, InstanceId, TokenMismatch, MAX_THREADS_PER_REPLICA};
+use crate::stats::LogCounters;
+#[cfg(feature = "stats")]
+use crate::stats::LogStats;
+use crate::wait::{Spin, WaitStrategy};
 
 /// A token that identifies a replica for a log.
 ///
-/// The replica is supposed to call [`Log::register()`] to get the token.
+/// The replica is supposed to call [`Log::register()`] to get the token. It
+/// also identifies the log that issued it (see [`InstanceId`]).
 #[derive(Eq, PartialEq, Debug)]
 #[cfg(not(loom))]
-pub struct LogToken(pub(crate) usize);
+pub struct LogToken(pub(crate) usize, pub(crate) InstanceId);
 #[cfg(loom)]
-pub struct LogToken(pub usize);
+pub struct LogToken(pub usize, pub InstanceId);
+
+/// A logical index into the [`Log`].
+///
+/// Unlike the physical index into the circular buffer, the logical index of an
+/// operation on the log increases monotonically and is never reused.
+pub type LogPos = usize;
 
 /// The default size of the shared log in bytes. If constructed using the default
 /// constructor, the log will be these many bytes in size.
@@ -40,8 +52,9 @@ pub struct LogToken(pub usize);
 pub const DEFAULT_LOG_BYTES: usize = 2 * 1024 * 1024;
 const_assert!(DEFAULT_LOG_BYTES.is_power_of_two());
 
-/// The maximum number of replicas that can be registered with the log (plus one
-/// since replica registration starts at 0).
+/// The default for the maximum number of replicas that can be registered with
+/// the log (plus one since replica registration starts at 0), see the `R`
+/// parameter of [`Log`].
 ///
 /// Should be equal or greater than the max. amount of NUMA nodes we expect on
 /// our system Can't make it arbitrarily high as it will lead to more memory
@@ -59,9 +72,26 @@ pub const MAX_REPLICAS_PER_LOG: usize = 3;
 /// possible append after deciding to perform GC. This largest possible append is when
 /// every thread within a replica has a full batch of writes to be appended to the shared
 /// log.
+///
+/// This is the value for the default capacity limits, logs created by
+/// [`crate::nr::NodeReplicated`] use the limits of its replicas instead.
 pub const GC_FROM_HEAD: usize = MAX_PENDING_OPS * MAX_THREADS_PER_REPLICA;
 const_assert!(GC_FROM_HEAD.is_power_of_two());
 
+/// Value of a replica's local tail (in [`Log::ltails`]) once the replica was
+/// removed with [`Log::unregister()`].
+///
+/// Such a slot doesn't hold back garbage collection and can be reused by
+/// [`Log::register_from()`].
+pub(crate) const UNREGISTERED: usize = usize::MAX;
+
+/// Value of a replica's local tail once the replica was poisoned (see
+/// [`Log::poison()`]).
+///
+/// Like [`UNREGISTERED`], such a slot doesn't hold back garbage collection,
+/// but it isn't reused by [`Log::register_from()`].
+pub(crate) const POISONED: usize = usize::MAX - 1;
+
 /// Threshold after how many iterations we abort and report the replica we're waiting for
 /// as stuck for busy spinning loops.
 ///
@@ -81,7 +111,8 @@ where
     T: Sized + Clone,
     M: Default,
 {
-    /// The operation that this entry represents.
+    /// The operation that this entry represents (`None` for the tombstone
+    /// of an operation that NR's combiner rejected).
     pub(crate) operation: Option<T>,
 
     /// Identifies the replica that issued the above operation.
@@ -127,9 +158,12 @@ where
 /// operation that will go on the log. Typically this is somes enum with
 /// variants identifying the different mutable operations.
 ///
+/// `R` is the maximum number of replicas that can register with the log
+/// (defaults to [`MAX_REPLICAS_PER_LOG`]).
+///
 /// This struct is aligned to 64 bytes to optimize cache access.
 #[repr(align(64))]
-pub struct Log<T, LM, M>
+pub struct Log<T, LM, M, const R: usize = MAX_REPLICAS_PER_LOG>
 where
     T: Sized + Clone,
     M: Default,
@@ -153,7 +187,7 @@ where
     /// Required for garbage collection; since replicas make progress over the log
     /// independently, we want to make sure that we don't garbage collect operations
     /// that haven't been executed by all replicas.
-    pub(crate) ltails: [CachePadded<AtomicUsize>; MAX_REPLICAS_PER_LOG],
+    pub(crate) ltails: [CachePadded<AtomicUsize>; R],
 
     /// Identifier that will be allocated to the next replica that registers with
     /// this Log. Also required to correctly index into ltails above.
@@ -162,13 +196,27 @@ where
     /// Array consisting of local alive masks for each registered replica. Required
     /// because replicas make independent progress over the log, so we need to
     /// track log wrap-arounds for each of them separately.
-    pub(crate) lmasks: [CachePadded<Cell<bool>>; MAX_REPLICAS_PER_LOG],
+    pub(crate) lmasks: [CachePadded<Cell<bool>>; R],
 
     /// Meta-data used by log implementations.
     pub(crate) metadata: LM,
+
+    /// Identifies the log in the [`LogToken`]s it issues.
+    pub(crate) instance: InstanceId,
+
+    /// Number of entries that have to be free at the end of the log for an
+    /// append to go through: the maximum number of operations a replica can
+    /// append at once (defaults to [`GC_FROM_HEAD`]).
+    pub(crate) gc_from_head: usize,
+
+    /// Runtime statistics (only maintained with the `stats` feature).
+    pub(crate) counters: LogCounters<R>,
+
+    /// How threads wait for space on the log.
+    pub(crate) wait: Arc<dyn WaitStrategy>,
 }
 
-impl<T, LM, M> fmt::Debug for Log<T, LM, M>
+impl<T, LM, M, const R: usize> fmt::Debug for Log<T, LM, M, R>
 where
     T: Sized + Clone,
     M: Default,
@@ -183,7 +231,7 @@ where
 }
 
 /// The Log is Send. The *mut u8 (`rawp`) is never dereferenced.
-unsafe impl<T, LM, M> Send for Log<T, LM, M>
+unsafe impl<T, LM, M, const R: usize> Send for Log<T, LM, M, R>
 where
     T: Sized + Clone,
     M: Default,
@@ -192,14 +240,14 @@ where
 
 /// The Log is Sync. We know this because: `head` and `tail` are atomic variables, `append()`
 /// reserves entries using a CAS, and exec() does not concurrently mutate entries on the log.
-unsafe impl<T, LM, M> Sync for Log<T, LM, M>
+unsafe impl<T, LM, M, const R: usize> Sync for Log<T, LM, M, R>
 where
     T: Sized + Clone,
     M: Default,
 {
 }
 
-impl<T, LM, M> Log<T, LM, M>
+impl<T, LM, M, const R: usize> Log<T, LM, M, R>
 where
     T: Sized + Clone,
     M: Default,
@@ -230,8 +278,19 @@ where
     /// This method allocates memory for the log upfront. No further allocations
     /// will be performed once this method returns.
     pub fn new_with_entries(num: usize, metadata: LM) -> Self {
+        Log::new_with_gc_from_head(num, metadata, GC_FROM_HEAD)
+    }
+
+    /// Constructs and returns a log of (approximately) `num` entries, for
+    /// replicas that append up to `gc_from_head` operations at once.
+    ///
+    /// The log holds at least `2 * gc_from_head` entries.
+    pub(crate) fn new_with_gc_from_head(num: usize, metadata: LM, gc_from_head: usize) -> Self {
+        assert!(R > 0, "A log needs room for at least one replica");
+        assert!(gc_from_head.is_power_of_two());
+
         // Allocate the log
-        let mut v = Vec::with_capacity(Log::<T, LM, M>::entries_to_log_entries(num));
+        let mut v = Vec::with_capacity(Self::entries_to_log_entries(num, gc_from_head));
         for _ in 0..v.capacity() {
             v.push(Default::default());
         }
@@ -252,27 +311,32 @@ where
                 head: CachePadded::new(AtomicUsize::new(0usize)),
                 tail: CachePadded::new(AtomicUsize::new(0usize)),
                 ctail: CachePadded::new(AtomicUsize::new(0usize)),
-                ltails: [LTAIL_DEFAULT; MAX_REPLICAS_PER_LOG],
+                ltails: [LTAIL_DEFAULT; R],
                 next: CachePadded::new(AtomicUsize::new(1usize)),
-                lmasks: [LMASK_DEFAULT; MAX_REPLICAS_PER_LOG],
+                lmasks: [LMASK_DEFAULT; R],
                 metadata,
+                instance: InstanceId::next(),
+                gc_from_head,
+                counters: Default::default(),
+                wait: Arc::new(Spin),
             }
         }
-        // `AtomicUsize::new` is not const in loom. This code block (including arr
-        // dependency) becomes redundant once
-        // https://github.com/tokio-rs/loom/issues/170 is fixed:
+        // `AtomicUsize::new` is not const in loom.
         #[cfg(loom)]
         {
-            use arr_macro::arr;
             Log {
                 slog: raw,
                 head: CachePadded::new(AtomicUsize::new(0usize)),
                 tail: CachePadded::new(AtomicUsize::new(0usize)),
                 ctail: CachePadded::new(AtomicUsize::new(0usize)),
-                ltails: arr![CachePadded::new(AtomicUsize::new(0)); 3], // MAX_REPLICAS_PER_LOG
+                ltails: core::array::from_fn(|_| CachePadded::new(AtomicUsize::new(0))),
                 next: CachePadded::new(AtomicUsize::new(1usize)),
-                lmasks: [LMASK_DEFAULT; MAX_REPLICAS_PER_LOG],
+                lmasks: [LMASK_DEFAULT; R],
                 metadata,
+                instance: InstanceId::next(),
+                gc_from_head,
+                counters: Default::default(),
+                wait: Arc::new(Spin),
             }
         }
     }
@@ -319,11 +383,11 @@ where
 
     /// Determines the number of entries in the log. This is likely just `entries` rounded
     /// to the next power of two -- as long as it's above the minimal threshold required
-    /// for the log to work (2*GC_FROM_HEAD).
-    fn entries_to_log_entries(entries: usize) -> usize {
-        core::cmp::max(2 * GC_FROM_HEAD, entries)
+    /// for the log to work (2*gc_from_head).
+    fn entries_to_log_entries(entries: usize, gc_from_head: usize) -> usize {
+        core::cmp::max(2 * gc_from_head, entries)
             .checked_next_power_of_two()
-            .unwrap_or(2 * GC_FROM_HEAD)
+            .unwrap_or(2 * gc_from_head)
     }
 
     /// Converts a size (in bytes), to the number of log entries required to fill this
@@ -335,7 +399,7 @@ where
         // Calculate the number of entries that will go into the log, and retrieve a
         // slice to it from the allocated region of memory.
         // Make sure the log is large enough to allow for periodic garbage collection.
-        let mut num = core::cmp::max(2 * GC_FROM_HEAD, bytes / Log::<T, LM, M>::entry_size());
+        let mut num = core::cmp::max(2 * GC_FROM_HEAD, bytes / Self::entry_size());
 
         // Round off to the next power of two if required. If we overflow, then set
         // the number of entries to the minimum required for GC. This is unlikely since
@@ -384,7 +448,7 @@ where
             let n = self.next.load(Ordering::Relaxed);
 
             // Check if we've exceeded the maximum number of replicas the log can support.
-            if n > MAX_REPLICAS_PER_LOG {
+            if n > R {
                 return None;
             };
 
@@ -396,7 +460,124 @@ where
                 continue;
             };
 
-            return Some(LogToken(n));
+            return Some(LogToken(n, self.instance));
+        }
+    }
+
+    /// Registers a new replica with the log which starts executing operations
+    /// at the same position as the (already registered) replica `idx`.
+    ///
+    /// This is used to add replicas to a log that is already in use: the new
+    /// replica has to start off with a copy of the state of `idx` at its
+    /// current local tail. It's up to the caller to make sure that `idx`
+    /// doesn't make progress on the log until the copy is made.
+    ///
+    /// Slots of replicas that were removed with [`Log::unregister`] are reused
+    /// before new ones are handed out. Returns `None` if the log can't support
+    /// any more replicas.
+    pub(crate) fn register_from(&self, idx: &LogToken) -> Option<LogToken> {
+        let ltail = self.ltails[idx.0 - 1].load(Ordering::Relaxed);
+        let lmask = self.lmasks[idx.0 - 1].get();
+
+        // `idx` prevents GC from advancing the head beyond `ltail`, so there's
+        // no harm in publishing the local tail of the new replica right away.
+        let n = self.next.load(Ordering::Relaxed);
+        for i in 0..n - 1 {
+            if self.ltails[i]
+                .compare_exchange(UNREGISTERED, ltail, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.lmasks[i].set(lmask);
+                return Some(LogToken(i + 1, self.instance));
+            }
+        }
+
+        let new_idx = self.register()?;
+        self.lmasks[new_idx.0 - 1].set(lmask);
+        self.ltails[new_idx.0 - 1].store(ltail, Ordering::SeqCst);
+        Some(new_idx)
+    }
+
+    /// Removes a replica from the log.
+    ///
+    /// The replica's local tail no longer holds back garbage collection and
+    /// its slot can be reused by a replica that is added later on (see
+    /// [`Log::register_from`]). Operations the replica appended to the log
+    /// remain on it and are executed by all other replicas.
+    pub fn unregister(&self, idx: LogToken) {
+        self.check_token(&idx).unwrap_or_else(|e| token_mismatch(e));
+        self.ltails[idx.0 - 1].store(UNREGISTERED, Ordering::SeqCst);
+    }
+
+    /// Returns the id of the log, which is embedded in the [`LogToken`]s it
+    /// issues.
+    pub fn instance_id(&self) -> InstanceId {
+        self.instance
+    }
+
+    /// Checks that `idx` was issued by this log.
+    #[inline(always)]
+    pub(crate) fn check_token(&self, idx: &LogToken) -> Result<(), TokenMismatch> {
+        idx.1.check(self.instance)
+    }
+
+    /// Stops a replica that can't apply operations anymore (because it
+    /// panicked while doing so) from holding back garbage collection.
+    ///
+    /// The replica keeps its slot until it is revived with
+    /// [`Log::revive_from`].
+    pub(crate) fn poison(&self, idx: &LogToken) {
+        self.ltails[idx.0 - 1].store(POISONED, Ordering::SeqCst);
+    }
+
+    /// Lets the poisoned replica `idx` continue from where replica `from`
+    /// is on the log, e.g., once its data was replaced with a copy of the
+    /// data of `from`.
+    ///
+    /// Like in [`Log::register_from`], `from` must not make progress on the
+    /// log while this is called.
+    pub(crate) fn revive_from(&self, idx: &LogToken, from: &LogToken) {
+        debug_assert_eq!(self.ltails[idx.0 - 1].load(Ordering::Relaxed), POISONED);
+        let ltail = self.ltails[from.0 - 1].load(Ordering::Relaxed);
+        self.lmasks[idx.0 - 1].set(self.lmasks[from.0 - 1].get());
+        self.ltails[idx.0 - 1].store(ltail, Ordering::SeqCst);
+    }
+
+    /// Moves the (empty) log forward so the next operation that is appended
+    /// ends up at logical index `pos`.
+    ///
+    /// This is used to continue a log where an earlier one left off, e.g.,
+    /// when replicas are restored from a snapshot that reflects all
+    /// operations before `pos`.
+    ///
+    /// # Panics
+    /// If a replica already registered with the log.
+    pub(crate) fn start_at(&self, pos: LogPos) {
+        assert_eq!(
+            self.next.load(Ordering::Relaxed),
+            1,
+            "Replicas registered with the log already"
+        );
+
+        // Replicas flip their alive mask every time they wrap around the log,
+        // hence (starting with `true`) the mask for the round `pos` is in:
+        let lmask = (pos / self.slog.len()) & 1 == 0;
+
+        self.head.store(pos, Ordering::SeqCst);
+        self.tail.store(pos, Ordering::SeqCst);
+        self.ctail.store(pos, Ordering::SeqCst);
+        for r in 0..R {
+            self.ltails[r].store(pos, Ordering::Relaxed);
+            self.lmasks[r].set(lmask);
+        }
+
+        // Entries from `pos` to the end of the slice have to look dead to
+        // replicas in this round, the ones before `pos` to replicas in the
+        // next round.
+        for i in 0..self.slog.len() {
+            let e = self.slog[i].as_ptr();
+            let alivef = if i < self.index(pos) { lmask } else { !lmask };
+            unsafe { (*e).alivef.store(alivef, Ordering::Release) };
         }
     }
 
@@ -407,6 +588,7 @@ where
     }
 
     /// Loops over all `ltails` and finds the replica with the lowest tail.
+    /// Replicas that were removed (see [`Log::unregister`]) are skipped.
     ///
     /// # Returns
     /// The ID (in `LogToken`) of the replica with the lowest tail and the
@@ -449,7 +631,7 @@ where
         self.next.store(1, Ordering::SeqCst);
 
         // Next, reset replica-local metadata.
-        for r in 0..MAX_REPLICAS_PER_LOG {
+        for r in 0..R {
             self.ltails[r].store(0, Ordering::Relaxed);
             self.lmasks[r].set(true);
         }
@@ -531,9 +713,24 @@ where
     pub(crate) fn get_ctail(&self) -> usize {
         self.ctail.load(Ordering::Relaxed)
     }
+
+    /// Returns the local tail of the replica identified by `idx`, i.e., the
+    /// logical index of the next operation it will execute.
+    #[inline(always)]
+    pub(crate) fn get_ltail(&self, idx: &LogToken) -> LogPos {
+        self.ltails[idx.0 - 1].load(Ordering::Relaxed)
+    }
+
+    /// Returns the runtime statistics of the log.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LogStats<R> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Relaxed);
+        self.counters.stats(tail.saturating_sub(head))
+    }
 }
 
-impl<T, LM, M> Default for Log<T, LM, M>
+impl<T, LM, M, const R: usize> Default for Log<T, LM, M, R>
 where
     T: Sized + Clone,
     LM: Default,
@@ -667,7 +864,7 @@ mod tests {
     #[test]
     fn test_log_register() {
         let l = Log::<Operation, (), ()>::new_with_bytes(1024, ());
-        assert_eq!(l.register(), Some(LogToken(1)));
+        assert_eq!(l.register(), Some(LogToken(1, l.instance)));
         assert_eq!(l.next.load(Ordering::Relaxed), 2);
     }
 
@@ -680,4 +877,4 @@ mod tests {
         }
         assert!(l.register().is_none());
     }
-}
\ No newline at end of file
+}
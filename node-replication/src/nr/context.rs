@@ -4,8 +4,25 @@
 //! NR specific Context.
 
 pub use crate::context::MAX_PENDING_OPS;
+use crate::log::LogPos;
+
+/// Meta-data of an operation in the NR per-thread context.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct OpMeta {
+    /// Number of operations that follow this one in the same atomic group
+    /// (see [`crate::nr::NodeReplicated::execute_mut_atomic`]), zero for
+    /// the last one (and operations that aren't part of a group).
+    pub(crate) group: usize,
+    /// Position at which the combiner appended the operation to the log.
+    pub(crate) pos: LogPos,
+    /// The response of the operation is dropped (see
+    /// [`crate::nr::NodeReplicated::execute_mut_detached`]).
+    pub(crate) detached: bool,
+}
 
 /// The NR per-thread context.
 ///
-/// It stores every outstanding request (`T`) and response (`R`) pair.
-pub(crate) type Context<T, R> = crate::context::Context<T, R, ()>;
+/// It stores every outstanding request (`T`) and response (`R`) pair, up to
+/// `P` of them.
+pub(crate) type Context<T, R, const P: usize = MAX_PENDING_OPS> =
+    crate::context::Context<T, R, OpMeta, P>;
@@ -0,0 +1,194 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Futures returned by the async interface of [`NodeReplicated`].
+//!
+//! Instead of spinning until a response is available, the futures enqueue
+//! their operation in the thread's context, try to combine once and then
+//! return [`Poll::Pending`]. The combiner wakes them up once it has written
+//! their response (or, for reads and operations that didn't fit into the
+//! context, once it releases the combiner lock).
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::replica::unwrap_validated;
+use super::{Dispatch, NodeReplicated, ThreadToken};
+
+/// Future for [`NodeReplicated::async_execute_mut`].
+pub(crate) struct ExecuteMutFuture<'a, D, const R: usize, const T: usize, const P: usize>
+where
+    D: Dispatch + Sized + Sync,
+{
+    nr: &'a NodeReplicated<D, R, T, P>,
+    tkn: ThreadToken,
+    /// The operation, until it is enqueued in the thread context.
+    op: Option<<D as Dispatch>::WriteOperation>,
+    /// Slot of the operation in the thread context, once it is enqueued.
+    slot: Option<usize>,
+}
+
+impl<'a, D, const R: usize, const T: usize, const P: usize> ExecuteMutFuture<'a, D, R, T, P>
+where
+    D: Dispatch + Sized + Sync,
+{
+    pub(crate) fn new(
+        nr: &'a NodeReplicated<D, R, T, P>,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> Self {
+        Self {
+            nr,
+            tkn,
+            op: Some(op),
+            slot: None,
+        }
+    }
+}
+
+/// We never hand out pinned references to any of the fields.
+impl<D, const R: usize, const T: usize, const P: usize> Unpin for ExecuteMutFuture<'_, D, R, T, P> where
+    D: Dispatch + Sized + Sync
+{
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Future for ExecuteMutFuture<'_, D, R, T, P>
+where
+    D: Dispatch + Sized + Sync,
+{
+    type Output = <D as Dispatch>::Response;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let replica = this.nr.check(this.tkn);
+
+        let slot = match this.slot {
+            Some(slot) => slot,
+            None => {
+                let op = this.op.as_ref().expect("Polled after completion");
+                let slot = match replica.poll_enqueue(op, this.tkn.rtkn, cx.waker()) {
+                    Some(slot) => slot,
+                    None => {
+                        // The context is full with other in-flight operations
+                        // of this thread: help to drain it and retry once a
+                        // slot is free.
+                        this.nr.try_combine(this.tkn.rid);
+                        match replica.enqueue(op.clone(), this.tkn.rtkn) {
+                            Some(slot) => slot,
+                            None => return Poll::Pending,
+                        }
+                    }
+                };
+                this.op = None;
+                this.slot = Some(slot);
+                slot
+            }
+        };
+
+        if let Some(resp) = replica.poll_response(this.tkn.rtkn, slot, cx.waker()) {
+            this.slot = None;
+            return Poll::Ready(unwrap_validated::<D>(resp));
+        }
+
+        this.nr.try_combine(this.tkn.rid);
+        match replica.poll_response(this.tkn.rtkn, slot, cx.waker()) {
+            Some(resp) => {
+                this.slot = None;
+                Poll::Ready(unwrap_validated::<D>(resp))
+            }
+            None if replica.is_stopped() => {
+                // Nobody is going to answer, don't wait for it in `drop`.
+                this.slot = None;
+                this.nr.stopped(this.tkn)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Drop for ExecuteMutFuture<'_, D, R, T, P>
+where
+    D: Dispatch + Sized + Sync,
+{
+    /// An operation can't be retracted once it is enqueued. If the future is
+    /// dropped before it completed, its response is discarded once it arrives.
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            if let Ok(replica) = self.nr.replica_of(self.tkn) {
+                replica.abandon(self.tkn.rtkn, slot);
+            }
+        }
+    }
+}
+
+/// Future for [`NodeReplicated::async_execute`].
+pub(crate) struct ExecuteFuture<'a, 'rop, D, const R: usize, const T: usize, const P: usize>
+where
+    D: Dispatch + Sized + Sync,
+{
+    nr: &'a NodeReplicated<D, R, T, P>,
+    tkn: ThreadToken,
+    op: Option<<D as Dispatch>::ReadOperation<'rop>>,
+    /// The completed tail of the log at the time we were first polled, the
+    /// replica has to be synced up to this point before we can read.
+    ctail: Option<usize>,
+}
+
+impl<'a, 'rop, D, const R: usize, const T: usize, const P: usize>
+    ExecuteFuture<'a, 'rop, D, R, T, P>
+where
+    D: Dispatch + Sized + Sync,
+{
+    pub(crate) fn new(
+        nr: &'a NodeReplicated<D, R, T, P>,
+        op: <D as Dispatch>::ReadOperation<'rop>,
+        tkn: ThreadToken,
+    ) -> Self {
+        Self {
+            nr,
+            tkn,
+            op: Some(op),
+            ctail: None,
+        }
+    }
+}
+
+/// We never hand out pinned references to any of the fields.
+impl<D, const R: usize, const T: usize, const P: usize> Unpin for ExecuteFuture<'_, '_, D, R, T, P> where
+    D: Dispatch + Sized + Sync
+{
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Future for ExecuteFuture<'_, '_, D, R, T, P>
+where
+    D: Dispatch + Sized + Sync,
+{
+    type Output = <D as Dispatch>::Response;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let replica = this.nr.check(this.tkn);
+        let log = &this.nr.log;
+        let ctail = *this.ctail.get_or_insert_with(|| log.get_ctail());
+
+        let op = this.op.take().expect("Polled after completion");
+        let op = match replica.try_execute_synced(log, ctail, op, this.tkn.rtkn) {
+            Ok(resp) => return Poll::Ready(resp),
+            Err(op) => op,
+        };
+
+        replica.register_reader(cx.waker());
+        this.nr.try_combine(this.tkn.rid);
+        match replica.try_execute_synced(log, ctail, op, this.tkn.rtkn) {
+            Ok(resp) => Poll::Ready(resp),
+            Err(_op) if replica.is_stopped() => this.nr.stopped(this.tkn),
+            Err(op) => {
+                // Another thread is the combiner and is busy applying the log
+                // to our replica, it wakes us up once it's done.
+                this.op = Some(op);
+                Poll::Pending
+            }
+        }
+    }
+}
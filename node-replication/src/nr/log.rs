@@ -4,8 +4,15 @@
 //! Contains the shared Log, in a nutshell it's a multi-producer, multi-consumer
 //! circular-buffer.
 
+use core::ops::Range;
 use core::sync::atomic::Ordering;
 
+use super::trace::TraceSlot;
+use crate::replica::{token_mismatch, ReplicaId, ThreadIdx};
+use crate::wait::{WaitStrategy, Waiter};
+use crate::wal::Wal;
+
+pub use crate::log::LogPos;
 pub use crate::log::LogToken;
 pub use crate::log::DEFAULT_LOG_BYTES;
 pub use crate::log::MAX_REPLICAS_PER_LOG;
@@ -14,9 +21,97 @@ pub use crate::log::GC_FROM_HEAD;
 
 pub use crate::log::WARN_THRESHOLD;
 
-pub type Log<T> = crate::log::Log<T, (), ()>;
+/// The log used by [`NodeReplicated`](super::NodeReplicated), for up to `R`
+/// replicas.
+pub type Log<T, const R: usize = MAX_REPLICAS_PER_LOG> = crate::log::Log<T, (), (), R>;
+
+/// Entries on the log that were reserved with [`Log::reserve`].
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    /// Logical index of the first reserved entry.
+    pub(crate) pos: LogPos,
+    /// Number of reserved entries.
+    nops: usize,
+    /// Whether the head of the log has to be advanced once the entries are
+    /// published.
+    advance: bool,
+}
+
+/// Returns the ranges of consecutive operations among the first `nops` for
+/// which `accepted` holds.
+fn accepted_runs(
+    nops: usize,
+    accepted: &impl Fn(usize) -> bool,
+) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut i = 0;
+    core::iter::from_fn(move || {
+        while i < nops && !accepted(i) {
+            i += 1;
+        }
+        let start = i;
+        while i < nops && accepted(i) {
+            i += 1;
+        }
+        (start < i).then_some(start..i)
+    })
+}
+
+/// Observes the operations the replicas of a
+/// [`NodeReplicated`](super::NodeReplicated) append to the log: records them in
+/// its write-ahead log and trace.
+pub(crate) struct LogHooks<T> {
+    /// Optional write-ahead log that records all appended operations.
+    pub(crate) wal: Option<Wal<T>>,
+    /// Recorder that traces the appended operations (if there is one).
+    pub(crate) trace: TraceSlot<T>,
+}
+
+impl<T> LogHooks<T> {
+    /// Creates hooks that record operations in `wal` (if any), but don't
+    /// trace them (see [`TraceSlot::replace`]).
+    pub(crate) fn new(wal: Option<Wal<T>>) -> Self {
+        Self {
+            wal,
+            trace: TraceSlot::new(),
+        }
+    }
+
+    /// Records the operations that replica `rid` is about to publish in
+    /// `reservation` (see [`Log::publish`]). This has to happen before they
+    /// become visible to any replica, so nothing is executed that wouldn't be
+    /// recovered after a crash (given a sync policy of
+    /// [`SyncPolicy::Always`](crate::wal::SyncPolicy::Always)).
+    ///
+    /// Combiners take turns (in log order), waiting with `wait`.
+    pub(crate) fn record(
+        &self,
+        reservation: &Reservation,
+        rid: ReplicaId,
+        ops: &[T],
+        tids: &[ThreadIdx],
+        accepted: impl Fn(usize) -> bool,
+        wait: &dyn WaitStrategy,
+    ) {
+        let Reservation { pos, nops, .. } = *reservation;
+        if let Some(wal) = &self.wal {
+            wal.append(
+                pos,
+                nops,
+                accepted_runs(nops, &accepted).map(|r| (pos + r.start, &ops[r])),
+                wait,
+            );
+        }
+        self.trace.record(
+            pos,
+            nops,
+            rid,
+            accepted_runs(nops, &accepted).map(|r| (pos + r.start, &tids[r.clone()], &ops[r])),
+            wait,
+        );
+    }
+}
 
-impl<T> Log<T>
+impl<T, const R: usize> Log<T, R>
 where
     T: Sized + Clone,
 {
@@ -83,22 +178,41 @@ where
         idx: &LogToken,
         mut s: F,
     ) -> Result<Option<usize>, usize> {
-        let nops = ops.len();
+        let reservation = self.reserve(ops.len(), idx, &mut s)?;
+        self.publish(&reservation, ops, |_i| true, idx);
+        Ok(self.finish(&reservation, idx, &mut s))
+    }
+
+    /// Reserves `nops` consecutive entries at the tail of the log. The
+    /// entries stay dead (i.e., replicas executing the log wait for them)
+    /// until they are filled in with [`Log::publish`].
+    ///
+    /// Accepts a closure `s` which is passed into exec() while waiting for GC
+    /// (see [`Log::append`]). Returns `Err(usize)` if we waited too long for
+    /// the replica indicated by the `usize`.
+    pub(crate) fn reserve<F: FnMut(T, bool)>(
+        &self,
+        nops: usize,
+        idx: &LogToken,
+        s: &mut F,
+    ) -> Result<Reservation, usize> {
+        self.check_token(idx).unwrap_or_else(|e| token_mismatch(e));
         let mut iteration = 1;
         let mut waitgc = 1;
+        let mut gc_waiter = Waiter::new(&*self.wait);
 
-        // Keep trying to reserve entries and add operations to the log until
-        // we succeed in doing so.
+        // Keep trying to reserve entries until we succeed in doing so.
         loop {
             if iteration % WARN_THRESHOLD == 0 {
                 let (min_replica_idx, _min_local_tail) = self.find_min_tail();
                 warn!(
                     "append(ops.len()={}, {}) takes too many iterations ({}) to complete (waiting for {})...",
-                    ops.len(),
+                    nops,
                     idx.0,
                     iteration,
                     min_replica_idx,
                 );
+                self.counters.no_log_space[min_replica_idx].add(1);
                 return Err(min_replica_idx);
             }
             iteration += 1;
@@ -106,84 +220,120 @@ where
             let tail = self.tail.load(Ordering::Relaxed);
             let head = self.head.load(Ordering::Relaxed);
 
-            // If there are fewer than `GC_FROM_HEAD` entries on the log, then just
-            // try again. The replica that reserved entry (h + self.slog.len() - GC_FROM_HEAD)
+            // If there are fewer than `gc_from_head` entries on the log, then just
+            // try again. The replica that reserved entry (h + self.slog.len() - gc_from_head)
             // is currently trying to advance the head of the log. Keep refreshing the
             // replica against the log to make sure that it isn't deadlocking GC.
-            if tail > head + self.slog.len() - GC_FROM_HEAD {
+            if tail > head + self.slog.len() - self.gc_from_head {
                 if waitgc % WARN_THRESHOLD == 0 {
                     warn!(
                         "append(ops.len()={}, {}) takes too many iterations ({}) waiting for gc...",
-                        ops.len(),
-                        idx.0,
-                        waitgc,
+                        nops, idx.0, waitgc,
                     );
                     let (min_replica_idx, _min_local_tail) = self.find_min_tail();
+                    self.counters.no_log_space[min_replica_idx].add(1);
                     return Err(min_replica_idx);
                 }
+                gc_waiter.wait();
                 waitgc += 1;
-                self.exec(idx, &mut s);
-                self.advance_head(idx, &mut s)?;
+                self.exec(idx, s);
+                if let Err(min_replica_idx) = self.advance_head(idx, s) {
+                    self.counters.no_log_space[min_replica_idx].add(1);
+                    return Err(min_replica_idx);
+                }
 
                 #[cfg(loom)]
                 loom::thread::yield_now();
                 continue;
             }
 
-            // If on adding in the above entries there would be fewer than `GC_FROM_HEAD`
+            // If on adding in the above entries there would be fewer than `gc_from_head`
             // entries left on the log, then we need to advance the head of the log.
             let mut advance = false;
-            if tail + nops > head + self.slog.len() - GC_FROM_HEAD {
+            if tail + nops > head + self.slog.len() - self.gc_from_head {
                 advance = true
             };
 
             // Try reserving slots for the operations. If that fails, then restart
-            // from the beginning of this loop.
+            // from the beginning of this loop. (`SeqCst` so a trace that starts
+            // after the reservation is seen when recording, see
+            // `TraceSlot::replace`.)
             if self.tail.compare_exchange_weak(
                 tail,
                 tail + nops,
-                Ordering::Acquire,
+                Ordering::SeqCst,
                 Ordering::Acquire,
             ) != Ok(tail)
             {
                 continue;
             };
 
-            // Successfully reserved entries on the shared log. Add the operations in.
-            for (i, op) in ops.iter().enumerate().take(nops) {
-                let e = self.slog[self.index(tail + i)].as_ptr();
-                let mut m = self.lmasks[idx.0 - 1].get();
-
-                // This entry was just reserved so it should be dead (!= m). However, if
-                // the log has wrapped around, then the alive mask has flipped. In this
-                // case, we flip the mask we were originally going to write into the
-                // allocated entry. We cannot flip lmasks[idx - 1] because this replica
-                // might still need to execute a few entries before the wrap around.
-                if unsafe { (*e).alivef.load(Ordering::Relaxed) == m } {
-                    m = !m;
-                }
+            return Ok(Reservation {
+                pos: tail,
+                nops,
+                advance,
+            });
+        }
+    }
 
-                unsafe { (*e).operation = Some(op.clone()) };
-                unsafe { (*e).replica = idx.0 };
-                unsafe { (*e).alivef.store(m, Ordering::Release) };
+    /// Fills the entries of `reservation` in with `ops` and makes them
+    /// visible to all replicas. Entries of operations that are not
+    /// `accepted(i)` become tombstones, which are skipped by exec().
+    pub(crate) fn publish(
+        &self,
+        reservation: &Reservation,
+        ops: &[T],
+        accepted: impl Fn(usize) -> bool,
+        idx: &LogToken,
+    ) {
+        let Reservation { pos, nops, .. } = *reservation;
+
+        // Add the operations into the reserved entries.
+        for (i, p) in (pos..pos + nops).enumerate() {
+            let e = self.slog[self.index(p)].as_ptr();
+            let mut m = self.lmasks[idx.0 - 1].get();
+
+            // This entry was just reserved so it should be dead (!= m). However, if
+            // the log has wrapped around, then the alive mask has flipped. In this
+            // case, we flip the mask we were originally going to write into the
+            // allocated entry. We cannot flip lmasks[idx - 1] because this replica
+            // might still need to execute a few entries before the wrap around.
+            if unsafe { (*e).alivef.load(Ordering::Relaxed) == m } {
+                m = !m;
             }
 
-            // If needed, advance the head of the log forward to make room on the log.
-            return if advance {
-                // If `advance_head()` fails to advance it will return the
-                // replica we're waiting for as an error. If a client calls
-                // `combine()` this isn't considered an error as we have
-                // succesfully applied the operations. But, we should still make
-                // sure to eventually `unstuck` the replica we waited for, so
-                // we transform the error to an Ok(Option<usize>) to convey this
-                // information to clients.
-                match self.advance_head(idx, &mut s) {
-                    Ok(_) => Ok(None),
-                    Err(min_replica_idx) => Ok(Some(min_replica_idx)),
-                }
-            } else {
-                Ok(None)
-            };
+            unsafe { (*e).operation = accepted(i).then(|| ops[i].clone()) };
+            unsafe { (*e).replica = idx.0 };
+            unsafe { (*e).alivef.store(m, Ordering::Release) };
+        }
+    }
+
+    /// Completes an append once the entries of `reservation` were published.
+    ///
+    /// If needed, advances the head of the log forward to make room on the
+    /// log. Returns the replica we're waiting for if that didn't work.
+    pub(crate) fn finish<F: FnMut(T, bool)>(
+        &self,
+        reservation: &Reservation,
+        idx: &LogToken,
+        s: &mut F,
+    ) -> Option<usize> {
+        if !reservation.advance {
+            return None;
+        }
+
+        // If `advance_head()` fails to advance it will return the replica
+        // we're waiting for as an error. If a client calls `combine()` this
+        // isn't considered an error as we have succesfully applied the
+        // operations. But, we should still make sure to eventually `unstuck`
+        // the replica we waited for, so we transform the error to an
+        // Option<usize> to convey this information to clients.
+        match self.advance_head(idx, s) {
+            Ok(_) => None,
+            Err(min_replica_idx) => {
+                self.counters.gc_failed[min_replica_idx].add(1);
+                Some(min_replica_idx)
+            }
         }
     }
 
@@ -235,12 +385,20 @@ where
     /// ```
     #[inline(always)]
     pub(crate) fn exec<F: FnMut(T, bool)>(&self, idx: &LogToken, d: &mut F) {
+        self.exec_until(idx, self.tail.load(Ordering::Relaxed), d)
+    }
+
+    /// Same as [`Log::exec`], but only executes the operations before logical
+    /// index `gtail`, e.g., to catch up with the log right before entries the
+    /// replica reserved.
+    #[inline(always)]
+    pub(crate) fn exec_until<F: FnMut(T, bool)>(&self, idx: &LogToken, gtail: LogPos, d: &mut F) {
         // Load the logical log offset from which we must execute operations.
         let ltail = self.ltails[idx.0 - 1].load(Ordering::Relaxed);
 
-        // Check if we have any work to do by comparing our local tail with the log's
-        // global tail. If they're equal, then we're done here and can simply return.
-        let gtail = self.tail.load(Ordering::Relaxed);
+        // Check if we have any work to do by comparing our local tail with the
+        // tail we execute up to. If they're equal, then we're done here and can
+        // simply return.
         if ltail == gtail {
             return;
         }
@@ -258,29 +416,34 @@ where
         for i in ltail..gtail {
             let mut iteration = 1;
             let e = self.slog[self.index(i)].as_ptr();
-
-            while unsafe { (*e).alivef.load(Ordering::Acquire) != self.lmasks[idx.0 - 1].get() } {
-                if iteration % WARN_THRESHOLD == 0 {
-                    warn!(
-                        "alivef not being set for self.index(i={}) = {} (self.lmasks[{}] is {})...",
-                        i,
-                        self.index(i),
-                        idx.0 - 1,
-                        self.lmasks[idx.0 - 1].get()
-                    );
+            let alive =
+                || unsafe { (*e).alivef.load(Ordering::Acquire) == self.lmasks[idx.0 - 1].get() };
+
+            if !alive() {
+                let mut waiter = Waiter::new(&*self.wait);
+                while !alive() {
+                    if iteration % WARN_THRESHOLD == 0 {
+                        warn!(
+                            "alivef not being set for self.index(i={}) = {} (self.lmasks[{}] is {})...",
+                            i,
+                            self.index(i),
+                            idx.0 - 1,
+                            self.lmasks[idx.0 - 1].get()
+                        );
+                    }
+                    waiter.wait();
+                    iteration += 1;
+
+                    #[cfg(loom)]
+                    loom::thread::yield_now();
                 }
-                iteration += 1;
-
-                #[cfg(loom)]
-                loom::thread::yield_now();
             }
 
-            unsafe {
-                d(
-                    (*e).operation.as_ref().unwrap().clone(),
-                    (*e).replica == idx.0,
-                )
-            };
+            // Tombstones of rejected operations (see `Log::publish`) aren't
+            // executed.
+            if let Some(op) = unsafe { (*e).operation.as_ref() } {
+                d(op.clone(), unsafe { (*e).replica } == idx.0);
+            }
 
             // Looks like we're going to wrap around now; flip this replica's local mask.
             if self.index(i) == self.slog.len() - 1 {
@@ -304,7 +467,9 @@ where
         // on the log. If one of the replicas has stopped making progress, then
         // this method might never return.
         let mut iteration = 1;
+        let mut waiter = Waiter::new(&*self.wait);
         loop {
+            self.counters.advance_head_iterations.add(1);
             let global_head = self.head.load(Ordering::Relaxed);
             let f = self.tail.load(Ordering::Relaxed);
             let (min_replica_idx, min_local_tail) = self.find_min_tail();
@@ -312,11 +477,15 @@ where
             // If we cannot advance the head further, then start
             // from the beginning of this loop again. Before doing so, try consuming
             // any new entries on the log to prevent deadlock.
-            if min_local_tail == global_head {
+            //
+            // The minimum can be below the head while a replica that was just
+            // added (see `Log::register_from`) hasn't published its local tail.
+            if min_local_tail <= global_head {
                 if iteration % WARN_THRESHOLD == 0 {
                     warn!("Spending a long time in `advance_head`, are we starving (min_replica_idx = {})?", min_replica_idx);
                     return Err(min_replica_idx);
                 }
+                waiter.wait();
                 iteration += 1;
                 self.exec(rid, &mut s);
 
@@ -331,7 +500,7 @@ where
             // Make sure that we freed up enough space so that threads waiting for
             // GC in append can make progress. Otherwise, try to make progress again.
             // If we're making progress again, then try consuming entries on the log.
-            if f < min_local_tail + self.slog.len() - GC_FROM_HEAD {
+            if f < min_local_tail + self.slog.len() - self.gc_from_head {
                 return Ok(());
             } else {
                 self.exec(rid, &mut s);
@@ -411,6 +580,29 @@ mod tests {
         assert_eq!(l.head.load(Ordering::Relaxed), 224);
     }
 
+    // Tests that a removed replica no longer holds back the head of the log and
+    // that its slot is reused by the next replica that is added.
+    #[test]
+    fn test_log_unregister_register_from() {
+        let l = Log::<Operation>::default();
+        let lt = l.register().unwrap();
+        let lagging = l.register().unwrap();
+
+        l.ltails[0].store(1023, Ordering::Relaxed);
+        l.ltails[1].store(224, Ordering::Relaxed);
+        l.unregister(lagging);
+
+        assert!(l
+            .advance_head(&lt, &mut |_o: Operation, _mine: bool| {})
+            .is_ok());
+        assert_eq!(l.head.load(Ordering::Relaxed), 1023);
+
+        let added = l.register_from(&lt).unwrap();
+        assert_eq!(added, LogToken(2, l.instance));
+        assert_eq!(l.ltails[1].load(Ordering::Relaxed), 1023);
+        assert_eq!(l.next.load(Ordering::Relaxed), 3);
+    }
+
     // Tests that the head of the log is advanced when we're close to filling up the entire log.
     #[test]
     fn test_log_append_gc() {
@@ -685,8 +877,8 @@ mod tests {
         let one = l.register().unwrap();
         let two = l.register().unwrap();
 
-        assert_eq!(one, LogToken(1));
-        assert_eq!(two, LogToken(2));
+        assert_eq!(one, LogToken(1, l.instance));
+        assert_eq!(two, LogToken(2, l.instance));
 
         let o = [Operation::Read];
         let mut f = |op: Operation, mine: bool| {
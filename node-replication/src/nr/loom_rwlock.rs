@@ -6,25 +6,26 @@
 
 #![cfg(loom)]
 
-pub struct RwLock<T>
+/// `N` (the maximum number of readers) is ignored by loom's lock.
+pub struct RwLock<T, const N: usize = { crate::replica::MAX_THREADS_PER_REPLICA }>
 where
     T: Sized + Sync,
 {
     inner: loom::sync::RwLock<T>,
 }
 
-impl<T> Default for RwLock<T>
+impl<T, const N: usize> Default for RwLock<T, N>
 where
     T: Sized + Default + Sync,
 {
-    fn default() -> RwLock<T> {
+    fn default() -> RwLock<T, N> {
         RwLock {
             inner: loom::sync::RwLock::new(Default::default()),
         }
     }
 }
 
-impl<T> RwLock<T>
+impl<T, const N: usize> RwLock<T, N>
 where
     T: Sized + Sync,
 {
@@ -41,4 +42,20 @@ where
     pub fn read(&self, _tid: usize) -> loom::sync::RwLockReadGuard<'_, T> {
         self.inner.read().unwrap()
     }
+
+    pub fn write_with(
+        &self,
+        n: usize,
+        _wait: &dyn crate::wait::WaitStrategy,
+    ) -> loom::sync::RwLockWriteGuard<'_, T> {
+        self.write(n)
+    }
+
+    pub fn read_with(
+        &self,
+        tid: usize,
+        _wait: &dyn crate::wait::WaitStrategy,
+    ) -> loom::sync::RwLockReadGuard<'_, T> {
+        self.read(tid)
+    }
 }
@@ -71,20 +71,31 @@
 //! ```
 
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt::Debug;
+use core::hint::spin_loop;
 use core::marker::Sync;
 use core::num::NonZeroUsize;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 #[cfg(feature = "async")]
 use reusable_box::ReusableBoxFuture;
 
 use arrayvec::ArrayVec;
+use crossbeam_utils::CachePadded;
+
+use crate::replica::token_mismatch;
+use crate::wait::{WaitStrategy, Waiter};
+use crate::wal::Wal;
 
 mod context;
+#[cfg(feature = "async")]
+mod future;
 pub mod log;
 pub mod replica;
 #[cfg(feature = "async")]
 pub mod reusable_box;
+pub mod trace;
 
 #[cfg(not(loom))]
 #[path = "rwlock.rs"]
@@ -93,7 +104,12 @@ pub mod rwlock;
 #[path = "loom_rwlock.rs"]
 pub mod rwlock;
 
-pub use log::{Log, MAX_REPLICAS_PER_LOG};
+pub use crate::replica::{InstanceId, TokenMismatch};
+use context::MAX_PENDING_OPS;
+use log::LogHooks;
+pub use log::{Log, LogPos, MAX_REPLICAS_PER_LOG};
+use replica::MAX_THREADS_PER_REPLICA;
+use replica::{unwrap_validated, Validated};
 pub use replica::{CombinerLock, Replica, ReplicaError, ReplicaId, ReplicaToken};
 
 /// Trait that a (single-threaded) data structure must implement to be usable
@@ -127,6 +143,22 @@ pub trait Dispatch {
     /// `ReadOperation` or a `WriteOperation` successfully executes against it.
     type Response: Sized + Clone;
 
+    /// The error returned for a `WriteOperation` that was rejected by
+    /// [`Dispatch::validate`].
+    ///
+    /// Defaults to [`Infallible`](core::convert::Infallible) for data
+    /// structures which don't validate their operations.
+    type Error: Sized + Clone = core::convert::Infallible;
+
+    /// Set to `true` by data structures that override [`Dispatch::validate`]:
+    /// it's only called if this is set.
+    ///
+    /// Validation isn't free: the combiner has to apply the log up to its
+    /// operations before it can publish them, so other replicas can't apply
+    /// them at the same time as the combiner does. Without validation, the
+    /// operations are published as soon as they have a place on the log.
+    const VALIDATES: bool = false;
+
     /// Method on the data structure that allows a read-only operation to be
     /// executed against it.
     fn dispatch(&self, op: Self::ReadOperation<'_>) -> Self::Response;
@@ -134,6 +166,52 @@ pub trait Dispatch {
     /// Method on the data structure that allows a write operation to be
     /// executed against it.
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response;
+
+    /// Checks whether a write operation should be executed at all.
+    ///
+    /// If [`Dispatch::VALIDATES`] is set, the combiner calls this on its
+    /// local replica for every operation it collects, once the replica caught
+    /// up with the log up to the entry of the operation. Hence, the operation
+    /// is checked against exactly the state it would be applied to on every
+    /// replica, including the effects of the operations collected before it
+    /// in the same batch. A rejected operation is never executed on any
+    /// replica and its error is returned to the caller of
+    /// [`NodeReplicated::execute_mut_checked`].
+    ///
+    /// # Note
+    /// - The entry of an operation on the log is reserved before it's
+    ///   validated (that's what pins down the state it's checked against). So
+    ///   a rejected operation still takes up its entry: a tombstone that every
+    ///   replica skips (and that isn't recorded in the write-ahead log or
+    ///   trace).
+    /// - The operations of an atomic group (see
+    ///   [`NodeReplicated::execute_mut_atomic`]) are all checked against the
+    ///   state before the group.
+    /// - Methods which don't return a [`Result`] (e.g.,
+    ///   [`NodeReplicated::execute_mut`]) panic if their operation is
+    ///   rejected.
+    ///
+    /// The default implementation accepts every operation.
+    fn validate(&self, _op: &Self::WriteOperation) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Optional trait for data structures that can capture their state in a
+/// snapshot and be re-created from it.
+///
+/// It is used by [`NodeReplicated::snapshot`] and [`NodeReplicated::restore`],
+/// e.g., to restart quickly or to capture the state for debugging.
+/// [`NodeReplicated::checkpoint`] works without it.
+pub trait Snapshot: Dispatch {
+    /// The captured state of the data structure.
+    type Snapshot;
+
+    /// Captures the current state of the data structure.
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Creates a data structure with the state captured in `snapshot`.
+    fn restore(snapshot: &Self::Snapshot) -> Self;
 }
 
 /// A token handed out to threads registered with replicas.
@@ -148,10 +226,12 @@ pub struct ThreadToken {
     ///
     /// # Note
     /// Usually this would represent e.g., the NUMA node of the thread.
-    rid: ReplicaId,
+    pub(crate) rid: ReplicaId,
     /// The registration token for this thread that we got from the replica
     /// (through [`Replica::register`]) identified by `rid`.
-    rtkn: ReplicaToken,
+    pub(crate) rtkn: ReplicaToken,
+    /// The [`NodeReplicated`] instance that issued the token.
+    pub(crate) nr: InstanceId,
 }
 
 impl ThreadToken {
@@ -160,10 +240,15 @@ impl ThreadToken {
     /// # Safety
     /// This method should only ever be used for the benchmark harness to create
     /// additional, fake replica implementations. If we had something like `pub(test)` we
-    /// should declare it like that instead of just `pub`.
+    /// should declare it like that instead of just `pub`. The token is
+    /// accepted by every [`NodeReplicated`] instance.
     #[doc(hidden)]
     pub fn new(rid: ReplicaId, rtkn: ReplicaToken) -> Self {
-        Self { rid, rtkn }
+        Self {
+            rid,
+            rtkn,
+            nr: InstanceId::ANY,
+        }
     }
 }
 
@@ -171,6 +256,88 @@ impl ThreadToken {
 #[cfg(not(feature = "async"))]
 impl !Send for ThreadToken {}
 
+/// A handle for a mutable operation that was submitted with
+/// [`NodeReplicated::submit_mut`].
+///
+/// It can be used to retrieve the operation's response with
+/// [`NodeReplicated::poll`] or [`NodeReplicated::wait`]. If the ticket is
+/// dropped before, the operation is still executed but its response is
+/// discarded.
+#[must_use = "the operation's response is discarded if the ticket is dropped"]
+pub struct Ticket<
+    'a,
+    D: Dispatch + Sync,
+    const R: usize = MAX_REPLICAS_PER_LOG,
+    const T: usize = MAX_THREADS_PER_REPLICA,
+    const P: usize = MAX_PENDING_OPS,
+> {
+    /// The instance the operation was submitted to.
+    nr: &'a NodeReplicated<D, R, T, P>,
+    /// The thread that submitted the operation.
+    tkn: ThreadToken,
+    /// Logical index of the operation in the thread's context.
+    slot: usize,
+    /// Set once the response was handed out by [`NodeReplicated::poll`].
+    completed: bool,
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Debug for Ticket<'_, D, R, T, P>
+where
+    D: Dispatch + Sync,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Ticket")
+            .field("tkn", &self.tkn)
+            .field("slot", &self.slot)
+            .field("completed", &self.completed)
+            .finish()
+    }
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Drop for Ticket<'_, D, R, T, P>
+where
+    D: Dispatch + Sync,
+{
+    /// Releases the operation's context slot, its response is dropped once it
+    /// arrives.
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        if let Ok(replica) = self.nr.replica_of(self.tkn) {
+            replica.abandon(self.tkn.rtkn, self.slot);
+        }
+    }
+}
+
+/// A mutable operation handed back by [`NodeReplicated::execute_mut_timeout`]
+/// or [`NodeReplicated::try_execute_mut`] in an [`ExecuteError`].
+pub enum Pending<
+    'a,
+    D: Dispatch + Sync,
+    const R: usize = MAX_REPLICAS_PER_LOG,
+    const T: usize = MAX_THREADS_PER_REPLICA,
+    const P: usize = MAX_PENDING_OPS,
+> {
+    /// The operation was not submitted and can be retried.
+    Op(<D as Dispatch>::WriteOperation),
+    /// The operation was submitted, its response can be retrieved with the
+    /// ticket.
+    Submitted(Ticket<'a, D, R, T, P>),
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Debug for Pending<'_, D, R, T, P>
+where
+    D: Dispatch + Sync,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Pending::Op(_) => f.write_str("Op(..)"),
+            Pending::Submitted(ticket) => f.debug_tuple("Submitted").field(ticket).finish(),
+        }
+    }
+}
+
 /// Argument that is passed to a user specified function (in
 /// [`NodeReplicated::new`]) to indicate that our thread will change the replica
 /// it's operating on.
@@ -220,14 +387,14 @@ pub enum AffinityChange {
 /// replica (passed as an argument) should come from.
 ///
 /// See also [`AffinityChange`] and [`AffinityToken`].
-type AffinityChangeFn = dyn Fn(AffinityChange) -> usize + Send + Sync;
+pub(crate) type AffinityChangeFn = dyn Fn(AffinityChange) -> usize + Send + Sync;
 
 /// The [`AffinityManager`] creates affinity tokens whenever we request to
 /// change the memory allocation affinity for a given thread.
 ///
 /// The tokens take care of calling the `af_change_fn` that's usually provided
 /// by a user.
-struct AffinityManager {
+pub(crate) struct AffinityManager {
     af_change_fn: Box<AffinityChangeFn>,
 }
 
@@ -238,7 +405,7 @@ impl AffinityManager {
     /// - `af_change_fn`: User provided function, or can be some default for
     /// e.g., Linux that relies on migrating threads a NUMA aware mallocs and
     /// the first-touch policy.
-    fn new(af_change_fn: Box<AffinityChangeFn>) -> Self {
+    pub(crate) fn new(af_change_fn: Box<AffinityChangeFn>) -> Self {
         Self { af_change_fn }
     }
 
@@ -247,14 +414,14 @@ impl AffinityManager {
     /// The token will call the user-provided function to change the memory
     /// affinity and once it gets dropped, it will tell the user to revert the
     /// change.
-    fn switch(&self, rid: ReplicaId) -> AffinityToken<'_> {
+    pub(crate) fn switch(&self, rid: ReplicaId) -> AffinityToken<'_> {
         AffinityToken::new(&self.af_change_fn, rid)
     }
 }
 
 /// A token that is in charge of orchestrating memory affinity changes for a
 /// thread.
-struct AffinityToken<'f> {
+pub(crate) struct AffinityToken<'f> {
     af_chg_fn: &'f dyn Fn(AffinityChange) -> usize,
     old: usize,
 }
@@ -276,11 +443,228 @@ impl<'f> Drop for AffinityToken<'f> {
     }
 }
 
+/// The replicas of a [`NodeReplicated`] instance, indexed by [`ReplicaId`].
+///
+/// Replicas are added and removed through a shared reference while other
+/// threads use them. Threads pin the slot of a replica while they hold a
+/// reference to it (see [`ReplicaRef`]), so removing (or replacing) a replica
+/// unlinks it from its slot and frees it once the slot is no longer pinned.
+struct ReplicaTable<D, const R: usize, const T: usize, const P: usize>
+where
+    D: Dispatch + Sync,
+{
+    /// The current replica of every [`ReplicaId`].
+    slots: Vec<ReplicaSlot<D, R, T, P>>,
+    /// One past the highest [`ReplicaId`] that was ever used.
+    len: AtomicUsize,
+    /// Serializes changes to the table.
+    locked: AtomicBool,
+}
+
+/// A slot of the [`ReplicaTable`].
+struct ReplicaSlot<D, const R: usize, const T: usize, const P: usize>
+where
+    D: Dispatch + Sync,
+{
+    /// The replica (from `Box::into_raw`), null if there is none.
+    replica: AtomicPtr<Replica<D, R, T, P>>,
+    /// Number of threads that may hold a reference to the replica.
+    pins: CachePadded<AtomicUsize>,
+}
+
+// The replicas are shared between threads through `slots`, and only freed
+// once nobody can reference them anymore.
+unsafe impl<D, const R: usize, const T: usize, const P: usize> Sync for ReplicaTable<D, R, T, P>
+where
+    D: Dispatch + Sync,
+    Replica<D, R, T, P>: Sync,
+{
+}
+
+unsafe impl<D, const R: usize, const T: usize, const P: usize> Send for ReplicaTable<D, R, T, P>
+where
+    D: Dispatch + Sync,
+    Replica<D, R, T, P>: Send,
+{
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> ReplicaTable<D, R, T, P>
+where
+    D: Dispatch + Sync,
+{
+    /// Creates an empty table with room for `R` replicas.
+    fn new() -> Result<Self, NodeReplicatedError> {
+        let mut slots = Vec::new();
+        slots.try_reserve_exact(R)?;
+        slots.resize_with(R, || ReplicaSlot {
+            replica: AtomicPtr::new(core::ptr::null_mut()),
+            pins: CachePadded::new(AtomicUsize::new(0)),
+        });
+        Ok(Self {
+            slots,
+            len: AtomicUsize::new(0),
+            locked: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the current replica with id `rid`, if there is one. It isn't
+    /// freed before the returned reference is dropped.
+    #[inline(always)]
+    fn get(&self, rid: ReplicaId) -> Option<ReplicaRef<'_, D, R, T, P>> {
+        let slot = self.slots.get(rid)?;
+        // Pin the slot before we load the replica: `unlink` clears the slot
+        // first and only frees the replica once it's no longer pinned.
+        slot.pins.fetch_add(1, Ordering::SeqCst);
+        let replica = slot.replica.load(Ordering::SeqCst);
+        // Safety: The replica is alive until we unpin the slot.
+        match unsafe { replica.as_ref() } {
+            Some(replica) => Some(ReplicaRef {
+                replica,
+                pins: &slot.pins,
+            }),
+            None => {
+                slot.pins.fetch_sub(1, Ordering::Release);
+                None
+            }
+        }
+    }
+
+    /// Returns the current replicas together with their ids.
+    fn iter(&self) -> impl Iterator<Item = (ReplicaId, ReplicaRef<'_, D, R, T, P>)> {
+        (0..self.len()).filter_map(move |rid| Some((rid, self.get(rid)?)))
+    }
+
+    /// Returns one past the highest [`ReplicaId`] that was ever used.
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Returns mutable references to the current replicas.
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Replica<D, R, T, P>> {
+        let slots = &self.slots;
+        (0..*self.len.get_mut()).filter_map(move |rid| {
+            // Safety: We have exclusive access to the table.
+            unsafe { slots[rid].replica.load(Ordering::Relaxed).as_mut() }
+        })
+    }
+
+    /// Locks the table for changes until the returned guard is dropped.
+    fn lock(&self) -> ReplicaTableGuard<'_, D, R, T, P> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        ReplicaTableGuard { table: self }
+    }
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Drop for ReplicaTable<D, R, T, P>
+where
+    D: Dispatch + Sync,
+{
+    fn drop(&mut self) {
+        for slot in self.slots.iter_mut() {
+            let replica = *slot.replica.get_mut();
+            if !replica.is_null() {
+                // Safety: Installed replicas come from `Box::into_raw`.
+                drop(unsafe { Box::from_raw(replica) });
+            }
+        }
+    }
+}
+
+/// A replica of a [`ReplicaTable`], which pins its slot until it's dropped.
+struct ReplicaRef<'a, D, const R: usize, const T: usize, const P: usize>
+where
+    D: Dispatch + Sync,
+{
+    replica: &'a Replica<D, R, T, P>,
+    pins: &'a AtomicUsize,
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> core::ops::Deref
+    for ReplicaRef<'_, D, R, T, P>
+where
+    D: Dispatch + Sync,
+{
+    type Target = Replica<D, R, T, P>;
+
+    fn deref(&self) -> &Self::Target {
+        self.replica
+    }
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Drop for ReplicaRef<'_, D, R, T, P>
+where
+    D: Dispatch + Sync,
+{
+    fn drop(&mut self) {
+        self.pins.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Holds the lock of a [`ReplicaTable`], which is released on drop.
+struct ReplicaTableGuard<'a, D, const R: usize, const T: usize, const P: usize>
+where
+    D: Dispatch + Sync,
+{
+    table: &'a ReplicaTable<D, R, T, P>,
+}
+
+impl<'a, D, const R: usize, const T: usize, const P: usize> ReplicaTableGuard<'a, D, R, T, P>
+where
+    D: Dispatch + Sync,
+{
+    /// Makes `replica` the replica with id `rid`, the slot has to be empty.
+    fn install(&mut self, rid: ReplicaId, replica: Box<Replica<D, R, T, P>>) {
+        let slot = &self.table.slots[rid];
+        debug_assert!(slot.replica.load(Ordering::Relaxed).is_null());
+        slot.replica
+            .store(Box::into_raw(replica), Ordering::Release);
+        self.table.len.fetch_max(rid + 1, Ordering::AcqRel);
+    }
+
+    /// Unlinks the replica with id `rid` from the table and frees it.
+    ///
+    /// Waits until no thread holds a [`ReplicaRef`] to it anymore, so the
+    /// replica has to be stopped first (see [`Replica::retire`]): threads
+    /// that still use it then bail out instead of waiting for it.
+    fn unlink(&mut self, rid: ReplicaId) {
+        let slot = &self.table.slots[rid];
+        let replica = slot.replica.swap(core::ptr::null_mut(), Ordering::SeqCst);
+        // Pairs with the `fetch_add` in `get`: threads that pin the slot from
+        // now on don't see the replica anymore.
+        while slot.pins.load(Ordering::SeqCst) != 0 {
+            spin_loop();
+        }
+        if !replica.is_null() {
+            // Safety: Installed replicas come from `Box::into_raw`, and
+            // nobody references it anymore.
+            drop(unsafe { Box::from_raw(replica) });
+        }
+    }
+}
+
 /// Errors that can be encountered by interacting with [`NodeReplicated`].
 #[derive(Debug)]
 pub enum NodeReplicatedError {
     /// Not enough memory to create a [`NodeReplicated`] instance.
     OutOfMemory,
+    /// The [`Log`] can't support any more replicas (see the `R` parameter of
+    /// [`NodeReplicated`]).
+    TooManyReplicas,
+    /// The capacity limits `R`, `T` and `P` of [`NodeReplicated`] can't be
+    /// used: `R` and `T` have to be greater than zero, `P` has to be a power
+    /// of two and `T * P` must not overflow.
+    InvalidCapacity,
+    /// The [`ReplicaId`] doesn't refer to a replica that can be removed (or
+    /// recovered).
+    InvalidReplica,
+    /// All replicas are poisoned (see [`NodeReplicated::recover_replica`]).
+    Poisoned,
 }
 
 impl From<core::alloc::AllocError> for NodeReplicatedError {
@@ -295,6 +679,97 @@ impl From<alloc::collections::TryReserveError> for NodeReplicatedError {
     }
 }
 
+/// Errors returned by the bounded and non-blocking execute variants of
+/// [`NodeReplicated`]. Most of them hand back `T` so the caller can retry.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ExecuteError<T> {
+    /// Another thread is the combiner (or the log is full) and no response
+    /// is ready (see [`NodeReplicated::try_execute_mut`]). Contains the
+    /// operation, which was not executed.
+    WouldBlock(T),
+    /// The [`Deadline`] expired before the operation completed.
+    TimedOut(T),
+    /// The thread's replica is poisoned (see
+    /// [`NodeReplicated::recover_replica`]).
+    Poisoned(T),
+    /// The [`ThreadToken`] was rejected (see
+    /// [`NodeReplicated::check_token`]) and the operation was not executed.
+    /// Retrying won't help, so the operation isn't handed back.
+    TokenMismatch(TokenMismatch),
+    /// The operations don't fit into the thread's context at once (see
+    /// [`NodeReplicated::execute_mut_atomic`]), none was executed.
+    TooLarge(T),
+}
+
+impl<T> ExecuteError<T> {
+    /// Maps the value that is handed back with `f`.
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> ExecuteError<U> {
+        match self {
+            ExecuteError::WouldBlock(t) => ExecuteError::WouldBlock(f(t)),
+            ExecuteError::TimedOut(t) => ExecuteError::TimedOut(f(t)),
+            ExecuteError::Poisoned(t) => ExecuteError::Poisoned(f(t)),
+            ExecuteError::TokenMismatch(e) => ExecuteError::TokenMismatch(e),
+            ExecuteError::TooLarge(t) => ExecuteError::TooLarge(f(t)),
+        }
+    }
+}
+
+/// Bounds how long [`NodeReplicated::execute_mut_timeout`] and
+/// [`NodeReplicated::execute_timeout`] wait for an operation to complete.
+///
+/// The library is `no_std`, so it has no notion of time on its own. Either
+/// the number of attempts is bounded or the caller supplies a clock.
+#[derive(Copy, Clone)]
+pub enum Deadline<'c> {
+    /// Gives up after this many attempts to make progress (every attempt
+    /// tries to flat combine once and then waits according to the
+    /// [`WaitStrategy`]).
+    Iterations(usize),
+    /// Gives up once `clock()` returns a value that is greater or equal to
+    /// `at`. The unit is up to the caller (e.g., nanoseconds or TSC ticks).
+    At {
+        /// Returns the current time.
+        clock: &'c dyn Fn() -> u64,
+        /// Point in time (as returned by `clock`) at which we give up.
+        at: u64,
+    },
+    /// Never gives up. The bounded variants then wait like
+    /// [`NodeReplicated::execute`] and [`NodeReplicated::execute_mut`], but
+    /// report errors instead of panicking.
+    Never,
+}
+
+impl Deadline<'_> {
+    /// Returns true if we should give up after `iteration` attempts.
+    fn expired(&self, iteration: usize) -> bool {
+        match self {
+            Deadline::Iterations(n) => iteration >= *n,
+            Deadline::At { clock, at } => clock() >= *at,
+            Deadline::Never => false,
+        }
+    }
+}
+
+impl Debug for Deadline<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Deadline::Iterations(n) => f.debug_tuple("Iterations").field(n).finish(),
+            Deadline::At { at, .. } => f.debug_struct("At").field("at", at).finish(),
+            Deadline::Never => f.write_str("Never"),
+        }
+    }
+}
+
+/// Used by the methods of [`NodeReplicated`] which can't return
+/// [`ReplicaError::Poisoned`] to their caller.
+#[cold]
+fn poisoned(rid: ReplicaId) -> ! {
+    panic!(
+        "Replica {} is poisoned by a panic in `Dispatch::dispatch_mut`, see `recover_replica`",
+        rid
+    )
+}
+
 /// The "main" type of NR which users interact with.
 ///
 /// It is used to wrap a single threaded data-structure that implements
@@ -304,13 +779,64 @@ impl From<alloc::collections::TryReserveError> for NodeReplicatedError {
 /// [`NodeReplicated`] instance. Finally, it routes threads to the correct
 /// replica and handles liveness of replicas by making sure to advance replicas
 /// which are behind automatically.
-pub struct NodeReplicated<D: Dispatch + Sync> {
-    log: Log<D::WriteOperation>,
-    replicas: Vec<Box<Replica<D>>>,
+///
+/// The capacity limits `R` (replicas), `T` (threads per replica) and `P`
+/// (pending operations per thread) are passed on to the [`Log`] and the
+/// [`Replica`]s. The defaults fit most machines; embedded users can shrink
+/// them to save memory and larger machines can raise them. `P` has to be a
+/// power of two. The log holds at least twice `T * P` (rounded up to the next
+/// power of two) entries, regardless of the requested log size.
+///
+/// # Example
+///
+/// ```
+/// #![feature(generic_associated_types)]
+/// use core::num::NonZeroUsize;
+/// use node_replication::nr::{Dispatch, NodeReplicated};
+///
+/// #[derive(Default)]
+/// struct Counter(u64);
+///
+/// impl Dispatch for Counter {
+///     type ReadOperation<'rop> = ();
+///     type WriteOperation = u64;
+///     type Response = u64;
+///
+///     fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+///         self.0
+///     }
+///
+///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+///         self.0 += op;
+///         self.0
+///     }
+/// }
+///
+/// // Up to 4 replicas with 8 threads each, which have at most 4 pending
+/// // operations.
+/// let nr = NodeReplicated::<Counter, 4, 8, 4>::new(NonZeroUsize::new(2).unwrap(), |_| 0)
+///     .unwrap();
+/// let ttkn = nr.register(0).unwrap();
+/// assert_eq!(nr.execute_mut(1, ttkn), 1);
+/// ```
+pub struct NodeReplicated<
+    D: Dispatch + Sync,
+    const R: usize = MAX_REPLICAS_PER_LOG,
+    const T: usize = MAX_THREADS_PER_REPLICA,
+    const P: usize = MAX_PENDING_OPS,
+> {
+    log: Log<D::WriteOperation, R>,
+    /// Write-ahead log and trace of the operations the replicas append to
+    /// `log`, shared with all replicas.
+    hooks: Arc<LogHooks<D::WriteOperation>>,
+    /// The replicas, indexed by [`ReplicaId`].
+    replicas: ReplicaTable<D, R, T, P>,
     affinity_mngr: AffinityManager,
+    /// Unique id of the instance, embedded in the [`ThreadToken`]s it issues.
+    instance: InstanceId,
 }
 
-impl<D> NodeReplicated<D>
+impl<D, const R: usize, const T: usize, const P: usize> NodeReplicated<D, R, T, P>
 where
     D: Default + Dispatch + Sized + Sync,
 {
@@ -373,6 +899,14 @@ where
     /// let replicas = NonZeroUsize::new(2).unwrap();
     /// let nrht = NodeReplicated::<NrHashMap>::new(replicas, linux_chg_affinity).unwrap();
     /// ```
+    ///
+    /// # Errors
+    /// - [`NodeReplicatedError::TooManyReplicas`] if `num_replicas` exceeds
+    ///   `R`.
+    /// - [`NodeReplicatedError::InvalidCapacity`] if the capacity limits `R`,
+    ///   `T` and `P` can't be used (see [`NodeReplicated`]).
+    /// - [`NodeReplicatedError::OutOfMemory`] if allocating the log or the
+    ///   replicas fails.
     pub fn new(
         num_replicas: NonZeroUsize,
         chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
@@ -387,395 +921,3824 @@ where
         chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
         log_size: usize,
     ) -> Result<Self, NodeReplicatedError> {
-        assert!(num_replicas.get() < MAX_REPLICAS_PER_LOG);
-        let affinity_mngr = AffinityManager::new(Box::try_new(chg_mem_affinity)?);
-        let log = Log::new_with_bytes(log_size, ());
-
-        let mut replicas = Vec::new();
-        replicas.try_reserve(num_replicas.get())?;
-
-        for replica_id in 0..num_replicas.get() {
-            let log_token = log
-                .register()
-                .expect("Succeeds (num_replicas < MAX_REPLICAS_PER_LOG)");
-
-            let r = {
-                // Allocate the replica on the proper NUMA node
-                let _aff_tkn = affinity_mngr.switch(replica_id);
-                Box::try_new(Replica::new(log_token))?
-                // aff_tkn is dropped here
-            };
-
-            // This succeeds, we did `try_reserve` earlier so no `try_push` is
-            // necessary.
-            replicas.push(r);
-        }
-
-        Ok(NodeReplicated {
-            replicas,
-            log,
-            affinity_mngr,
+        Self::with_factory_and_log_size(num_replicas, chg_mem_affinity, log_size, |_rid| {
+            Default::default()
         })
     }
 }
 
-impl<D> NodeReplicated<D>
+impl<D, const R: usize, const T: usize, const P: usize> NodeReplicated<D, R, T, P>
 where
     D: Clone + Dispatch + Sized + Sync,
 {
     /// Same as [`NodeReplicated::new`], but provide the initial data-structure
     /// `ds` (which may not have a [`Default`] constructor).
     ///
-    /// `ds` will be cloned for each replica.
-    pub fn with_data(
-        _num_replicas: NonZeroUsize,
-        _chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
-        _ds: D,
-    ) -> Result<Self, NodeReplicatedError> {
-        unimplemented!("complete me")
-    }
-}
-
-impl<D> NodeReplicated<D>
-where
-    D: Dispatch + Sized + Sync,
-{
-    /// Registers a thread with a given replica in the [`NodeReplicated`]
-    /// data-structure. Returns an Option containing a [`ThreadToken`] if the
-    /// registration was successful. None if the registration failed.
-    ///
-    /// The [`ThreadToken`] is used to identify the thread to issue the
-    /// operation for subsequent [`NodeReplicated::execute`] and
-    /// [`NodeReplicated::execute_mut`] calls.
-    ///
-    /// # Arguments
-    ///
-    /// - `replica_id`: Which replica the thread should be registered with. This
-    /// should be less than the `num_replicas` argument provided in the
-    /// constructor (see [`NodeReplicated::new`]). In most cases, `replica_id`
-    /// will correspond to the NUMA node that the thread is running on.
+    /// `ds` will be cloned for each replica. The clone for a given replica is
+    /// made after switching the memory affinity to that replica (see
+    /// [`AffinityChange`]), so any memory the clone allocates comes from the
+    /// right NUMA node.
     ///
     /// # Example
     ///
     /// ```
     /// #![feature(generic_associated_types)]
     /// use core::num::NonZeroUsize;
-    /// use node_replication::nr::NodeReplicated;
     /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
     ///
-    /// #[derive(Default)]
-    /// struct Void;
-    /// impl Dispatch for Void {
+    /// // The data structure we want replicated (note: no `Default`).
+    /// #[derive(Clone)]
+    /// struct Counter {
+    ///     value: u64,
+    /// }
+    ///
+    /// impl Dispatch for Counter {
     ///     type ReadOperation<'rop> = ();
-    ///     type WriteOperation = ();
-    ///     type Response = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
     ///
-    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {}
-    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {}
+    ///     fn dispatch<'rop>(
+    ///         &self,
+    ///         _op: Self::ReadOperation<'rop>,
+    ///     ) -> Self::Response {
+    ///         self.value
+    ///     }
+    ///
+    ///     fn dispatch_mut(
+    ///         &mut self,
+    ///         op: Self::WriteOperation,
+    ///     ) -> Self::Response {
+    ///         self.value += op;
+    ///         self.value
+    ///     }
     /// }
     ///
     /// let replicas = NonZeroUsize::new(2).unwrap();
-    /// let nrht = NodeReplicated::<Void>::new(replicas, |_| { 0 }).unwrap();
-    /// let ttkn = nrht.register(0).unwrap();
-    /// assert!(nrht.register(replicas.get()).is_none());
+    /// let nrht =
+    ///     NodeReplicated::<Counter>::with_data(replicas, |_| 0, Counter { value: 100 }).unwrap();
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert_eq!(nrht.execute((), ttkn), 100);
     /// ```
-    pub fn register(&self, replica_id: ReplicaId) -> Option<ThreadToken> {
-        if replica_id < self.replicas.len() {
-            let rtkn = self.replicas[replica_id].register()?;
-            Some(ThreadToken::new(replica_id, rtkn))
-        } else {
-            None
-        }
+    pub fn with_data(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        ds: D,
+    ) -> Result<Self, NodeReplicatedError> {
+        Self::with_data_and_log_size(num_replicas, chg_mem_affinity, log::DEFAULT_LOG_BYTES, ds)
     }
 
-    fn try_execute_mut<'a>(
-        &'a self,
-        op: <D as Dispatch>::WriteOperation,
-        tkn: ThreadToken,
-        cl: Option<CombinerLock<'a, D>>,
-    ) -> Result<<D as Dispatch>::Response, ReplicaError<D>> {
-        if let Some(combiner_lock) = cl {
-            // We expect to have already enqueued the op (it's a re-try since have the combiner lock),
-            // so technically its not needed to supply it again (but we currently do it anyways...)
-            self.replicas[tkn.rid].execute_mut_locked(&self.log, op, tkn.rtkn, combiner_lock)
-        } else {
-            self.replicas[tkn.rid].execute_mut(&self.log, op, tkn.rtkn)
-        }
+    /// Same as [`NodeReplicated::with_data`], but in addition use a non-default
+    /// size (provided in bytes) for the [`Log`].
+    pub fn with_data_and_log_size(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        log_size: usize,
+        ds: D,
+    ) -> Result<Self, NodeReplicatedError> {
+        Self::with_factory_and_log_size(num_replicas, chg_mem_affinity, log_size, |_rid| ds.clone())
     }
+}
 
-    /// Executes a mutable operation against the data-structure.
+impl<D, const R: usize, const T: usize, const P: usize> NodeReplicated<D, R, T, P>
+where
+    D: Dispatch + Sized + Sync,
+{
+    /// Same as [`NodeReplicated::new`], but every replica's initial
+    /// data-structure is created by calling `factory` with the [`ReplicaId`]
+    /// of the replica.
     ///
-    /// Thanks to the [`Log`] that [`NodeReplicated`] uses, all replicas will
-    /// execute all mutable operations in the same order.
+    /// `factory` is invoked after switching the memory affinity to the replica
+    /// it's called for, so each data-structure can be built directly on its own
+    /// NUMA node rather than being cloned from a remote copy.
     ///
-    ///  This method is similar to the one found in [`Replica::execute_mut`],
-    /// but in addition, it handles liveness issues due to lagging replicas
-    /// (which a single replica can not).
+    /// # Note
+    /// `factory` must produce an identical initial state for every replica. If
+    /// not, operations executed on different replicas may give different
+    /// results.
+    pub fn with_factory(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        factory: impl FnMut(ReplicaId) -> D,
+    ) -> Result<Self, NodeReplicatedError> {
+        Self::with_factory_and_log_size(
+            num_replicas,
+            chg_mem_affinity,
+            log::DEFAULT_LOG_BYTES,
+            factory,
+        )
+    }
+
+    /// Same as [`NodeReplicated::with_factory`], but in addition use a
+    /// non-default size (provided in bytes) for the [`Log`].
+    pub fn with_factory_and_log_size(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        log_size: usize,
+        mut factory: impl FnMut(ReplicaId) -> D,
+    ) -> Result<Self, NodeReplicatedError> {
+        let log = Self::new_log(log_size)?;
+        Self::with_log(num_replicas, chg_mem_affinity, log, |_log, rid| {
+            factory(rid)
+        })
+    }
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> NodeReplicated<D, R, T, P>
+where
+    D: Dispatch + Sized + Sync,
+{
+    /// Creates a new, replicated data-structure from a `snapshot` (see
+    /// [`NodeReplicated::snapshot`]) with [`Snapshot::restore`].
     ///
-    /// # Arguments
-    /// - `op`: Which operation to execute.
-    /// - `tkn`: Which thread executes the operation (see also
-    ///   [`NodeReplicated::register`]).
+    /// The log starts off at `pos`, the [`LogPos`] the snapshot was taken at,
+    /// so the positions of subsequent operations continue where the original
+    /// data-structure left off.
     ///
-    /// # Flow
-    /// Eventually, this method calls [`Replica::execute_mut`] which will call
-    /// into [`Dispatch::dispatch_mut`].
+    /// See [`NodeReplicated::new`] for a description of the other arguments.
     ///
     /// # Example
     /// ```
     /// #![feature(generic_associated_types)]
     /// use core::num::NonZeroUsize;
-    /// use node_replication::nr::NodeReplicated;
-    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::{Dispatch, NodeReplicated, Snapshot};
     ///
     /// #[derive(Default)]
-    /// struct Void;
-    /// impl Dispatch for Void {
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
     ///     type ReadOperation<'rop> = ();
     ///     type WriteOperation = usize;
     ///     type Response = usize;
     ///
     ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
-    ///         unreachable!("no read-op is issued")
+    ///         self.0
     ///     }
     ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
-    ///         assert!(op == 99);
-    ///         // "eventually", because if we just do one `execute_mut` call
-    ///         // we won't immediately advance the 2nd replica
-    ///         println!("Having two replicas means I'm (eventually) called twice");
-    ///         0xbeef
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// impl Snapshot for Counter {
+    ///     type Snapshot = usize;
+    ///
+    ///     fn snapshot(&self) -> usize {
+    ///         self.0
+    ///     }
+    ///     fn restore(snapshot: &usize) -> Self {
+    ///         Counter(*snapshot)
     ///     }
     /// }
     ///
     /// let replicas = NonZeroUsize::new(2).unwrap();
-    /// let nrht = NodeReplicated::<Void>::new(replicas, |_| { 0 }).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
     /// let ttkn = nrht.register(0).unwrap();
+    /// nrht.execute_mut(5, ttkn);
+    /// let (snapshot, pos) = nrht.snapshot(ttkn);
+    /// assert_eq!((snapshot, pos), (5, 1));
     ///
-    /// assert_eq!(nrht.execute_mut(99, ttkn), 0xbeef);
+    /// let restored = NodeReplicated::<Counter>::restore(replicas, |_| { 0 }, &snapshot, pos).unwrap();
+    /// let ttkn = restored.register(1).unwrap();
+    /// assert_eq!(restored.execute((), ttkn), 5);
     /// ```
-    pub fn execute_mut(
-        &self,
-        op: <D as Dispatch>::WriteOperation,
-        tkn: ThreadToken,
-    ) -> <D as Dispatch>::Response {
-        /// An enum to keep track of a stack of operations we should do on Replicas.
-        ///
-        /// e.g., either `Sync` an out-of-date, behind replica, or call `execute_locked` or
-        /// `execute_mut_locked` to resume the operation with a combiner lock.
-        enum ResolveOp<'a, D: core::marker::Sync + Dispatch + Sized> {
-            /// Resumes a replica that earlier returned with an Error (and the CombinerLock).
-            Exec(Option<CombinerLock<'a, D>>),
-            /// Indicates need to [`Replica::sync()`] a replica with the given ID.
-            Sync(ReplicaId),
-        }
-
-        let mut q = ArrayVec::<ResolveOp<D>, { crate::log::MAX_REPLICAS_PER_LOG }>::new();
-        loop {
-            match q.pop().unwrap_or(ResolveOp::Exec(None)) {
-                ResolveOp::Exec(cl) => match self.try_execute_mut(op.clone(), tkn, cl) {
-                    Ok(resp) => {
-                        assert!(q.is_empty());
-                        return resp;
-                    }
-                    Err(ReplicaError::NoLogSpace(stuck_ridx, cl_acq)) => {
-                        assert_ne!(stuck_ridx, tkn.rid);
-                        q.push(ResolveOp::Exec(Some(cl_acq)));
-                        q.push(ResolveOp::Sync(stuck_ridx));
-                    }
-                    Err(ReplicaError::GcFailed(stuck_ridx)) => {
-                        {
-                            assert_ne!(stuck_ridx, tkn.rid);
-                            let _aftkn = self.affinity_mngr.switch(stuck_ridx);
-                            self.replicas[stuck_ridx].sync(&self.log);
-                            // Affinity is reverted here, _aftkn is dropped.
-                        }
-                        return self.replicas[tkn.rid]
-                            .get_response(&self.log, tkn.rtkn.tid())
-                            .expect("GcFailed has to produced a response");
-                    }
-                },
-                ResolveOp::Sync(ridx) => {
-                    // Holds trivially because of all the other asserts in this function
-                    debug_assert_ne!(ridx, tkn.rid);
-                    //warn!("execute_mut ResolveOp::Sync {}", ridx);
-                    let _aftkn = self.affinity_mngr.switch(ridx);
-                    self.replicas[ridx].try_sync(&self.log);
-                    // _aftkn is dropped here, reverting affinity change
-                }
-            }
-        }
+    pub fn restore(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        snapshot: &D::Snapshot,
+        pos: LogPos,
+    ) -> Result<Self, NodeReplicatedError>
+    where
+        D: Snapshot,
+    {
+        Self::restore_with_log_size(
+            num_replicas,
+            chg_mem_affinity,
+            log::DEFAULT_LOG_BYTES,
+            snapshot,
+            pos,
+        )
     }
 
-    fn try_execute<'a, 'rop>(
-        &'a self,
-        op: <D as Dispatch>::ReadOperation<'rop>,
-        tkn: ThreadToken,
-        cl: Option<CombinerLock<'a, D>>,
-    ) -> Result<<D as Dispatch>::Response, (ReplicaError<D>, <D as Dispatch>::ReadOperation<'rop>)>
+    /// Same as [`NodeReplicated::restore`], but in addition use a non-default
+    /// size (provided in bytes) for the [`Log`].
+    pub fn restore_with_log_size(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        log_size: usize,
+        snapshot: &D::Snapshot,
+        pos: LogPos,
+    ) -> Result<Self, NodeReplicatedError>
+    where
+        D: Snapshot,
     {
-        if let Some(combiner_lock) = cl {
-            self.replicas[tkn.rid].execute_locked(&self.log, op, tkn.rtkn, combiner_lock)
-        } else {
-            self.replicas[tkn.rid].execute(&self.log, op, tkn.rtkn)
+        let log = Self::new_log(log_size)?;
+        log.start_at(pos);
+        Self::with_log(num_replicas, chg_mem_affinity, log, |_log, _rid| {
+            D::restore(snapshot)
+        })
+    }
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> NodeReplicated<D, R, T, P>
+where
+    D: Dispatch + Sized + Sync,
+{
+    /// Creates a log of (approximately) `log_size` bytes with enough room for
+    /// the largest possible append of a replica (`T * P` operations, rounded
+    /// up to the next power of two).
+    ///
+    /// Fails with [`NodeReplicatedError::InvalidCapacity`] if the capacity
+    /// limits can't be used.
+    fn new_log(log_size: usize) -> Result<Log<D::WriteOperation, R>, NodeReplicatedError> {
+        if R == 0 || T == 0 || !P.is_power_of_two() {
+            return Err(NodeReplicatedError::InvalidCapacity);
         }
+        let gc_from_head = T
+            .checked_mul(P)
+            .and_then(usize::checked_next_power_of_two)
+            .ok_or(NodeReplicatedError::InvalidCapacity)?;
+
+        let entries = log_size / Log::<D::WriteOperation, R>::entry_size();
+        Ok(Log::new_with_gc_from_head(entries, (), gc_from_head))
     }
 
-    /// Executes a immutable operation against the data-structure.
+    /// Creates the replicas (using `factory`) and registers them with `log`.
     ///
-    /// Multiple threads can read from the data-structure in parallel.
+    /// `factory` is called for every replica before it registers with `log`.
+    fn with_log(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        log: Log<D::WriteOperation, R>,
+        mut factory: impl FnMut(&Log<D::WriteOperation, R>, ReplicaId) -> D,
+    ) -> Result<Self, NodeReplicatedError> {
+        if num_replicas.get() > R {
+            return Err(NodeReplicatedError::TooManyReplicas);
+        }
+        let affinity_mngr = AffinityManager::new(Box::try_new(chg_mem_affinity)?);
+        let hooks = Arc::try_new(LogHooks::new(None))?;
+
+        let replicas = ReplicaTable::new()?;
+        let mut table = replicas.lock();
+        for replica_id in 0..num_replicas.get() {
+            let mut r = {
+                // Allocate the replica (and its data) on the proper NUMA node
+                let _aff_tkn = affinity_mngr.switch(replica_id);
+                let data = factory(&log, replica_id);
+                let log_token = log.register().expect("Succeeds (num_replicas <= R)");
+                Box::try_new(Replica::with_data(log_token, data))?
+                // aff_tkn is dropped here
+            };
+            r.set_log_hooks(hooks.clone());
+
+            table.install(replica_id, r);
+        }
+        drop(table);
+
+        Ok(NodeReplicated {
+            replicas,
+            log,
+            hooks,
+            affinity_mngr,
+            instance: InstanceId::next(),
+        })
+    }
+
+    /// Creates replicas that continue where the replicas that wrote `wal`
+    /// left off (e.g., before a crash). The new replicas record their
+    /// operations in `wal` from then on.
     ///
-    ///  This method is similar to the one found in [`Replica::execute`], but in
-    /// addition, it handles liveness issues due to lagging replicas (which a
-    /// single replica can not).
+    /// Every replica starts with the data-structure returned by `factory`,
+    /// which has to reflect all operations before `pos`, and replays the
+    /// operations from `pos` onwards that are stored in `wal`. To start a
+    /// durable data-structure from scratch, pass an empty `wal`, `pos = 0` and
+    /// a factory that returns the initial data-structure. If the
+    /// data-structure implements [`Snapshot`], a factory that calls
+    /// [`Snapshot::restore`] with the latest persisted snapshot (as returned
+    /// together with its position by [`NodeReplicated::snapshot`]) bounds
+    /// the amount of operations to replay.
     ///
     /// # Arguments
-    /// - `op`: Which operation to execute.
-    /// - `tkn`: Which thread executes the operation (see also
-    ///   [`NodeReplicated::register`]).
-    ///
-    /// # Flow
-    /// Eventually, this method calls [`Replica::execute`] which will call into
-    /// [`Dispatch::dispatch`].
+    /// - `num_replicas`, `chg_mem_affinity`: See [`NodeReplicated::new`].
+    /// - `wal`: The write-ahead log to recover from.
+    /// - `pos`: Position in the log the data-structure returned by `factory`
+    ///   is at.
+    /// - `factory`: Creates the data-structure for a given replica, it's
+    ///   called after switching the memory affinity to the replica.
     ///
     /// # Example
+    ///
     /// ```
     /// #![feature(generic_associated_types)]
     /// use core::num::NonZeroUsize;
-    /// use node_replication::nr::NodeReplicated;
-    /// use node_replication::nr::Dispatch;
+    /// use std::sync::{Arc, Mutex};
+    /// use node_replication::nr::{Dispatch, LogPos, NodeReplicated};
+    /// use node_replication::wal::{SyncPolicy, Wal, WalBackend};
     ///
     /// #[derive(Default)]
-    /// struct Void;
-    /// impl Dispatch for Void {
-    ///     type ReadOperation<'rop> = usize;
-    ///     type WriteOperation = ();
-    ///     type Response = usize;
+    /// struct Counter(u64);
     ///
-    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
-    ///         assert!(op == 99);
-    ///         0xbeef
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+    ///         self.0
     ///     }
-    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
-    ///         unreachable!("no immutable op is issued")
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// // Keeps the operations in memory, a real backend would store them
+    /// // durably (e.g., `node_replication::wal::FileSegment`).
+    /// #[derive(Clone, Default)]
+    /// struct Memory(Arc<Mutex<Vec<(LogPos, u64)>>>);
+    ///
+    /// impl WalBackend<u64> for Memory {
+    ///     fn write(&self, pos: LogPos, ops: &[u64]) {
+    ///         let mut wal = self.0.lock().unwrap();
+    ///         wal.extend(ops.iter().enumerate().map(|(i, op)| (pos + i, *op)));
+    ///     }
+    ///     fn sync(&self) {}
+    ///     fn truncate(&self, _pos: LogPos) {}
+    ///     fn replay(&self, from: LogPos, f: &mut dyn FnMut(LogPos, u64)) {
+    ///         let wal = self.0.lock().unwrap();
+    ///         wal.iter().filter(|(pos, _)| *pos >= from).for_each(|(pos, op)| f(*pos, *op));
     ///     }
     /// }
     ///
     /// let replicas = NonZeroUsize::new(2).unwrap();
-    /// let nrht = NodeReplicated::<Void>::new(replicas, |_| { 0 }).unwrap();
+    /// let storage = Memory::default();
+    /// let wal = Wal::new(storage.clone(), SyncPolicy::Always);
+    /// let nrht = NodeReplicated::<Counter>::recover(replicas, |_| 0, wal, 0, |_| Counter(0)).unwrap();
     /// let ttkn = nrht.register(0).unwrap();
+    /// nrht.execute_mut(5, ttkn);
+    /// nrht.execute_mut(2, ttkn);
+    /// drop(nrht);
     ///
-    /// assert_eq!(nrht.execute(99, ttkn), 0xbeef);
+    /// let wal = Wal::new(storage, SyncPolicy::Always);
+    /// let recovered = NodeReplicated::<Counter>::recover(replicas, |_| 0, wal, 0, |_| Counter(0)).unwrap();
+    /// let ttkn = recovered.register(1).unwrap();
+    /// assert_eq!(recovered.execute((), ttkn), 7);
     /// ```
-    pub fn execute(
-        &self,
-        op: <D as Dispatch>::ReadOperation<'_>,
-        tkn: ThreadToken,
-    ) -> <D as Dispatch>::Response {
-        /// An enum to keep track of a stack of operations we should do on Replicas.
-        ///
-        /// e.g., either `Sync` an out-of-date, behind replica, or call `execute_locked` or
-        /// `execute_mut_locked` to resume the operation with a combiner lock.
-        enum ResolveOp<'a, 'rop, D: core::marker::Sync + Dispatch + Sized> {
-            /// Resumes a replica that earlier returned with an Error (and the CombinerLock).
-            Exec(Option<CombinerLock<'a, D>>, D::ReadOperation<'rop>),
-            /// Indicates need to [`Replica::sync()`] a replica with the given ID.
-            Sync(ReplicaId),
-        }
+    pub fn recover(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        wal: Wal<D::WriteOperation>,
+        pos: LogPos,
+        factory: impl FnMut(ReplicaId) -> D,
+    ) -> Result<Self, NodeReplicatedError> {
+        Self::recover_with_log_size(
+            num_replicas,
+            chg_mem_affinity,
+            log::DEFAULT_LOG_BYTES,
+            wal,
+            pos,
+            factory,
+        )
+    }
 
-        let mut q = ArrayVec::<ResolveOp<D>, { crate::log::MAX_REPLICAS_PER_LOG }>::new();
-        q.push(ResolveOp::Exec(None, op));
-        loop {
-            match q.pop().unwrap() {
-                ResolveOp::Exec(cl, op) => match self.try_execute(op, tkn, cl) {
-                    Ok(resp) => {
-                        assert!(q.is_empty());
-                        return resp;
-                    }
-                    Err((ReplicaError::NoLogSpace(stuck_ridx, cl_acq), op)) => {
-                        assert!(stuck_ridx != tkn.rid);
-                        q.push(ResolveOp::Exec(Some(cl_acq), op));
-                        q.push(ResolveOp::Sync(stuck_ridx));
-                    }
-                    Err((ReplicaError::GcFailed(stuck_ridx), op)) => {
-                        assert_ne!(stuck_ridx, tkn.rid);
-                        q.push(ResolveOp::Exec(None, op));
-                        q.push(ResolveOp::Sync(stuck_ridx));
-                    }
-                },
-                ResolveOp::Sync(ridx) => {
-                    // Holds trivially because of all the other asserts in this function
-                    debug_assert_ne!(ridx, tkn.rid);
-                    let _aftkn = self.affinity_mngr.switch(ridx);
-                    self.replicas[ridx].try_sync(&self.log);
-                    // _aftkn is dropped here, reverting affinity change
-                }
+    /// Same as [`NodeReplicated::recover`], but in addition use a non-default
+    /// size (provided in bytes) for the [`Log`].
+    pub fn recover_with_log_size(
+        num_replicas: NonZeroUsize,
+        chg_mem_affinity: impl Fn(AffinityChange) -> usize + Send + Sync + 'static,
+        log_size: usize,
+        wal: Wal<D::WriteOperation>,
+        pos: LogPos,
+        mut factory: impl FnMut(ReplicaId) -> D,
+    ) -> Result<Self, NodeReplicatedError> {
+        let log = Self::new_log(log_size)?;
+        let mut nr = Self::with_log(num_replicas, chg_mem_affinity, log, |log, rid| {
+            // Every replica replays the recorded operations straight from the
+            // backend, so they never have to fit in memory at once.
+            let mut data = factory(rid);
+            let end = wal.replay(pos, |op| {
+                data.dispatch_mut(op);
+            });
+            if rid == 0 {
+                // Now we know where the log continues, and no replica is
+                // registered yet.
+                log.start_at(end);
             }
+            data
+        })?;
+        nr.set_log_hooks(LogHooks::new(Some(wal)))?;
+        Ok(nr)
+    }
+
+    /// Replaces the hooks that record the operations the replicas append to
+    /// the log.
+    fn set_log_hooks(
+        &mut self,
+        hooks: LogHooks<D::WriteOperation>,
+    ) -> Result<(), NodeReplicatedError> {
+        let hooks = Arc::try_new(hooks)?;
+        for replica in self.replicas.iter_mut() {
+            replica.set_log_hooks(hooks.clone());
         }
+        self.hooks = hooks;
+        Ok(())
     }
 
-    /// Executes a mutable operation asynchronously on a replica, and returns
-    /// the response in `resp`
+    /// Sets how threads wait, e.g., for responses, locks or space on the log
+    /// (see [`crate::wait`]). The default is [`Spin`](crate::wait::Spin).
     ///
-    /// # Note
-    /// Currently a trivial "async" implementation as we just call the blocking
-    /// call [`NodeReplicated::execute`] and wrap it in `async`.
-    #[cfg(feature = "async")]
-    pub async fn async_execute_mut<'a>(
-        &'a self,
-        op: <D as Dispatch>::WriteOperation,
-        tkn: ThreadToken,
-        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
-    ) {
-        resp.set(async move { self.execute_mut(op, tkn) });
+    /// # Example
+    ///
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::{Dispatch, NodeReplicated};
+    /// use node_replication::wait::SpinThenYield;
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(u64);
+    ///
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+    ///         self.0
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| 0)
+    ///     .unwrap()
+    ///     .with_wait_strategy(SpinThenYield {
+    ///         spins: 1 << 10,
+    ///         yield_now: std::thread::yield_now,
+    ///     });
+    /// let ttkn = nrht.register(0).unwrap();
+    /// assert_eq!(nrht.execute_mut(5, ttkn), 5);
+    /// ```
+    pub fn with_wait_strategy(mut self, wait: impl WaitStrategy + 'static) -> Self {
+        let wait: Arc<dyn WaitStrategy> = Arc::new(wait);
+        for replica in self.replicas.iter_mut() {
+            replica.set_wait_strategy(wait.clone());
+        }
+        self.log.wait = wait;
+        self
     }
 
-    /// Executes an immutable operation asynchronously on a replica, and returns
-    /// the response in `resp`.
+    /// Registers a thread with a given replica in the [`NodeReplicated`]
+    /// data-structure. Returns an Option containing a [`ThreadToken`] if the
+    /// registration was successful. None if the registration failed.
     ///
-    /// # Note
-    /// Currently a trivial "async" implementation as we just call the blocking
-    /// call [`NodeReplicated::execute`] and wrap it in `async`.
-    #[cfg(feature = "async")]
-    pub fn async_execute<'a, 'rop: 'a>(
-        &'a self,
-        op: <D as Dispatch>::ReadOperation<'rop>,
-        tkn: ThreadToken,
-        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
-    ) {
-        resp.set(async move { self.execute(op, tkn) });
+    /// The [`ThreadToken`] is used to identify the thread to issue the
+    /// operation for subsequent [`NodeReplicated::execute`] and
+    /// [`NodeReplicated::execute_mut`] calls.
+    ///
+    /// # Arguments
+    ///
+    /// - `replica_id`: Which replica the thread should be registered with. This
+    /// should be less than the `num_replicas` argument provided in the
+    /// constructor (see [`NodeReplicated::new`]). In most cases, `replica_id`
+    /// will correspond to the NUMA node that the thread is running on.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::NodeReplicated;
+    /// use node_replication::nr::Dispatch;
+    ///
+    /// #[derive(Default)]
+    /// struct Void;
+    /// impl Dispatch for Void {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = ();
+    ///     type Response = ();
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {}
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {}
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Void>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// assert!(nrht.register(replicas.get()).is_none());
+    /// ```
+    pub fn register(&self, replica_id: ReplicaId) -> Option<ThreadToken> {
+        let rtkn = self.replicas.get(replica_id)?.register()?;
+        Some(ThreadToken {
+            rid: replica_id,
+            rtkn,
+            nr: self.instance,
+        })
     }
 
-    #[doc(hidden)]
-    pub fn sync(&self, tkn: ThreadToken) {
-        self.replicas[tkn.rid].sync(&self.log)
+    /// Returns the id of the instance, which is embedded in the
+    /// [`ThreadToken`]s it issues.
+    ///
+    /// Passing a token to another instance is a bug, see
+    /// [`NodeReplicated::check_token`].
+    pub fn instance_id(&self) -> InstanceId {
+        self.instance
     }
-}
 
-#[cfg(feature = "async")]
-#[cfg(test)]
-mod test {
-    use super::replica::test::Data;
-    use super::reusable_box::ReusableBoxFuture;
-    use super::*;
+    /// Checks that `tkn` can be used with this instance.
+    ///
+    /// Passing a token to another instance is a bug. It's detected in debug
+    /// builds (and with the `token-checks` feature). Tokens of a replica that
+    /// was removed (or replaced, see [`NodeReplicated::remove_replica`]) are
+    /// always rejected, also in release builds.
+    ///
+    /// Methods that return a [`Result`] report a rejected token as
+    /// [`ExecuteError::TokenMismatch`], all others panic. Use this method (or
+    /// [`NodeReplicated::execute_timeout`] and
+    /// [`NodeReplicated::execute_mut_timeout`] with [`Deadline::Never`]) to
+    /// handle the error instead.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
+    ///
+    /// #[derive(Default, Clone)]
+    /// struct Void;
+    /// impl Dispatch for Void {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = ();
+    ///     type Response = ();
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {}
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {}
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Void>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert!(nrht.check_token(ttkn).is_ok());
+    ///
+    /// nrht.remove_replica(1).unwrap();
+    /// assert!(nrht.check_token(ttkn).is_err());
+    /// ```
+    pub fn check_token(&self, tkn: ThreadToken) -> Result<(), TokenMismatch> {
+        self.replica_of(tkn).map(|_replica| ())
+    }
+
+    /// Checks that `tkn` was issued by this instance and by the current
+    /// replica with id `tkn.rid`, and returns that replica.
+    ///
+    /// The replica is always checked: the id of a removed replica is reused
+    /// by [`NodeReplicated::add_replica`].
+    #[inline(always)]
+    fn replica_of(&self, tkn: ThreadToken) -> Result<ReplicaRef<'_, D, R, T, P>, TokenMismatch> {
+        tkn.nr.check(self.instance)?;
+        match self.replicas.get(tkn.rid) {
+            // A retired replica is about to be removed (or replaced).
+            Some(replica) if replica.is_retired() => Err(TokenMismatch {
+                expected: InstanceId::ANY,
+                found: tkn.rtkn.1,
+            }),
+            Some(replica)
+                if tkn.rtkn.1 == replica.instance_id() || tkn.rtkn.1 == InstanceId::ANY =>
+            {
+                Ok(replica)
+            }
+            replica => Err(TokenMismatch {
+                expected: replica.map_or(InstanceId::ANY, |r| r.instance_id()),
+                found: tkn.rtkn.1,
+            }),
+        }
+    }
+
+    /// Returns the replica of `tkn`, panics if `tkn` wasn't issued by this
+    /// instance (see [`NodeReplicated::check_token`]).
+    #[inline(always)]
+    fn check(&self, tkn: ThreadToken) -> ReplicaRef<'_, D, R, T, P> {
+        self.replica_of(tkn).unwrap_or_else(|e| token_mismatch(e))
+    }
+
+    /// Panics because the replica of `tkn` stopped executing operations:
+    /// either it's poisoned, or it was removed and `tkn` is rejected.
+    #[cold]
+    fn stopped(&self, tkn: ThreadToken) -> ! {
+        match self.replica_of(tkn) {
+            Ok(_replica) => poisoned(tkn.rid),
+            Err(e) => token_mismatch(e),
+        }
+    }
+
+    /// Same as [`NodeReplicated::stopped`], but returns the error (which
+    /// hands back `t` if the replica is poisoned).
+    #[cold]
+    fn stopped_error<O>(&self, tkn: ThreadToken, t: O) -> ExecuteError<O> {
+        match self.replica_of(tkn) {
+            Ok(_replica) => ExecuteError::Poisoned(t),
+            Err(e) => ExecuteError::TokenMismatch(e),
+        }
+    }
+
+    /// Deregisters a thread from the [`NodeReplicated`] data-structure.
+    ///
+    /// Operations of the thread that are still pending are executed first (but
+    /// their responses are dropped). Afterwards, the thread's slot in its
+    /// replica is reused by a subsequent [`NodeReplicated::register`] call.
+    /// This allows threads to come and go without exhausting the
+    /// [`MAX_THREADS_PER_REPLICA`](crate::replica::MAX_THREADS_PER_REPLICA)
+    /// registrations a replica supports.
+    ///
+    /// `tkn` (and all copies of it) must not be used anymore afterwards.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
+    ///
+    /// #[derive(Default)]
+    /// struct Void;
+    /// impl Dispatch for Void {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = ();
+    ///     type Response = ();
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {}
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {}
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(1).unwrap();
+    /// let nrht = NodeReplicated::<Void>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// nrht.deregister(ttkn);
+    /// assert_eq!(nrht.register(0), Some(ttkn));
+    /// ```
+    ///
+    /// # Panics
+    /// If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    pub fn deregister(&self, tkn: ThreadToken) {
+        let replica = self.check(tkn);
+        while replica.deregister(&self.log, tkn.rtkn).is_err() {
+            // Other replicas have to make progress first, `try_combine` takes
+            // care of them.
+            self.try_combine(tkn.rid);
+        }
+    }
+
+    /// Adds a new replica to the [`NodeReplicated`] data-structure at runtime
+    /// and returns its [`ReplicaId`].
+    ///
+    /// The state of the new replica is cloned from an existing replica after
+    /// it was brought up-to-date with the [`Log`], and the new replica starts
+    /// to consume the log from there on. The clone is made with the memory
+    /// affinity of the new replica (see [`AffinityChange`]).
+    ///
+    /// The id of a previously removed replica (see
+    /// [`NodeReplicated::remove_replica`]) is reused before a new one is
+    /// handed out. [`ThreadToken`]s of the removed replica are still rejected
+    /// by the new one.
+    ///
+    /// Replicas can be added and removed while other threads use the
+    /// [`NodeReplicated`] instance, changes to the set of replicas are
+    /// serialized.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
+    ///
+    /// #[derive(Default, Clone)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(1).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// nrht.execute_mut(5, ttkn);
+    ///
+    /// let rid = nrht.add_replica().unwrap();
+    /// let ttkn = nrht.register(rid).unwrap();
+    /// assert_eq!(nrht.execute((), ttkn), 5);
+    /// ```
+    pub fn add_replica(&self) -> Result<ReplicaId, NodeReplicatedError>
+    where
+        D: Clone,
+    {
+        let mut table = self.replicas.lock();
+        let (_rid, source) = self
+            .replicas
+            .iter()
+            .find(|(_rid, r)| !r.is_poisoned())
+            .ok_or(NodeReplicatedError::Poisoned)?;
+
+        let affinity_mngr = &self.affinity_mngr;
+        let (replica_id, mut r) = source
+            .fork(&self.log, |log_tkn, data| {
+                let replica_id = log_tkn.0 - 1;
+                // Allocate the replica (and its data) on the proper NUMA node
+                let _aff_tkn = affinity_mngr.switch(replica_id);
+                Box::try_new(Replica::with_data(log_tkn, data.clone())).map(|r| (replica_id, r))
+                // aff_tkn is dropped here
+            })
+            .ok_or(NodeReplicatedError::TooManyReplicas)??;
+        r.set_wait_strategy(self.log.wait.clone());
+        r.set_log_hooks(self.hooks.clone());
+
+        table.install(replica_id, r);
+        Ok(replica_id)
+    }
+
+    /// Removes replica `replica_id` from the [`NodeReplicated`] data-structure
+    /// at runtime.
+    ///
+    /// Operations that are still pending in the contexts of the replica's
+    /// threads are appended to the [`Log`] first. Afterwards, the replica no
+    /// longer holds back garbage collection of the log, and its memory is
+    /// released once threads that still use it noticed it's gone.
+    ///
+    /// # Note
+    /// Responses to operations that were not retrieved yet (e.g., with
+    /// [`NodeReplicated::poll`]) are lost, and the [`ThreadToken`]s of the
+    /// replica become invalid: using them panics (or returns
+    /// [`ExecuteError::TokenMismatch`]), also once its id is reused.
+    ///
+    /// # Errors
+    /// [`NodeReplicatedError::InvalidReplica`] if `replica_id` doesn't exist
+    /// or is the last remaining replica.
+    pub fn remove_replica(&self, replica_id: ReplicaId) -> Result<(), NodeReplicatedError> {
+        let mut table = self.replicas.lock();
+        let num_replicas = self.replicas.iter().count();
+        let replica = match self.replicas.get(replica_id) {
+            Some(replica) if num_replicas > 1 => replica,
+            _ => return Err(NodeReplicatedError::InvalidReplica),
+        };
+
+        let combiner_lock = {
+            // Drain the replica: make sure none of its operations get lost.
+            let _aftkn = self.affinity_mngr.switch(replica_id);
+            self.drain(replica_id, &replica)
+            // _aftkn is dropped here, reverting affinity change
+        };
+
+        self.log.unregister(replica.retire(combiner_lock));
+        drop(replica);
+        table.unlink(replica_id);
+        Ok(())
+    }
+
+    /// Combines on `replica` (with id `replica_id`) until none of its threads
+    /// has operations that weren't appended to the log (or it's poisoned).
+    ///
+    /// Waits for the combiner lock (and for lagging replicas) and returns it,
+    /// so no operation can be combined afterwards.
+    fn drain<'a>(
+        &'a self,
+        replica_id: ReplicaId,
+        replica: &'a Replica<D, R, T, P>,
+    ) -> CombinerLock<'a, D, R, T, P> {
+        let mut combiner_lock = replica.wait_for_combiner_lock();
+        while replica.has_uncombined() && !replica.is_poisoned() {
+            combiner_lock = match replica.combine(&self.log, combiner_lock) {
+                Ok(()) | Err(ReplicaError::Poisoned(_)) => replica.wait_for_combiner_lock(),
+                Err(ReplicaError::NoLogSpace(stuck_ridx, cl_acq)) => {
+                    assert_ne!(stuck_ridx, replica_id);
+                    let _aftkn = self.affinity_mngr.switch(stuck_ridx);
+                    if let Some(stuck) = self.replica(stuck_ridx) {
+                        stuck.try_sync(&self.log);
+                    }
+                    cl_acq
+                }
+                Err(ReplicaError::GcFailed(stuck_ridx)) => {
+                    assert_ne!(stuck_ridx, replica_id);
+                    {
+                        let _aftkn = self.affinity_mngr.switch(stuck_ridx);
+                        if let Some(stuck) = self.replica(stuck_ridx) {
+                            stuck.try_sync(&self.log);
+                        }
+                    }
+                    replica.wait_for_combiner_lock()
+                }
+                Err(ReplicaError::TokenMismatch(e)) => token_mismatch(e),
+            };
+        }
+        combiner_lock
+    }
+
+    /// Returns true if replica `replica_id` was poisoned because
+    /// [`Dispatch::dispatch_mut`] panicked while the replica applied
+    /// operations.
+    ///
+    /// A poisoned replica doesn't execute operations anymore: the methods of
+    /// threads registered with it panic (or return
+    /// [`ExecuteError::Poisoned`]) instead of waiting forever. The other
+    /// replicas are not held back by it, see
+    /// [`NodeReplicated::recover_replica`] to replace it.
+    ///
+    /// # Panics
+    /// If the replica was removed.
+    pub fn is_poisoned(&self, replica_id: ReplicaId) -> bool {
+        self.replica(replica_id)
+            .expect("Replica was removed from NodeReplicated")
+            .is_poisoned()
+    }
+
+    /// Replaces the poisoned replica `replica_id` with a copy of a healthy
+    /// replica.
+    ///
+    /// Like for [`NodeReplicated::add_replica`], the healthy replica is
+    /// brought up-to-date with the [`Log`] first. The copy is made with the
+    /// memory affinity of `replica_id`, and continues to consume the log
+    /// where the healthy replica left off.
+    ///
+    /// # Note
+    /// Responses of operations that were not retrieved from the poisoned
+    /// replica yet are lost (the operations themselves may or may not have
+    /// made it into the log). Threads have to [`NodeReplicated::register`]
+    /// again.
+    ///
+    /// Recovery only helps if the panic was caused by a transient condition
+    /// (e.g., running out of memory). The operation that panicked stays in the
+    /// log, and replicas that didn't apply it yet (such as the copy, if the
+    /// healthy replica was behind) still have to: if it panics every time it
+    /// is applied, it poisons them as well.
+    ///
+    /// # Errors
+    /// - [`NodeReplicatedError::InvalidReplica`] if `replica_id` doesn't
+    ///   exist or isn't poisoned.
+    /// - [`NodeReplicatedError::Poisoned`] if there's no healthy replica
+    ///   left.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use std::panic::{catch_unwind, AssertUnwindSafe};
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
+    ///
+    /// /// Simulates a transient failure the next time an operation is applied.
+    /// static FAIL: AtomicBool = AtomicBool::new(false);
+    ///
+    /// #[derive(Default, Clone)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         assert!(!FAIL.swap(false, Ordering::SeqCst), "Out of memory");
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| 0).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// assert_eq!(nrht.execute_mut(1, ttkn), 1);
+    ///
+    /// // Replica 1 fails to apply the operation while it catches up.
+    /// FAIL.store(true, Ordering::SeqCst);
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert!(catch_unwind(AssertUnwindSafe(|| nrht.execute((), ttkn))).is_err());
+    /// assert!(nrht.is_poisoned(1));
+    ///
+    /// nrht.recover_replica(1).unwrap();
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert_eq!(nrht.execute((), ttkn), 1);
+    /// ```
+    pub fn recover_replica(&self, replica_id: ReplicaId) -> Result<(), NodeReplicatedError>
+    where
+        D: Clone,
+    {
+        let mut table = self.replicas.lock();
+        let poisoned = match self.replicas.get(replica_id) {
+            Some(r) if r.is_poisoned() => r,
+            _ => return Err(NodeReplicatedError::InvalidReplica),
+        };
+        let (_rid, source) = self
+            .replicas
+            .iter()
+            .find(|(_rid, r)| !r.is_poisoned())
+            .ok_or(NodeReplicatedError::Poisoned)?;
+
+        // The poisoned replica has to be gone before the copy takes over its
+        // slot of the log.
+        let retired = poisoned.retire(poisoned.wait_for_combiner_lock());
+        drop(poisoned);
+        table.unlink(replica_id);
+
+        let affinity_mngr = &self.affinity_mngr;
+        let instance = self.log.instance_id();
+        let r = source.fork_into(&self.log, retired, |log_tkn, data| {
+            // Allocate the replica (and its data) on the proper NUMA node
+            let _aff_tkn = affinity_mngr.switch(replica_id);
+            let idx = log_tkn.0;
+            Box::try_new(Replica::with_data(log_tkn, data.clone()))
+                .map_err(|_| log::LogToken(idx, instance))
+            // aff_tkn is dropped here
+        });
+        let mut r = match r {
+            Ok(r) => r,
+            Err(log_tkn) => {
+                // The poisoned replica is gone already, so it's removed.
+                self.log.unregister(log_tkn);
+                return Err(NodeReplicatedError::OutOfMemory);
+            }
+        };
+        r.set_wait_strategy(self.log.wait.clone());
+        r.set_log_hooks(self.hooks.clone());
+        table.install(replica_id, r);
+
+        Ok(())
+    }
+
+    /// Captures the state of the data-structure by calling `f` on the replica
+    /// of thread `tkn`.
+    ///
+    /// The replica is brought up-to-date with the tail of the [`Log`] first,
+    /// and doesn't make progress while `f` runs. Returns the result of `f`
+    /// together with the [`LogPos`] of the first operation that isn't
+    /// reflected in it (i.e., the number of operations applied so far).
+    ///
+    /// The write-ahead log (see [`NodeReplicated::recover`]) isn't truncated
+    /// automatically. Once the result of `f` is stored durably, call
+    /// [`NodeReplicated::truncate_wal`] with the returned position so the
+    /// write-ahead log doesn't grow forever.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// nrht.execute_mut(2, ttkn);
+    /// nrht.execute_mut(3, ttkn);
+    ///
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert_eq!(nrht.checkpoint(ttkn, |c| c.0), (5, 2));
+    /// ```
+    ///
+    /// # Panics
+    /// - If the thread's replica is poisoned (see
+    ///   [`NodeReplicated::is_poisoned`]).
+    /// - If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    pub fn checkpoint<S>(&self, tkn: ThreadToken, f: impl FnOnce(&D) -> S) -> (S, LogPos) {
+        self.check(tkn).checkpoint(&self.log, f)
+    }
+
+    /// Same as [`NodeReplicated::checkpoint`], but captures the state with
+    /// [`Snapshot::snapshot`].
+    ///
+    /// The result can be passed to [`NodeReplicated::restore`].
+    ///
+    /// # Panics
+    /// See [`NodeReplicated::checkpoint`].
+    pub fn snapshot(&self, tkn: ThreadToken) -> (D::Snapshot, LogPos)
+    where
+        D: Snapshot,
+    {
+        self.checkpoint(tkn, D::snapshot)
+    }
+
+    /// Makes all operations recorded in the write-ahead log durable (see
+    /// [`Wal::sync`]).
+    ///
+    /// Does nothing if the replicas weren't created with
+    /// [`NodeReplicated::recover`].
+    pub fn sync_wal(&self) {
+        if let Some(wal) = &self.hooks.wal {
+            wal.sync(&*self.log.wait);
+        }
+    }
+
+    /// Discards all operations before `pos` from the write-ahead log.
+    ///
+    /// Call this once a checkpoint at `pos` (see
+    /// [`NodeReplicated::checkpoint`]) was stored durably, so recovery starts
+    /// from the checkpoint and the write-ahead log doesn't grow forever.
+    ///
+    /// Does nothing if the replicas weren't created with
+    /// [`NodeReplicated::recover`].
+    pub fn truncate_wal(&self, pos: LogPos) {
+        if let Some(wal) = &self.hooks.wal {
+            wal.truncate(pos);
+        }
+    }
+
+    /// Returns the runtime statistics of the log and all replicas.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::{Dispatch, NodeReplicated};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(u64);
+    ///
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = u64;
+    ///     type Response = u64;
+    ///
+    ///     fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+    ///         self.0
+    ///     }
+    ///
+    ///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let nrht = NodeReplicated::<Counter>::new(NonZeroUsize::new(2).unwrap(), |_| 0).unwrap();
+    /// let ttkn = nrht.register(1).unwrap();
+    /// nrht.execute_mut(5, ttkn);
+    ///
+    /// let stats = nrht.stats();
+    /// assert_eq!(stats.log.occupancy, 1);
+    /// assert_eq!(stats.replicas[1].as_ref().unwrap().appended_ops, 1);
+    /// ```
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::Stats<R> {
+        crate::stats::Stats {
+            log: self.log.stats(),
+            replicas: (0..self.replicas.len())
+                .map(|rid| self.replica(rid).map(|r| r.stats()))
+                .collect(),
+        }
+    }
+
+    /// Records all mutable operations that are appended to the log from now
+    /// on in `sink`: in log order, together with the replica and thread that
+    /// issued them (see [`trace`]).
+    ///
+    /// To be able to reproduce the state of the replicas with
+    /// [`trace::replay`], start recording right after creating the replicas
+    /// (or take a [`NodeReplicated::checkpoint`] at the same time).
+    ///
+    /// Replaces the sink of an earlier call. Waits until no combiner records
+    /// operations in it, operations that were appended before that are not
+    /// recorded in `sink`.
+    pub fn record_trace(&self, sink: impl trace::TraceSink<D::WriteOperation> + 'static) {
+        self.hooks.trace.replace(
+            Some(Box::new(sink)),
+            || self.log.tail.load(Ordering::SeqCst),
+            &*self.log.wait,
+        );
+    }
+
+    /// Stops recording mutable operations (see
+    /// [`NodeReplicated::record_trace`]).
+    pub fn stop_trace(&self) {
+        self.hooks.trace.replace(
+            None,
+            || self.log.tail.load(Ordering::SeqCst),
+            &*self.log.wait,
+        );
+    }
+
+    /// Returns the current replica with id `rid`, `None` if it was removed.
+    ///
+    /// Use [`NodeReplicated::check`] to get the replica of a thread.
+    #[inline(always)]
+    fn replica(&self, rid: ReplicaId) -> Option<ReplicaRef<'_, D, R, T, P>> {
+        self.replicas.get(rid)
+    }
+
+    /// Executes a mutable operation against the data-structure.
+    ///
+    /// Thanks to the [`Log`] that [`NodeReplicated`] uses, all replicas will
+    /// execute all mutable operations in the same order.
+    ///
+    ///  This method is similar to the one found in [`Replica::execute_mut`],
+    /// but in addition, it handles liveness issues due to lagging replicas
+    /// (which a single replica can not).
+    ///
+    /// # Arguments
+    /// - `op`: Which operation to execute.
+    /// - `tkn`: Which thread executes the operation (see also
+    ///   [`NodeReplicated::register`]).
+    ///
+    /// # Flow
+    /// The operation is enqueued in the thread's context of the [`Replica`],
+    /// which will eventually call into [`Dispatch::dispatch_mut`] when it flat
+    /// combines.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::NodeReplicated;
+    /// use node_replication::nr::Dispatch;
+    ///
+    /// #[derive(Default)]
+    /// struct Void;
+    /// impl Dispatch for Void {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         unreachable!("no read-op is issued")
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         assert!(op == 99);
+    ///         // "eventually", because if we just do one `execute_mut` call
+    ///         // we won't immediately advance the 2nd replica
+    ///         println!("Having two replicas means I'm (eventually) called twice");
+    ///         0xbeef
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Void>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    ///
+    /// assert_eq!(nrht.execute_mut(99, ttkn), 0xbeef);
+    /// ```
+    ///
+    /// # Panics
+    /// - If the operation is rejected by [`Dispatch::validate`] (see
+    ///   [`NodeReplicated::execute_mut_checked`]).
+    /// - If [`Dispatch::dispatch_mut`] panics, or panicked earlier on the
+    ///   thread's replica (see [`NodeReplicated::is_poisoned`]).
+    /// - If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    pub fn execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> <D as Dispatch>::Response {
+        unwrap_validated::<D>(self.execute_mut_checked(op, tkn))
+    }
+
+    /// Same as [`NodeReplicated::execute_mut`], but returns the error if the
+    /// operation was rejected by [`Dispatch::validate`].
+    ///
+    /// A rejected operation is never executed on any replica.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
+    ///
+    /// #[derive(Default)]
+    /// struct Balance(usize);
+    /// impl Dispatch for Balance {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///     type Error = &'static str;
+    ///     const VALIDATES: bool = true;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    ///     fn validate(&self, op: &<Self as Dispatch>::WriteOperation) -> Result<(), Self::Error> {
+    ///         if *op == 0 { Err("nothing to add") } else { Ok(()) }
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Balance>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    ///
+    /// assert_eq!(nrht.execute_mut_checked(10, ttkn), Ok(10));
+    /// assert_eq!(nrht.execute_mut_checked(0, ttkn), Err("nothing to add"));
+    /// ```
+    ///
+    /// # Panics
+    /// In the cases listed for [`NodeReplicated::execute_mut`], except for
+    /// rejected operations.
+    pub fn execute_mut_checked(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> Result<<D as Dispatch>::Response, <D as Dispatch>::Error> {
+        self.execute_mut_validated(op, tkn).0
+    }
+
+    /// Same as [`NodeReplicated::execute_mut`], but also returns the position
+    /// at which the operation was appended to the [`Log`].
+    ///
+    /// The position can be passed to [`NodeReplicated::execute_after`] (by
+    /// any thread) to read from a replica that reflects the operation.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::{Dispatch, NodeReplicated};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// assert_eq!(nrht.execute_mut_positioned(2, ttkn), (2, 0));
+    /// let (resp, pos) = nrht.execute_mut_positioned(3, ttkn);
+    /// assert_eq!((resp, pos), (5, 1));
+    ///
+    /// // A thread on another replica observes both writes.
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert_eq!(nrht.execute_after((), ttkn, pos), 5);
+    /// ```
+    ///
+    /// # Panics
+    /// See [`NodeReplicated::execute_mut`].
+    pub fn execute_mut_positioned(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> (<D as Dispatch>::Response, LogPos) {
+        let (resp, pos) = self.execute_mut_validated(op, tkn);
+        (unwrap_validated::<D>(resp), pos)
+    }
+
+    /// Executes `op`, returns the verdict of [`Dispatch::validate`] (or the
+    /// response) and the position of the operation on the log.
+    fn execute_mut_validated(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> (Validated<D>, LogPos) {
+        let replica = self.check(tkn);
+        let mut waiter = Waiter::new(&*self.log.wait);
+        while !replica.make_pending(op.clone(), tkn.rtkn.tid()) {
+            self.make_room(tkn, &mut waiter);
+        }
+        self.get_response(tkn)
+    }
+
+    /// Executes a batch of mutable operations against the data-structure and
+    /// returns their responses (in the same order as `ops`).
+    ///
+    /// Compared to calling [`NodeReplicated::execute_mut`] for every operation,
+    /// this enqueues as many operations as fit into the thread's context before
+    /// flat combining, so a whole batch only pays for a single round trip
+    /// through the log.
+    ///
+    /// # Arguments
+    /// - `ops`: The operations to execute.
+    /// - `tkn`: Which thread executes the operations (see also
+    ///   [`NodeReplicated::register`]).
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    ///
+    /// assert_eq!(nrht.execute_mut_batch(&[1, 2, 3], ttkn), vec![1, 3, 6]);
+    /// ```
+    ///
+    /// # Panics
+    /// In the cases listed for [`NodeReplicated::execute_mut`].
+    pub fn execute_mut_batch(
+        &self,
+        ops: &[<D as Dispatch>::WriteOperation],
+        tkn: ThreadToken,
+    ) -> Vec<<D as Dispatch>::Response> {
+        let mut resps = Vec::with_capacity(ops.len());
+        self.execute_mut_batch_into(ops.iter().cloned(), tkn, &mut resps);
+        resps
+    }
+
+    /// Same as [`NodeReplicated::execute_mut_batch`], but takes the operations
+    /// from an iterator and appends the responses to `resps` (which can be
+    /// reused across calls to avoid allocating).
+    ///
+    /// # Panics
+    /// See [`NodeReplicated::execute_mut_batch`].
+    pub fn execute_mut_batch_into(
+        &self,
+        ops: impl IntoIterator<Item = <D as Dispatch>::WriteOperation>,
+        tkn: ThreadToken,
+        resps: &mut Vec<<D as Dispatch>::Response>,
+    ) {
+        let replica = self.check(tkn);
+        let mut ops = ops.into_iter().peekable();
+        resps.reserve(ops.size_hint().0);
+
+        let mut waiter = Waiter::new(&*self.log.wait);
+        while ops.peek().is_some() {
+            let enqueued = replica.make_pending_batch(&mut ops, tkn.rtkn.tid());
+            if enqueued == 0 {
+                self.make_room(tkn, &mut waiter);
+            }
+            for _ in 0..enqueued {
+                resps.push(unwrap_validated::<D>(self.get_response(tkn).0));
+            }
+        }
+    }
+
+    /// Executes `ops` as an atomic group and returns their responses (in the
+    /// same order as `ops`).
+    ///
+    /// The operations occupy consecutive entries of the [`Log`] (no operation
+    /// of another thread or replica is interleaved with them), and readers on
+    /// any replica either observe all of them or none. This allows to express
+    /// small transactions with existing operations.
+    ///
+    /// All operations are validated (see [`Dispatch::validate`]) against the
+    /// state right before the group, i.e., the state that includes every
+    /// operation before the group in the log, but none of the group itself.
+    /// If one of them is rejected, none is executed (on any replica).
+    ///
+    /// # Errors
+    /// [`ExecuteError::TooLarge`] with `ops` if they don't fit into the
+    /// thread's context (more than `P` operations, see [`NodeReplicated`]).
+    ///
+    /// # Panics
+    /// - If an operation is rejected (see
+    ///   [`NodeReplicated::execute_mut_atomic_checked`]), and in the cases
+    ///   listed for [`NodeReplicated::execute_mut`].
+    /// - If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::{Dispatch, NodeReplicated};
+    ///
+    /// #[derive(Clone, Debug, PartialEq)]
+    /// enum Op {
+    ///     Withdraw(usize, u64),
+    ///     Deposit(usize, u64),
+    /// }
+    ///
+    /// #[derive(Default)]
+    /// struct Accounts([u64; 2]);
+    /// impl Dispatch for Accounts {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = Op;
+    ///     type Response = u64;
+    ///     type Error = ();
+    ///     const VALIDATES: bool = true;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0.iter().sum()
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         match op {
+    ///             Op::Withdraw(a, n) => self.0[a] -= n,
+    ///             Op::Deposit(a, n) => self.0[a] += n,
+    ///         }
+    ///         self.0.iter().sum()
+    ///     }
+    ///     fn validate(&self, op: &<Self as Dispatch>::WriteOperation) -> Result<(), Self::Error> {
+    ///         match op {
+    ///             Op::Withdraw(a, n) if self.0[*a] < *n => Err(()),
+    ///             _ => Ok(()),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Accounts>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// nrht.execute_mut(Op::Deposit(0, 10), ttkn);
+    ///
+    /// // Readers never see the money in flight.
+    /// let transfer = [Op::Withdraw(0, 7), Op::Deposit(1, 7)];
+    /// assert_eq!(nrht.execute_mut_atomic(&transfer, ttkn), Ok(vec![3, 10]));
+    /// // Not enough money left, so the deposit isn't executed either.
+    /// assert_eq!(nrht.execute_mut_atomic_checked(&transfer, ttkn), Ok(Err(())));
+    /// ```
+    pub fn execute_mut_atomic<'o>(
+        &self,
+        ops: &'o [<D as Dispatch>::WriteOperation],
+        tkn: ThreadToken,
+    ) -> Result<Vec<<D as Dispatch>::Response>, ExecuteError<&'o [<D as Dispatch>::WriteOperation]>>
+    {
+        let verdicts = self.execute_mut_group(ops, tkn)?;
+        Ok(verdicts.into_iter().map(unwrap_validated::<D>).collect())
+    }
+
+    /// Same as [`NodeReplicated::execute_mut_atomic`], but returns the error
+    /// if an operation of the group was rejected by [`Dispatch::validate`].
+    ///
+    /// # Errors
+    /// See [`NodeReplicated::execute_mut_atomic`].
+    ///
+    /// # Panics
+    /// In the cases listed for [`NodeReplicated::execute_mut_atomic`], except
+    /// for rejected operations.
+    #[allow(clippy::type_complexity)]
+    pub fn execute_mut_atomic_checked<'o>(
+        &self,
+        ops: &'o [<D as Dispatch>::WriteOperation],
+        tkn: ThreadToken,
+    ) -> Result<
+        Result<Vec<<D as Dispatch>::Response>, <D as Dispatch>::Error>,
+        ExecuteError<&'o [<D as Dispatch>::WriteOperation]>,
+    > {
+        let verdicts = self.execute_mut_group(ops, tkn)?;
+        Ok(verdicts.into_iter().collect())
+    }
+
+    /// Executes `ops` as an atomic group, returns their verdicts (or
+    /// responses).
+    #[allow(clippy::type_complexity)]
+    fn execute_mut_group<'o>(
+        &self,
+        ops: &'o [<D as Dispatch>::WriteOperation],
+        tkn: ThreadToken,
+    ) -> Result<Vec<Validated<D>>, ExecuteError<&'o [<D as Dispatch>::WriteOperation]>> {
+        let replica = self.check(tkn);
+        if ops.len() > P {
+            return Err(ExecuteError::TooLarge(ops));
+        }
+        if ops.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut waiter = Waiter::new(&*self.log.wait);
+        while !replica.make_pending_group(ops, tkn.rtkn.tid()) {
+            self.make_room(tkn, &mut waiter);
+        }
+        // Retrieve all responses (even if the group was rejected), so the
+        // context is empty when we return.
+        Ok((0..ops.len()).map(|_| self.get_response(tkn).0).collect())
+    }
+
+    /// Enqueues a mutable operation and returns right away, without waiting
+    /// for it to be executed. The response is dropped (as is the error if
+    /// the operation is rejected by [`Dispatch::validate`]).
+    ///
+    /// The operation is executed once a combiner picks it up, e.g., when the
+    /// thread (or another thread on its replica) executes an operation or
+    /// calls [`NodeReplicated::flush`]. If the thread's context is full, this
+    /// flat combines to make room.
+    ///
+    /// # Note
+    /// Operations of one thread are still executed in the order they were
+    /// issued. Don't mix this with tickets (see
+    /// [`NodeReplicated::submit_mut`]) or the `async` variants on the same
+    /// `tkn` while they're outstanding.
+    ///
+    /// # Panics
+    /// - If the thread's replica is poisoned (see
+    ///   [`NodeReplicated::is_poisoned`]) while waiting for room.
+    /// - If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::{Dispatch, NodeReplicated};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// for _ in 0..100 {
+    ///     nrht.execute_mut_detached(1, ttkn);
+    /// }
+    /// nrht.flush(ttkn);
+    ///
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert_eq!(nrht.execute((), ttkn), 100);
+    /// ```
+    pub fn execute_mut_detached(&self, op: <D as Dispatch>::WriteOperation, tkn: ThreadToken) {
+        let replica = self.check(tkn);
+        let mut waiter = Waiter::new(&*self.log.wait);
+        while !replica.make_pending_detached(op.clone(), tkn.rtkn.tid()) {
+            self.make_room(tkn, &mut waiter);
+        }
+    }
+
+    /// Waits until all operations of the thread (e.g., those issued with
+    /// [`NodeReplicated::execute_mut_detached`]) were appended to the [`Log`]
+    /// and applied to the thread's replica, flat combining in the meantime.
+    ///
+    /// # Panics
+    /// - If the thread's replica is poisoned (see
+    ///   [`NodeReplicated::is_poisoned`]).
+    /// - If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    pub fn flush(&self, tkn: ThreadToken) {
+        let replica = self.check(tkn);
+        let mut waiter = Waiter::new(&*self.log.wait);
+        while !replica.is_flushed(tkn.rtkn.tid()) {
+            self.make_room(tkn, &mut waiter);
+        }
+    }
+
+    /// Flat combines on the replica of `tkn` because a thread context is full
+    /// (of detached operations) or not yet flushed, and there is no guarantee
+    /// another thread will.
+    ///
+    /// # Panics
+    /// If the replica is poisoned or `tkn` is rejected.
+    fn make_room(&self, tkn: ThreadToken, waiter: &mut Waiter<'_>) {
+        if self.replica(tkn.rid).map_or(true, |r| r.is_stopped()) {
+            self.stopped(tkn);
+        }
+        self.try_combine(tkn.rid);
+        waiter.wait();
+    }
+
+    /// Submits a mutable operation without waiting for it to be executed.
+    ///
+    /// The returned [`Ticket`] is used to retrieve the response later (see
+    /// [`NodeReplicated::poll`] and [`NodeReplicated::wait`]). This way, a
+    /// thread can pipeline up to [`crate::context::MAX_PENDING_OPS`] operations
+    /// and do other work while a combiner applies them.
+    ///
+    /// # Note
+    /// Operations of one thread are still executed in submission order, but
+    /// their responses can be retrieved in any order. Don't call
+    /// [`NodeReplicated::execute_mut`] or
+    /// [`NodeReplicated::execute_mut_batch`] with the same `tkn` while
+    /// tickets are outstanding.
+    ///
+    /// # Errors
+    /// - [`ExecuteError::WouldBlock`] if the thread already has `P` (by
+    ///   default [`crate::context::MAX_PENDING_OPS`]) outstanding tickets.
+    /// - [`ExecuteError::Poisoned`] if the thread's replica is poisoned.
+    /// - [`ExecuteError::TokenMismatch`] if `tkn` is rejected (see
+    ///   [`NodeReplicated::check_token`]).
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::Dispatch;
+    /// use node_replication::nr::NodeReplicated;
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(1).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    ///
+    /// let mut first = nrht.submit_mut(1, ttkn).unwrap();
+    /// let second = nrht.submit_mut(2, ttkn).unwrap();
+    /// // ... do something else in the meantime ...
+    /// assert_eq!(nrht.wait(second), 3);
+    /// assert_eq!(nrht.poll(&mut first), Some(1));
+    /// ```
+    pub fn submit_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> Result<Ticket<'_, D, R, T, P>, ExecuteError<<D as Dispatch>::WriteOperation>> {
+        let replica = self.replica_of(tkn).map_err(ExecuteError::TokenMismatch)?;
+        if replica.is_stopped() {
+            return Err(self.stopped_error(tkn, op));
+        }
+        let slot = match replica.enqueue(op.clone(), tkn.rtkn) {
+            Some(slot) => slot,
+            None => return Err(ExecuteError::WouldBlock(op)),
+        };
+        // Give it a head start, in case nobody else is combining.
+        self.try_combine(tkn.rid);
+
+        Ok(Ticket {
+            nr: self,
+            tkn,
+            slot,
+            completed: false,
+        })
+    }
+
+    /// Returns the response for a [`Ticket`] if the operation was executed
+    /// already, or `None` otherwise.
+    ///
+    /// If the response isn't ready yet, this tries to flat combine once (but
+    /// never waits for the combiner lock).
+    ///
+    /// # Panics
+    /// - If called again after the response was returned.
+    /// - If the replica is poisoned (see [`NodeReplicated::is_poisoned`]).
+    /// - If the token of the ticket is rejected (see
+    ///   [`NodeReplicated::check_token`]).
+    pub fn poll(&self, ticket: &mut Ticket<'_, D, R, T, P>) -> Option<<D as Dispatch>::Response> {
+        self.poll_checked(ticket)
+            .unwrap_or_else(|tkn| self.stopped(tkn))
+    }
+
+    /// Same as [`NodeReplicated::poll`], but returns the token of the ticket
+    /// if it's rejected, or if its replica is poisoned and the response is not
+    /// available.
+    fn poll_checked(
+        &self,
+        ticket: &mut Ticket<'_, D, R, T, P>,
+    ) -> Result<Option<<D as Dispatch>::Response>, ThreadToken> {
+        assert!(!ticket.completed, "Ticket polled after completion");
+        let replica = self.replica_of(ticket.tkn).map_err(|_e| ticket.tkn)?;
+
+        let resp = replica
+            .try_response(ticket.tkn.rtkn, ticket.slot)
+            .or_else(|| {
+                self.try_combine(ticket.tkn.rid);
+                replica.try_response(ticket.tkn.rtkn, ticket.slot)
+            });
+        if resp.is_none() && replica.is_stopped() {
+            return Err(ticket.tkn);
+        }
+        ticket.completed = resp.is_some();
+        Ok(resp.map(unwrap_validated::<D>))
+    }
+
+    /// Waits until the operation of the [`Ticket`] was executed and returns
+    /// its response.
+    ///
+    /// # Panics
+    /// - If the replica is poisoned (see [`NodeReplicated::is_poisoned`]).
+    /// - If the token of the ticket is rejected (see
+    ///   [`NodeReplicated::check_token`]).
+    pub fn wait(&self, mut ticket: Ticket<'_, D, R, T, P>) -> <D as Dispatch>::Response {
+        let mut waiter = Waiter::new(&*self.log.wait);
+        loop {
+            if let Some(resp) = self.poll(&mut ticket) {
+                return resp;
+            }
+            waiter.wait();
+        }
+    }
+
+    /// Executes a mutable operation if it can be done without waiting for
+    /// another thread.
+    ///
+    /// If another thread is the combiner of the replica (or the thread's
+    /// context is full), this returns [`ExecuteError::WouldBlock`] with
+    /// [`Pending::Op`] right away. Otherwise, this thread becomes the combiner
+    /// and executes the operation (and those of other threads on the replica).
+    ///
+    /// Appending to the [`Log`] may have to wait for a lagging replica. If
+    /// the log is still full after nudging that replica once, this gives up
+    /// and returns [`ExecuteError::WouldBlock`] with a [`Pending::Submitted`]
+    /// ticket to retrieve the response later (see [`NodeReplicated::poll`]
+    /// and [`NodeReplicated::wait`]).
+    ///
+    /// # Panics
+    /// If the operation is rejected by [`Dispatch::validate`].
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::{Dispatch, ExecuteError, NodeReplicated, Pending};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(1).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    ///
+    /// let resp = match nrht.try_execute_mut(5, ttkn) {
+    ///     Ok(resp) => resp,
+    ///     Err(ExecuteError::WouldBlock(Pending::Op(op))) => nrht.execute_mut(op, ttkn),
+    ///     Err(ExecuteError::WouldBlock(Pending::Submitted(ticket))) => nrht.wait(ticket),
+    ///     Err(_) => unreachable!(),
+    /// };
+    /// assert_eq!(resp, 5);
+    /// ```
+    pub fn try_execute_mut(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+    ) -> Result<<D as Dispatch>::Response, ExecuteError<Pending<'_, D, R, T, P>>> {
+        let replica = self.replica_of(tkn).map_err(ExecuteError::TokenMismatch)?;
+        let combiner_lock = match replica.acquire_combiner_lock() {
+            Some(combiner_lock) => combiner_lock,
+            None => return Err(ExecuteError::WouldBlock(Pending::Op(op))),
+        };
+        if replica.is_stopped() {
+            return Err(self.stopped_error(tkn, Pending::Op(op)));
+        }
+        let slot = match replica.enqueue(op.clone(), tkn.rtkn) {
+            Some(slot) => slot,
+            None => return Err(ExecuteError::WouldBlock(Pending::Op(op))),
+        };
+
+        if !self.resolve_combine(tkn.rid, &replica, replica.combine(&self.log, combiner_lock)) {
+            let ticket = Ticket {
+                nr: self,
+                tkn,
+                slot,
+                completed: false,
+            };
+            return Err(ExecuteError::WouldBlock(Pending::Submitted(ticket)));
+        }
+        let resp = replica
+            .try_response(tkn.rtkn, slot)
+            .expect("We were the combiner, so our operation was executed");
+        Ok(unwrap_validated::<D>(resp))
+    }
+
+    /// Same as [`NodeReplicated::execute_mut`], but gives up once `deadline`
+    /// expires.
+    ///
+    /// An operation can't be taken back once it was submitted, so on timeout
+    /// the operation stays enqueued and [`ExecuteError::TimedOut`] contains a
+    /// [`Pending::Submitted`] ticket to retrieve its response later (see
+    /// [`NodeReplicated::poll`] and [`NodeReplicated::wait`]).
+    ///
+    /// # Errors
+    /// - [`ExecuteError::WouldBlock`] with [`Pending::Op`] if the thread
+    ///   already has `P` outstanding tickets.
+    /// - [`ExecuteError::Poisoned`] if the replica is poisoned, with
+    ///   [`Pending::Op`] if the operation wasn't submitted yet.
+    ///
+    /// # Panics
+    /// If the operation is rejected by [`Dispatch::validate`].
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use std::time::{Duration, Instant};
+    /// use node_replication::nr::{Deadline, Dispatch, ExecuteError, NodeReplicated, Pending};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    ///
+    /// let start = Instant::now();
+    /// let clock = || start.elapsed().as_micros() as u64;
+    /// let deadline = Deadline::At { clock: &clock, at: 1_000 };
+    /// let resp = match nrht.execute_mut_timeout(5, ttkn, deadline) {
+    ///     Ok(resp) => resp,
+    ///     Err(ExecuteError::TimedOut(Pending::Submitted(ticket))) => nrht.wait(ticket),
+    ///     Err(_) => unreachable!(),
+    /// };
+    /// assert_eq!(resp, 5);
+    /// ```
+    pub fn execute_mut_timeout(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+        deadline: Deadline<'_>,
+    ) -> Result<<D as Dispatch>::Response, ExecuteError<Pending<'_, D, R, T, P>>> {
+        let mut ticket = self.submit_mut(op, tkn).map_err(|e| e.map(Pending::Op))?;
+        let mut waiter = Waiter::new(&*self.log.wait);
+        loop {
+            match self.poll_checked(&mut ticket) {
+                Ok(Some(resp)) => return Ok(resp),
+                Ok(None) => {}
+                Err(tkn) => return Err(self.stopped_error(tkn, Pending::Submitted(ticket))),
+            }
+            if deadline.expired(waiter.iteration()) {
+                return Err(ExecuteError::TimedOut(Pending::Submitted(ticket)));
+            }
+            waiter.wait();
+        }
+    }
+
+    /// Waits for the next response of an operation enqueued by thread `tkn`,
+    /// flat combining on its replica in the meantime.
+    ///
+    /// This handles liveness issues due to lagging replicas (which a single
+    /// replica can not). Returns the response together with the position of
+    /// the operation on the log.
+    fn get_response(&self, tkn: ThreadToken) -> (Validated<D>, LogPos) {
+        /// An enum to keep track of a stack of operations we should do on Replicas.
+        ///
+        /// e.g., either `Sync` an out-of-date, behind replica, or call `combine` to
+        /// resume the operation with a combiner lock.
+        enum ResolveOp<
+            'a,
+            D: core::marker::Sync + Dispatch + Sized,
+            const R: usize,
+            const T: usize,
+            const P: usize,
+        > {
+            /// Resumes a replica that earlier returned with an Error (and the CombinerLock).
+            Exec(Option<CombinerLock<'a, D, R, T, P>>),
+            /// Indicates need to [`Replica::sync()`] a replica with the given ID.
+            Sync(ReplicaId),
+        }
+
+        let replica = self.check(tkn);
+        let mut q = ArrayVec::<ResolveOp<D, R, T, P>, R>::new();
+        loop {
+            match q.pop().unwrap_or(ResolveOp::Exec(None)) {
+                ResolveOp::Exec(cl) => {
+                    let res = match cl {
+                        Some(combiner_lock) => replica.combine(&self.log, combiner_lock),
+                        None => replica.try_combine(&self.log),
+                    };
+                    match res.and_then(|()| replica.get_response(&self.log, tkn.rtkn.tid())) {
+                        Ok(resp) => {
+                            assert!(q.is_empty());
+                            return resp;
+                        }
+                        Err(ReplicaError::NoLogSpace(stuck_ridx, cl_acq)) => {
+                            assert_ne!(stuck_ridx, tkn.rid);
+                            q.push(ResolveOp::Exec(Some(cl_acq)));
+                            q.push(ResolveOp::Sync(stuck_ridx));
+                        }
+                        Err(ReplicaError::GcFailed(stuck_ridx)) => {
+                            {
+                                assert_ne!(stuck_ridx, tkn.rid);
+                                let _aftkn = self.affinity_mngr.switch(stuck_ridx);
+                                if let Some(stuck) = self.replica(stuck_ridx) {
+                                    stuck.sync(&self.log);
+                                }
+                                // Affinity is reverted here, _aftkn is dropped.
+                            }
+                            return match replica.get_response(&self.log, tkn.rtkn.tid()) {
+                                Ok(positioned) => positioned,
+                                Err(ReplicaError::Poisoned(_rid)) => self.stopped(tkn),
+                                Err(ReplicaError::TokenMismatch(e)) => token_mismatch(e),
+                                Err(e) => panic!("GcFailed has to produced a response: {:?}", e),
+                            };
+                        }
+                        Err(ReplicaError::Poisoned(_rid)) => self.stopped(tkn),
+                        Err(ReplicaError::TokenMismatch(e)) => token_mismatch(e),
+                    }
+                }
+                ResolveOp::Sync(ridx) => {
+                    // Holds trivially because of all the other asserts in this function
+                    debug_assert_ne!(ridx, tkn.rid);
+                    let _aftkn = self.affinity_mngr.switch(ridx);
+                    if let Some(stuck) = self.replica(ridx) {
+                        stuck.try_sync(&self.log);
+                    }
+                    // _aftkn is dropped here, reverting affinity change
+                }
+            }
+        }
+    }
+
+    fn try_execute<'a, 'rop>(
+        &'a self,
+        replica: &'a Replica<D, R, T, P>,
+        op: <D as Dispatch>::ReadOperation<'rop>,
+        tkn: ThreadToken,
+        cl: Option<CombinerLock<'a, D, R, T, P>>,
+    ) -> Result<
+        <D as Dispatch>::Response,
+        (
+            ReplicaError<D, R, T, P>,
+            <D as Dispatch>::ReadOperation<'rop>,
+        ),
+    > {
+        if let Some(combiner_lock) = cl {
+            replica.execute_locked(&self.log, op, tkn.rtkn, combiner_lock)
+        } else {
+            replica.execute(&self.log, op, tkn.rtkn)
+        }
+    }
+
+    /// Executes a immutable operation against the data-structure.
+    ///
+    /// Multiple threads can read from the data-structure in parallel.
+    ///
+    ///  This method is similar to the one found in [`Replica::execute`], but in
+    /// addition, it handles liveness issues due to lagging replicas (which a
+    /// single replica can not).
+    ///
+    /// # Arguments
+    /// - `op`: Which operation to execute.
+    /// - `tkn`: Which thread executes the operation (see also
+    ///   [`NodeReplicated::register`]).
+    ///
+    /// # Flow
+    /// Eventually, this method calls [`Replica::execute`] which will call into
+    /// [`Dispatch::dispatch`].
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::NodeReplicated;
+    /// use node_replication::nr::Dispatch;
+    ///
+    /// #[derive(Default)]
+    /// struct Void;
+    /// impl Dispatch for Void {
+    ///     type ReadOperation<'rop> = usize;
+    ///     type WriteOperation = ();
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         assert!(op == 99);
+    ///         0xbeef
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         unreachable!("no immutable op is issued")
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Void>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    ///
+    /// assert_eq!(nrht.execute(99, ttkn), 0xbeef);
+    /// ```
+    ///
+    /// # Panics
+    /// - If the thread's replica is poisoned (see
+    ///   [`NodeReplicated::is_poisoned`]).
+    /// - If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    pub fn execute(
+        &self,
+        op: <D as Dispatch>::ReadOperation<'_>,
+        tkn: ThreadToken,
+    ) -> <D as Dispatch>::Response {
+        let replica = self.check(tkn);
+        /// An enum to keep track of a stack of operations we should do on Replicas.
+        ///
+        /// e.g., either `Sync` an out-of-date, behind replica, or call `execute_locked` or
+        /// `execute_mut_locked` to resume the operation with a combiner lock.
+        enum ResolveOp<
+            'a,
+            'rop,
+            D: core::marker::Sync + Dispatch + Sized,
+            const R: usize,
+            const T: usize,
+            const P: usize,
+        > {
+            /// Resumes a replica that earlier returned with an Error (and the CombinerLock).
+            Exec(Option<CombinerLock<'a, D, R, T, P>>, D::ReadOperation<'rop>),
+            /// Indicates need to [`Replica::sync()`] a replica with the given ID.
+            Sync(ReplicaId),
+        }
+
+        let mut q = ArrayVec::<ResolveOp<D, R, T, P>, R>::new();
+        q.push(ResolveOp::Exec(None, op));
+        loop {
+            match q.pop().unwrap() {
+                ResolveOp::Exec(cl, op) => match self.try_execute(&replica, op, tkn, cl) {
+                    Ok(resp) => {
+                        assert!(q.is_empty());
+                        return resp;
+                    }
+                    Err((ReplicaError::NoLogSpace(stuck_ridx, cl_acq), op)) => {
+                        assert!(stuck_ridx != tkn.rid);
+                        q.push(ResolveOp::Exec(Some(cl_acq), op));
+                        q.push(ResolveOp::Sync(stuck_ridx));
+                    }
+                    Err((ReplicaError::GcFailed(stuck_ridx), op)) => {
+                        assert_ne!(stuck_ridx, tkn.rid);
+                        q.push(ResolveOp::Exec(None, op));
+                        q.push(ResolveOp::Sync(stuck_ridx));
+                    }
+                    Err((ReplicaError::Poisoned(_rid), _op)) => self.stopped(tkn),
+                    Err((ReplicaError::TokenMismatch(e), _op)) => token_mismatch(e),
+                },
+                ResolveOp::Sync(ridx) => {
+                    // Holds trivially because of all the other asserts in this function
+                    debug_assert_ne!(ridx, tkn.rid);
+                    let _aftkn = self.affinity_mngr.switch(ridx);
+                    if let Some(stuck) = self.replica(ridx) {
+                        stuck.try_sync(&self.log);
+                    }
+                    // _aftkn is dropped here, reverting affinity change
+                }
+            }
+        }
+    }
+
+    /// Same as [`NodeReplicated::execute`], but gives up once `deadline`
+    /// expires (e.g., because the replica lags behind and its combiner is
+    /// busy) and hands back the operation.
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::{Deadline, Dispatch, NodeReplicated};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// nrht.execute_mut(5, ttkn);
+    ///
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert_eq!(nrht.execute_timeout((), ttkn, Deadline::Iterations(100)), Ok(5));
+    /// ```
+    pub fn execute_timeout<'rop>(
+        &self,
+        op: <D as Dispatch>::ReadOperation<'rop>,
+        tkn: ThreadToken,
+        deadline: Deadline<'_>,
+    ) -> Result<<D as Dispatch>::Response, ExecuteError<<D as Dispatch>::ReadOperation<'rop>>> {
+        let replica = self.replica_of(tkn).map_err(ExecuteError::TokenMismatch)?;
+        let ctail = self.log.get_ctail();
+        let mut waiter = Waiter::new(&*self.log.wait);
+        loop {
+            if replica.is_synced_for_reads(&self.log, ctail) {
+                return replica
+                    .read(op, tkn.rtkn)
+                    .map_err(|op| self.stopped_error(tkn, op));
+            }
+            if replica.is_stopped() {
+                return Err(self.stopped_error(tkn, op));
+            }
+            if deadline.expired(waiter.iteration()) {
+                return Err(ExecuteError::TimedOut(op));
+            }
+            self.try_combine(tkn.rid);
+            waiter.wait();
+        }
+    }
+
+    /// Same as [`NodeReplicated::execute`], but tolerates a replica that is
+    /// slightly out-of-date.
+    ///
+    /// If the thread's replica has applied all but (at most) `max_lag`
+    /// operations of the [`Log`], the read is served right away, without
+    /// flat combining on behalf of writers first. Otherwise, the replica is
+    /// brought up-to-date like in [`NodeReplicated::execute`]. With
+    /// `max_lag = 0` the read linearizes like one issued with
+    /// [`NodeReplicated::execute`].
+    ///
+    /// # Example
+    /// ```
+    /// #![feature(generic_associated_types)]
+    /// use core::num::NonZeroUsize;
+    /// use node_replication::nr::{Dispatch, NodeReplicated};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(usize);
+    /// impl Dispatch for Counter {
+    ///     type ReadOperation<'rop> = ();
+    ///     type WriteOperation = usize;
+    ///     type Response = usize;
+    ///
+    ///     fn dispatch<'rop>(&self, op: <Self as Dispatch>::ReadOperation<'rop>) -> <Self as Dispatch>::Response {
+    ///         self.0
+    ///     }
+    ///     fn dispatch_mut(&mut self, op: <Self as Dispatch>::WriteOperation) -> <Self as Dispatch>::Response {
+    ///         self.0 += op;
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// let replicas = NonZeroUsize::new(2).unwrap();
+    /// let nrht = NodeReplicated::<Counter>::new(replicas, |_| { 0 }).unwrap();
+    /// let ttkn = nrht.register(0).unwrap();
+    /// nrht.execute_mut(5, ttkn);
+    ///
+    /// // Replica 1 didn't apply the write yet.
+    /// let ttkn = nrht.register(1).unwrap();
+    /// assert_eq!(nrht.execute_stale((), ttkn, 1), 0);
+    /// assert_eq!(nrht.execute_stale((), ttkn, 0), 5);
+    /// ```
+    ///
+    /// # Panics
+    /// See [`NodeReplicated::execute`].
+    pub fn execute_stale(
+        &self,
+        op: <D as Dispatch>::ReadOperation<'_>,
+        tkn: ThreadToken,
+        max_lag: usize,
+    ) -> <D as Dispatch>::Response {
+        let bound = self.log.get_ctail().saturating_sub(max_lag);
+        self.execute_from(op, tkn, bound)
+    }
+
+    /// Same as [`NodeReplicated::execute`], but only waits until the
+    /// thread's replica has applied the operation at `pos`.
+    ///
+    /// This is cheaper than [`NodeReplicated::execute`] if the replica
+    /// already applied the operation (but isn't up-to-date with the log),
+    /// while the read still observes it and all operations that precede it
+    /// on the [`Log`]. This way, a position returned by
+    /// [`NodeReplicated::execute_mut_positioned`] gives read-your-writes
+    /// and causal reads across threads and replicas.
+    ///
+    /// `pos` must have been returned by
+    /// [`NodeReplicated::execute_mut_positioned`] of this instance.
+    ///
+    /// # Panics
+    /// See [`NodeReplicated::execute`].
+    pub fn execute_after(
+        &self,
+        op: <D as Dispatch>::ReadOperation<'_>,
+        tkn: ThreadToken,
+        pos: LogPos,
+    ) -> <D as Dispatch>::Response {
+        self.execute_from(op, tkn, pos + 1)
+    }
+
+    /// Executes `op` right away if the thread's replica applied all
+    /// operations before `bound`, otherwise it's executed like in
+    /// [`NodeReplicated::execute`].
+    fn execute_from(
+        &self,
+        op: <D as Dispatch>::ReadOperation<'_>,
+        tkn: ThreadToken,
+        bound: LogPos,
+    ) -> <D as Dispatch>::Response {
+        let replica = self.check(tkn);
+        if !replica.is_synced_for_reads(&self.log, bound) {
+            return self.execute(op, tkn);
+        }
+        match replica.read(op, tkn.rtkn) {
+            Ok(resp) => resp,
+            // Poisoned, `execute` deals with it.
+            Err(op) => self.execute(op, tkn),
+        }
+    }
+
+    /// Executes a mutable operation asynchronously on a replica, and returns
+    /// the response in `resp`
+    ///
+    /// When `resp` is polled, the operation is enqueued in the thread's
+    /// context and we try to combine once. If another thread is the combiner
+    /// the future returns [`core::task::Poll::Pending`] and is woken up by the
+    /// combiner once the response is ready. This allows many operations to be
+    /// in-flight on the same executor thread.
+    ///
+    /// # Note
+    /// - Futures for the same `tkn` may complete in any order, but they must
+    ///   not be polled concurrently from different threads.
+    /// - Don't mix these futures with the blocking
+    ///   [`NodeReplicated::execute_mut`] on the same `tkn` while they're
+    ///   in-flight.
+    /// - Once polled, an operation can't be retracted: if the future is
+    ///   dropped before it completes, the operation is still executed and its
+    ///   response is discarded once it arrives.
+    ///
+    /// # Panics
+    /// - If the thread's replica is poisoned (see
+    ///   [`NodeReplicated::is_poisoned`]).
+    /// - If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    #[cfg(feature = "async")]
+    pub async fn async_execute_mut<'a>(
+        &'a self,
+        op: <D as Dispatch>::WriteOperation,
+        tkn: ThreadToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) {
+        self.check(tkn);
+        resp.set(future::ExecuteMutFuture::new(self, op, tkn));
+    }
+
+    /// Executes an immutable operation asynchronously on a replica, and returns
+    /// the response in `resp`.
+    ///
+    /// When `resp` is polled and the replica isn't up-to-date with the log
+    /// yet, we try to combine once. If another thread is the combiner, the
+    /// future yields ([`core::task::Poll::Pending`]) instead of spinning and
+    /// is woken up once the combiner releases its lock.
+    ///
+    /// # Panics
+    /// - If the thread's replica is poisoned (see
+    ///   [`NodeReplicated::is_poisoned`]).
+    /// - If `tkn` is rejected (see [`NodeReplicated::check_token`]).
+    #[cfg(feature = "async")]
+    pub fn async_execute<'a, 'rop: 'a>(
+        &'a self,
+        op: <D as Dispatch>::ReadOperation<'rop>,
+        tkn: ThreadToken,
+        resp: &mut ReusableBoxFuture<'a, <D as Dispatch>::Response>,
+    ) {
+        self.check(tkn);
+        resp.set(future::ExecuteFuture::new(self, op, tkn));
+    }
+
+    /// Does (at most) one round of flat combining on replica `rid` without
+    /// waiting for the combiner lock.
+    ///
+    /// Like [`NodeReplicated::execute_mut`], it nudges lagging replicas in
+    /// case we run out of log space or can't garbage collect the log, but it
+    /// never blocks on them.
+    fn try_combine(&self, rid: ReplicaId) {
+        if let Some(replica) = self.replica(rid) {
+            self.resolve_combine(rid, &replica, replica.try_combine(&self.log));
+        }
+    }
+
+    /// Resolves the outcome `res` of a flat combining round on `replica`
+    /// (with id `rid`), see [`NodeReplicated::try_combine`].
+    ///
+    /// If there is no space on the log, the replica which is behind is nudged
+    /// and the round retried once. Returns false if the log is still full
+    /// then: the operations of the round stay enqueued (and the combiner lock
+    /// is released), so the caller can decide whether to try again.
+    fn resolve_combine<'a>(
+        &'a self,
+        rid: ReplicaId,
+        replica: &'a Replica<D, R, T, P>,
+        mut res: Result<(), ReplicaError<'a, D, R, T, P>>,
+    ) -> bool {
+        let mut retried = false;
+        loop {
+            match res {
+                Ok(()) => return true,
+                Err(ReplicaError::NoLogSpace(stuck_ridx, cl_acq)) => {
+                    assert_ne!(stuck_ridx, rid);
+                    {
+                        let _aftkn = self.affinity_mngr.switch(stuck_ridx);
+                        if let Some(stuck) = self.replica(stuck_ridx) {
+                            stuck.try_sync(&self.log);
+                        }
+                        // _aftkn is dropped here, reverting affinity change
+                    }
+                    if retried {
+                        return false;
+                    }
+                    retried = true;
+                    res = replica.combine(&self.log, cl_acq);
+                }
+                Err(ReplicaError::GcFailed(stuck_ridx)) => {
+                    // Our operations made it into the log, just poke the
+                    // replica which is behind.
+                    assert_ne!(stuck_ridx, rid);
+                    let _aftkn = self.affinity_mngr.switch(stuck_ridx);
+                    if let Some(stuck) = self.replica(stuck_ridx) {
+                        stuck.try_sync(&self.log);
+                    }
+                    return true;
+                }
+                // Callers find out when they look for their response.
+                Err(ReplicaError::Poisoned(_rid)) => return true,
+                Err(ReplicaError::TokenMismatch(e)) => token_mismatch(e),
+            }
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn sync(&self, tkn: ThreadToken) {
+        self.check(tkn).sync(&self.log)
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg(test)]
+mod test {
+    use super::context::MAX_PENDING_OPS;
+    use super::replica::test::Data;
+    use super::reusable_box::ReusableBoxFuture;
+    use super::*;
+    use alloc::vec;
     use core::num::NonZeroUsize;
 
-    #[tokio::test]
-    async fn test_box_reuse() {
-        use futures::executor::block_on;
+    #[tokio::test]
+    async fn test_box_reuse() {
+        use futures::executor::block_on;
+
+        let replicas = NonZeroUsize::new(1).unwrap();
+        let async_ds = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = async_ds.register(0).expect("Unable to register with log");
+
+        let op = 0;
+        let mut resp: ReusableBoxFuture<<Data as Dispatch>::Response> =
+            ReusableBoxFuture::new(async move { Ok(0) });
+        async_ds.async_execute_mut(op, ttkn, &mut resp).await;
+        let res = block_on(&mut resp).unwrap();
+        assert_eq!(res, 107);
+
+        async_ds.async_execute(op, ttkn, &mut resp);
+        let res = block_on(resp).unwrap();
+        assert_eq!(res, 1);
+    }
+
+    /// Many async operations of one thread can be in-flight at the same time
+    /// and each future gets the response to its own operation.
+    #[tokio::test]
+    async fn test_async_in_flight() {
+        use futures::future::join_all;
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let async_ds = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = async_ds.register(0).expect("Unable to register with log");
+
+        let mut futures = Vec::new();
+        for _ in 0..2 * MAX_PENDING_OPS {
+            let mut resp: ReusableBoxFuture<<Data as Dispatch>::Response> =
+                ReusableBoxFuture::new(async move { Ok(0) });
+            async_ds.async_execute_mut(0, ttkn, &mut resp).await;
+            futures.push(resp);
+        }
+        for resp in join_all(futures).await {
+            assert_eq!(resp, Ok(107));
+        }
+
+        let mut resp: ReusableBoxFuture<<Data as Dispatch>::Response> =
+            ReusableBoxFuture::new(async move { Ok(0) });
+        async_ds.async_execute(0, ttkn, &mut resp);
+        assert_eq!(resp.await, Ok(2 * MAX_PENDING_OPS as u64));
+    }
+
+    /// A pending future is woken up by a combiner on another thread.
+    #[test]
+    fn test_async_woken_by_combiner() {
+        extern crate std;
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::sync::atomic::{AtomicBool, Ordering};
+        use core::task::{Context, Poll};
+        use futures::task::{waker, ArcWake};
+        use std::sync::Arc;
+
+        struct Flag(AtomicBool);
+        impl ArcWake for Flag {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let replicas = NonZeroUsize::new(1).unwrap();
+        let async_ds =
+            Arc::new(NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds"));
+        let ttkn = async_ds.register(0).expect("Unable to register with log");
+        let ttkn2 = async_ds.register(0).expect("Unable to register with log");
+
+        // Pretend someone else is the combiner, so our future can't finish.
+        let cl = async_ds
+            .replica(0)
+            .unwrap()
+            .acquire_combiner_lock()
+            .expect("Nobody else is combining");
+        let mut fut = future::ExecuteMutFuture::new(&async_ds, 0, ttkn);
+        let woken = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+        assert!(!woken.0.load(Ordering::SeqCst));
+
+        // The combiner picks up our operation and wakes us up.
+        async_ds
+            .replica(0)
+            .unwrap()
+            .combine(&async_ds.log, cl)
+            .unwrap();
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Ok(107)));
+        assert_eq!(async_ds.execute(0, ttkn2), Ok(1));
+    }
+
+    /// Pending reads are woken up once the combiner lock is released, and
+    /// dropping a pending write doesn't wait for its response.
+    #[test]
+    fn test_async_woken_after_release() {
+        extern crate std;
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::sync::atomic::{AtomicBool, Ordering};
+        use core::task::{Context, Poll};
+        use futures::task::{waker, ArcWake};
+        use std::sync::Arc;
+
+        struct Flag(AtomicBool);
+        impl ArcWake for Flag {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let async_ds = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = async_ds.register(0).expect("Unable to register with log");
+        let ttkn2 = async_ds.register(1).expect("Unable to register with log");
+
+        // Replica 1 is behind and someone else is its combiner.
+        assert_eq!(async_ds.execute_mut(0, ttkn), Ok(107));
+        let cl = async_ds
+            .replica(1)
+            .unwrap()
+            .acquire_combiner_lock()
+            .expect("Nobody else is combining");
+
+        let mut wfut = future::ExecuteMutFuture::new(&async_ds, 0, ttkn2);
+        let mut rfut = future::ExecuteFuture::new(&async_ds, 0, ttkn2);
+        let woken = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut wfut).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut rfut).poll(&mut cx).is_pending());
+        drop(wfut);
+        assert!(!woken.0.load(Ordering::SeqCst));
+
+        drop(cl);
+        assert!(woken.0.load(Ordering::SeqCst));
+        // The write is executed even though nobody waits for it anymore.
+        assert_eq!(Pin::new(&mut rfut).poll(&mut cx), Poll::Ready(Ok(2)));
+        assert_eq!(async_ds.execute_mut(0, ttkn2), Ok(107));
+        assert_eq!(async_ds.execute(0, ttkn), Ok(3));
+    }
+
+    /// A batch larger than the thread context is split up and the responses
+    /// are returned in order.
+    #[test]
+    fn test_execute_mut_batch() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn2 = nr.register(1).expect("Unable to register with log");
+
+        let ops = vec![0; 3 * MAX_PENDING_OPS + 5];
+        let resps = nr.execute_mut_batch(&ops, ttkn);
+        assert_eq!(resps, vec![Ok(107); ops.len()]);
+        assert_eq!(nr.execute(0, ttkn2), Ok(ops.len() as u64));
+
+        let mut resps = vec![Err(())];
+        nr.execute_mut_batch_into((0..7).map(|_| 0), ttkn2, &mut resps);
+        assert_eq!(resps.len(), 8);
+        assert_eq!(resps[0], Err(()));
+        assert!(resps[1..].iter().all(|r| *r == Ok(107)));
+        assert_eq!(nr.execute(0, ttkn), Ok(ops.len() as u64 + 7));
+
+        nr.execute_mut_batch_into(core::iter::empty(), ttkn, &mut resps);
+        assert_eq!(resps.len(), 8);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_stats() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn2 = nr.register(1).expect("Unable to register with log");
+
+        let ops = vec![0; 3 * MAX_PENDING_OPS + 5];
+        nr.execute_mut_batch(&ops, ttkn);
+        nr.execute(0, ttkn2).unwrap();
+
+        let stats = nr.stats();
+        assert_eq!(stats.log.occupancy, ops.len());
+        assert_eq!(stats.log.advance_head_iterations, 0);
+        assert!(stats.log.no_log_space.iter().all(|n| *n == 0));
+        assert!(stats.log.gc_failed.iter().all(|n| *n == 0));
+
+        let r0 = stats.replicas[0].as_ref().unwrap();
+        assert_eq!(r0.appended_ops, ops.len());
+        assert_eq!(r0.max_batch_size, MAX_PENDING_OPS);
+        assert!(r0.combine_rounds >= 4);
+        assert_eq!(
+            r0.avg_batch_size(),
+            ops.len() as f64 / r0.combine_rounds as f64
+        );
+        assert_eq!(stats.replicas[1].as_ref().unwrap().appended_ops, 0);
+    }
+
+    /// Threads that give up the CPU while waiting still see all operations.
+    #[test]
+    fn test_wait_strategy() {
+        use crate::wait::{Backoff, SpinThenYield};
+        use std::sync::Arc;
+
+        fn run(nr: NodeReplicated<Data>) {
+            let nr = Arc::new(nr);
+            let threads: Vec<_> = (0..8)
+                .map(|i| {
+                    let nr = nr.clone();
+                    std::thread::spawn(move || {
+                        let ttkn = nr.register(i % 2).expect("Unable to register with log");
+                        for _ in 0..500 {
+                            assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+
+            let ttkn = nr.register(1).expect("Unable to register with log");
+            assert_eq!(nr.execute(0, ttkn), Ok(8 * 500));
+        }
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        run(nr.with_wait_strategy(SpinThenYield {
+            spins: 4,
+            yield_now: std::thread::yield_now,
+        }));
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        run(nr.with_wait_strategy(Backoff::default()));
+    }
+
+    /// Responses of submitted operations can be retrieved in any order, once
+    /// the operations were applied.
+    #[test]
+    fn test_submit_poll_wait() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn2 = nr.register(0).expect("Unable to register with log");
+
+        // Hold the combiner lock, so nothing gets applied for now.
+        let cl = nr.replica(0).unwrap().acquire_combiner_lock().unwrap();
+        let mut tickets: Vec<_> = (0..MAX_PENDING_OPS)
+            .map(|_| nr.submit_mut(0, ttkn).unwrap())
+            .collect();
+        assert!(tickets.iter_mut().all(|t| nr.poll(t).is_none()));
+        assert!(matches!(
+            nr.submit_mut(0, ttkn),
+            Err(ExecuteError::WouldBlock(0))
+        ));
+        drop(cl);
+
+        let last = tickets.pop().unwrap();
+        assert_eq!(nr.wait(last), Ok(107));
+        for mut ticket in tickets.into_iter().rev() {
+            assert_eq!(nr.poll(&mut ticket), Some(Ok(107)));
+        }
+        assert_eq!(nr.execute(0, ttkn2), Ok(MAX_PENDING_OPS as u64));
+
+        // All slots were released again.
+        let tickets: Vec<_> = (0..MAX_PENDING_OPS)
+            .map(|_| nr.submit_mut(0, ttkn).unwrap())
+            .collect();
+        for ticket in tickets {
+            assert_eq!(nr.wait(ticket), Ok(107));
+        }
+    }
+
+    /// Dropped tickets release their context slot, the operations are still
+    /// executed.
+    #[test]
+    fn test_drop_tickets() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn2 = nr.register(1).expect("Unable to register with log");
+
+        for _ in 0..4 {
+            let tickets: Vec<_> = (0..MAX_PENDING_OPS)
+                .map(|_| nr.submit_mut(0, ttkn).unwrap())
+                .collect();
+            drop(tickets);
+        }
+
+        // Slots of operations that weren't executed yet are released once a
+        // combiner got to them.
+        let cl = nr.replica(0).unwrap().acquire_combiner_lock().unwrap();
+        let tickets: Vec<_> = (0..MAX_PENDING_OPS)
+            .map(|_| nr.submit_mut(0, ttkn).unwrap())
+            .collect();
+        drop(tickets);
+        drop(cl);
+
+        assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+        let ticket = nr.submit_mut(0, ttkn).unwrap();
+        assert_eq!(nr.wait(ticket), Ok(107));
+        assert_eq!(nr.execute(0, ttkn2), Ok(5 * MAX_PENDING_OPS as u64 + 2));
+    }
+
+    /// The bounded and non-blocking variants give up while another thread
+    /// holds the combiner lock, and succeed once it is released.
+    #[test]
+    fn test_execute_timeout() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn2 = nr.register(1).expect("Unable to register with log");
+
+        let cl = nr.replica(0).unwrap().acquire_combiner_lock().unwrap();
+        assert!(matches!(
+            nr.try_execute_mut(7, ttkn),
+            Err(ExecuteError::WouldBlock(Pending::Op(7)))
+        ));
+        let ticket = match nr.execute_mut_timeout(0, ttkn, Deadline::Iterations(10)) {
+            Err(ExecuteError::TimedOut(Pending::Submitted(ticket))) => ticket,
+            r => panic!("Unexpected result {:?}", r),
+        };
+
+        let now = core::cell::Cell::new(0);
+        let clock = || {
+            now.set(now.get() + 1);
+            now.get()
+        };
+        let deadline = Deadline::At {
+            clock: &clock,
+            at: 5,
+        };
+        let ticket2 = match nr.execute_mut_timeout(0, ttkn, deadline) {
+            Err(ExecuteError::TimedOut(Pending::Submitted(ticket))) => ticket,
+            r => panic!("Unexpected result {:?}", r),
+        };
+        assert_eq!(now.get(), 5);
+
+        // Without a free slot in the context the operation is handed back.
+        let tickets: Vec<_> = (2..MAX_PENDING_OPS)
+            .map(|_| nr.submit_mut(0, ttkn).unwrap())
+            .collect();
+        match nr.execute_mut_timeout(9, ttkn, Deadline::Iterations(10)) {
+            Err(ExecuteError::WouldBlock(Pending::Op(9))) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+        drop(cl);
+
+        assert_eq!(nr.wait(ticket), Ok(107));
+        assert_eq!(nr.wait(ticket2), Ok(107));
+        for ticket in tickets {
+            assert_eq!(nr.wait(ticket), Ok(107));
+        }
+        assert!(matches!(nr.try_execute_mut(0, ttkn), Ok(Ok(107))));
+
+        // Replica 1 has to catch up with the log first.
+        let cl = nr.replica(1).unwrap().acquire_combiner_lock().unwrap();
+        assert_eq!(
+            nr.execute_timeout(0, ttkn2, Deadline::Iterations(10)),
+            Err(ExecuteError::TimedOut(0))
+        );
+        drop(cl);
+        assert_eq!(
+            nr.execute_timeout(0, ttkn2, Deadline::Iterations(10)),
+            Ok(Ok(MAX_PENDING_OPS as u64 + 1))
+        );
+    }
+
+    /// `try_execute_mut` gives up instead of waiting for a stalled replica
+    /// that holds back a full log, and hands back a ticket.
+    #[test]
+    fn test_try_execute_mut_full_log() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data, 2, 4, 2>::with_log_size(replicas, |_ac| 0, 0)
+            .expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn2 = nr.register(1).expect("Unable to register with log");
+
+        // The combiner of replica 1 is stuck (e.g., it was preempted).
+        let cl = nr.replica(1).unwrap().acquire_combiner_lock().unwrap();
+        let mut executed = 0;
+        let ticket = loop {
+            match nr.try_execute_mut(0, ttkn) {
+                Ok(resp) => assert_eq!(resp, Ok(107)),
+                Err(ExecuteError::WouldBlock(Pending::Submitted(ticket))) => break ticket,
+                r => panic!("Unexpected result {:?}", r),
+            }
+            executed += 1;
+            assert!(executed <= nr.log.slog.len());
+        };
+        drop(cl);
+
+        assert_eq!(nr.wait(ticket), Ok(107));
+        assert_eq!(nr.execute(0, ttkn2), Ok(executed as u64 + 1));
+    }
+
+    /// Every replica starts out with a copy of the seed passed to `with_data`.
+    #[test]
+    fn test_with_data_seeds_all_replicas() {
+        let replicas = NonZeroUsize::new(3).unwrap();
+        let nr = NodeReplicated::<Data>::with_data(replicas, |_ac| 0, Data { junk: 42 })
+            .expect("Can't create Ds");
+
+        for rid in 0..replicas.get() {
+            let ttkn = nr.register(rid).expect("Unable to register with log");
+            assert_eq!(nr.execute(0, ttkn), Ok(42));
+        }
+    }
+
+    /// The factory is called once per replica, with the affinity switched to
+    /// that replica, and the custom log size is used.
+    #[test]
+    fn test_with_factory_affinity() {
+        extern crate std;
+        use std::sync::{Arc, Mutex};
+        use std::vec::Vec;
+
+        let current = Arc::new(Mutex::new(usize::MAX));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let af_current = current.clone();
+        let af_change = move |ac: AffinityChange| -> usize {
+            let mut cur = af_current.lock().unwrap();
+            match ac {
+                AffinityChange::Replica(rid) => core::mem::replace(&mut *cur, rid),
+                AffinityChange::Revert(old) => {
+                    *cur = old;
+                    0
+                }
+            }
+        };
+
+        let replicas = NonZeroUsize::new(3).unwrap();
+        let log_size = 4 * log::DEFAULT_LOG_BYTES;
+        let (f_current, f_seen) = (current.clone(), seen.clone());
+        let nr = NodeReplicated::<Data>::with_factory_and_log_size(
+            replicas,
+            af_change,
+            log_size,
+            |rid| {
+                f_seen
+                    .lock()
+                    .unwrap()
+                    .push((rid, *f_current.lock().unwrap()));
+                Data { junk: 7 }
+            },
+        )
+        .expect("Can't create Ds");
+
+        // Each replica was allocated with the affinity switched to it.
+        assert_eq!(*seen.lock().unwrap(), [(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(*current.lock().unwrap(), usize::MAX);
+        assert_eq!(
+            nr.log.slog.len(),
+            (log_size / Log::<u64>::entry_size()).next_power_of_two()
+        );
+
+        for rid in 0..replicas.get() {
+            let ttkn = nr.register(rid).expect("Unable to register with log");
+            assert_eq!(nr.execute(0, ttkn), Ok(7));
+        }
+    }
+
+    /// An added replica starts off with the state of the other replicas and
+    /// keeps up with operations issued afterwards.
+    #[test]
+    fn test_add_replica() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        for _ in 0..3 {
+            assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+        }
+
+        let rid = nr.add_replica().expect("Can't add replica");
+        assert_eq!(rid, 2);
+        let new_ttkn = nr.register(rid).expect("Unable to register with log");
+        assert_eq!(nr.execute(0, new_ttkn), Ok(3));
+
+        assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+        assert_eq!(nr.execute_mut(0, new_ttkn), Ok(107));
+        for rid in 0..3 {
+            let ttkn = nr.register(rid).expect("Unable to register with log");
+            assert_eq!(nr.execute(0, ttkn), Ok(5));
+        }
+    }
+
+    /// A removed replica doesn't hold back GC and its id is reused.
+    #[test]
+    fn test_remove_replica() {
+        let replicas = NonZeroUsize::new(3).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let removed_ttkn = nr.register(1).expect("Unable to register with log");
+        drop(nr.submit_mut(0, removed_ttkn).unwrap());
+
+        nr.remove_replica(1).expect("Can't remove replica");
+        assert!(nr.register(1).is_none());
+        assert_eq!(
+            nr.log.ltails[1].load(core::sync::atomic::Ordering::Relaxed),
+            crate::log::UNREGISTERED
+        );
+        assert!(matches!(
+            nr.remove_replica(1),
+            Err(NodeReplicatedError::InvalidReplica)
+        ));
+
+        // The pending operation of the removed replica was not lost, and we
+        // can fill the log multiple times without syncing the removed replica.
+        assert_eq!(nr.execute(0, ttkn), Ok(1));
+        for _ in 0..2 * nr.log.slog.len() {
+            assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+        }
+
+        assert_eq!(nr.add_replica().expect("Can't add replica"), 1);
+        let ttkn = nr.register(1).expect("Unable to register with log");
+        assert_eq!(nr.execute(0, ttkn), Ok(1 + 2 * nr.log.slog.len() as u64));
+
+        nr.remove_replica(0).expect("Can't remove replica");
+        nr.remove_replica(2).expect("Can't remove replica");
+        assert!(matches!(
+            nr.remove_replica(1),
+            Err(NodeReplicatedError::InvalidReplica)
+        ));
+    }
 
+    /// Removing a replica waits for its current combiner and appends the
+    /// operations that combiner didn't pick up.
+    #[test]
+    fn test_remove_replica_while_combining() {
+        use std::sync::Barrier;
+        use std::time::Duration;
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let removed_ttkn = nr.register(1).expect("Unable to register with log");
+        let barrier = Barrier::new(2);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // Another thread is in the middle of a combining round.
+                let _combiner_lock = nr.replica(1).unwrap().wait_for_combiner_lock();
+                barrier.wait();
+                std::thread::sleep(Duration::from_millis(50));
+            });
+
+            barrier.wait();
+            nr.execute_mut_detached(0, removed_ttkn);
+            nr.remove_replica(1).expect("Can't remove replica");
+        });
+
+        assert_eq!(nr.execute(0, ttkn), Ok(1));
+    }
+
+    /// Tokens of a removed replica are rejected once its id is reused, and
+    /// replicas can come and go while other threads execute operations.
+    #[test]
+    fn test_remove_replica_concurrently() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::sync::Arc;
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = Arc::new(NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds"));
+        let stale = nr.register(1).expect("Unable to register with log");
+        nr.remove_replica(1).expect("Can't remove replica");
+        assert_eq!(nr.add_replica().expect("Can't add replica"), 1);
+        assert!(matches!(
+            nr.submit_mut(0, stale),
+            Err(ExecuteError::TokenMismatch(_))
+        ));
+        assert!(catch_unwind(AssertUnwindSafe(|| nr.execute(0, stale))).is_err());
+
+        let worker = {
+            let nr = nr.clone();
+            std::thread::spawn(move || {
+                let ttkn = nr.register(0).expect("Unable to register with log");
+                for _ in 0..2000 {
+                    assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+                }
+            })
+        };
+        for _ in 0..20 {
+            let rid = nr.add_replica().expect("Can't add replica");
+            let ttkn = nr.register(rid).expect("Unable to register with log");
+            assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+            nr.remove_replica(rid).expect("Can't remove replica");
+        }
+        worker.join().unwrap();
+
+        let ttkn = nr.register(1).expect("Unable to register with log");
+        assert_eq!(nr.execute(0, ttkn), Ok(2000 + 20));
+    }
+
+    /// Removed replicas are freed, and threads that still use them see their
+    /// token rejected (instead of a poisoned replica).
+    #[test]
+    fn test_remove_replica_frees_it() {
+        use std::sync::Arc;
+
+        /// Number of `Counted` instances that are alive.
+        static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counted(u64);
+
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                LIVE.fetch_add(1, Ordering::SeqCst);
+                Counted(self.0)
+            }
+        }
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                LIVE.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        impl Dispatch for Counted {
+            type ReadOperation<'rop> = ();
+            type WriteOperation = u64;
+            type Response = u64;
+
+            fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+                self.0
+            }
+
+            fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+                self.0 += op;
+                self.0
+            }
+        }
+
+        LIVE.fetch_add(1, Ordering::SeqCst);
         let replicas = NonZeroUsize::new(1).unwrap();
-        let async_ds = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
-        let ttkn = async_ds.register(0).expect("Unable to register with log");
+        let nr = Arc::new(
+            NodeReplicated::<Counted>::with_data(replicas, |_ac| 0, Counted(0))
+                .expect("Can't create Ds"),
+        );
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let live = LIVE.load(Ordering::SeqCst);
 
-        let op = 0;
-        let mut resp: ReusableBoxFuture<<Data as Dispatch>::Response> =
-            ReusableBoxFuture::new(async move { Ok(0) });
-        async_ds.async_execute_mut(op, ttkn, &mut resp).await;
-        let res = block_on(&mut resp).unwrap();
-        assert_eq!(res, 107);
+        for i in 0..200 {
+            let rid = nr.add_replica().expect("Can't add replica");
+            assert_eq!(LIVE.load(Ordering::SeqCst), live + 1);
+            let worker = {
+                let nr = nr.clone();
+                let wtkn = nr.register(rid).expect("Unable to register with log");
+                std::thread::spawn(move || loop {
+                    match nr.execute_mut_timeout(1, wtkn, Deadline::Never) {
+                        Ok(_resp) => {}
+                        Err(ExecuteError::TokenMismatch(_)) => break,
+                        Err(e) => panic!("Unexpected error: {:?}", e),
+                    }
+                })
+            };
+            assert!(nr.execute_mut(1, ttkn) > i);
+            nr.remove_replica(rid).expect("Can't remove replica");
+            assert_eq!(LIVE.load(Ordering::SeqCst), live);
+            worker.join().unwrap();
+        }
+    }
 
-        async_ds.async_execute(op, ttkn, &mut resp);
-        let res = block_on(resp).unwrap();
-        assert_eq!(res, 1);
+    /// A panic in `dispatch_mut` poisons the replica without holding back the
+    /// other replicas, and the replica can be rebuilt from a healthy one.
+    #[test]
+    fn test_poisoned_replica() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::string::String;
+
+        /// Makes applying the next operation fail (e.g., because the replica
+        /// ran out of memory), on whichever replica that happens.
+        static FAIL: AtomicBool = AtomicBool::new(false);
+
+        #[derive(Clone, Default)]
+        struct Fragile(u64);
+
+        impl Dispatch for Fragile {
+            type ReadOperation<'rop> = ();
+            type WriteOperation = u64;
+            type Response = u64;
+
+            fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+                self.0
+            }
+
+            fn dispatch_mut(&mut self, _op: Self::WriteOperation) -> Self::Response {
+                assert!(!FAIL.swap(false, Ordering::SeqCst), "Transient failure");
+                self.0 += 1;
+                self.0
+            }
+        }
+
+        let replicas = NonZeroUsize::new(3).unwrap();
+        let nr = NodeReplicated::<Fragile>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn1 = nr.register(1).expect("Unable to register with log");
+        let ttkn1b = nr.register(1).expect("Unable to register with log");
+        assert_eq!(nr.execute_mut(1, ttkn), 1);
+
+        // Replica 1 panics while it catches up with the log (after appending
+        // the operation of `ttkn1`).
+        FAIL.store(true, Ordering::SeqCst);
+        assert!(catch_unwind(AssertUnwindSafe(|| nr.execute_mut(0, ttkn1))).is_err());
+        assert!(nr.is_poisoned(1));
+        assert!(!nr.is_poisoned(0));
+        assert_eq!(
+            nr.log.ltails[1].load(core::sync::atomic::Ordering::Relaxed),
+            crate::log::POISONED
+        );
+
+        // Other threads on the replica don't wait forever.
+        let err = catch_unwind(AssertUnwindSafe(|| nr.execute((), ttkn1b))).unwrap_err();
+        assert!(err.downcast::<String>().unwrap().contains("poisoned"));
+        assert!(matches!(
+            nr.try_execute_mut(0, ttkn1b),
+            Err(ExecuteError::Poisoned(Pending::Op(0)))
+        ));
+        assert_eq!(
+            nr.execute_timeout((), ttkn1b, Deadline::Iterations(10)),
+            Err(ExecuteError::Poisoned(()))
+        );
+        assert!(matches!(
+            nr.execute_mut_timeout(0, ttkn1b, Deadline::Iterations(10)),
+            Err(ExecuteError::Poisoned(_))
+        ));
+
+        // The other replicas can fill the log multiple times.
+        for _ in 0..2 * nr.log.slog.len() {
+            nr.execute_mut(0, ttkn);
+        }
+        let expected = 2 + 2 * nr.log.slog.len() as u64;
+        assert_eq!(nr.execute((), ttkn), expected);
+
+        assert!(matches!(
+            nr.recover_replica(0),
+            Err(NodeReplicatedError::InvalidReplica)
+        ));
+        nr.recover_replica(1).expect("Can't recover replica");
+        assert!(!nr.is_poisoned(1));
+        let ttkn1 = nr.register(1).expect("Unable to register with log");
+        assert_eq!(nr.execute((), ttkn1), expected);
+        assert_eq!(nr.execute_mut(1, ttkn1), expected + 1);
+        let ttkn2 = nr.register(2).expect("Unable to register with log");
+        assert_eq!(nr.execute((), ttkn2), expected + 1);
+    }
+
+    /// Threads can register and deregister way more often than a replica
+    /// supports registered threads at a time.
+    #[test]
+    fn test_deregister_reuses_slots() {
+        use crate::replica::MAX_THREADS_PER_REPLICA;
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        for i in 0..2 * MAX_THREADS_PER_REPLICA {
+            let ttkn = nr.register(i % 2).expect("Unable to register with log");
+            let _ticket = nr.submit_mut(0, ttkn).unwrap();
+            nr.deregister(ttkn);
+        }
+
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        assert_eq!(nr.execute(0, ttkn), Ok(2 * MAX_THREADS_PER_REPLICA as u64));
+    }
+
+    /// The capacity limits can be lowered, the log then only needs room for
+    /// `T * P` operations per append.
+    #[test]
+    fn test_custom_capacity_limits() {
+        let replicas = NonZeroUsize::new(1).unwrap();
+        let nr = NodeReplicated::<Data, 2, 4, 2>::with_log_size(replicas, |_ac| 0, 0)
+            .expect("Can't create Ds");
+        assert_eq!(nr.log.slog.len(), 2 * 4 * 2);
+
+        let ttkns: Vec<ThreadToken> = (0..4)
+            .map(|_| nr.register(0).expect("Unable to register with log"))
+            .collect();
+        assert!(nr.register(0).is_none());
+
+        // Wraps around the log a couple of times with full contexts.
+        for _ in 0..10 {
+            let tickets: Vec<_> = ttkns
+                .iter()
+                .flat_map(|&ttkn| {
+                    [
+                        nr.submit_mut(0, ttkn).unwrap(),
+                        nr.submit_mut(0, ttkn).unwrap(),
+                    ]
+                })
+                .collect();
+            for ticket in tickets {
+                assert_eq!(nr.wait(ticket), Ok(107));
+            }
+        }
+
+        let rid = nr.add_replica().expect("Can't add replica");
+        assert!(matches!(
+            nr.add_replica(),
+            Err(NodeReplicatedError::TooManyReplicas)
+        ));
+        let ttkn = nr.register(rid).expect("Unable to register with log");
+        assert_eq!(nr.execute(0, ttkn), Ok(80));
+    }
+
+    /// `R` replicas fit in the log, `T * P` doesn't have to be a power of two
+    /// and unusable limits are rejected.
+    #[test]
+    fn test_capacity_limits_validated() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data, 2, 3, 2>::with_log_size(replicas, |_ac| 0, 0)
+            .expect("Can't create Ds");
+        assert_eq!(nr.log.slog.len(), 2 * 8);
+        let ttkn = nr.register(1).expect("Unable to register with log");
+        for _ in 0..20 {
+            assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+        }
+
+        let replicas = NonZeroUsize::new(3).unwrap();
+        assert!(matches!(
+            NodeReplicated::<Data, 2, 3, 2>::new(replicas, |_ac| 0),
+            Err(NodeReplicatedError::TooManyReplicas)
+        ));
+
+        let replicas = NonZeroUsize::new(1).unwrap();
+        assert!(matches!(
+            NodeReplicated::<Data, 2, 4, 3>::new(replicas, |_ac| 0),
+            Err(NodeReplicatedError::InvalidCapacity)
+        ));
+        assert!(matches!(
+            NodeReplicated::<Data, 2, 0, 2>::new(replicas, |_ac| 0),
+            Err(NodeReplicatedError::InvalidCapacity)
+        ));
+    }
+
+    /// Stale reads are served by a lagging replica as long as it is within
+    /// `max_lag` operations of the log.
+    #[test]
+    fn test_execute_stale() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn1 = nr.register(1).expect("Unable to register with log");
+        for _ in 0..3 {
+            assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+        }
+
+        assert_eq!(nr.execute_stale(0, ttkn1, usize::MAX), Ok(0));
+        assert_eq!(nr.execute_stale(0, ttkn1, 3), Ok(0));
+        // Too far behind, so the replica catches up first.
+        assert_eq!(nr.execute_stale(0, ttkn1, 2), Ok(3));
+
+        assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+        assert_eq!(nr.execute_stale(0, ttkn1, 1), Ok(3));
+        assert_eq!(nr.execute_stale(0, ttkn1, 0), Ok(4));
+        assert_eq!(nr.execute_stale(0, ttkn, 0), Ok(4));
+    }
+
+    /// The positions of operations reflect the order of the log, and a
+    /// replica which already applied an operation serves reads after it.
+    #[test]
+    fn test_execute_positioned() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn1 = nr.register(1).expect("Unable to register with log");
+
+        assert_eq!(nr.execute_mut_positioned(0, ttkn), (Ok(107), 0));
+        assert_eq!(nr.execute_mut_positioned(0, ttkn1), (Ok(107), 1));
+        let ticket = nr.submit_mut(0, ttkn).unwrap();
+        assert_eq!(nr.wait(ticket), Ok(107));
+        assert_eq!(nr.execute_mut_positioned(0, ttkn), (Ok(107), 3));
+
+        // Replica 1 applied the first two operations only.
+        assert_eq!(nr.execute_after(0, ttkn1, 1), Ok(2));
+        assert_eq!(nr.execute_after(0, ttkn1, 3), Ok(4));
+        assert_eq!(nr.execute_after(0, ttkn, 0), Ok(4));
+
+        // Batches get consecutive positions, at the end of the log.
+        let ttkn2 = nr.register(1).expect("Unable to register with log");
+        assert_eq!(nr.execute_mut_batch(&[0, 0], ttkn2), vec![Ok(107), Ok(107)]);
+        assert_eq!(nr.execute_mut_positioned(0, ttkn2), (Ok(107), 6));
+    }
+
+    /// Readers never observe a partially applied atomic group, and a group
+    /// with a rejected operation isn't executed at all.
+    #[test]
+    fn test_execute_mut_atomic() {
+        use std::sync::Arc;
+        use std::thread;
+
+        #[derive(Clone, Debug, PartialEq)]
+        enum Op {
+            Withdraw(usize, u64),
+            Deposit(usize, u64),
+        }
+
+        #[derive(Default)]
+        struct Accounts([u64; 4]);
+
+        impl Dispatch for Accounts {
+            type ReadOperation<'rop> = ();
+            type WriteOperation = Op;
+            type Response = u64;
+            type Error = usize;
+            const VALIDATES: bool = true;
+
+            fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+                self.0.iter().sum()
+            }
+
+            fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+                match op {
+                    Op::Withdraw(a, n) => self.0[a] -= n,
+                    Op::Deposit(a, n) => self.0[a] += n,
+                }
+                self.0.iter().sum()
+            }
+
+            fn validate(&self, op: &Self::WriteOperation) -> Result<(), Self::Error> {
+                match op {
+                    Op::Withdraw(a, n) if self.0[*a] < *n => Err(*a),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = Arc::new(
+            NodeReplicated::<Accounts>::with_log_size(replicas, |_ac| 0, 64 * 1024)
+                .expect("Can't create Ds"),
+        );
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let deposits: Vec<Op> = (0..4).map(|a| Op::Deposit(a, 100)).collect();
+        assert_eq!(
+            nr.execute_mut_atomic(&deposits, ttkn),
+            Ok(vec![100, 200, 300, 400])
+        );
+
+        let mut threads = Vec::new();
+        for i in 0..4 {
+            let nr = nr.clone();
+            threads.push(thread::spawn(move || {
+                let ttkn = nr.register(i % 2).expect("Unable to register with log");
+                for j in 0..500 {
+                    if i % 2 == 0 {
+                        // Writers move money around, readers (on both
+                        // replicas) make sure none of it gets lost.
+                        let (from, to) = ((i + j) % 4, (i + j + 1) % 4);
+                        let transfer = [Op::Withdraw(from, 1), Op::Deposit(to, 1)];
+                        let _ = nr.execute_mut_atomic_checked(&transfer, ttkn);
+                    }
+                    assert_eq!(nr.execute((), ttkn), 400);
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let ttkn = nr.register(1).expect("Unable to register with log");
+        let overdraw = [Op::Deposit(1, 1000), Op::Withdraw(0, 1000)];
+        assert_eq!(nr.execute_mut_atomic_checked(&overdraw, ttkn), Ok(Err(0)));
+        assert_eq!(nr.execute((), ttkn), 400);
+        // The group sees the operations before it (appended by another
+        // replica, which replica 1 didn't apply yet).
+        let ttkn0 = nr.register(0).expect("Unable to register with log");
+        nr.execute_mut_checked(Op::Deposit(0, 1000), ttkn0).unwrap();
+        let transfer = [Op::Withdraw(0, 1000), Op::Deposit(1, 1000)];
+        assert_eq!(
+            nr.execute_mut_atomic_checked(&transfer, ttkn),
+            Ok(Ok(vec![400, 1400]))
+        );
+        assert_eq!(nr.execute((), ttkn0), 1400);
+        assert_eq!(nr.execute_mut_atomic(&[], ttkn), Ok(vec![]));
+
+        // A group that doesn't fit into a context is handed back.
+        let deposits = vec![Op::Deposit(2, 1); MAX_PENDING_OPS + 1];
+        assert_eq!(
+            nr.execute_mut_atomic(&deposits, ttkn),
+            Err(ExecuteError::TooLarge(&deposits[..]))
+        );
+        assert_eq!(
+            nr.execute_mut_atomic_checked(&deposits, ttkn),
+            Err(ExecuteError::TooLarge(&deposits[..]))
+        );
+        assert_eq!(nr.execute((), ttkn), 1400);
+        assert_eq!(
+            nr.execute_mut_atomic(&deposits[1..], ttkn),
+            Ok((1..=MAX_PENDING_OPS as u64).map(|i| 1400 + i).collect())
+        );
+    }
+
+    /// Detached operations are executed eventually (or on a flush), their
+    /// responses never show up as responses of other operations.
+    #[test]
+    fn test_execute_mut_detached() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        let ttkn1 = nr.register(1).expect("Unable to register with log");
+
+        // Nobody combined yet.
+        nr.execute_mut_detached(0, ttkn);
+        assert_eq!(nr.execute(0, ttkn1), Ok(0));
+        nr.flush(ttkn);
+        assert_eq!(nr.execute(0, ttkn1), Ok(1));
+
+        // Way more operations than fit into a context.
+        for _ in 0..10 * MAX_PENDING_OPS {
+            nr.execute_mut_detached(0, ttkn);
+        }
+        assert_eq!(
+            nr.execute_mut_positioned(0, ttkn),
+            (Ok(107), 10 * MAX_PENDING_OPS + 1)
+        );
+        nr.execute_mut_detached(0, ttkn);
+        nr.flush(ttkn);
+        assert!(nr.replica(0).unwrap().is_flushed(ttkn.rtkn.tid()));
+        let expected = 10 * MAX_PENDING_OPS as u64 + 3;
+        assert_eq!(nr.execute(0, ttkn1), Ok(expected));
+        assert_eq!(nr.execute(0, ttkn), Ok(expected));
+
+        // Combining on behalf of others and flushing an empty context.
+        nr.execute_mut_detached(0, ttkn);
+        assert_eq!(nr.execute_mut(0, ttkn1), Ok(107));
+        nr.flush(ttkn);
+        nr.flush(ttkn1);
+        assert_eq!(nr.execute(0, ttkn), Ok(expected + 2));
+    }
+
+    /// Tokens of one instance are rejected by another one.
+    #[test]
+    #[cfg(any(debug_assertions, feature = "token-checks"))]
+    fn test_token_mismatch() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::string::String;
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let other = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        assert_ne!(nr.instance_id(), other.instance_id());
+        let ttkn = nr.register(1).expect("Unable to register with log");
+        let otkn = other.register(1).expect("Unable to register with log");
+        assert_eq!(ttkn.rtkn.tid(), otkn.rtkn.tid());
+
+        let mismatch = TokenMismatch {
+            expected: nr.instance_id(),
+            found: other.instance_id(),
+        };
+        assert!(matches!(
+            nr.try_execute_mut(1, otkn),
+            Err(ExecuteError::TokenMismatch(e)) if e == mismatch
+        ));
+        assert_eq!(
+            nr.execute_timeout(0, otkn, Deadline::Iterations(10)),
+            Err(ExecuteError::TokenMismatch(mismatch))
+        );
+        assert!(matches!(
+            nr.execute_mut_timeout(1, otkn, Deadline::Never),
+            Err(ExecuteError::TokenMismatch(e)) if e == mismatch
+        ));
+        assert_eq!(nr.check_token(otkn), Err(mismatch));
+        assert_eq!(nr.check_token(ttkn), Ok(()));
+        let err = catch_unwind(AssertUnwindSafe(|| nr.execute_mut(1, otkn))).unwrap_err();
+        assert!(err
+            .downcast::<String>()
+            .unwrap()
+            .contains("passed to instance"));
+        assert!(catch_unwind(AssertUnwindSafe(|| nr.execute(0, otkn))).is_err());
+
+        // Nothing was executed by the rejected calls.
+        assert_eq!(nr.execute_mut(1, ttkn), Ok(107));
+        assert_eq!(nr.execute(0, ttkn), Ok(1));
+        assert_eq!(other.execute(0, otkn), Ok(0));
+
+        // A replica rejects tokens of other replicas, and logs it isn't
+        // registered with.
+        let replica = nr.replica(1).unwrap();
+        let rtkn = other
+            .replica(1)
+            .unwrap()
+            .register()
+            .expect("Unable to register");
+        assert!(matches!(
+            replica.execute_mut(&nr.log, 1, rtkn),
+            Err(ReplicaError::TokenMismatch(_))
+        ));
+        assert!(matches!(
+            replica.execute_mut(&other.log, 1, ttkn.rtkn),
+            Err(ReplicaError::TokenMismatch(_))
+        ));
+    }
+
+    impl Snapshot for Data {
+        type Snapshot = u64;
+
+        fn snapshot(&self) -> u64 {
+            self.junk
+        }
+
+        fn restore(snapshot: &u64) -> Self {
+            Data { junk: *snapshot }
+        }
+    }
+
+    /// A restored data-structure continues at the log position of the
+    /// snapshot, even if that's somewhere in the middle of a later round
+    /// around the log.
+    #[test]
+    fn test_checkpoint_restore() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        for _ in 0..3 {
+            assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+        }
+
+        let other = nr.register(1).expect("Unable to register with log");
+        assert_eq!(nr.checkpoint(other, |d| d.junk * 2), (6, 3));
+        assert_eq!(nr.snapshot(ttkn), (3, 3));
+
+        let pos = 3 * nr.log.slog.len() + 5;
+        let restored =
+            NodeReplicated::<Data>::restore(replicas, |_ac| 0, &3, pos).expect("Can't restore Ds");
+        let ttkns = [
+            restored.register(0).expect("Unable to register with log"),
+            restored.register(1).expect("Unable to register with log"),
+        ];
+        let ops = 2 * restored.log.slog.len();
+        for i in 0..ops {
+            assert_eq!(restored.execute_mut(0, ttkns[i % 2]), Ok(107));
+        }
+
+        for ttkn in ttkns {
+            assert_eq!(restored.execute(0, ttkn), Ok(3 + ops as u64));
+            assert_eq!(restored.snapshot(ttkn), (3 + ops as u64, pos + ops));
+        }
+    }
+
+    /// Keeps the write-ahead log in memory (shared, so it outlives the
+    /// replicas) and counts the syncs.
+    #[derive(Clone, Default)]
+    struct MemoryWal(
+        std::sync::Arc<std::sync::Mutex<(alloc::collections::BTreeMap<LogPos, u64>, usize)>>,
+    );
+
+    impl crate::wal::WalBackend<u64> for MemoryWal {
+        fn write(&self, pos: LogPos, ops: &[u64]) {
+            let mut wal = self.0.lock().unwrap();
+            for (i, op) in ops.iter().enumerate() {
+                assert!(wal.0.insert(pos + i, *op).is_none());
+            }
+        }
+
+        fn sync(&self) {
+            self.0.lock().unwrap().1 += 1;
+        }
+
+        fn truncate(&self, pos: LogPos) {
+            let mut wal = self.0.lock().unwrap();
+            wal.0 = wal.0.split_off(&pos);
+        }
+
+        fn replay(&self, from: LogPos, f: &mut dyn FnMut(LogPos, u64)) {
+            let wal = self.0.lock().unwrap();
+            wal.0.range(from..).for_each(|(pos, op)| f(*pos, *op));
+        }
+    }
+
+    #[test]
+    fn test_wal_recover() {
+        use crate::wal::SyncPolicy;
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let storage = MemoryWal::default();
+        let wal = Wal::new(storage.clone(), SyncPolicy::Every(2));
+        let nr = NodeReplicated::<Data>::recover(replicas, |_ac| 0, wal, 0, |_rid| Data::default())
+            .expect("Can't create Ds");
+        let ttkns = [
+            nr.register(0).expect("Unable to register with log"),
+            nr.register(1).expect("Unable to register with log"),
+        ];
+        for i in 0..5 {
+            assert_eq!(nr.execute_mut(i, ttkns[i as usize % 2]), Ok(107));
+        }
+        assert_eq!(storage.0.lock().unwrap().1, 2);
+        nr.sync_wal();
+        assert_eq!(storage.0.lock().unwrap().1, 3);
+
+        let (snapshot, pos) = nr.checkpoint(ttkns[0], |d| d.clone());
+        assert_eq!((snapshot.junk, pos), (5, 5));
+        nr.execute_mut(5, ttkns[1]).unwrap();
+        nr.truncate_wal(pos);
+        assert_eq!(
+            storage.0.lock().unwrap().0.iter().collect::<Vec<_>>(),
+            [(&5, &5)]
+        );
+        drop(nr);
+
+        let wal = Wal::new(storage.clone(), SyncPolicy::Manual);
+        let nr =
+            NodeReplicated::<Data>::recover(replicas, |_ac| 0, wal, pos, |_rid| snapshot.clone())
+                .expect("Can't recover Ds");
+        let ttkn = nr.register(1).expect("Unable to register with log");
+        assert_eq!(nr.execute(0, ttkn), Ok(6));
+        nr.execute_mut(6, ttkn).unwrap();
+        assert_eq!(nr.snapshot(ttkn), (7, 7));
+        assert_eq!(storage.0.lock().unwrap().0.get(&6), Some(&6));
+    }
+
+    /// Combiners that park while `sync_wal` holds their turn are woken up.
+    #[test]
+    fn test_wal_sync_wakes_writers() {
+        use crate::wait::SpinThenPark;
+        use crate::wal::SyncPolicy;
+
+        // Blocks until the epoch changes, so a lost wake-up hangs the test.
+        fn park(epoch: &AtomicUsize, expected: usize) {
+            while epoch.load(Ordering::SeqCst) == expected {
+                std::thread::yield_now();
+            }
+        }
+
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let wal = Wal::new(MemoryWal::default(), SyncPolicy::Manual);
+        let nr = NodeReplicated::<Data>::recover(replicas, |_ac| 0, wal, 0, |_rid| Data::default())
+            .expect("Can't create Ds")
+            .with_wait_strategy(SpinThenPark::new(0, park, |_epoch| {}));
+        let nr = Arc::new(nr);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let (nr, done) = (nr.clone(), done.clone());
+                std::thread::spawn(move || {
+                    let ttkn = nr.register(i % 2).expect("Unable to register with log");
+                    for _ in 0..500 {
+                        assert_eq!(nr.execute_mut(0, ttkn), Ok(107));
+                    }
+                    done.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        while done.load(Ordering::SeqCst) < 4 {
+            nr.sync_wal();
+        }
+        for w in writers {
+            w.join().unwrap();
+        }
+
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        assert_eq!(nr.execute(0, ttkn), Ok(4 * 500));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_wal_recover_torn_record() {
+        use crate::wal::{FileSegment, SyncPolicy};
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(std::format!(
+            "nr-wal-recover-test-{}.seg",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let recover = || {
+            let wal = Wal::new(FileSegment::open(&path).unwrap(), SyncPolicy::Always);
+            NodeReplicated::<Data>::recover(replicas, |_ac| 0, wal, 0, |_rid| Data::default())
+                .expect("Can't recover Ds")
+        };
+
+        let nr = recover();
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        nr.execute_mut(0, ttkn).unwrap();
+        nr.execute_mut(1, ttkn).unwrap();
+        drop(nr);
+
+        // Crash in the middle of writing the record of the third operation.
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&2u64.to_le_bytes()).unwrap();
+        file.write_all(&[8, 0]).unwrap();
+        drop(file);
+
+        let nr = recover();
+        let ttkn = nr.register(1).expect("Unable to register with log");
+        assert_eq!(nr.snapshot(ttkn), (2, 2));
+        nr.execute_mut(2, ttkn).unwrap();
+        drop(nr);
+
+        let nr = recover();
+        for rid in 0..2 {
+            let ttkn = nr.register(rid).expect("Unable to register with log");
+            assert_eq!(nr.snapshot(ttkn), (3, 3));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Keeps the trace in memory.
+    #[derive(Clone, Default)]
+    struct MemoryTrace(std::sync::Arc<std::sync::Mutex<Vec<trace::TraceEntry<u64>>>>);
+
+    impl trace::TraceSink<u64> for MemoryTrace {
+        fn record(
+            &self,
+            pos: LogPos,
+            rid: ReplicaId,
+            tids: &[crate::replica::ThreadIdx],
+            ops: &[u64],
+        ) {
+            let mut trace = self.0.lock().unwrap();
+            for (i, (tid, op)) in tids.iter().zip(ops).enumerate() {
+                trace.push(trace::TraceEntry {
+                    pos: pos + i,
+                    rid,
+                    tid: *tid,
+                    op: *op,
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay_trace() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkn = nr.register(0).expect("Unable to register with log");
+        nr.execute_mut(0, ttkn).unwrap();
+
+        let sink = MemoryTrace::default();
+        nr.record_trace(sink.clone());
+        let ttkns = [
+            nr.register(1).expect("Unable to register with log"),
+            nr.register(0).expect("Unable to register with log"),
+            ttkn,
+        ];
+        for i in 0..6 {
+            nr.execute_mut(i, ttkns[i as usize % 3]).unwrap();
+        }
+        nr.stop_trace();
+        nr.execute_mut(0, ttkn).unwrap();
+
+        let entries = sink.0.lock().unwrap().clone();
+        let issuers: Vec<_> = entries
+            .iter()
+            .map(|e| (e.pos, e.rid, e.tid, e.op))
+            .collect();
+        assert_eq!(
+            issuers,
+            [
+                (1, 1, 1, 0),
+                (2, 0, 2, 1),
+                (3, 0, 1, 2),
+                (4, 1, 1, 3),
+                (5, 0, 2, 4),
+                (6, 0, 1, 5)
+            ]
+        );
+
+        let mut data = Data { junk: 1 };
+        let mut positions = Vec::new();
+        trace::replay(&mut data, entries, |entry, data, resp| {
+            assert_eq!(resp, Ok(107));
+            positions.push((entry.pos, data.junk));
+        });
+        assert_eq!(positions, [(1, 2), (2, 3), (3, 4), (4, 5), (5, 6), (6, 7)]);
+    }
+
+    /// Traces can be started and stopped while other threads append
+    /// operations, each one records a consecutive part of the log.
+    #[test]
+    fn test_record_trace_concurrently() {
+        let replicas = NonZeroUsize::new(2).unwrap();
+        let nr = NodeReplicated::<Data>::new(replicas, |_ac| 0).expect("Can't create Ds");
+        let ttkns = [
+            nr.register(0).expect("Unable to register with log"),
+            nr.register(1).expect("Unable to register with log"),
+        ];
+
+        let sinks: Vec<MemoryTrace> = (0..10).map(|_| MemoryTrace::default()).collect();
+        std::thread::scope(|s| {
+            for ttkn in ttkns {
+                let nr = &nr;
+                s.spawn(move || {
+                    for i in 0..2000 {
+                        assert_eq!(nr.execute_mut(i, ttkn), Ok(107));
+                    }
+                });
+            }
+            for sink in &sinks {
+                nr.record_trace(sink.clone());
+                std::thread::yield_now();
+            }
+            nr.stop_trace();
+        });
+
+        for sink in sinks {
+            let entries = sink.0.lock().unwrap();
+            for (a, b) in entries.iter().zip(entries.iter().skip(1)) {
+                assert_eq!(a.pos + 1, b.pos);
+            }
+        }
     }
 }
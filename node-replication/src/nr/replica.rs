@@ -7,25 +7,55 @@
 //! the data-structure are synchronized with respect to the order in the shared
 //! [`Log`].
 
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::fmt::{self, Debug};
-use core::hint::spin_loop;
+#[cfg(all(feature = "async", not(loom)))]
+use core::sync::atomic::fence;
 #[cfg(not(loom))]
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "async")]
+use core::task::Waker;
 
 use crossbeam_utils::CachePadded;
+#[cfg(all(feature = "async", loom))]
+use loom::sync::atomic::fence;
 #[cfg(loom)]
-use loom::sync::atomic::{AtomicUsize, Ordering};
+use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use super::context::Context;
-use super::log::{Log, LogToken};
+use super::context::{Context, OpMeta, MAX_PENDING_OPS};
+use super::log::{Log, LogHooks, LogPos, LogToken, Reservation, MAX_REPLICAS_PER_LOG};
 use super::rwlock::RwLock;
 use super::Dispatch;
+#[cfg(feature = "async")]
+use crate::context::WakerList;
 
 pub use crate::replica::ReplicaId;
 pub use crate::replica::ReplicaToken;
 pub use crate::replica::MAX_THREADS_PER_REPLICA;
+use crate::replica::{token_mismatch, FreeList, InstanceId, ThreadIdx, TokenMismatch};
+use crate::stats::ReplicaCounters;
+#[cfg(feature = "stats")]
+use crate::stats::ReplicaStats;
+use crate::wait::{Spin, WaitStrategy, Waiter};
+
+/// The outcome of a write operation as it is handed back to the issuing thread:
+/// either the operation's response, or the error it was rejected with by
+/// [`Dispatch::validate`].
+pub(crate) type Validated<D> = Result<<D as Dispatch>::Response, <D as Dispatch>::Error>;
+
+/// Returns the response of a write operation, panics if it was rejected.
+///
+/// Used by all methods which can't return the error of [`Dispatch::validate`]
+/// to their caller.
+#[inline(always)]
+pub(crate) fn unwrap_validated<D: Dispatch>(resp: Validated<D>) -> <D as Dispatch>::Response {
+    match resp {
+        Ok(resp) => resp,
+        Err(_) => panic!("Operation rejected by `Dispatch::validate`, use a `*_checked` method"),
+    }
+}
 
 /// Errors a replica can encounter (and return to clients) when they execute
 /// operations.
@@ -34,8 +64,13 @@ pub use crate::replica::MAX_THREADS_PER_REPLICA;
 /// [`crate::nr::NodeReplicated`] logic and not passed on to clients. Therefore,
 /// clients of the library don't need to worry about this if they don't
 /// implement their own version of [`crate::nr::NodeReplicated`].
-pub enum ReplicaError<'r, D>
-where
+pub enum ReplicaError<
+    'r,
+    D,
+    const R: usize = MAX_REPLICAS_PER_LOG,
+    const T: usize = MAX_THREADS_PER_REPLICA,
+    const P: usize = MAX_PENDING_OPS,
+> where
     D: Sized + Dispatch + Sync,
 {
     /// We don't have space in the log to enqueue our batch of operations.
@@ -57,7 +92,7 @@ where
     /// of this error) [`CombinerLock`] of our local replica. A client is
     /// supposed to call [`Replica::execute_locked`] or
     /// [`Replica::execute_mut_locked`] with the combiner lock.
-    NoLogSpace(ReplicaId, CombinerLock<'r, D>),
+    NoLogSpace(ReplicaId, CombinerLock<'r, D, R, T, P>),
 
     /// After we enqueue operations in the log there is a process known as
     /// garbage-collection for old entries in the log. It tries to occasionally
@@ -72,9 +107,33 @@ where
     /// If we get this error during [`Replica::execute_mut`] it means that the
     /// system did manage to execute the operations since GC happens afterwards.
     GcFailed(ReplicaId),
+
+    /// The replica with the given [`ReplicaId`] (our own) was poisoned because
+    /// [`Dispatch::dispatch_mut`] panicked while it was applying operations.
+    ///
+    /// Its data may be in an inconsistent state, so the replica refuses to
+    /// execute any further operations. Responses of operations that were
+    /// executed before the panic can still be retrieved. The operations the
+    /// replica collected in the round that panicked are still executed by the
+    /// other replicas if they were appended already, unless they're validated
+    /// (see [`Dispatch::VALIDATES`]): then they are dropped. The replica no
+    /// longer holds back garbage collection of the log, so other replicas
+    /// continue to make progress. See
+    /// [`crate::nr::NodeReplicated::recover_replica`] for how to replace it.
+    ///
+    /// It's also returned once the replica was removed from its
+    /// [`crate::nr::NodeReplicated`] instance, which reports that as a
+    /// rejected token instead.
+    Poisoned(ReplicaId),
+
+    /// A token passed to the replica wasn't issued by it (or the log passed
+    /// to it isn't the one the replica is registered with).
+    ///
+    /// This is a bug in the client, retrying won't help.
+    TokenMismatch(TokenMismatch),
 }
 
-impl<D> Debug for ReplicaError<'_, D>
+impl<D, const R: usize, const T: usize, const P: usize> Debug for ReplicaError<'_, D, R, T, P>
 where
     D: Sized + Dispatch + Sync,
 {
@@ -86,6 +145,12 @@ where
             ReplicaError::GcFailed(rid) => {
                 write!(f, "ReplicaError::GcFailed(rid = {})", rid)
             }
+            ReplicaError::Poisoned(rid) => {
+                write!(f, "ReplicaError::Poisoned(rid = {})", rid)
+            }
+            ReplicaError::TokenMismatch(e) => {
+                write!(f, "ReplicaError::TokenMismatch({})", e)
+            }
         }
     }
 }
@@ -96,6 +161,14 @@ where
 /// Takes in one generic type argument: `D` which is the underlying sequential
 /// data structure. `D` must implement the [`Dispatch`] trait.
 ///
+/// The capacity limits are const generic parameters, they default to the
+/// crate-wide constants:
+/// - `R`: Maximum number of replicas on the [`Log`] ([`MAX_REPLICAS_PER_LOG`]).
+/// - `T`: Maximum number of threads that can register with the replica
+///   ([`MAX_THREADS_PER_REPLICA`]).
+/// - `P`: Maximum number of pending operations per thread
+///   ([`MAX_PENDING_OPS`]), must be a power of two.
+///
 /// - A thread can be registered against the replica by calling
 ///   [`Replica::register()`].
 ///
@@ -114,8 +187,12 @@ where
 /// [`crate::nr::NodeReplicated`] which encapsulates multiple replica objects
 /// and a log, handles registration and ensures liveness when using multiple
 /// replicas.
-pub struct Replica<D>
-where
+pub struct Replica<
+    D,
+    const R: usize = MAX_REPLICAS_PER_LOG,
+    const T: usize = MAX_THREADS_PER_REPLICA,
+    const P: usize = MAX_PENDING_OPS,
+> where
     D: Sized + Dispatch + Sync,
 {
     /// An identifier that we got from the Log when the replica was registered
@@ -123,22 +200,36 @@ where
     /// log when consuming operations from the log.
     log_tkn: LogToken,
 
+    /// Unique id of the replica, embedded in the [`ReplicaToken`]s it issues.
+    instance: InstanceId,
+
     /// Stores the index of the thread currently doing flat combining. Field is
     /// zero if there isn't any thread actively performing flat-combining.
     /// Atomic since this acts as the combiner lock.
     combiner: CachePadded<AtomicUsize>,
 
+    /// Set if [`Dispatch::dispatch_mut`] panicked while the replica was
+    /// applying operations (see [`ReplicaError::Poisoned`]).
+    poisoned: AtomicBool,
+
+    /// Set once the replica was removed (or replaced) and must not touch the
+    /// log anymore, see [`Replica::retire`].
+    retired: AtomicBool,
+
     /// Thread index that will be handed out to the next thread that registers
     /// with the replica when calling [`Replica::register()`].
     next: CachePadded<AtomicUsize>,
 
+    /// Thread indices given back with [`Replica::deregister()`], these are
+    /// handed out again before `next` is incremented.
+    free: FreeList,
+
     /// List of per-thread contexts. Threads buffer write operations here when
     /// they cannot perform flat combining (because another thread might already
     /// be doing so).
     ///
-    /// The vector is initialized with [`MAX_THREADS_PER_REPLICA`] [`Context`]
-    /// elements.
-    contexts: Vec<Context<<D as Dispatch>::WriteOperation, <D as Dispatch>::Response>>,
+    /// The vector is initialized with `T` [`Context`] elements.
+    contexts: Vec<Context<<D as Dispatch>::WriteOperation, Validated<D>, P>>,
 
     /// A buffer of operations for flat combining.
     ///
@@ -147,10 +238,21 @@ where
     /// `compare_and_swap` on the tail of the log.
     buffer: RefCell<Vec<<D as Dispatch>::WriteOperation>>,
 
+    /// The outcome of [`Dispatch::validate`] for every operation staged in
+    /// `buffer`: `None` if the operation was accepted, the error otherwise.
+    verdicts: RefCell<Vec<Option<<D as Dispatch>::Error>>>,
+
+    /// The thread that issued every operation staged in `buffer`.
+    issuers: RefCell<Vec<ThreadIdx>>,
+
+    /// The number of operations that follow every operation staged in
+    /// `buffer` in the same atomic group (see [`OpMeta::group`]).
+    groups: RefCell<Vec<usize>>,
+
     /// Number of operations collected by the combiner from each thread at any
     /// given point of time. Index `i` holds the number of operations collected
     /// from thread with [`crate::replica::ThreadIdx`] `i + 1`.
-    inflight: RefCell<[usize; MAX_THREADS_PER_REPLICA]>,
+    inflight: RefCell<[usize; T]>,
 
     /// A buffer of results collected after flat combining. With the help of
     /// `inflight`, the combiner enqueues these results into the appropriate
@@ -160,16 +262,35 @@ where
     /// The underlying data structure. This is shared among all threads that are
     /// registered with this replica. Each replica maintains its own copy of
     /// `data`.
-    data: CachePadded<RwLock<D>>,
+    data: CachePadded<RwLock<D, T>>,
+
+    /// Runtime statistics (only maintained with the `stats` feature).
+    counters: ReplicaCounters,
+
+    /// How threads wait (e.g., for responses or the lock on `data`).
+    wait: Arc<dyn WaitStrategy>,
+
+    /// Records the operations the combiner appends to the log (e.g., in the
+    /// write-ahead log of the [`NodeReplicated`](super::NodeReplicated)
+    /// instance the replica belongs to).
+    hooks: Option<Arc<LogHooks<<D as Dispatch>::WriteOperation>>>,
+
+    /// Tasks waiting for the replica to catch up with the log before they can
+    /// read, woken up whenever the combiner lock is released.
+    #[cfg(feature = "async")]
+    readers: WakerList,
 }
 
 /// The Replica is [`Sync`].
 ///
 /// Member variables are protected by the combiner lock of the replica
 /// (`combiner`). Contexts are thread-safe.
-unsafe impl<D> Sync for Replica<D> where D: Sized + Sync + Dispatch {}
+unsafe impl<D, const R: usize, const T: usize, const P: usize> Sync for Replica<D, R, T, P> where
+    D: Sized + Sync + Dispatch
+{
+}
 
-impl<D> core::fmt::Debug for Replica<D>
+impl<D, const R: usize, const T: usize, const P: usize> core::fmt::Debug for Replica<D, R, T, P>
 where
     D: Sized + Sync + Dispatch,
 {
@@ -178,7 +299,7 @@ where
     }
 }
 
-impl<D> Replica<D>
+impl<D, const R: usize, const T: usize, const P: usize> Replica<D, R, T, P>
 where
     D: Sized + Default + Dispatch + Sync,
 {
@@ -236,7 +357,7 @@ where
     /// let ltkn = log.register().unwrap();
     /// let replica = Replica::<Data>::new(ltkn);
     /// ```
-    pub fn new(log_tkn: LogToken) -> Replica<D> {
+    pub fn new(log_tkn: LogToken) -> Replica<D, R, T, P> {
         Replica::with_data(log_tkn, Default::default())
     }
 }
@@ -246,14 +367,19 @@ where
 ///
 /// The atomic `combiner` field is set to the [`crate::replica::ThreadIdx`] of the owner. On `drop` we have
 /// to reset it to 0.
-pub struct CombinerLock<'a, D>
-where
+pub struct CombinerLock<
+    'a,
+    D,
+    const R: usize = MAX_REPLICAS_PER_LOG,
+    const T: usize = MAX_THREADS_PER_REPLICA,
+    const P: usize = MAX_PENDING_OPS,
+> where
     D: Sized + Dispatch + Sync,
 {
-    replica: &'a Replica<D>,
+    replica: &'a Replica<D, R, T, P>,
 }
 
-impl<'a, D> CombinerLock<'a, D>
+impl<'a, D, const R: usize, const T: usize, const P: usize> CombinerLock<'a, D, R, T, P>
 where
     D: Sized + Dispatch + Sync,
 {
@@ -262,12 +388,12 @@ where
     /// # Safety
     /// This should basically only ever be called in [`Replica::acquire_combiner_lock()`]
     /// if the compare exchange succeeds.
-    unsafe fn new(replica: &'a Replica<D>) -> Self {
+    unsafe fn new(replica: &'a Replica<D, R, T, P>) -> Self {
         Self { replica }
     }
 }
 
-impl<D> Drop for CombinerLock<'_, D>
+impl<D, const R: usize, const T: usize, const P: usize> Drop for CombinerLock<'_, D, R, T, P>
 where
     D: Sized + Dispatch + Sync,
 {
@@ -283,12 +409,19 @@ where
     /// contexts and to the staging buffer in [`Replica`] before this is
     /// dropped. Right now if the [`Replica`] code accidentially drops this it
     /// would be a disaster.
+    ///
+    /// With the `async` feature, tasks which enqueued operations after we
+    /// collected ours (and failed to become the combiner themselves) are woken
+    /// up so they can retry combining.
     fn drop(&mut self) {
         self.replica.combiner.store(0, Ordering::Release);
+        self.replica.wait.wake();
+        #[cfg(feature = "async")]
+        self.replica.wake_uncollected();
     }
 }
 
-impl<D> Debug for CombinerLock<'_, D>
+impl<D, const R: usize, const T: usize, const P: usize> Debug for CombinerLock<'_, D, R, T, P>
 where
     D: Sized + Dispatch + Sync,
 {
@@ -297,7 +430,74 @@ where
     }
 }
 
-impl<D> Replica<D>
+/// Poisons the replica if it is dropped while we unwind from a panic in
+/// [`Dispatch`], i.e., before [`PoisonGuard::disarm`] is called.
+///
+/// This works without `std::thread::panicking` (we're `no_std`): the guard is
+/// created by the combiner right after it acquired the write lock on the data
+/// and disarmed once it's done. Since it is dropped before the write lock and
+/// the [`CombinerLock`] are released, readers and waiting threads see the
+/// poisoned replica by the time they get hold of either lock.
+struct PoisonGuard<'a, D, const R: usize, const T: usize, const P: usize>
+where
+    D: Sized + Dispatch + Sync,
+{
+    replica: &'a Replica<D, R, T, P>,
+    slog: &'a Log<<D as Dispatch>::WriteOperation, R>,
+    /// Entries the combiner reserved on the log but didn't publish yet.
+    reserved: Cell<Option<Reservation>>,
+}
+
+impl<'a, D, const R: usize, const T: usize, const P: usize> PoisonGuard<'a, D, R, T, P>
+where
+    D: Sized + Dispatch + Sync,
+{
+    fn new(
+        replica: &'a Replica<D, R, T, P>,
+        slog: &'a Log<<D as Dispatch>::WriteOperation, R>,
+    ) -> Self {
+        Self {
+            replica,
+            slog,
+            reserved: Cell::new(None),
+        }
+    }
+
+    /// The combiner finished without panicking.
+    fn disarm(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Drop for PoisonGuard<'_, D, R, T, P>
+where
+    D: Sized + Dispatch + Sync,
+{
+    fn drop(&mut self) {
+        // Other replicas wait for the entries we reserved. None of our
+        // operations should be executed by them, we didn't hand out any
+        // responses.
+        if let Some(reservation) = self.reserved.take() {
+            if let Some(hooks) = &self.replica.hooks {
+                hooks.record(
+                    &reservation,
+                    self.replica.id(),
+                    &[],
+                    &[],
+                    |_i| false,
+                    &*self.slog.wait,
+                );
+            }
+            self.slog
+                .publish(&reservation, &[], |_i| false, &self.replica.log_tkn);
+        }
+        self.replica.poisoned.store(true, Ordering::Release);
+        self.slog.poison(&self.replica.log_tkn);
+        self.replica.wait.wake();
+    }
+}
+
+impl<D, const R: usize, const T: usize, const P: usize> Replica<D, R, T, P>
 where
     D: Sized + Dispatch + Sync,
 {
@@ -310,43 +510,113 @@ where
     ///   [`Copy`] of `d` is passed to every Replica object of the replicated
     ///   data-structure. If not, operations when executed on different replicas
     ///   may give different results.
-    pub fn with_data(log_tkn: LogToken, d: D) -> Replica<D> {
-        let mut contexts = Vec::with_capacity(MAX_THREADS_PER_REPLICA);
-        // Add `MAX_THREADS_PER_REPLICA` contexts
-        for _idx in 0..MAX_THREADS_PER_REPLICA {
+    pub fn with_data(log_tkn: LogToken, d: D) -> Replica<D, R, T, P> {
+        let mut contexts = Vec::with_capacity(T);
+        // Add `T` contexts
+        for _idx in 0..T {
             contexts.push(Default::default());
         }
 
         Replica {
             log_tkn,
+            instance: InstanceId::next(),
             combiner: CachePadded::new(AtomicUsize::new(0)),
+            poisoned: AtomicBool::new(false),
+            retired: AtomicBool::new(false),
             next: CachePadded::new(AtomicUsize::new(1)),
+            free: FreeList::new(T),
             contexts,
             buffer:
                 RefCell::new(
                     Vec::with_capacity(
-                        MAX_THREADS_PER_REPLICA
+                        T
                             * Context::<
                                 <D as Dispatch>::WriteOperation,
                                 <D as Dispatch>::Response,
+                                P,
                             >::batch_size(),
                     ),
                 ),
-            inflight: RefCell::new([0; MAX_THREADS_PER_REPLICA]),
+            verdicts: RefCell::new(Vec::with_capacity(
+                T * Context::<<D as Dispatch>::WriteOperation, Validated<D>, P>::batch_size(),
+            )),
+            issuers: RefCell::new(Vec::with_capacity(
+                T * Context::<<D as Dispatch>::WriteOperation, Validated<D>, P>::batch_size(),
+            )),
+            groups: RefCell::new(Vec::with_capacity(
+                T * Context::<<D as Dispatch>::WriteOperation, Validated<D>, P>::batch_size(),
+            )),
+            inflight: RefCell::new([0; T]),
             result:
                 RefCell::new(
                     Vec::with_capacity(
-                        MAX_THREADS_PER_REPLICA
+                        T
                             * Context::<
                                 <D as Dispatch>::WriteOperation,
                                 <D as Dispatch>::Response,
+                                P,
                             >::batch_size(),
                     ),
                 ),
-            data: CachePadded::new(RwLock::<D>::new(d)),
+            data: CachePadded::new(RwLock::<D, T>::new(d)),
+            counters: Default::default(),
+            wait: Arc::new(Spin),
+            hooks: None,
+            #[cfg(feature = "async")]
+            readers: WakerList::default(),
         }
     }
 
+    /// Sets how threads wait (e.g., for responses or the lock on the data).
+    pub(crate) fn set_wait_strategy(&mut self, wait: Arc<dyn WaitStrategy>) {
+        self.wait = wait;
+    }
+
+    /// Sets what records the operations the combiner appends to the log.
+    pub(crate) fn set_log_hooks(&mut self, hooks: Arc<LogHooks<<D as Dispatch>::WriteOperation>>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Returns true if the replica was poisoned by a panic in
+    /// [`Dispatch::dispatch_mut`] (see [`ReplicaError::Poisoned`]).
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the replica was retired (see [`Replica::retire`]).
+    pub(crate) fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the replica doesn't execute operations anymore,
+    /// because it's poisoned or retired.
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.is_poisoned() || self.is_retired()
+    }
+
+    /// The id of this replica.
+    fn id(&self) -> ReplicaId {
+        self.log_tkn.0 - 1
+    }
+
+    /// Returns the id of the replica instance, which is embedded in the
+    /// [`ReplicaToken`]s it issues.
+    pub fn instance_id(&self) -> InstanceId {
+        self.instance
+    }
+
+    /// Checks that `idx` was issued by this replica and that `slog` is the
+    /// log the replica is registered with.
+    #[inline(always)]
+    fn check(
+        &self,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        idx: ReplicaToken,
+    ) -> Result<(), TokenMismatch> {
+        idx.1.check(self.instance)?;
+        slog.check_token(&self.log_tkn)
+    }
+
     /// Registers a thread with this replica. Returns a [`ReplicaToken`] if the
     /// registration was successfull. None if the registration failed.
     ///
@@ -397,11 +667,16 @@ where
     /// let thrtkn = replica.register().expect("Failed to register with replica.");
     /// ```
     pub fn register(&self) -> Option<ReplicaToken> {
+        // Reuse the index of a thread that deregistered, if there is one.
+        if let Some(idx) = self.free.pop() {
+            return Some(ReplicaToken(idx, self.instance));
+        }
+
         // Loop until we either run out of identifiers or we manage to increment `next`.
         loop {
             let idx = self.next.load(Ordering::SeqCst);
 
-            if idx > MAX_THREADS_PER_REPLICA {
+            if idx > T {
                 return None;
             };
 
@@ -413,18 +688,55 @@ where
                 continue;
             };
 
-            return Some(ReplicaToken(idx));
+            return Some(ReplicaToken(idx, self.instance));
         }
     }
 
+    /// Deregisters a thread from this replica, its [`ReplicaToken`] must not
+    /// be used anymore afterwards.
+    ///
+    /// Operations of the thread that are still pending are appended to the
+    /// log first (responses which weren't retrieved are dropped). Then the
+    /// thread's context is handed out again by a later [`Replica::register`].
+    ///
+    /// # Arguments
+    /// - `slog`: Is a reference to the shared log. It is a bug to supply a log
+    ///    reference that does not match the log-token supplied to the
+    ///    constructor of the Replica (this is checked at runtime, see
+    ///    [`ReplicaError::TokenMismatch`]).
+    /// - `idx`: Is the identifier of the thread that deregisters.
+    ///
+    /// # Errors
+    /// Like [`Replica::execute_mut`], this may fail in case other replicas
+    /// need to make progress before the pending operations can be appended.
+    /// The thread stays registered in this case and the call can be retried.
+    pub fn deregister(
+        &self,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        idx: ReplicaToken,
+    ) -> Result<(), ReplicaError<D, R, T, P>> {
+        self.check(slog, idx).map_err(ReplicaError::TokenMismatch)?;
+        let context = &self.contexts[idx.tid() - 1];
+        // Nobody is going to pick up the operations of a stopped replica.
+        let mut waiter = Waiter::new(&*self.wait);
+        while context.has_uncombined() && !self.is_stopped() {
+            self.try_combine(slog)?;
+            waiter.wait();
+        }
+
+        context.discard_responses();
+        self.free.push(idx.tid());
+        Ok(())
+    }
+
     /// Executes a mutable operation against this replica and returns a
     /// response.
     ///
     /// # Arguments
     /// - `slog`: Is a reference to the shared log. It is a bug to supply a log
     ///    reference that does not match the log-token supplied to the
-    ///    constructor of the Replica. Ideally, this is runtime checked and
-    ///    panics in the future.
+    ///    constructor of the Replica (this is checked at runtime, see
+    ///    [`ReplicaError::TokenMismatch`]).
     /// - `op`: The operation we want to execute.
     /// - `idx`: Is the identifier for the thread performing the execute
     ///   operation obtained from [`Replica::register`].
@@ -480,16 +792,18 @@ where
     /// ```
     pub fn execute_mut(
         &self,
-        slog: &Log<<D as Dispatch>::WriteOperation>,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
         op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
-    ) -> Result<<D as Dispatch>::Response, ReplicaError<D>> {
+    ) -> Result<<D as Dispatch>::Response, ReplicaError<D, R, T, P>> {
+        self.check(slog, idx).map_err(ReplicaError::TokenMismatch)?;
         // Enqueue the operation onto the thread local batch and then try to flat combine.
         while !self.make_pending(op.clone(), idx.tid()) {}
         self.try_combine(slog)?;
 
         // Return the response to the caller function.
         self.get_response(slog, idx.tid())
+            .map(|(resp, _pos)| unwrap_validated::<D>(resp))
     }
 
     /// See [`Replica::execute_mut()`] for a general description of this method.
@@ -504,14 +818,16 @@ where
     /// for an example on how to use this method.
     pub fn execute_mut_locked<'lock>(
         &'lock self,
-        slog: &Log<<D as Dispatch>::WriteOperation>,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
         _op: <D as Dispatch>::WriteOperation,
         idx: ReplicaToken,
-        combiner_lock: CombinerLock<'lock, D>,
-    ) -> Result<<D as Dispatch>::Response, ReplicaError<D>> {
+        combiner_lock: CombinerLock<'lock, D, R, T, P>,
+    ) -> Result<<D as Dispatch>::Response, ReplicaError<D, R, T, P>> {
+        self.check(slog, idx).map_err(ReplicaError::TokenMismatch)?;
         // Enqueue the operation onto the thread local batch and then try to flat combine.
         self.combine(slog, combiner_lock)?;
         self.get_response(slog, idx.tid())
+            .map(|(resp, _pos)| unwrap_validated::<D>(resp))
     }
 
     /// Executes an immutable operation against this replica and returns a
@@ -520,8 +836,8 @@ where
     /// # Arguments
     /// - `slog`: Is a reference to the shared log. It is a bug to supply a log
     ///    reference that does not match the log-token supplied to the
-    ///    constructor of the Replica. Ideally, this is runtime checked and
-    ///    panics in the future.
+    ///    constructor of the Replica (this is checked at runtime, see
+    ///    [`ReplicaError::TokenMismatch`]).
     /// - `op`: The operation we want to execute.
     /// - `idx`: Is the identifier for the thread performing the execute
     ///   operation obtained from [`Replica::register`].
@@ -584,22 +900,32 @@ where
     /// Makes sure the replica is synced up against the log before doing so.
     pub fn execute<'rop>(
         &self,
-        slog: &Log<<D as Dispatch>::WriteOperation>,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
         op: <D as Dispatch>::ReadOperation<'rop>,
         idx: ReplicaToken,
-    ) -> Result<<D as Dispatch>::Response, (ReplicaError<D>, <D as Dispatch>::ReadOperation<'rop>)>
-    {
+    ) -> Result<
+        <D as Dispatch>::Response,
+        (
+            ReplicaError<D, R, T, P>,
+            <D as Dispatch>::ReadOperation<'rop>,
+        ),
+    > {
+        if let Err(e) = self.check(slog, idx) {
+            return Err((ReplicaError::TokenMismatch(e), op));
+        }
         // We can perform the read only if our replica is synced up against
         // the shared log. If it isn't, then try to combine until it is synced up.
         let ctail = slog.get_ctail();
+        let mut waiter = Waiter::new(&*self.wait);
         while !slog.is_replica_synced_for_reads(&self.log_tkn, ctail) {
             if let Err(e) = self.try_combine(slog) {
                 return Err((e, op));
             }
-            spin_loop();
+            waiter.wait();
         }
 
-        return Ok(self.data.read(idx.tid() - 1).dispatch(op));
+        self.read(op, idx)
+            .map_err(|op| (ReplicaError::Poisoned(self.id()), op))
     }
 
     /// See [`Replica::execute()`] for a general description of this method.
@@ -614,12 +940,20 @@ where
     /// for an example on how to use this method.
     pub fn execute_locked<'rop, 'lock>(
         &'lock self,
-        slog: &Log<<D as Dispatch>::WriteOperation>,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
         op: <D as Dispatch>::ReadOperation<'rop>,
         idx: ReplicaToken,
-        combiner_lock: CombinerLock<'lock, D>,
-    ) -> Result<<D as Dispatch>::Response, (ReplicaError<D>, <D as Dispatch>::ReadOperation<'rop>)>
-    {
+        combiner_lock: CombinerLock<'lock, D, R, T, P>,
+    ) -> Result<
+        <D as Dispatch>::Response,
+        (
+            ReplicaError<D, R, T, P>,
+            <D as Dispatch>::ReadOperation<'rop>,
+        ),
+    > {
+        if let Err(e) = self.check(slog, idx) {
+            return Err((ReplicaError::TokenMismatch(e), op));
+        }
         // We can perform the read only if our replica is synced up against
         // the shared log. If it isn't, then try to combine until it is synced up.
         let ctail = slog.get_ctail();
@@ -632,46 +966,92 @@ where
         // (because we return errors in some cases now, all of the ones that
         // make this assert fail?), we can get rid of the while below...
         assert!(slog.is_replica_synced_for_reads(&self.log_tkn, ctail));
+        let mut waiter = Waiter::new(&*self.wait);
         while !slog.is_replica_synced_for_reads(&self.log_tkn, ctail) {
             if let Err(e) = self.try_combine(slog) {
                 return Err((e, op));
             }
-            spin_loop();
+            waiter.wait();
+        }
+
+        self.read(op, idx)
+            .map_err(|op| (ReplicaError::Poisoned(self.id()), op))
+    }
+
+    /// Executes the read-only `op` against the data, unless the replica is
+    /// poisoned or retired (then `op` is handed back).
+    ///
+    /// The combiner poisons the replica before it releases the write lock, so
+    /// it's enough to check after we got the read lock.
+    pub(crate) fn read<'rop>(
+        &self,
+        op: <D as Dispatch>::ReadOperation<'rop>,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, <D as Dispatch>::ReadOperation<'rop>> {
+        let data = self.data.read_with(idx.tid() - 1, &*self.wait);
+        if self.is_stopped() {
+            return Err(op);
         }
+        Ok(data.dispatch(op))
+    }
 
-        Ok(self.data.read(idx.tid() - 1).dispatch(op))
+    /// Returns true if the replica applied all operations of `slog` before
+    /// `ctail`.
+    pub(crate) fn is_synced_for_reads(
+        &self,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        ctail: usize,
+    ) -> bool {
+        slog.is_replica_synced_for_reads(&self.log_tkn, ctail)
     }
 
     /// Busy waits until a response is available within the thread's context.
+    /// Returns it together with the position of the operation on the log.
     ///
     /// # Arguments
     /// - `slog`: The shared log.
     /// - `idx`: identifies this thread.
     pub(crate) fn get_response(
         &self,
-        slog: &Log<<D as Dispatch>::WriteOperation>,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
         idx: usize,
-    ) -> Result<<D as Dispatch>::Response, ReplicaError<D>> {
-        let mut iter = 0;
-        let interval = 1 << 29;
+    ) -> Result<(Validated<D>, LogPos), ReplicaError<D, R, T, P>> {
+        let mut waiter = Waiter::new(&*self.wait);
+        let interval = self.wait.combine_interval();
 
         // Keep trying to retrieve a response from the thread context. After trying `interval`
         // times with no luck, try to perform flat combining to make some progress.
         loop {
-            let r = self.contexts[idx - 1].res();
-            if let Some(resp) = r {
-                return Ok(resp);
+            let r = self.contexts[idx - 1].res_with_meta();
+            match r {
+                // Nobody is waiting for the responses of detached operations.
+                Some((_resp, meta)) if meta.detached => continue,
+                Some((resp, meta)) => {
+                    self.counters
+                        .response_spins
+                        .add(waiter.iteration() % interval);
+                    return Ok((resp, meta.pos));
+                }
+                None => {}
+            }
+            if self.is_stopped() {
+                return Err(ReplicaError::Poisoned(self.id()));
             }
 
-            iter += 1;
-
-            if iter == interval {
+            waiter.wait();
+            if waiter.iteration() % interval == 0 {
+                self.counters.response_spins.add(interval);
                 self.try_combine(slog)?;
-                iter = 0;
             }
         }
     }
 
+    /// Returns the runtime statistics of this replica.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ReplicaStats {
+        self.counters.stats()
+    }
+
     /// Executes a passed in closure against the replica's underlying data structure.
     /// Useful for unit testing; can be used to verify certain properties of the data
     /// structure after issuing a bunch of operations against it.
@@ -680,20 +1060,21 @@ where
     /// There is no need for a regular client to ever call this function. Only use for
     /// testing.
     #[doc(hidden)]
-    pub fn verify<F: FnMut(&D)>(&self, slog: &Log<<D as Dispatch>::WriteOperation>, mut v: F) {
+    pub fn verify<F: FnMut(&D)>(&self, slog: &Log<<D as Dispatch>::WriteOperation, R>, mut v: F) {
         // Acquire the combiner lock before attempting anything on the data structure.
         // Use an idx greater than the maximum that can be allocated.
-        while self.combiner.compare_exchange_weak(
-            0,
-            MAX_THREADS_PER_REPLICA + 2,
-            Ordering::Acquire,
-            Ordering::Acquire,
-        ) != Ok(0)
+        let mut waiter = Waiter::new(&*self.wait);
+        while self
+            .combiner
+            .compare_exchange_weak(0, T + 2, Ordering::Acquire, Ordering::Acquire)
+            != Ok(0)
         {
-            spin_loop();
+            waiter.wait();
         }
 
-        let mut data = self.data.write(self.next.load(Ordering::Relaxed));
+        let mut data = self
+            .data
+            .write_with(self.next.load(Ordering::Relaxed), &*self.wait);
         let mut f = |o: <D as Dispatch>::WriteOperation, _mine: bool| {
             data.dispatch_mut(o);
         };
@@ -701,8 +1082,10 @@ where
         slog.exec(&self.log_tkn, &mut f);
 
         v(&data);
+        drop(data);
 
         self.combiner.store(0, Ordering::Release);
+        self.wait.wake();
     }
 
     /// Synchronizes the replica by applying the outstanding operations in the
@@ -719,19 +1102,120 @@ where
     ///
     /// - `slog`: The corresponding operation log. It is a bug to supply a log
     /// reference that does not match the log-token supplied to the constructor
-    /// of the Replica. Ideally, this is runtime checked and panics in the
-    /// future.
+    /// of the Replica, the method panics if it does.
     ///
     /// # See also
     /// - [`Replica::try_sync`]
-    pub fn sync(&self, slog: &Log<<D as Dispatch>::WriteOperation>) {
+    pub fn sync(&self, slog: &Log<<D as Dispatch>::WriteOperation, R>) {
+        slog.check_token(&self.log_tkn)
+            .unwrap_or_else(|e| token_mismatch(e));
         let ctail = slog.get_ctail();
-        while !slog.is_replica_synced_for_reads(&self.log_tkn, ctail) {
+        let mut waiter = Waiter::new(&*self.wait);
+        // A stopped replica doesn't make progress on the log anymore.
+        while !slog.is_replica_synced_for_reads(&self.log_tkn, ctail) && !self.is_stopped() {
             self.try_sync(slog);
-            spin_loop();
+            waiter.wait();
         }
     }
 
+    /// Brings the replica up-to-date with the tail of `slog` and calls `f`
+    /// with its data.
+    ///
+    /// The combiner lock is held while `f` runs, so the replica can't make
+    /// progress on the log in the meantime. Threads can still read from the
+    /// replica.
+    fn synced<O>(
+        &self,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        f: impl FnOnce(&D) -> O,
+    ) -> O {
+        let _combiner_lock = self.wait_for_combiner_lock();
+
+        assert!(!self.is_poisoned(), "Replica {} is poisoned", self.id());
+        assert!(!self.is_retired(), "Replica {} was removed", self.id());
+        self.exec(slog);
+        // Only the combiner writes to `data`, so a read guard suffices. Any
+        // reader slot works: the slots count their readers.
+        let data = self.data.read_with(0, &*self.wait);
+        f(&data)
+    }
+
+    /// Registers a new replica with `slog` that starts off from the current
+    /// state of this replica.
+    ///
+    /// This replica is brought up-to-date with the tail of the log first. While
+    /// holding the combiner lock (so it can't make progress on the log), `f` is
+    /// called with the [`LogToken`] of the new replica and this replica's data
+    /// to construct the new replica.
+    ///
+    /// Returns `None` if the log can't support any more replicas.
+    pub(crate) fn fork<O>(
+        &self,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        f: impl FnOnce(LogToken, &D) -> O,
+    ) -> Option<O> {
+        self.synced(slog, |data| {
+            let log_tkn = slog.register_from(&self.log_tkn)?;
+            Some(f(log_tkn, data))
+        })
+    }
+
+    /// Same as [`Replica::fork`], but the new replica takes over the
+    /// [`LogToken`] of a poisoned replica (see [`Log::revive_from`]) instead
+    /// of registering a new one.
+    pub(crate) fn fork_into<O>(
+        &self,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        log_tkn: LogToken,
+        f: impl FnOnce(LogToken, &D) -> O,
+    ) -> O {
+        self.synced(slog, |data| {
+            slog.revive_from(&log_tkn, &self.log_tkn);
+            f(log_tkn, data)
+        })
+    }
+
+    /// Brings the replica up-to-date with the tail of `slog`, then calls `f`
+    /// with its data and returns the result along with the [`LogPos`] of the
+    /// first operation that is not reflected in it.
+    pub(crate) fn checkpoint<S>(
+        &self,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        f: impl FnOnce(&D) -> S,
+    ) -> (S, LogPos) {
+        self.synced(slog, |data| (f(data), slog.get_ltail(&self.log_tkn)))
+    }
+
+    /// Acquires the combiner lock, waiting for the current combiner (if any).
+    pub(crate) fn wait_for_combiner_lock(&self) -> CombinerLock<'_, D, R, T, P> {
+        let mut waiter = Waiter::new(&*self.wait);
+        loop {
+            if let Some(combiner_lock) = self.acquire_combiner_lock() {
+                return combiner_lock;
+            }
+            waiter.wait();
+        }
+    }
+
+    /// Returns true if any thread of the replica has operations that haven't
+    /// been picked up by a combiner yet.
+    pub(crate) fn has_uncombined(&self) -> bool {
+        self.contexts.iter().any(|c| c.has_uncombined())
+    }
+
+    /// Stops the replica for good and returns (a copy of) the [`LogToken`] it
+    /// was registered with, e.g., to pass it to [`Log::unregister`].
+    ///
+    /// Marks the replica as retired while we hold its `combiner_lock`, so
+    /// threads that still use it bail out instead of touching the log slot,
+    /// which may be handed to another replica afterwards.
+    pub(crate) fn retire(&self, combiner_lock: CombinerLock<'_, D, R, T, P>) -> LogToken {
+        self.retired.store(true, Ordering::Release);
+        drop(combiner_lock);
+        self.wait.wake();
+        LogToken(self.log_tkn.0, self.log_tkn.1)
+    }
+
     /// Similar to [`Replica::sync`] but doesn't repeatedly try to acquire the
     /// combiner lock: if another thread already holds the lock and works
     /// towards advancing the replica it will just return.
@@ -742,7 +1226,7 @@ where
     /// [`Replica::sync`] can lead to "a thundering herd effect" if many threads
     /// call it at the same time.
     #[inline(always)]
-    pub(crate) fn try_sync(&self, slog: &Log<<D as Dispatch>::WriteOperation>) {
+    pub(crate) fn try_sync(&self, slog: &Log<<D as Dispatch>::WriteOperation, R>) {
         // Try to become the combiner here. If this fails, then simply return.
         if let Some(_combiner_lock) = self.acquire_combiner_lock() {
             // Successfully became the combiner; perform one round of flat combining.
@@ -753,13 +1237,111 @@ where
     /// Enqueues an operation inside a thread local context. Returns a boolean
     /// indicating whether the operation was enqueued (true) or not (false).
     #[inline(always)]
-    fn make_pending(&self, op: <D as Dispatch>::WriteOperation, idx: usize) -> bool {
-        self.contexts[idx - 1].enqueue(op, ())
+    pub(crate) fn make_pending(&self, op: <D as Dispatch>::WriteOperation, idx: usize) -> bool {
+        let ctxt = &self.contexts[idx - 1];
+        ctxt.discard_responses_while(|meta| meta.detached);
+        ctxt.enqueue(op, OpMeta::default())
+    }
+
+    /// Enqueues `op` in the context of thread `idx`, its response is dropped
+    /// (responses of earlier detached operations are dropped first to make
+    /// room). Returns false if the context is full.
+    pub(crate) fn make_pending_detached(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: usize,
+    ) -> bool {
+        let ctxt = &self.contexts[idx - 1];
+        ctxt.discard_responses_while(|meta| meta.detached);
+        ctxt.enqueue(
+            op,
+            OpMeta {
+                detached: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Returns true once all operations of thread `idx` were appended to the
+    /// log and applied to the replica. Responses of detached operations are
+    /// dropped.
+    pub(crate) fn is_flushed(&self, idx: usize) -> bool {
+        let ctxt = &self.contexts[idx - 1];
+        if ctxt.has_uncombined() {
+            return false;
+        }
+        ctxt.discard_responses_while(|meta| meta.detached);
+        true
+    }
+
+    /// Enqueues `op` in the context of thread `idx` without trying to combine
+    /// or waiting for a response.
+    ///
+    /// Returns the slot of the operation within the context which can be
+    /// passed to [`Replica::try_response`], or None if the context is full.
+    pub(crate) fn enqueue(
+        &self,
+        op: <D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+    ) -> Option<usize> {
+        let ctxt = &self.contexts[idx.tid() - 1];
+        ctxt.discard_responses_while(|meta| meta.detached);
+        ctxt.enqueue_slot(op, OpMeta::default())
+    }
+
+    /// Returns the response for the operation in `slot` of thread `idx` if a
+    /// combiner already executed it.
+    pub(crate) fn try_response(&self, idx: ReplicaToken, slot: usize) -> Option<Validated<D>> {
+        self.contexts[idx.tid() - 1].res_at(slot)
+    }
+
+    /// Drops the response for the operation in `slot` of thread `idx` once a
+    /// combiner executed it (see [`Replica::enqueue`]).
+    pub(crate) fn abandon(&self, idx: ReplicaToken, slot: usize) {
+        self.contexts[idx.tid() - 1].abandon(slot)
+    }
+
+    /// Enqueues `ops` as an atomic group inside the thread local context: they
+    /// are collected by the same combiner (and therefore appended to the log
+    /// next to each other), and either all or none of them is accepted by
+    /// [`Dispatch::validate`]. Returns false if they don't fit.
+    #[inline(always)]
+    pub(crate) fn make_pending_group(
+        &self,
+        ops: &[<D as Dispatch>::WriteOperation],
+        idx: usize,
+    ) -> bool {
+        let ctxt = &self.contexts[idx - 1];
+        ctxt.discard_responses_while(|meta| meta.detached);
+        ctxt.enqueue_all(ops, |i| OpMeta {
+            group: ops.len() - 1 - i,
+            pos: 0,
+            detached: false,
+        })
+    }
+
+    /// Enqueues as many operations from `ops` as fit inside the thread local
+    /// context. Returns the number of operations that were enqueued.
+    #[inline(always)]
+    pub(crate) fn make_pending_batch(
+        &self,
+        ops: &mut impl Iterator<Item = <D as Dispatch>::WriteOperation>,
+        idx: usize,
+    ) -> usize {
+        let ctxt = &self.contexts[idx - 1];
+        ctxt.discard_responses_while(|meta| meta.detached);
+        let mut enqueued = 0;
+        for op in ops.take(ctxt.free_slots()) {
+            let success = ctxt.enqueue(op, OpMeta::default());
+            debug_assert!(success, "We checked that there is space");
+            enqueued += 1;
+        }
+        enqueued
     }
 
     // Try to become acquire the combiner lock here. If this fails, then return None.
     #[inline(always)]
-    fn acquire_combiner_lock(&self) -> Option<CombinerLock<D>> {
+    pub(crate) fn acquire_combiner_lock(&self) -> Option<CombinerLock<D, R, T, P>> {
         // First, check if there already is a flat combiner. If there is no active flat combiner
         // then try to acquire the combiner lock. If there is, then just return.
         for _ in 0..4 {
@@ -785,10 +1367,10 @@ where
 
     /// Appends an operation to the log and attempts to perform flat combining.
     /// Accepts a thread `tid` as an argument. Required to acquire the combiner lock.
-    fn try_combine<'r>(
+    pub(crate) fn try_combine<'r>(
         &'r self,
-        slog: &Log<<D as Dispatch>::WriteOperation>,
-    ) -> Result<(), ReplicaError<D>> {
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+    ) -> Result<(), ReplicaError<D, R, T, P>> {
         // Try to become the combiner here. If this fails, then simply return.
         if let Some(combiner_lock) = self.acquire_combiner_lock() {
             // Successfully became the combiner; perform one round of flat combining.
@@ -802,11 +1384,16 @@ where
     }
 
     #[inline(always)]
-    fn exec(&self, slog: &Log<<D as Dispatch>::WriteOperation>) {
+    fn exec(&self, slog: &Log<<D as Dispatch>::WriteOperation, R>) {
+        if self.is_stopped() {
+            return;
+        }
+
         // Execute any operations on the shared log against this replica.
         let next = self.next.load(Ordering::Relaxed);
         {
-            let mut data = self.data.write(next);
+            let mut data = self.data.write_with(next, &*self.wait);
+            let guard = PoisonGuard::new(self, slog);
             let mut f = |o: <D as Dispatch>::WriteOperation, mine: bool| {
                 let _resp = data.dispatch_mut(o);
                 if mine {
@@ -814,19 +1401,64 @@ where
                 }
             };
             slog.exec(&self.log_tkn, &mut f);
+            guard.disarm();
         }
     }
 
     #[inline(always)]
-    fn collect_thread_ops(&self, buffer: &mut Vec<D::WriteOperation>, operations: &mut [usize]) {
-        let num_registered_threads = self.next.load(Ordering::Relaxed);
-
+    fn collect_thread_ops(
+        &self,
+        buffer: &mut Vec<D::WriteOperation>,
+        issuers: &mut Vec<ThreadIdx>,
+        groups: &mut Vec<usize>,
+        operations: &mut [usize],
+        num_registered_threads: usize,
+    ) {
         // Collect operations from each thread registered with this replica.
+        // The combiner hands out responses to the same threads, so threads
+        // that registered after `num_registered_threads` was read have to
+        // wait for the next round.
         for i in 1..num_registered_threads {
             let ctxt_iter = self.contexts[i - 1].iter();
             operations[i - 1] = ctxt_iter.len();
-            // meta-data is (), throw it away
-            buffer.extend(ctxt_iter.map(|op| op.0));
+            for (op, meta) in ctxt_iter {
+                buffer.push(op);
+                issuers.push(i);
+                groups.push(meta.group);
+            }
+        }
+    }
+
+    /// Validates the collected operations (see [`Dispatch::validate`]) and
+    /// applies the accepted ones to `data`, in order. Every operation is
+    /// validated against the state that includes all operations before it, an
+    /// atomic group is validated against the state before the group: it's
+    /// only accepted if all of its operations are, otherwise all of them are
+    /// rejected with the first error.
+    ///
+    /// `data` has to be up-to-date with the log up to the entries reserved
+    /// for `buffer`.
+    #[inline(always)]
+    fn apply_collected_ops(
+        data: &mut D,
+        buffer: &[D::WriteOperation],
+        groups: &[usize],
+        verdicts: &mut Vec<Option<D::Error>>,
+        results: &mut Vec<D::Response>,
+    ) {
+        let mut i = 0;
+        while i < buffer.len() {
+            let end = core::cmp::min(i + groups[i] + 1, buffer.len());
+            match buffer[i..end].iter().find_map(|op| data.validate(op).err()) {
+                None => {
+                    for op in &buffer[i..end] {
+                        results.push(data.dispatch_mut(op.clone()));
+                        verdicts.push(None);
+                    }
+                }
+                Some(e) => verdicts.extend((i..end).map(|_| Some(e.clone()))),
+            }
+            i = end;
         }
     }
 
@@ -834,76 +1466,238 @@ where
     #[inline(always)]
     pub(crate) fn combine<'r>(
         &'r self,
-        slog: &Log<<D as Dispatch>::WriteOperation>,
-        combiner_lock: CombinerLock<'r, D>,
-    ) -> Result<(), ReplicaError<D>> {
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        combiner_lock: CombinerLock<'r, D, R, T, P>,
+    ) -> Result<(), ReplicaError<D, R, T, P>> {
+        if self.is_stopped() {
+            return Err(ReplicaError::Poisoned(self.id()));
+        }
+
         let num_registered_threads = self.next.load(Ordering::Relaxed);
         let mut results = self.result.borrow_mut();
         let mut buffer = self.buffer.borrow_mut();
+        let mut issuers = self.issuers.borrow_mut();
+        let mut groups = self.groups.borrow_mut();
+        let mut verdicts = self.verdicts.borrow_mut();
         let mut operations = self.inflight.borrow_mut();
         results.clear();
         buffer.clear();
+        issuers.clear();
+        groups.clear();
+        verdicts.clear();
+
+        let mut data = self.data.write_with(num_registered_threads, &*self.wait);
+        let guard = PoisonGuard::new(self, slog);
+        self.collect_thread_ops(
+            &mut buffer,
+            &mut issuers,
+            &mut groups,
+            operations.as_mut_slice(),
+            num_registered_threads,
+        );
+        debug_assert!(
+            D::VALIDATES || buffer.iter().all(|op| data.validate(op).is_ok()),
+            "`Dispatch::validate` rejected an operation, but `Dispatch::VALIDATES` isn't set"
+        );
 
-        self.collect_thread_ops(&mut buffer, operations.as_mut_slice());
-
-        // Append all collected operations into the shared log. We pass a closure
-        // in here because operations on the log might need to be consumed for GC.
-        let res = {
-            let mut data = self.data.write(num_registered_threads);
-            let f = |o: <D as Dispatch>::WriteOperation, mine: bool| {
-                #[cfg(not(loom))]
-                let resp = data.dispatch_mut(o);
-                #[cfg(loom)]
+        // Executes operations on the shared log against this replica. If we
+        // validate, our own operations are applied (and their results
+        // collected) by `apply_collected_ops` instead. We pass this closure to
+        // the log because operations on the log might need to be consumed for
+        // GC.
+        let mut f = |o: <D as Dispatch>::WriteOperation, mine: bool| {
+            if !(mine && D::VALIDATES) {
                 let resp = data.dispatch_mut(o);
                 if mine {
                     results.push(resp);
                 }
-            };
-            match slog.append(&buffer, &self.log_tkn, f) {
-                Ok(None) => Ok(()),
-                Ok(Some(r)) => {
-                    // We inserted the entries (and can apply them below), but
-                    // we want to also notify about the slow `r` so it can be
-                    // forced to make some progress
-                    Err(ReplicaError::GcFailed(r))
-                }
-                Err(r) => {
-                    // return here because we couldn't insert our entries and
-                    // need to try again later
-                    return Err(ReplicaError::NoLogSpace(r, combiner_lock));
-                }
             }
         };
 
-        // Execute outstanding operations on the shared log against this replica
-        {
-            let mut data = self.data.write(num_registered_threads);
-            let mut f = |o: <D as Dispatch>::WriteOperation, mine: bool| {
+        // Reserve entries for all collected operations in the shared log.
+        let reservation = match slog.reserve(buffer.len(), &self.log_tkn, &mut f) {
+            Ok(reservation) => reservation,
+            Err(r) => {
+                // return here because we couldn't reserve our entries and
+                // need to try again later
+                guard.disarm();
+                return Err(ReplicaError::NoLogSpace(r, combiner_lock));
+            }
+        };
+        let first_pos = reservation.pos;
+        let reservation = if D::VALIDATES {
+            guard.reserved.set(Some(reservation));
+
+            // Catch up with the log right before our entries, so our
+            // operations are validated against (and applied to) the state
+            // they'll be applied to on every other replica. Rejected
+            // operations leave tombstones in their entries.
+            slog.exec_until(&self.log_tkn, first_pos, &mut f);
+            Self::apply_collected_ops(&mut data, &buffer, &groups, &mut verdicts, &mut results);
+            guard.reserved.take().expect("Reservation is gone")
+        } else {
+            // Nothing gets rejected: publish our entries right away, so other
+            // replicas can apply them while we do.
+            verdicts.resize_with(buffer.len(), || None);
+            reservation
+        };
+        if let Some(hooks) = &self.hooks {
+            hooks.record(
+                &reservation,
+                self.id(),
+                &buffer,
+                &issuers,
+                |i| verdicts[i].is_none(),
+                &*slog.wait,
+            );
+        }
+        slog.publish(
+            &reservation,
+            &buffer,
+            |i| verdicts[i].is_none(),
+            &self.log_tkn,
+        );
+
+        let mut f = |o: <D as Dispatch>::WriteOperation, mine: bool| {
+            if !(mine && D::VALIDATES) {
                 let resp = data.dispatch_mut(o);
                 if mine {
-                    results.push(resp)
+                    results.push(resp);
                 }
-            };
-            slog.exec(&self.log_tkn, &mut f);
-        }
+            }
+        };
+        let res = match slog.finish(&reservation, &self.log_tkn, &mut f) {
+            None => Ok(()),
+            // We inserted the entries (and applied them), but we want to also
+            // notify about the slow `r` so it can be forced to make some
+            // progress
+            Some(r) => Err(ReplicaError::GcFailed(r)),
+        };
+
+        self.counters.combine_rounds.add(1);
+        self.counters.appended_ops.add(results.len());
+        self.counters.max_batch_size.max(results.len());
+
+        // Execute outstanding operations on the shared log against this replica
+        slog.exec(&self.log_tkn, &mut f);
+        drop(data);
 
         // Return/Enqueue responses back into the appropriate thread context(s).
-        let (mut s, mut f) = (0, 0);
+        // Every collected operation has a verdict and a position on the log
+        // (in the same order), the accepted ones also got a result.
+        let mut results = results.drain(..);
+        let mut verdicts = verdicts.drain(..);
+        let mut pos = first_pos;
         for i in 1..num_registered_threads {
-            if operations[i - 1] == 0 {
-                continue;
-            };
-
-            f += operations[i - 1];
-            self.contexts[i - 1].enqueue_resps(&results[s..f]);
-            s += operations[i - 1];
+            for _ in 0..operations[i - 1] {
+                let resp = match verdicts.next().expect("No verdict for operation") {
+                    None => Ok(results.next().expect("No result for operation")),
+                    Some(e) => Err(e),
+                };
+                self.contexts[i - 1].enqueue_resp_with_meta(resp, |meta| meta.pos = pos);
+                pos += 1;
+            }
             operations[i - 1] = 0;
         }
+        guard.disarm();
+        self.wait.wake();
 
         res
     }
 }
 
+/// Non-blocking building blocks for the futures returned by
+/// [`crate::nr::NodeReplicated::async_execute_mut`] and
+/// [`crate::nr::NodeReplicated::async_execute`].
+#[cfg(feature = "async")]
+impl<D, const R: usize, const T: usize, const P: usize> Replica<D, R, T, P>
+where
+    D: Sized + Dispatch + Sync,
+{
+    /// Same as [`Replica::try_response`], but if the response isn't available
+    /// yet `waker` is registered to be woken up once it arrives.
+    pub(crate) fn poll_response(
+        &self,
+        idx: ReplicaToken,
+        slot: usize,
+        waker: &Waker,
+    ) -> Option<Validated<D>> {
+        let ctxt = &self.contexts[idx.tid() - 1];
+        if let Some(resp) = ctxt.res_at(slot) {
+            return Some(resp);
+        }
+
+        ctxt.register_waker(slot, waker);
+        // Pairs with the fence in `wake_uncollected`: either the current
+        // combiner sees our operation (and wakes us) when it releases the
+        // lock, or we see the released lock in the next `try_combine`.
+        fence(Ordering::SeqCst);
+        ctxt.res_at(slot)
+    }
+
+    /// Same as [`Replica::enqueue`], but if the context is full `waker` is
+    /// registered to be woken up once a slot might have become free.
+    ///
+    /// The caller should try to combine before it checks again, the combiner
+    /// lock might have been released before we registered.
+    pub(crate) fn poll_enqueue(
+        &self,
+        op: &<D as Dispatch>::WriteOperation,
+        idx: ReplicaToken,
+        waker: &Waker,
+    ) -> Option<usize> {
+        if let Some(slot) = self.enqueue(op.clone(), idx) {
+            return Some(slot);
+        }
+
+        self.contexts[idx.tid() - 1].register_room_waker(waker);
+        // Pairs with the fence in `wake_uncollected`.
+        fence(Ordering::SeqCst);
+        self.enqueue(op.clone(), idx)
+    }
+
+    /// Registers `waker` to be woken up the next time the combiner lock is
+    /// released, i.e., once the replica might have caught up with the log.
+    ///
+    /// The caller has to check again afterwards (and try to combine, in case
+    /// the combiner lock was released before we registered).
+    pub(crate) fn register_reader(&self, waker: &Waker) {
+        self.readers.register(waker);
+        // Pairs with the fence in `wake_uncollected`.
+        fence(Ordering::SeqCst);
+    }
+
+    /// Executes the read-only `op` if the replica has applied the log up to
+    /// `ctail`, otherwise (or if the replica is stopped) hands back `op` to
+    /// the caller.
+    pub(crate) fn try_execute_synced<'rop>(
+        &self,
+        slog: &Log<<D as Dispatch>::WriteOperation, R>,
+        ctail: usize,
+        op: <D as Dispatch>::ReadOperation<'rop>,
+        idx: ReplicaToken,
+    ) -> Result<<D as Dispatch>::Response, <D as Dispatch>::ReadOperation<'rop>> {
+        if self.is_synced_for_reads(slog, ctail) {
+            self.read(op, idx)
+        } else {
+            Err(op)
+        }
+    }
+
+    /// Wakes up tasks with operations that have not been collected by a
+    /// combiner yet, as well as tasks waiting for a free slot in their
+    /// context or for the replica to catch up. Called whenever the combiner
+    /// lock is released.
+    fn wake_uncollected(&self) {
+        fence(Ordering::SeqCst);
+        let num_registered_threads = self.next.load(Ordering::Relaxed);
+        for ctxt in &self.contexts[..num_registered_threads - 1] {
+            ctxt.wake_uncollected();
+        }
+        self.readers.wake_all();
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod test {
     extern crate std;
@@ -912,7 +1706,7 @@ pub(crate) mod test {
     use std::vec;
 
     // Really dumb data structure to test against the Replica and shared log.
-    #[derive(Default)]
+    #[derive(Default, Clone)]
     pub(crate) struct Data {
         pub(crate) junk: u64,
     }
@@ -959,10 +1753,10 @@ pub(crate) mod test {
         let slog = Log::<<Data as Dispatch>::WriteOperation>::new_with_bytes(1024, ());
         let lt = slog.register().unwrap();
         let repl = Replica::<Data>::new(lt);
-        assert_eq!(repl.register(), Some(ReplicaToken(1)));
+        assert_eq!(repl.register(), Some(ReplicaToken(1, repl.instance)));
         assert_eq!(repl.next.load(Ordering::SeqCst), 2);
         repl.next.store(17, Ordering::SeqCst);
-        assert_eq!(repl.register(), Some(ReplicaToken(17)));
+        assert_eq!(repl.register(), Some(ReplicaToken(17, repl.instance)));
         assert_eq!(repl.next.load(Ordering::SeqCst), 18);
     }
 
@@ -977,6 +1771,27 @@ pub(crate) mod test {
         assert!(repl.register().is_none());
     }
 
+    // Tests that a deregistered thread's pending operation is executed and that
+    // its slot is reused, even once the replica is full.
+    #[test]
+    fn test_replica_deregister() {
+        let slog = Log::<<Data as Dispatch>::WriteOperation>::new_with_bytes(1024, ());
+        let lt = slog.register().unwrap();
+        let repl = Replica::<Data>::new(lt);
+
+        let idx = repl.register().unwrap();
+        repl.next
+            .store(MAX_THREADS_PER_REPLICA + 1, Ordering::SeqCst);
+        assert!(repl.make_pending(121, idx.tid()));
+        assert!(repl.deregister(&slog, idx).is_ok());
+        assert!(!repl.contexts[idx.tid() - 1].has_uncombined());
+        assert_eq!(repl.contexts[idx.tid() - 1].res(), None);
+
+        assert_eq!(repl.register(), Some(idx));
+        assert!(repl.register().is_none());
+        repl.verify(&slog, |data| assert_eq!(data.junk, 1));
+    }
+
     // Tests that we can successfully allow operations to go pending on this replica.
     #[test]
     fn test_replica_make_pending() {
@@ -1018,7 +1833,7 @@ pub(crate) mod test {
 
         assert_eq!(repl.combiner.load(Ordering::SeqCst), 0);
         assert_eq!(repl.data.read(0).junk, 1);
-        assert_eq!(repl.contexts[0].res(), Some(Ok(107)));
+        assert_eq!(repl.contexts[0].res(), Some(Ok(Ok(107))));
     }
 
     // Tests whether try_combine() also applies pending operations on other threads to the log.
@@ -1033,7 +1848,7 @@ pub(crate) mod test {
         assert!(repl.try_combine(&slog).is_ok());
 
         assert_eq!(repl.data.read(0).junk, 1);
-        assert_eq!(repl.contexts[7].res(), Some(Ok(107)));
+        assert_eq!(repl.contexts[7].res(), Some(Ok(Ok(107))));
     }
 
     // Tests whether try_combine() fails if someone else is currently flat combining.
@@ -1075,7 +1890,7 @@ pub(crate) mod test {
 
         repl.make_pending(121, 1);
 
-        assert_eq!(repl.get_response(&slog, 1).unwrap(), Ok(107));
+        assert_eq!(repl.get_response(&slog, 1).unwrap(), (Ok(Ok(107)), 0));
     }
 
     // Tests whether we can issue a read-only operation against the replica.
@@ -1107,4 +1922,84 @@ pub(crate) mod test {
         let t1 = repl.register().expect("Failed to register with replica.");
         assert_eq!(Ok(2), repl.execute(&slog, 11, t1).unwrap());
     }
+
+    // Tests that synced() brings the replica up-to-date and doesn't block
+    // readers while the closure runs.
+    #[test]
+    fn test_replica_synced_allows_reads() {
+        let slog = Log::<<Data as Dispatch>::WriteOperation>::default();
+        let lt = slog.register().unwrap();
+        let repl = Replica::<Data>::new(lt);
+
+        let lt = slog.register().unwrap();
+        let o = [121, 212];
+        assert!(slog.append(&o, &lt, |_o, _mine| {}).is_ok());
+        slog.exec(&lt, &mut |_o, _mine| {});
+
+        let t1 = repl.register().expect("Failed to register with replica.");
+        let junk = repl.synced(&slog, |data| {
+            std::thread::scope(|s| {
+                s.spawn(|| assert_eq!(Ok(2), repl.execute(&slog, 11, t1).unwrap()));
+            });
+            data.junk
+        });
+        assert_eq!(junk, 2);
+    }
+
+    // Tests that operations collected in the same round are validated against
+    // the state that includes the operations before them (on the log and in
+    // the round), and that rejected operations aren't executed anywhere.
+    #[test]
+    fn test_replica_validate_in_round() {
+        #[derive(Default)]
+        struct Balance(u64);
+
+        impl Dispatch for Balance {
+            type ReadOperation<'rop> = ();
+            type WriteOperation = i64;
+            type Response = u64;
+            type Error = u64;
+            const VALIDATES: bool = true;
+
+            fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+                self.0
+            }
+
+            fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+                self.0 = (self.0 as i64 + op) as u64;
+                self.0
+            }
+
+            fn validate(&self, op: &Self::WriteOperation) -> Result<(), Self::Error> {
+                if self.0 as i64 + op < 0 {
+                    Err(self.0)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let slog = Log::<<Balance as Dispatch>::WriteOperation>::default();
+        let repl1 = Replica::<Balance>::new(slog.register().unwrap());
+        let repl2 = Replica::<Balance>::new(slog.register().unwrap());
+        let t1 = repl1.register().unwrap();
+        let t2 = repl1.register().unwrap();
+        let t3 = repl2.register().unwrap();
+
+        // Replica 1 doesn't know about the deposit before it combines.
+        assert_eq!(repl2.execute_mut(&slog, 10, t3).unwrap(), 10);
+        // Both withdrawals are fine for the state before the round, but the
+        // second one conflicts with the first.
+        assert!(repl1.make_pending(-8, t1.tid()));
+        assert!(repl1.make_pending(-5, t2.tid()));
+        assert!(repl1.make_pending(2, t2.tid()));
+        repl1.try_combine(&slog).unwrap();
+        assert_eq!(repl1.get_response(&slog, t1.tid()).unwrap(), (Ok(2), 1));
+        assert_eq!(repl1.get_response(&slog, t2.tid()).unwrap(), (Err(2), 2));
+        assert_eq!(repl1.get_response(&slog, t2.tid()).unwrap(), (Ok(4), 3));
+
+        // The rejected operation's entry is skipped by the other replica.
+        assert_eq!(repl2.execute(&slog, (), t3).unwrap(), 4);
+        assert_eq!(repl1.execute(&slog, (), t1).unwrap(), 4);
+    }
 }
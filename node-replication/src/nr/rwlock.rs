@@ -16,7 +16,6 @@
 
 use core::cell::UnsafeCell;
 use core::default::Default;
-use core::hint::spin_loop;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
@@ -24,9 +23,10 @@ use crossbeam_utils::CachePadded;
 use static_assertions::const_assert;
 
 use crate::replica::MAX_THREADS_PER_REPLICA;
+use crate::wait::{Spin, WaitStrategy, Waiter};
 
-/// Maximum number of reader threads that this lock supports.
-const MAX_READER_THREADS: usize = MAX_THREADS_PER_REPLICA;
+/// Default for the maximum number of reader threads that a lock supports.
+pub const MAX_READER_THREADS: usize = MAX_THREADS_PER_REPLICA;
 const_assert!(MAX_READER_THREADS > 0);
 
 #[allow(clippy::declare_interior_mutable_const)]
@@ -37,10 +37,11 @@ const RLOCK_DEFAULT: CachePadded<AtomicUsize> = CachePadded::new(AtomicUsize::ne
 /// This lock favours reader performance over writers. Each reader thread gets
 /// its own "lock" while writers share a single lock.
 ///
-/// `T` represents the underlying type protected by the lock.
+/// `T` represents the underlying type protected by the lock, `N` is the
+/// maximum number of reader threads.
 /// Calling `read()` returns a read-guard that can be used to safely read `T`.
 /// Calling `write()` returns a write-guard that can be used to safely mutate `T`.
-pub struct RwLock<T>
+pub struct RwLock<T, const N: usize = MAX_READER_THREADS>
 where
     T: Sized + Sync,
 {
@@ -48,7 +49,7 @@ where
     wlock: CachePadded<AtomicBool>,
 
     /// Each reader use an individual lock to access the underlying data-structure.
-    rlock: [CachePadded<AtomicUsize>; MAX_READER_THREADS],
+    rlock: [CachePadded<AtomicUsize>; N],
 
     /// The underlying data-structure.
     data: UnsafeCell<T>,
@@ -56,47 +57,55 @@ where
 
 /// A read-guard that can be used to read the underlying data structure. Writes on
 /// the data structure will be blocked as long as one of these is lying around.
-pub struct ReadGuard<'a, T: Sized + Sync + 'a> {
+pub struct ReadGuard<'a, T: Sized + Sync + 'a, const N: usize = MAX_READER_THREADS> {
     /// Id of the thread that acquired this guard. Required at drop time so that
     /// we can release the appropriate read lock.
     tid: usize,
 
     /// A reference to the Rwlock wrapping the data-structure.
-    lock: &'a RwLock<T>,
+    lock: &'a RwLock<T, N>,
+
+    /// Woken up once the lock is released.
+    wait: &'a dyn WaitStrategy,
 }
 
 /// A write-guard that can be used to write to the underlying data structure. All
 /// reads will be blocked until this is dropped.
-pub struct WriteGuard<'a, T: Sized + Sync + 'a> {
+pub struct WriteGuard<'a, T: Sized + Sync + 'a, const N: usize = MAX_READER_THREADS> {
     /// A reference to the Rwlock wrapping the data-structure.
-    lock: &'a RwLock<T>,
+    lock: &'a RwLock<T, N>,
+
+    /// Woken up once the lock is released.
+    wait: &'a dyn WaitStrategy,
 }
 
-impl<T> Default for RwLock<T>
+impl<T, const N: usize> Default for RwLock<T, N>
 where
     T: Sized + Default + Sync,
 {
     /// Returns a new instance of a RwLock. Default constructs the
     /// underlying data structure.
-    fn default() -> RwLock<T> {
+    fn default() -> RwLock<T, N> {
+        assert!(N > 0, "Need at least one reader");
         RwLock {
             wlock: CachePadded::new(AtomicBool::new(false)),
-            rlock: [RLOCK_DEFAULT; MAX_READER_THREADS],
+            rlock: [RLOCK_DEFAULT; N],
             data: UnsafeCell::new(T::default()),
         }
     }
 }
 
-impl<T> RwLock<T>
+impl<T, const N: usize> RwLock<T, N>
 where
     T: Sized + Sync,
 {
     /// Returns a new instance of a RwLock. Default constructs the
     /// underlying data structure.
     pub fn new(t: T) -> Self {
+        assert!(N > 0, "Need at least one reader");
         Self {
             wlock: CachePadded::new(AtomicBool::new(false)),
-            rlock: [RLOCK_DEFAULT; MAX_READER_THREADS],
+            rlock: [RLOCK_DEFAULT; N],
             data: UnsafeCell::new(t),
         }
     }
@@ -121,8 +130,15 @@ where
     ///     let mut w_guard = lock.write(N_CONCURRENT_READERS);
     ///     *w_guard = 777;
     /// ```
-    pub fn write(&self, n: usize) -> WriteGuard<T> {
+    pub fn write(&self, n: usize) -> WriteGuard<T, N> {
+        self.write_with(n, &Spin)
+    }
+
+    /// Same as [`RwLock::write`], but waits for the lock with `wait` (which
+    /// is woken up once the lock is released).
+    pub fn write_with<'a>(&'a self, n: usize, wait: &'a dyn WaitStrategy) -> WriteGuard<'a, T, N> {
         // First, wait until we can acquire the writer lock.
+        let mut waiter = Waiter::new(wait);
         loop {
             match self.wlock.compare_exchange_weak(
                 false,
@@ -131,7 +147,7 @@ where
                 Ordering::Acquire,
             ) {
                 Ok(_) => break,
-                Err(_) => continue,
+                Err(_) => waiter.wait(),
             }
         }
 
@@ -143,10 +159,10 @@ where
             .take(n)
             .all(|item| item.load(Ordering::Relaxed) == 0)
         {
-            spin_loop();
+            waiter.wait();
         }
 
-        unsafe { WriteGuard::new(self) }
+        unsafe { WriteGuard::new(self, wait) }
     }
 
     /// Locks the underlying data-structure for reads. Allows multiple readers to acquire the lock.
@@ -166,7 +182,13 @@ where
     ///     const MY_THREAD_ID: usize = 16;
     ///     let r_guard = lock.read(MY_THREAD_ID);
     ///     assert_eq!(0, *r_guard);
-    pub fn read(&self, tid: usize) -> ReadGuard<T> {
+    pub fn read(&self, tid: usize) -> ReadGuard<T, N> {
+        self.read_with(tid, &Spin)
+    }
+
+    /// Same as [`RwLock::read`], but waits for the lock with `wait` (which is
+    /// woken up once the lock is released).
+    pub fn read_with<'a>(&'a self, tid: usize, wait: &'a dyn WaitStrategy) -> ReadGuard<'a, T, N> {
         // We perform a small optimization. Before attempting to acquire a read lock, we issue
         // naked reads to the write lock and wait until it is free. For that, we retrieve a
         // raw pointer to the write lock over here.
@@ -175,12 +197,13 @@ where
                 as *const bool)
         };
 
+        let mut waiter = Waiter::new(wait);
         loop {
             // First, wait until the write lock is free. This is the small
             // optimization spoken of earlier.
             unsafe {
                 while core::ptr::read_volatile(ptr) {
-                    spin_loop();
+                    waiter.wait();
                 }
             }
 
@@ -196,7 +219,7 @@ where
             self.rlock[tid].fetch_sub(1, Ordering::Release);
         }
 
-        unsafe { ReadGuard::new(self, tid) }
+        unsafe { ReadGuard::new(self, tid, wait) }
     }
 
     /// Unlocks the write lock; invoked by the drop() method.
@@ -218,28 +241,35 @@ where
     }
 }
 
-impl<'rwlock, T: Sized + Sync> ReadGuard<'rwlock, T> {
+impl<'rwlock, T: Sized + Sync, const N: usize> ReadGuard<'rwlock, T, N> {
     /// Returns a read guard over a passed in reader-writer lock.
-    unsafe fn new(lock: &'rwlock RwLock<T>, tid: usize) -> ReadGuard<'rwlock, T> {
-        ReadGuard { tid, lock }
+    unsafe fn new(
+        lock: &'rwlock RwLock<T, N>,
+        tid: usize,
+        wait: &'rwlock dyn WaitStrategy,
+    ) -> ReadGuard<'rwlock, T, N> {
+        ReadGuard { tid, lock, wait }
     }
 }
 
-impl<'rwlock, T: Sized + Sync> WriteGuard<'rwlock, T> {
+impl<'rwlock, T: Sized + Sync, const N: usize> WriteGuard<'rwlock, T, N> {
     /// Returns a write guard over a passed in reader-writer lock.
-    unsafe fn new(lock: &'rwlock RwLock<T>) -> WriteGuard<'rwlock, T> {
-        WriteGuard { lock }
+    unsafe fn new(
+        lock: &'rwlock RwLock<T, N>,
+        wait: &'rwlock dyn WaitStrategy,
+    ) -> WriteGuard<'rwlock, T, N> {
+        WriteGuard { lock, wait }
     }
 }
 
 /// `Sync` trait allows `RwLock` to be shared between threads. The `read()` and
 /// `write()` logic ensures that we will never have threads writing to and
 /// reading from the underlying data structure simultaneously.
-unsafe impl<T: Sized + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Sized + Sync, const N: usize> Sync for RwLock<T, N> {}
 
 /// This `Deref` trait allows a thread to use T from a ReadGuard.
 /// ReadGuard can only be dereferenced into an immutable reference.
-impl<T: Sized + Sync> Deref for ReadGuard<'_, T> {
+impl<T: Sized + Sync, const N: usize> Deref for ReadGuard<'_, T, N> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -249,7 +279,7 @@ impl<T: Sized + Sync> Deref for ReadGuard<'_, T> {
 
 /// This `Deref` trait allows a thread to use T from a WriteGuard.
 /// This allows us to dereference an immutable reference.
-impl<T: Sized + Sync> Deref for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const N: usize> Deref for WriteGuard<'_, T, N> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -259,7 +289,7 @@ impl<T: Sized + Sync> Deref for WriteGuard<'_, T> {
 
 /// This `DerefMut` trait allow a thread to use T from a WriteGuard.
 /// This allows us to dereference a mutable reference.
-impl<T: Sized + Sync> DerefMut for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const N: usize> DerefMut for WriteGuard<'_, T, N> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.data.get() }
     }
@@ -267,22 +297,24 @@ impl<T: Sized + Sync> DerefMut for WriteGuard<'_, T> {
 
 /// This `Drop` trait implements the unlock logic for a reader lock. Once the `ReadGuard`
 /// goes out of scope, the corresponding read lock is marked as released.
-impl<T: Sized + Sync> Drop for ReadGuard<'_, T> {
+impl<T: Sized + Sync, const N: usize> Drop for ReadGuard<'_, T, N> {
     fn drop(&mut self) {
         unsafe {
             let tid = self.tid;
             self.lock.read_unlock(tid);
         }
+        self.wait.wake();
     }
 }
 
 /// This `Drop` trait implements the unlock logic for a writer lock. Once the `WriteGuard`
 /// goes out of scope, the corresponding write lock is marked as released.
-impl<T: Sized + Sync> Drop for WriteGuard<'_, T> {
+impl<T: Sized + Sync, const N: usize> Drop for WriteGuard<'_, T, N> {
     fn drop(&mut self) {
         unsafe {
             self.lock.write_unlock();
         }
+        self.wait.wake();
     }
 }
 
@@ -0,0 +1,487 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Recording of all mutable operations in log order, and a sequential replay
+//! of such a trace.
+//!
+//! A trace captures every operation in the order it was appended to the
+//! [`Log`](super::Log), together with the replica and thread that issued it.
+//! Since all replicas execute the operations in exactly that order, feeding
+//! the trace into a fresh data-structure with [`replay`] reproduces the state
+//! of every replica, one operation at a time and on a single thread.
+//!
+//! # See also
+//! - [`super::NodeReplicated::record_trace`]
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::{Dispatch, LogPos, ReplicaId};
+use crate::replica::ThreadIdx;
+use crate::wait::{WaitStrategy, Waiter};
+
+#[cfg(feature = "std")]
+pub use file::TraceFile;
+
+/// A mutable operation in a trace.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceEntry<T> {
+    /// Logical index of the operation in the log.
+    pub pos: LogPos,
+    /// The replica the operation was issued on.
+    pub rid: ReplicaId,
+    /// The thread (registered with replica `rid`) that issued the operation.
+    pub tid: ThreadIdx,
+    /// The operation.
+    pub op: T,
+}
+
+/// Storage for a trace.
+pub trait TraceSink<T>: Send + Sync {
+    /// Records `ops`, which were appended to the log by replica `rid`
+    /// starting at logical index `pos`. `tids[i]` is the thread that issued
+    /// `ops[i]`.
+    ///
+    /// Calls are made in log order. There are gaps where the log holds
+    /// operations that were rejected by the combiner (see
+    /// [`Dispatch::validate`]), these are never recorded.
+    fn record(&self, pos: LogPos, rid: ReplicaId, tids: &[ThreadIdx], ops: &[T]);
+}
+
+/// Records the operations appended to a [`Log`](super::Log) in a
+/// [`TraceSink`].
+pub(crate) struct Recorder<T> {
+    sink: Box<dyn TraceSink<T>>,
+    /// Logical index of the first operation to be recorded.
+    start: LogPos,
+    /// Logical index of the next operation to be recorded. Combiners of
+    /// different replicas append concurrently, so they wait for their turn to
+    /// record (in log order) here.
+    next: CachePadded<AtomicUsize>,
+}
+
+impl<T> Recorder<T> {
+    /// Creates a recorder whose first operation will be at logical index
+    /// `pos`.
+    pub(crate) fn new(sink: Box<dyn TraceSink<T>>, pos: LogPos) -> Self {
+        Self {
+            sink,
+            start: pos,
+            next: CachePadded::new(AtomicUsize::new(pos)),
+        }
+    }
+
+    /// Records the operations of the `nops` entries that were appended to the
+    /// log by replica `rid` at logical index `pos`, given as `runs` of
+    /// consecutive operations (with their logical index and the threads that
+    /// issued them). Entries that are not part of a run hold rejected
+    /// operations, which aren't recorded.
+    ///
+    /// Waits (with `wait`) until all operations before `pos` are recorded.
+    /// Operations that were appended before the recorder was created are
+    /// ignored.
+    pub(crate) fn record<'a>(
+        &self,
+        pos: LogPos,
+        nops: usize,
+        rid: ReplicaId,
+        runs: impl Iterator<Item = (LogPos, &'a [ThreadIdx], &'a [T])>,
+        wait: &dyn WaitStrategy,
+    ) where
+        T: 'a,
+    {
+        if pos < self.start {
+            return;
+        }
+        let mut waiter = Waiter::new(wait);
+        while self.next.load(Ordering::Acquire) != pos {
+            waiter.wait();
+        }
+        drop(waiter);
+
+        for (pos, tids, ops) in runs {
+            debug_assert_eq!(tids.len(), ops.len(), "Need the thread of every operation");
+            self.sink.record(pos, rid, tids, ops);
+        }
+        self.next.store(pos + nops, Ordering::Release);
+        wait.wake();
+    }
+}
+
+/// Value of [`TraceSlot::users`] while the recorder is replaced.
+const REPLACING: usize = usize::MAX;
+
+/// Holds the (optional) [`Recorder`] of a log, which can be replaced while
+/// combiners record operations.
+pub(crate) struct TraceSlot<T> {
+    recorder: UnsafeCell<Option<Recorder<T>>>,
+    /// Whether there is a recorder, so combiners don't have to touch `users`
+    /// while nothing is traced.
+    enabled: AtomicBool,
+    /// Number of combiners that are currently recording operations, or
+    /// [`REPLACING`].
+    users: CachePadded<AtomicUsize>,
+}
+
+// Safety: `recorder` is only replaced while no combiner uses it (see
+// `users`), and the recorder itself only holds a `Send + Sync` sink.
+unsafe impl<T> Send for TraceSlot<T> {}
+unsafe impl<T> Sync for TraceSlot<T> {}
+
+/// Marks a combiner as user of a [`TraceSlot`], until it is dropped (which
+/// wakes up a thread that waits to replace the recorder).
+struct SlotUser<'a, T>(&'a TraceSlot<T>, &'a dyn WaitStrategy);
+
+impl<T> Drop for SlotUser<'_, T> {
+    fn drop(&mut self) {
+        self.0.users.fetch_sub(1, Ordering::Release);
+        self.1.wake();
+    }
+}
+
+impl<T> TraceSlot<T> {
+    /// Creates an empty slot (nothing is recorded).
+    pub(crate) fn new() -> Self {
+        Self {
+            recorder: UnsafeCell::new(None),
+            enabled: AtomicBool::new(false),
+            users: CachePadded::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Replaces the recorder with one that records in `sink` (or with none).
+    ///
+    /// Waits (with `wait`) until no combiner records operations. The new
+    /// recorder starts at the logical index returned by `tail`, which is
+    /// called after that and has to load the tail of the log with
+    /// [`Ordering::SeqCst`].
+    pub(crate) fn replace(
+        &self,
+        sink: Option<Box<dyn TraceSink<T>>>,
+        tail: impl FnOnce() -> LogPos,
+        wait: &dyn WaitStrategy,
+    ) {
+        let mut waiter = Waiter::new(wait);
+        while self
+            .users
+            .compare_exchange_weak(0, REPLACING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            waiter.wait();
+        }
+        drop(waiter);
+
+        // Combiners that reserved entries after we read the tail must see the
+        // flag (reservations and the flag's load in `record` are `SeqCst`
+        // too), the others are skipped by the recorder anyway.
+        self.enabled.store(sink.is_some(), Ordering::SeqCst);
+        let recorder = sink.map(|sink| Recorder::new(sink, tail()));
+        // Safety: No combiner uses the recorder while `users` is `REPLACING`.
+        let old = unsafe { core::mem::replace(&mut *self.recorder.get(), recorder) };
+        self.users.store(0, Ordering::Release);
+        wait.wake();
+        drop(old);
+    }
+
+    /// Records operations with the current recorder (if there is one), see
+    /// [`Recorder::record`].
+    pub(crate) fn record<'a>(
+        &self,
+        pos: LogPos,
+        nops: usize,
+        rid: ReplicaId,
+        runs: impl Iterator<Item = (LogPos, &'a [ThreadIdx], &'a [T])>,
+        wait: &dyn WaitStrategy,
+    ) where
+        T: 'a,
+    {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut waiter = Waiter::new(wait);
+        let mut users = self.users.load(Ordering::Relaxed);
+        let _user = loop {
+            if users == REPLACING {
+                waiter.wait();
+                users = self.users.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.users.compare_exchange_weak(
+                users,
+                users + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break SlotUser(self, wait),
+                Err(current) => users = current,
+            }
+        };
+        drop(waiter);
+
+        // Safety: The recorder isn't replaced while we're a user.
+        if let Some(recorder) = unsafe { &*self.recorder.get() } {
+            recorder.record(pos, nops, rid, runs, wait);
+        }
+    }
+}
+
+/// Replays the operations of a trace on `data`, in order.
+///
+/// `f` is called after each operation with the entry (minus the operation),
+/// the state of `data` and the response. This can be used to find the first
+/// operation that leads to an unexpected state.
+///
+/// # Panics
+/// If the trace is not sorted by log position.
+///
+/// # Example
+///
+/// ```
+/// #![feature(generic_associated_types)]
+/// use node_replication::nr::Dispatch;
+/// use node_replication::nr::trace::{replay, TraceEntry};
+///
+/// #[derive(Default)]
+/// struct Counter(u64);
+///
+/// impl Dispatch for Counter {
+///     type ReadOperation<'rop> = ();
+///     type WriteOperation = u64;
+///     type Response = u64;
+///
+///     fn dispatch<'rop>(&self, _op: Self::ReadOperation<'rop>) -> Self::Response {
+///         self.0
+///     }
+///
+///     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+///         self.0 += op;
+///         self.0
+///     }
+/// }
+///
+/// let trace = (0..10).map(|pos| TraceEntry { pos, rid: pos % 2, tid: 1, op: pos as u64 });
+/// let mut counter = Counter::default();
+/// replay(&mut counter, trace, |entry, counter, _resp| {
+///     assert!(entry.pos != 7 || counter.0 == 28, "Bad state after {:?}", entry);
+/// });
+/// assert_eq!(counter.0, 45);
+/// ```
+pub fn replay<D: Dispatch>(
+    data: &mut D,
+    trace: impl IntoIterator<Item = TraceEntry<D::WriteOperation>>,
+    mut f: impl FnMut(&TraceEntry<()>, &D, D::Response),
+) {
+    let mut next = 0;
+    for TraceEntry { pos, rid, tid, op } in trace {
+        // Positions of rejected operations are skipped.
+        assert!(pos >= next, "Trace not sorted at {}", pos);
+        next = pos + 1;
+
+        let resp = data.dispatch_mut(op);
+        f(
+            &TraceEntry {
+                pos,
+                rid,
+                tid,
+                op: (),
+            },
+            data,
+            resp,
+        );
+    }
+}
+
+#[cfg(feature = "std")]
+mod file {
+    use alloc::vec::Vec;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use super::{ReplicaId, TraceEntry, TraceSink};
+    use crate::log::LogPos;
+    use crate::replica::ThreadIdx;
+    use crate::wal::WalCodec;
+
+    /// Identifies a trace file (and its format version).
+    const MAGIC: &[u8; 8] = b"NRTRACE2";
+
+    /// A [`TraceSink`] that writes a compact binary trace file.
+    ///
+    /// The file starts with a magic number. Every operation is stored as the
+    /// number of positions skipped since the previous operation (starting at
+    /// logical index 0), the replica id, the thread id and the length of the
+    /// encoded operation (all as LEB128 varints), followed by the encoded
+    /// operation (see [`WalCodec`]).
+    pub struct TraceFile {
+        /// The file and the logical index after the last recorded operation.
+        file: Mutex<(File, LogPos)>,
+    }
+
+    impl TraceFile {
+        /// Creates (or truncates) the trace file at `path`.
+        pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+            let mut file = File::create(path)?;
+            file.write_all(MAGIC)?;
+            Ok(Self {
+                file: Mutex::new((file, 0)),
+            })
+        }
+
+        /// Reads all operations of the trace file at `path`, e.g., to pass
+        /// them to [`super::replay`].
+        ///
+        /// A partially written operation at the end of the file is ignored.
+        pub fn read<T: WalCodec>(path: impl AsRef<Path>) -> std::io::Result<Vec<TraceEntry<T>>> {
+            let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+
+            let mut buf = Vec::new();
+            File::open(path)?.read_to_end(&mut buf)?;
+            if !buf.starts_with(MAGIC) {
+                return Err(invalid("Not a trace file"));
+            }
+
+            let mut rest = &buf[MAGIC.len()..];
+            let mut entries = Vec::new();
+            let mut pos = 0;
+            while let (Some(skipped), Some(rid), Some(tid), Some(len)) = (
+                read_varint(&mut rest),
+                read_varint(&mut rest),
+                read_varint(&mut rest),
+                read_varint(&mut rest),
+            ) {
+                if rest.len() < len {
+                    break;
+                }
+                let op =
+                    T::decode(&rest[..len]).ok_or_else(|| invalid("Can't decode operation"))?;
+                pos += skipped;
+                entries.push(TraceEntry { pos, rid, tid, op });
+                rest = &rest[len..];
+                pos += 1;
+            }
+
+            Ok(entries)
+        }
+    }
+
+    fn write_varint(buf: &mut Vec<u8>, mut v: usize) {
+        while v >= 0x80 {
+            buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        buf.push(v as u8);
+    }
+
+    /// Reads a varint, `None` if `buf` ends before it does or it doesn't fit
+    /// in a `usize`.
+    fn read_varint(buf: &mut &[u8]) -> Option<usize> {
+        let mut v = 0usize;
+        for (i, b) in buf.iter().enumerate() {
+            let shift = 7 * i;
+            let bits = (b & 0x7f) as usize;
+            if shift >= usize::BITS as usize || (bits << shift) >> shift != bits {
+                return None;
+            }
+            v |= bits << shift;
+            if b & 0x80 == 0 {
+                *buf = &buf[i + 1..];
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    impl<T: WalCodec> TraceSink<T> for TraceFile {
+        fn record(&self, pos: LogPos, rid: ReplicaId, tids: &[ThreadIdx], ops: &[T]) {
+            let mut file = self.file.lock().unwrap();
+            let (file, next) = &mut *file;
+
+            let mut buf = Vec::new();
+            let mut op_buf = Vec::new();
+            for (i, (tid, op)) in tids.iter().zip(ops).enumerate() {
+                op_buf.clear();
+                op.encode(&mut op_buf);
+                write_varint(&mut buf, pos + i - *next);
+                *next = pos + i + 1;
+                write_varint(&mut buf, rid);
+                write_varint(&mut buf, *tid);
+                write_varint(&mut buf, op_buf.len());
+                buf.extend_from_slice(&op_buf);
+            }
+
+            file.write_all(&buf).expect("Can't write to trace file");
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_trace_file() {
+            let path =
+                std::env::temp_dir().join(std::format!("nr-trace-test-{}", std::process::id()));
+
+            let trace = TraceFile::create(&path).unwrap();
+            trace.record(300, 1, &[1, 200], &[10u64, 11]);
+            // Position 302 was rejected.
+            trace.record(303, 0, &[3], &[u64::MAX]);
+            trace.file.lock().unwrap().0.write_all(&[0, 1]).unwrap();
+
+            let entries = TraceFile::read::<u64>(&path).unwrap();
+            assert_eq!(
+                entries,
+                [
+                    TraceEntry {
+                        pos: 300,
+                        rid: 1,
+                        tid: 1,
+                        op: 10
+                    },
+                    TraceEntry {
+                        pos: 301,
+                        rid: 1,
+                        tid: 200,
+                        op: 11
+                    },
+                    TraceEntry {
+                        pos: 303,
+                        rid: 0,
+                        tid: 3,
+                        op: u64::MAX
+                    },
+                ]
+            );
+
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn test_read_varint() {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, usize::MAX);
+            write_varint(&mut buf, 300);
+            let mut rest = &buf[..];
+            assert_eq!(read_varint(&mut rest), Some(usize::MAX));
+            assert_eq!(read_varint(&mut rest), Some(300));
+            assert_eq!(read_varint(&mut rest), None);
+
+            // Too many bits for a `usize`.
+            let mut too_long = [0x80; 11];
+            too_long[10] = 0x01;
+            assert_eq!(read_varint(&mut &too_long[..]), None);
+            let mut too_large = [0xff; 10];
+            too_large[9] = 0x7f;
+            assert_eq!(read_varint(&mut &too_large[..]), None);
+        }
+    }
+}
@@ -3,9 +3,17 @@
 
 //! Common replica definitions, implementation for NR/CNR etc.
 
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicBool, Ordering};
 use static_assertions::const_assert;
 
-/// The maximum number of threads that can be registered with a replica.
+/// The default for the maximum number of threads that can be registered with a
+/// replica (see the `T` parameter of [`crate::nr::Replica`]).
 ///
 /// If more than this number of threads try to register, the
 /// [`crate::nr::Replica::register()`] or [`crate::cnr::Replica::register()`]
@@ -23,8 +31,11 @@ const_assert!(MAX_THREADS_PER_REPLICA.is_power_of_two());
 /// the NUMA node that this replica corresponds to.
 pub type ReplicaId = usize;
 
-/// A (monotoically increasing) number that uniquely identifies a thread that's
-/// registered with the replica.
+/// A number that uniquely identifies a thread that's registered with the
+/// replica.
+///
+/// Once a thread deregisters, its `ThreadIdx` can be handed out again to a
+/// thread that registers later on.
 ///
 /// `ThreadIdx` will start at 1 because they're used in the
 /// [`crate::nr::replica::CombinerLock`] to indicate which thread holds the
@@ -35,11 +46,78 @@ pub type ReplicaId = usize;
 /// - [`crate::cnr::Replica::register`]
 pub type ThreadIdx = usize;
 
+/// Identifies the instance of a [`crate::nr::NodeReplicated`], a replica or a
+/// [`crate::log::Log`] that issued a token.
+///
+/// Ids are unique for the lifetime of the program.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct InstanceId(usize);
+
+impl InstanceId {
+    /// Tokens created with the (hidden) token constructors for the benchmark
+    /// harness aren't tied to an instance, they are accepted by all of them.
+    pub(crate) const ANY: InstanceId = InstanceId(0);
+
+    /// Returns a new, unique id.
+    pub(crate) fn next() -> Self {
+        // Not modelled by loom, ids just need to be unique.
+        static NEXT: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(1);
+        InstanceId(NEXT.fetch_add(1, core::sync::atomic::Ordering::Relaxed))
+    }
+
+    /// Checks that a token issued by `self` is passed to the instance that
+    /// issued it, `expected`.
+    ///
+    /// Tokens are always checked in debug builds, release builds only check
+    /// them with the `token-checks` feature.
+    #[inline(always)]
+    pub(crate) fn check(self, expected: InstanceId) -> Result<(), TokenMismatch> {
+        if cfg!(any(debug_assertions, feature = "token-checks"))
+            && self != expected
+            && self != InstanceId::ANY
+        {
+            Err(TokenMismatch {
+                expected,
+                found: self,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A token was passed to an instance that didn't issue it (e.g., a
+/// [`crate::nr::ThreadToken`] of one [`crate::nr::NodeReplicated`] to
+/// another one).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TokenMismatch {
+    /// The instance the token was passed to.
+    pub expected: InstanceId,
+    /// The instance that issued the token.
+    pub found: InstanceId,
+}
+
+impl fmt::Display for TokenMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Token issued by instance {} passed to instance {}",
+            self.found.0, self.expected.0
+        )
+    }
+}
+
+/// Panics because of a token mismatch, for methods that can't return the
+/// error.
+#[cold]
+pub(crate) fn token_mismatch(err: TokenMismatch) -> ! {
+    panic!("{}", err)
+}
+
 /// A token handed out to threads that replicas with replicas.
 ///
 /// It is a bug to supply this token to another replica object than the one that
-/// issued it. This would ideally be a runtime check which leads to a panic in
-/// the future.
+/// issued it. The replica checks this at runtime (see [`InstanceId`]).
 ///
 /// # Implementation detail on types
 /// Ideally this would be an affine type (not Clone/Copy) for max. type safety
@@ -47,7 +125,7 @@ pub type ThreadIdx = usize;
 /// [`crate::nr::Replica::execute_mut`]. However it feels like this would hurt
 /// API ergonomics a lot.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub struct ReplicaToken(pub(crate) ThreadIdx);
+pub struct ReplicaToken(pub(crate) ThreadIdx, pub(crate) InstanceId);
 
 /// Make it harder to accidentially use the same ReplicaToken on multiple
 /// threads.
@@ -63,10 +141,11 @@ impl ReplicaToken {
     /// This should only ever be used for the benchmark harness to create additional fake
     /// replica implementations.
     ///
-    /// If `pub(test)` is ever supported, we should do that instead.
+    /// If `pub(test)` is ever supported, we should do that instead. The token
+    /// isn't tied to a replica, so it is never rejected.
     #[doc(hidden)]
     pub unsafe fn new(tid: ThreadIdx) -> Self {
-        ReplicaToken(tid)
+        ReplicaToken(tid, InstanceId::ANY)
     }
 
     /// Get the (replica specific) thread identifier for this particular token.
@@ -76,3 +155,61 @@ impl ReplicaToken {
         self.0
     }
 }
+
+/// Thread identifiers that were given back to a replica (when a thread
+/// deregisters) and can be handed out again by `register`.
+pub(crate) struct FreeList {
+    /// Index `i` is true if [`ThreadIdx`] `i + 1` is free.
+    free: Vec<AtomicBool>,
+}
+
+impl FreeList {
+    /// Creates an empty free-list for up to `capacity` threads.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            free: (0..capacity).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    /// Takes a free thread identifier out of the list, if there is one.
+    pub(crate) fn pop(&self) -> Option<ThreadIdx> {
+        self.free
+            .iter()
+            .position(|f| {
+                f.compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .map(|idx| idx + 1)
+    }
+
+    /// Puts `tid` back into the list.
+    pub(crate) fn push(&self, tid: ThreadIdx) {
+        let was_free = self.free[tid - 1].swap(true, Ordering::Release);
+        assert!(!was_free, "Thread {} was deregistered twice", tid);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_free_list() {
+        let fl = FreeList::new(MAX_THREADS_PER_REPLICA);
+        assert_eq!(fl.pop(), None);
+
+        fl.push(3);
+        fl.push(1);
+        assert_eq!(fl.pop(), Some(1));
+        assert_eq!(fl.pop(), Some(3));
+        assert_eq!(fl.pop(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_free_list_double_push() {
+        let fl = FreeList::new(MAX_THREADS_PER_REPLICA);
+        fl.push(2);
+        fl.push(2);
+    }
+}
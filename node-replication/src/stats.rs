@@ -0,0 +1,157 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Runtime statistics of replicas and logs.
+//!
+//! The counters are only maintained with the `stats` feature, otherwise they
+//! are zero-sized and updating them compiles to nothing.
+//!
+//! # See also
+//! - [`crate::nr::NodeReplicated::stats`]
+
+#[cfg(feature = "stats")]
+use alloc::vec::Vec;
+#[cfg(feature = "stats")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+
+use crate::log::MAX_REPLICAS_PER_LOG;
+
+/// A statistics counter.
+///
+/// Counters aren't modelled by loom, hence they always use `core` atomics.
+#[derive(Default)]
+pub(crate) struct Counter(#[cfg(feature = "stats")] AtomicUsize);
+
+impl Counter {
+    /// Adds `n` to the counter.
+    #[inline(always)]
+    pub(crate) fn add(&self, _n: usize) {
+        #[cfg(feature = "stats")]
+        self.0.fetch_add(_n, Ordering::Relaxed);
+    }
+
+    /// Sets the counter to `n` if it is larger than the current value.
+    #[inline(always)]
+    pub(crate) fn max(&self, _n: usize) {
+        #[cfg(feature = "stats")]
+        self.0.fetch_max(_n, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "stats")]
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters maintained by a [`Log`](crate::log::Log) with up to `R`
+/// replicas.
+pub(crate) struct LogCounters<const R: usize = MAX_REPLICAS_PER_LOG> {
+    /// Iterations of the loop in `advance_head`.
+    pub(crate) advance_head_iterations: CachePadded<Counter>,
+    /// How often appending failed because a replica (the index) was lagging.
+    pub(crate) no_log_space: CachePadded<[Counter; R]>,
+    /// How often the head couldn't be advanced (after appending) because a
+    /// replica (the index) was lagging.
+    pub(crate) gc_failed: CachePadded<[Counter; R]>,
+}
+
+impl<const R: usize> Default for LogCounters<R> {
+    fn default() -> Self {
+        Self {
+            advance_head_iterations: Default::default(),
+            no_log_space: CachePadded::new(core::array::from_fn(|_| Counter::default())),
+            gc_failed: CachePadded::new(core::array::from_fn(|_| Counter::default())),
+        }
+    }
+}
+
+/// Counters maintained by a replica.
+#[derive(Default)]
+pub(crate) struct ReplicaCounters {
+    /// Only updated by the combiner.
+    pub(crate) combine_rounds: CachePadded<Counter>,
+    pub(crate) appended_ops: Counter,
+    pub(crate) max_batch_size: Counter,
+    /// Updated by all threads waiting for a response.
+    pub(crate) response_spins: CachePadded<Counter>,
+}
+
+/// Statistics of a [`Log`](crate::log::Log).
+#[cfg(feature = "stats")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogStats<const R: usize = MAX_REPLICAS_PER_LOG> {
+    /// Number of entries between the head and the tail of the log.
+    pub occupancy: usize,
+    /// Total number of iterations spent waiting for lagging replicas while
+    /// advancing the head of the log.
+    pub advance_head_iterations: usize,
+    /// Number of `NoLogSpace` errors, indexed by the lagging replica.
+    pub no_log_space: [usize; R],
+    /// Number of `GcFailed` errors, indexed by the lagging replica.
+    pub gc_failed: [usize; R],
+}
+
+#[cfg(feature = "stats")]
+impl<const R: usize> LogCounters<R> {
+    pub(crate) fn stats(&self, occupancy: usize) -> LogStats<R> {
+        LogStats {
+            occupancy,
+            advance_head_iterations: self.advance_head_iterations.get(),
+            no_log_space: core::array::from_fn(|i| self.no_log_space[i].get()),
+            gc_failed: core::array::from_fn(|i| self.gc_failed[i].get()),
+        }
+    }
+}
+
+/// Statistics of a replica.
+#[cfg(feature = "stats")]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReplicaStats {
+    /// Number of flat combining rounds.
+    pub combine_rounds: usize,
+    /// Number of operations the replica appended to the log.
+    pub appended_ops: usize,
+    /// Maximum number of operations appended in a single combining round.
+    pub max_batch_size: usize,
+    /// Total number of iterations threads spent spinning for their
+    /// responses.
+    pub response_spins: usize,
+}
+
+#[cfg(feature = "stats")]
+impl ReplicaStats {
+    /// Average number of operations appended per combining round.
+    pub fn avg_batch_size(&self) -> f64 {
+        if self.combine_rounds == 0 {
+            0.0
+        } else {
+            self.appended_ops as f64 / self.combine_rounds as f64
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+impl ReplicaCounters {
+    pub(crate) fn stats(&self) -> ReplicaStats {
+        ReplicaStats {
+            combine_rounds: self.combine_rounds.get(),
+            appended_ops: self.appended_ops.get(),
+            max_batch_size: self.max_batch_size.get(),
+            response_spins: self.response_spins.get(),
+        }
+    }
+}
+
+/// Statistics of a [`NodeReplicated`](crate::nr::NodeReplicated) instance.
+#[cfg(feature = "stats")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Stats<const R: usize = MAX_REPLICAS_PER_LOG> {
+    /// Statistics of the shared log.
+    pub log: LogStats<R>,
+    /// Statistics of every replica, indexed by
+    /// [`ReplicaId`](crate::replica::ReplicaId) (`None` for removed
+    /// replicas).
+    pub replicas: Vec<Option<ReplicaStats>>,
+}
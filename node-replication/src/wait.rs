@@ -0,0 +1,296 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Strategies for threads that wait (e.g., for a response from the combiner,
+//! for a lock or for space on the log).
+//!
+//! By default threads busy-spin, which has the lowest latency as long as there
+//! is a core for every thread. If threads outnumber cores, spinning burns CPU
+//! time that the thread we're waiting for (e.g., a preempted combiner) could
+//! use, then one of the other strategies is a better choice.
+//!
+//! The library is `no_std`, so strategies that give up the CPU rely on hooks
+//! provided by the user (e.g., `std::thread::yield_now`).
+//!
+//! # See also
+//! - [`crate::nr::NodeReplicated::with_wait_strategy`]
+
+use core::hint::spin_loop;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+/// Decides how a thread waits until a condition it depends on might have
+/// changed.
+///
+/// A thread waiting for a condition calls [`WaitStrategy::prepare`] before
+/// every check of the condition, [`WaitStrategy::wait`] every time it doesn't
+/// hold and [`WaitStrategy::done`] once it stops waiting.
+pub trait WaitStrategy: Send + Sync {
+    /// Called before the condition is checked for the `iteration`-th time
+    /// (starting at 0). The returned token is passed to the
+    /// [`WaitStrategy::wait`] that follows if the condition doesn't hold.
+    ///
+    /// Strategies that put threads to sleep take a snapshot of their wake-up
+    /// state here, so a [`WaitStrategy::wake`] that happens after the check
+    /// isn't lost.
+    fn prepare(&self, _iteration: usize) -> usize {
+        0
+    }
+
+    /// Waits before the condition is checked again. `iteration` is the number
+    /// of times the thread waited for the current condition so far, `token`
+    /// was returned by the [`WaitStrategy::prepare`] before the last check.
+    fn wait(&self, iteration: usize, token: usize);
+
+    /// Called once the thread stops waiting for the condition, after it
+    /// waited `iterations` times.
+    fn done(&self, _iterations: usize) {}
+
+    /// Wakes up threads that gave up the CPU in [`WaitStrategy::wait`]. This
+    /// is called whenever a condition threads wait for might have changed:
+    /// at the end of every flat combining round and whenever a lock is
+    /// released.
+    fn wake(&self) {}
+
+    /// How often a thread waits for its response before it checks if it can
+    /// become the combiner (and make progress on its own).
+    fn combine_interval(&self) -> usize {
+        1 << 29
+    }
+}
+
+/// Busy-spins (the default).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Spin;
+
+impl WaitStrategy for Spin {
+    #[inline(always)]
+    fn wait(&self, _iteration: usize, _token: usize) {
+        spin_loop();
+    }
+}
+
+/// Busy-spins for a while, then yields the CPU (using `yield_now`) every time.
+#[derive(Copy, Clone, Debug)]
+pub struct SpinThenYield {
+    /// How many iterations to spin before yielding.
+    pub spins: usize,
+    /// Yields the CPU to another thread, e.g., `std::thread::yield_now`.
+    pub yield_now: fn(),
+}
+
+impl WaitStrategy for SpinThenYield {
+    fn wait(&self, iteration: usize, _token: usize) {
+        if iteration < self.spins {
+            spin_loop();
+        } else {
+            (self.yield_now)();
+        }
+    }
+
+    fn combine_interval(&self) -> usize {
+        self.spins.max(1)
+    }
+}
+
+/// Busy-spins for a while, then parks the thread using a futex-like hook.
+///
+/// Threads park on an epoch which is incremented every time waiting threads
+/// should be woken up. A thread that is done spinning registers itself before
+/// it checks the condition again, so a wake-up is never lost: either the waker
+/// sees the registration and increments the epoch, or the thread sees the
+/// changed condition.
+#[derive(Debug)]
+pub struct SpinThenPark {
+    spins: usize,
+    park: fn(&AtomicUsize, usize),
+    unpark: fn(&AtomicUsize),
+    epoch: AtomicUsize,
+    /// Number of threads that are done spinning and might park.
+    parked: AtomicUsize,
+}
+
+impl SpinThenPark {
+    /// Creates a strategy that spins `spins` times before it parks.
+    ///
+    /// # Arguments
+    /// - `park(epoch, expected)`: Blocks the thread as long as `epoch` is
+    ///   `expected` (e.g., a `FUTEX_WAIT` with a timeout).
+    /// - `unpark(epoch)`: Wakes up all threads blocked on `epoch` (e.g., a
+    ///   `FUTEX_WAKE`).
+    pub fn new(spins: usize, park: fn(&AtomicUsize, usize), unpark: fn(&AtomicUsize)) -> Self {
+        Self {
+            spins,
+            park,
+            unpark,
+            epoch: AtomicUsize::new(0),
+            parked: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl WaitStrategy for SpinThenPark {
+    fn prepare(&self, iteration: usize) -> usize {
+        if iteration < self.spins {
+            return 0;
+        }
+        if iteration == self.spins {
+            self.parked.fetch_add(1, Ordering::SeqCst);
+        }
+        // Orders the registration (and the epoch) before the check of the
+        // condition, see `wake`.
+        fence(Ordering::SeqCst);
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    fn wait(&self, iteration: usize, token: usize) {
+        if iteration < self.spins {
+            spin_loop();
+        } else {
+            (self.park)(&self.epoch, token);
+        }
+    }
+
+    fn done(&self, iterations: usize) {
+        if iterations >= self.spins {
+            self.parked.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn wake(&self) {
+        // Orders the change of the condition before the check for
+        // registered threads, see `prepare`.
+        fence(Ordering::SeqCst);
+        if self.parked.load(Ordering::SeqCst) > 0 {
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+            (self.unpark)(&self.epoch);
+        }
+    }
+
+    fn combine_interval(&self) -> usize {
+        self.spins.max(1)
+    }
+}
+
+/// Spins for an exponentially growing number of iterations (up to
+/// `2^limit`, at most `2^(usize::BITS - 1)`) every time.
+#[derive(Copy, Clone, Debug)]
+pub struct Backoff {
+    /// Upper bound for the exponent.
+    pub limit: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { limit: 10 }
+    }
+}
+
+impl WaitStrategy for Backoff {
+    fn wait(&self, iteration: usize, _token: usize) {
+        // Limits beyond the width of `usize` would overflow the shift.
+        let exp = iteration
+            .min(self.limit as usize)
+            .min(usize::BITS as usize - 1);
+        for _ in 0..1usize << exp {
+            spin_loop();
+        }
+    }
+
+    fn combine_interval(&self) -> usize {
+        64
+    }
+}
+
+/// Waits for a condition with a [`WaitStrategy`], following its protocol.
+///
+/// Create it right before the condition is checked for the first time and
+/// call [`Waiter::wait`] every time the condition doesn't hold.
+pub(crate) struct Waiter<'a> {
+    strategy: &'a dyn WaitStrategy,
+    iteration: usize,
+    token: usize,
+}
+
+impl<'a> Waiter<'a> {
+    pub(crate) fn new(strategy: &'a dyn WaitStrategy) -> Self {
+        Self {
+            strategy,
+            iteration: 0,
+            token: strategy.prepare(0),
+        }
+    }
+
+    /// Number of times the thread waited so far.
+    pub(crate) fn iteration(&self) -> usize {
+        self.iteration
+    }
+
+    /// Waits before the condition is checked again.
+    pub(crate) fn wait(&mut self) {
+        self.strategy.wait(self.iteration, self.token);
+        self.iteration += 1;
+        self.token = self.strategy.prepare(self.iteration);
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.strategy.done(self.iteration);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_spin_then_yield() {
+        static YIELDED: AtomicBool = AtomicBool::new(false);
+        let ws = SpinThenYield {
+            spins: 2,
+            yield_now: || YIELDED.store(true, Ordering::SeqCst),
+        };
+
+        ws.wait(0, 0);
+        ws.wait(1, 0);
+        assert!(!YIELDED.load(Ordering::SeqCst));
+        ws.wait(2, 0);
+        assert!(YIELDED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_spin_then_park() {
+        static UNPARKED: AtomicUsize = AtomicUsize::new(0);
+        static BLOCKED: AtomicBool = AtomicBool::new(false);
+        let ws = SpinThenPark::new(
+            1,
+            |epoch, token| BLOCKED.store(epoch.load(Ordering::SeqCst) == token, Ordering::SeqCst),
+            |_epoch| {
+                UNPARKED.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+
+        // Nobody waits, so there's nothing to wake up.
+        ws.wake();
+        assert_eq!(UNPARKED.load(Ordering::SeqCst), 0);
+
+        let mut waiter = Waiter::new(&ws);
+        waiter.wait();
+        // Done spinning: registered before the condition is checked again.
+        assert_eq!(ws.parked.load(Ordering::SeqCst), 1);
+
+        // A wake-up after the check isn't lost, the thread doesn't block.
+        ws.wake();
+        assert_eq!(UNPARKED.load(Ordering::SeqCst), 1);
+        waiter.wait();
+        assert!(!BLOCKED.load(Ordering::SeqCst));
+        waiter.wait();
+        assert!(BLOCKED.load(Ordering::SeqCst));
+
+        drop(waiter);
+        assert_eq!(ws.parked.load(Ordering::SeqCst), 0);
+        ws.wake();
+        assert_eq!(UNPARKED.load(Ordering::SeqCst), 1);
+    }
+}
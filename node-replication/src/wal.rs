@@ -0,0 +1,439 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Optional write-ahead logging (WAL) of the operations that are appended to
+//! the shared [`Log`](crate::log::Log), so the replicated state can be
+//! recovered after a crash.
+//!
+//! The library is `no_std`, hence the actual storage is provided by a
+//! [`WalBackend`]. With the `std` feature, [`FileSegment`] stores the
+//! operations in a file.
+//!
+//! # See also
+//! - [`crate::nr::NodeReplicated::recover`]
+
+use alloc::boxed::Box;
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::log::LogPos;
+use crate::wait::{WaitStrategy, Waiter};
+
+#[cfg(feature = "std")]
+pub use file::{FileSegment, WalCodec};
+
+/// Storage for the write-ahead log.
+///
+/// Implementations are expected to panic if they can't store operations, as
+/// the replicas can't continue safely after that.
+pub trait WalBackend<T>: Send + Sync {
+    /// Writes `ops`, which were appended to the log starting at logical index
+    /// `pos`.
+    ///
+    /// Calls are made in log order. There are gaps where the log holds
+    /// operations that were rejected by the combiner (see
+    /// [`Dispatch::validate`](crate::nr::Dispatch::validate)), these are
+    /// never written.
+    fn write(&self, pos: LogPos, ops: &[T]);
+
+    /// Makes all operations written so far durable.
+    fn sync(&self);
+
+    /// Discards all operations before `pos`, e.g., because they are reflected
+    /// in a checkpoint.
+    fn truncate(&self, pos: LogPos);
+
+    /// Calls `f` with every stored operation from `from` onwards (in log
+    /// order), together with its logical index.
+    fn replay(&self, from: LogPos, f: &mut dyn FnMut(LogPos, T));
+}
+
+/// When to make operations written to the [`WalBackend`] durable.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// After every batch of operations appended by a combiner, before the
+    /// responses are handed out.
+    Always,
+    /// Group commit: once (at least) the given number of operations were
+    /// written since the last sync.
+    Every(usize),
+    /// Only when [`Wal::sync`] is called.
+    Manual,
+}
+
+/// A write-ahead log which records the operations of a [`Log`](crate::log::Log)
+/// in a [`WalBackend`], following a [`SyncPolicy`].
+pub struct Wal<T> {
+    backend: Box<dyn WalBackend<T>>,
+    policy: SyncPolicy,
+    /// Logical index of the next operation to be written. Combiners of
+    /// different replicas append concurrently, so they wait for their turn to
+    /// write (in log order) here.
+    next: CachePadded<AtomicUsize>,
+    /// Operations written since the last sync.
+    unsynced: CachePadded<AtomicUsize>,
+}
+
+impl<T> Wal<T> {
+    /// Creates a write-ahead log that stores operations in `backend`.
+    pub fn new(backend: impl WalBackend<T> + 'static, policy: SyncPolicy) -> Self {
+        Self {
+            backend: Box::new(backend),
+            policy,
+            next: CachePadded::new(AtomicUsize::new(0)),
+            unsynced: CachePadded::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Records the operations of the `nops` entries that were appended to the
+    /// log at logical index `pos`, given as `runs` of consecutive operations
+    /// (with their logical index). Entries that are not part of a run hold
+    /// rejected operations, which aren't recorded.
+    ///
+    /// Waits (with `wait`) until all operations before `pos` are written, so
+    /// the backend sees operations in log order and a sync makes a prefix of
+    /// the log durable.
+    pub(crate) fn append<'a>(
+        &self,
+        pos: LogPos,
+        nops: usize,
+        runs: impl Iterator<Item = (LogPos, &'a [T])>,
+        wait: &dyn WaitStrategy,
+    ) where
+        T: 'a,
+    {
+        let mut waiter = Waiter::new(wait);
+        while self.next.load(Ordering::Acquire) != pos {
+            waiter.wait();
+        }
+        drop(waiter);
+
+        let mut written = 0;
+        for (pos, ops) in runs {
+            self.backend.write(pos, ops);
+            written += ops.len();
+        }
+        let unsynced = self.unsynced.load(Ordering::Relaxed) + written;
+        match self.policy {
+            SyncPolicy::Always => self.sync_written(),
+            SyncPolicy::Every(n) if unsynced >= n => self.sync_written(),
+            _ => self.unsynced.store(unsynced, Ordering::Relaxed),
+        }
+
+        self.next.store(pos + nops, Ordering::Release);
+        wait.wake();
+    }
+
+    fn sync_written(&self) {
+        self.backend.sync();
+        self.unsynced.store(0, Ordering::Relaxed);
+    }
+
+    /// Makes all recorded operations durable.
+    ///
+    /// Waits (with `wait`) for the turn of the next append, so it doesn't race
+    /// with combiners, and wakes them up once it's done.
+    pub fn sync(&self, wait: &dyn WaitStrategy) {
+        let mut waiter = Waiter::new(wait);
+        let pos = loop {
+            let pos = self.next.load(Ordering::Acquire);
+            if pos != usize::MAX
+                && self
+                    .next
+                    .compare_exchange_weak(pos, usize::MAX, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                break pos;
+            }
+            waiter.wait();
+        };
+        drop(waiter);
+
+        self.sync_written();
+        self.next.store(pos, Ordering::Release);
+        wait.wake();
+    }
+
+    /// Discards all recorded operations before `pos`.
+    ///
+    /// # Note
+    /// Only do this once a checkpoint of the state at `pos` was stored
+    /// durably.
+    pub fn truncate(&self, pos: LogPos) {
+        self.backend.truncate(pos);
+    }
+
+    /// Calls `f` with every recorded operation from `from` onwards (in log
+    /// order) and returns the logical index after the last one (or `from` if
+    /// there is none).
+    ///
+    /// Subsequent appends are expected to continue at the returned index.
+    pub(crate) fn replay(&self, from: LogPos, mut f: impl FnMut(T)) -> LogPos {
+        let mut end = from;
+        self.backend.replay(from, &mut |pos, op| {
+            debug_assert!(pos >= end, "Operations not replayed in log order");
+            f(op);
+            end = pos + 1;
+        });
+        self.next.store(end, Ordering::Release);
+        end
+    }
+}
+
+#[cfg(feature = "std")]
+mod file {
+    use alloc::vec::Vec;
+    use core::convert::TryInto;
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    use super::WalBackend;
+    use crate::log::LogPos;
+
+    /// Serialization of operations stored in a [`FileSegment`] (or a
+    /// [`TraceFile`](crate::nr::trace::TraceFile)).
+    pub trait WalCodec: Sized {
+        /// Appends the encoded operation to `buf`.
+        fn encode(&self, buf: &mut Vec<u8>);
+
+        /// Decodes an operation that was encoded by [`WalCodec::encode`].
+        fn decode(buf: &[u8]) -> Option<Self>;
+    }
+
+    /// Size of the header of a record: the logical index of the operation,
+    /// the length of the encoded operation and a CRC-32 of both and the
+    /// encoded operation.
+    const HEADER_BYTES: usize = 8 + 4 + 4;
+
+    /// Computes the CRC-32 (IEEE) of `bytes`, continuing from `crc`.
+    fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+        let mut crc = !crc;
+        for b in bytes {
+            crc ^= *b as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        !crc
+    }
+
+    /// A [`WalBackend`] that stores operations in a file.
+    ///
+    /// Each operation is stored as a record of its logical index, the length
+    /// of the encoded operation, a checksum and the encoded operation (see
+    /// [`WalCodec`]). A partially written or corrupted record at the end of
+    /// the file (e.g., after a crash) is cut off when the segment is opened,
+    /// together with everything after it.
+    pub struct FileSegment {
+        path: PathBuf,
+        file: Mutex<File>,
+        /// Serializes [`WalBackend::truncate`] calls, which copy most of the
+        /// segment without holding `file`.
+        truncating: Mutex<()>,
+    }
+
+    impl FileSegment {
+        /// Opens (or creates) the segment at `path`.
+        pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+            let path = path.as_ref().to_path_buf();
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&path)?;
+
+            // Appends have to start right after the last valid record.
+            let len = file.metadata()?.len();
+            let valid = Self::scan(&file, len, |_pos, _op| true)?;
+            if valid < len {
+                file.set_len(valid)?;
+                file.sync_all()?;
+            }
+
+            Ok(Self {
+                path,
+                file: Mutex::new(file),
+                truncating: Mutex::new(()),
+            })
+        }
+
+        /// Reads the records in the first `len` bytes of `file` in order and
+        /// calls `f` with the logical index and the encoded operation of
+        /// each valid one, until it returns `false`.
+        ///
+        /// Returns the offset of the record `f` stopped at, or the length of
+        /// the prefix of the file the valid records occupy.
+        fn scan(
+            mut file: &File,
+            len: u64,
+            mut f: impl FnMut(LogPos, &[u8]) -> bool,
+        ) -> std::io::Result<u64> {
+            file.seek(SeekFrom::Start(0))?;
+            let mut reader = BufReader::new(file);
+            let mut header = [0; HEADER_BYTES];
+            let mut op = Vec::new();
+
+            let mut offset = 0;
+            while len - offset >= HEADER_BYTES as u64 {
+                reader.read_exact(&mut header)?;
+                let pos = u64::from_le_bytes(header[..8].try_into().unwrap()) as LogPos;
+                let op_len = u32::from_le_bytes(header[8..12].try_into().unwrap());
+                let crc = u32::from_le_bytes(header[12..].try_into().unwrap());
+                if len - offset - (HEADER_BYTES as u64) < op_len as u64 {
+                    break;
+                }
+                op.resize(op_len as usize, 0);
+                reader.read_exact(&mut op)?;
+                if crc32(crc32(0, &header[..12]), &op) != crc || !f(pos, &op) {
+                    break;
+                }
+                offset += (HEADER_BYTES + op.len()) as u64;
+            }
+
+            Ok(offset)
+        }
+
+        fn encode_record<T: WalCodec>(pos: LogPos, op: &T, buf: &mut Vec<u8>) {
+            let start = buf.len();
+            buf.extend_from_slice(&(pos as u64).to_le_bytes());
+            buf.extend_from_slice(&[0; 8]);
+            op.encode(buf);
+
+            let record = &mut buf[start..];
+            let len = (record.len() - HEADER_BYTES) as u32;
+            record[8..12].copy_from_slice(&len.to_le_bytes());
+            let crc = crc32(crc32(0, &record[..12]), &record[HEADER_BYTES..]);
+            record[12..HEADER_BYTES].copy_from_slice(&crc.to_le_bytes());
+        }
+    }
+
+    impl<T: WalCodec> WalBackend<T> for FileSegment {
+        fn write(&self, pos: LogPos, ops: &[T]) {
+            let mut buf = Vec::new();
+            for (i, op) in ops.iter().enumerate() {
+                Self::encode_record(pos + i, op, &mut buf);
+            }
+
+            let mut file = self.file.lock().unwrap();
+            file.write_all(&buf).expect("Can't write to WAL segment");
+        }
+
+        fn sync(&self) {
+            let file = self.file.lock().unwrap();
+            file.sync_data().expect("Can't sync WAL segment");
+        }
+
+        fn truncate(&self, pos: LogPos) {
+            let _truncating = self.truncating.lock().unwrap();
+
+            // Records are in log order, so we keep everything from the first
+            // record at or after `pos` on. Copy what is there already to a new
+            // file without blocking writers.
+            let mut reader = File::open(&self.path).expect("Can't open WAL segment");
+            let len = reader.metadata().expect("Can't read WAL segment").len();
+            let start = Self::scan(&reader, len, |p, _op| p < pos).expect("Can't read WAL segment");
+
+            let mut tmp_path = self.path.clone().into_os_string();
+            tmp_path.push(".tmp");
+            let mut tmp = File::create(&tmp_path).expect("Can't create WAL segment");
+            reader
+                .seek(SeekFrom::Start(start))
+                .expect("Can't read WAL segment");
+            std::io::copy(&mut (&reader).take(len - start), &mut tmp)
+                .expect("Can't write WAL segment");
+
+            // Then the records that were written in the meantime, and
+            // atomically replace the segment with the new file.
+            let mut file = self.file.lock().unwrap();
+            reader
+                .seek(SeekFrom::Start(len))
+                .expect("Can't read WAL segment");
+            std::io::copy(&mut reader, &mut tmp).expect("Can't write WAL segment");
+            tmp.sync_all().expect("Can't sync WAL segment");
+            std::fs::rename(&tmp_path, &self.path).expect("Can't replace WAL segment");
+
+            *file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(&self.path)
+                .expect("Can't open WAL segment");
+        }
+
+        fn replay(&self, from: LogPos, f: &mut dyn FnMut(LogPos, T)) {
+            let file = self.file.lock().unwrap();
+            let len = file.metadata().expect("Can't read WAL segment").len();
+            Self::scan(&file, len, |pos, op| {
+                if pos >= from {
+                    f(pos, T::decode(op).expect("Can't decode WAL record"));
+                }
+                true
+            })
+            .expect("Can't read WAL segment");
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        impl WalCodec for u64 {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn decode(buf: &[u8]) -> Option<Self> {
+                Some(u64::from_le_bytes(buf.try_into().ok()?))
+            }
+        }
+
+        #[test]
+        fn test_file_segment() {
+            let path =
+                std::env::temp_dir().join(std::format!("nr-wal-test-{}.seg", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+
+            let segment = FileSegment::open(&path).unwrap();
+            segment.write(0, &[10u64, 11, 12]);
+            segment.write(3, &[13u64]);
+            WalBackend::<u64>::sync(&segment);
+            // A torn record at the end is cut off.
+            let len = std::fs::metadata(&path).unwrap().len();
+            segment.file.lock().unwrap().write_all(&[1, 2, 3]).unwrap();
+
+            let segment = FileSegment::open(&path).unwrap();
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+            let mut ops = Vec::new();
+            WalBackend::<u64>::replay(&segment, 1, &mut |pos, op| ops.push((pos, op)));
+            assert_eq!(ops, [(1, 11), (2, 12), (3, 13)]);
+
+            // So is a record that doesn't match its checksum (and everything
+            // after it).
+            segment.write(4, &[14u64, 15]);
+            drop(segment);
+            let mut bytes = std::fs::read(&path).unwrap();
+            bytes[len as usize + HEADER_BYTES] ^= 1;
+            std::fs::write(&path, &bytes).unwrap();
+
+            let segment = FileSegment::open(&path).unwrap();
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+            let mut ops = Vec::new();
+            WalBackend::<u64>::replay(&segment, 3, &mut |pos, op| ops.push((pos, op)));
+            assert_eq!(ops, [(3, 13)]);
+
+            WalBackend::<u64>::truncate(&segment, 2);
+            // Position 4 was rejected.
+            segment.write(5, &[15u64]);
+            let mut ops = Vec::new();
+            WalBackend::<u64>::replay(&segment, 2, &mut |pos, op| ops.push((pos, op)));
+            assert_eq!(ops, [(2, 12), (3, 13), (5, 15)]);
+
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tests for operations with a derived `LogMapper`.
+#![feature(generic_associated_types)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use node_replication::cnr::{log_for_key, Dispatch, Log, LogMapper, LogMetaData, Replica};
+
+#[derive(Debug, PartialEq, Clone, LogMapper)]
+enum OpWr {
+    #[log(key = 0)]
+    Put(usize, usize),
+    #[log(key = key)]
+    Remove { key: usize },
+    #[log(all)]
+    Clear,
+    #[log(all)]
+    Len,
+    #[log(fixed = 5)]
+    Compact,
+}
+
+#[derive(Debug, PartialEq, Clone, LogMapper)]
+#[log(key = 0)]
+enum OpRd<'a> {
+    Get(&'a str),
+    Contains(&'a str),
+    #[log(all)]
+    Len,
+}
+
+#[derive(Debug, PartialEq, Clone, LogMapper)]
+#[log(key = name)]
+struct Rename<K: std::hash::Hash> {
+    name: K,
+    to: String,
+}
+
+/// Checks that mappers can be derived for empty enums.
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Clone, LogMapper)]
+enum Never {}
+
+fn logs_of(op: &impl LogMapper, nlogs: usize) -> Vec<usize> {
+    let mut logs = Vec::with_capacity(nlogs);
+    op.hash(nlogs, &mut logs);
+    logs
+}
+
+#[test]
+fn derived_mappings() {
+    assert_eq!(logs_of(&OpWr::Put(5, 1), 4), vec![1]);
+    assert_eq!(logs_of(&OpWr::Remove { key: 5 }, 4), vec![1]);
+    assert_eq!(logs_of(&OpWr::Remove { key: 6 }, 4), vec![2]);
+    assert_eq!(logs_of(&OpWr::Clear, 4), vec![0, 1, 2, 3]);
+    assert_eq!(logs_of(&OpWr::Clear, 1), vec![0]);
+    assert_eq!(logs_of(&OpWr::Len, 2), vec![0, 1]);
+    assert_eq!(logs_of(&OpWr::Compact, 4), vec![1]);
+    assert_eq!(logs_of(&OpWr::Compact, 8), vec![5]);
+
+    // Variants without an attribute use the one of the enum.
+    assert_eq!(logs_of(&OpRd::Get("a"), 4), vec![log_for_key("a", 4)]);
+    assert_eq!(
+        logs_of(&OpRd::Get("a"), 4),
+        logs_of(&OpRd::Contains("a"), 4)
+    );
+    assert_eq!(logs_of(&OpRd::Len, 3), vec![0, 1, 2]);
+
+    let op = Rename {
+        name: String::from("a"),
+        to: String::from("b"),
+    };
+    assert_eq!(logs_of(&op, 4), vec![log_for_key("a", 4)]);
+    let op = Rename {
+        name: 9u64,
+        to: String::from("b"),
+    };
+    assert_eq!(logs_of(&op, 4), vec![1]);
+}
+
+#[test]
+fn log_for_key_is_in_range() {
+    for nlogs in 1..8 {
+        for key in 0..100usize {
+            assert_eq!(log_for_key(&key, nlogs), key % nlogs);
+            assert_eq!(log_for_key(&(key as u32), nlogs), key % nlogs);
+            assert!(log_for_key(&key.to_string(), nlogs) < nlogs);
+            assert!(log_for_key(&(key, key + 1), nlogs) < nlogs);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Map {
+    storage: Mutex<HashMap<usize, usize>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, LogMapper)]
+enum MapRd {
+    #[log(key = 0)]
+    Get(usize),
+}
+
+impl Dispatch for Map {
+    type ReadOperation<'rop> = MapRd;
+    type WriteOperation = OpWr;
+    type Response = Option<usize>;
+
+    fn dispatch<'rop>(&self, op: Self::ReadOperation<'rop>) -> Self::Response {
+        let storage = self.storage.lock().unwrap();
+        match op {
+            MapRd::Get(k) => storage.get(&k).cloned(),
+        }
+    }
+
+    fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+        let mut storage = self.storage.lock().unwrap();
+        match op {
+            OpWr::Put(k, v) => storage.insert(k, v),
+            OpWr::Remove { key } => storage.remove(&key),
+            OpWr::Clear => {
+                storage.clear();
+                None
+            }
+            OpWr::Len => Some(storage.len()),
+            OpWr::Compact => {
+                storage.shrink_to_fit();
+                None
+            }
+        }
+    }
+}
+
+/// Derived mappers work with multiple logs and replicas.
+#[test]
+fn derived_mappers_with_replicas() {
+    const NLOGS: usize = 4;
+    const NREPLICAS: usize = 2;
+    const NTHREADS: usize = 4;
+    const NOPS: usize = 1000;
+
+    let logs: Vec<_> = (0..NLOGS)
+        .map(|i| {
+            Arc::new(Log::<OpWr>::new_with_bytes(
+                1024 * 1024,
+                LogMetaData::new(i + 1),
+            ))
+        })
+        .collect();
+    let replicas: Vec<_> = (0..NREPLICAS)
+        .map(|_| Replica::<Map>::new(logs.clone()))
+        .collect();
+
+    let mut threads = Vec::with_capacity(NTHREADS);
+    for t in 0..NTHREADS {
+        let replica = replicas[t % NREPLICAS].clone();
+        threads.push(thread::spawn(move || {
+            let idx = replica.register().unwrap();
+            for i in 0..NOPS {
+                let key = t * NOPS + i;
+                replica.execute_mut(OpWr::Put(key, i), idx);
+                assert_eq!(replica.execute(MapRd::Get(key), idx), Some(i));
+                if i % 2 == 0 {
+                    assert_eq!(replica.execute_mut(OpWr::Remove { key }, idx), Some(i));
+                }
+            }
+            replica.execute_mut(OpWr::Compact, idx);
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    for replica in replicas.iter() {
+        let idx = replica.register().unwrap();
+        assert_eq!(
+            replica.execute_scan(OpWr::Len, idx),
+            Some(NTHREADS * NOPS / 2)
+        );
+    }
+    let idx = replicas[0].register().unwrap();
+    replicas[0].execute_mut_scan(OpWr::Clear, idx);
+    let idx = replicas[1].register().unwrap();
+    assert_eq!(replicas[1].execute_scan(OpWr::Len, idx), Some(0));
+}
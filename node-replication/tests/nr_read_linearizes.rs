@@ -161,7 +161,7 @@ fn test_read_linearizes_with_gc() {
     b.check(move || {
         // Make a log with just 4 entries, on adding a second entry, we start GC
         let log = Log::<<TheCounter as Dispatch>::WriteOperation>::new_with_entries(4, ());
-        let ltkn = LogToken(3);
+        let ltkn = LogToken(3, log.instance_id());
         log.append(&[OpWr::Noop, OpWr::Noop], &ltkn, |_op, _idx| {
             panic!("We're doing GC but we don't want to do it just yet...");
         })
@@ -0,0 +1,153 @@
+// Copyright © 2019-2022 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tests for data structures replicated with `#[replicated]`.
+#![feature(generic_associated_types)]
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::thread;
+
+use node_replication::nr::{Dispatch, NodeReplicated};
+use node_replication::replicated;
+
+#[derive(Default)]
+struct Stack {
+    storage: Vec<u32>,
+}
+
+#[replicated]
+impl Stack {
+    /// Not an operation.
+    fn with_capacity(capacity: usize) -> Self {
+        Stack {
+            storage: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn push(&mut self, v: u32) {
+        self.storage.push(v);
+    }
+
+    fn pop(&mut self) -> Option<u32> {
+        self.storage.pop()
+    }
+
+    fn push_all(&mut self, vs: Vec<u32>) -> usize {
+        self.storage.extend(vs);
+        self.storage.len()
+    }
+
+    fn peek(&self) -> Option<u32> {
+        self.storage.last().cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.storage.len()
+    }
+}
+
+#[derive(Default)]
+struct Map {
+    storage: HashMap<String, u64>,
+}
+
+#[replicated]
+impl Map {
+    fn put(&mut self, key: String, value: u64) -> Option<u64> {
+        self.storage.insert(key, value)
+    }
+
+    fn get(&self, key: &str) -> Option<u64> {
+        self.storage.get(key).cloned()
+    }
+
+    fn sum_of(&self, keys: &[&str]) -> u64 {
+        keys.iter().filter_map(|k| self.storage.get(*k)).sum()
+    }
+}
+
+/// The generated enums and `Dispatch` implementation call the methods.
+#[test]
+fn replicated_dispatch() {
+    let mut s = Stack::with_capacity(4);
+    assert!(matches!(
+        s.dispatch_mut(StackWriteOp::Push(1)),
+        StackResponse::Push(())
+    ));
+    assert!(matches!(
+        s.dispatch_mut(StackWriteOp::PushAll(vec![2, 3])),
+        StackResponse::PushAll(3)
+    ));
+    assert!(matches!(
+        s.dispatch(StackReadOp::Peek),
+        StackResponse::Peek(Some(3))
+    ));
+    assert!(matches!(
+        s.dispatch_mut(StackWriteOp::Pop),
+        StackResponse::Pop(Some(3))
+    ));
+    assert!(matches!(
+        s.dispatch(StackReadOp::Len),
+        StackResponse::Len(2)
+    ));
+    assert!(StackWriteOp::Push(1) == StackWriteOp::Push(1));
+    assert!(StackWriteOp::Push(1) != StackWriteOp::Pop);
+}
+
+/// The handle executes the methods on the replicated data structure.
+#[test]
+fn replicated_handle() {
+    let replicas = NonZeroUsize::new(2).unwrap();
+    let nr = NodeReplicated::<Map>::new(replicas, |_| 0).unwrap();
+    let m1 = MapHandle::new(&nr, nr.register(0).unwrap());
+    let m2 = MapHandle::new(&nr, nr.register(1).unwrap());
+
+    assert_eq!(m1.put(String::from("a"), 1), None);
+    assert_eq!(m1.put(String::from("b"), 2), None);
+    assert_eq!(m2.put(String::from("a"), 3), Some(1));
+    assert_eq!(m1.get("a"), Some(3));
+    assert_eq!(m2.get("c"), None);
+    assert_eq!(m2.sum_of(&["a", "b", "c"]), 5);
+}
+
+/// Handles work with `Arc` and on multiple threads.
+#[test]
+fn replicated_handle_threads() {
+    const THREADS: usize = 4;
+    const OPS: u32 = 1000;
+
+    let replicas = NonZeroUsize::new(2).unwrap();
+    let nr = Arc::new(NodeReplicated::<Stack>::new(replicas, |_| 0).unwrap());
+
+    let mut threads = Vec::with_capacity(THREADS);
+    for t in 0..THREADS {
+        let nr = nr.clone();
+        threads.push(thread::spawn(move || {
+            let ttkn = nr.register(t % 2).unwrap();
+            let stack = StackHandle::new(nr, ttkn);
+            for i in 0..OPS {
+                stack.push(i);
+            }
+            for _ in 0..OPS / 2 {
+                assert!(stack.pop().is_some());
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let stack = StackHandle::new(nr.clone(), nr.register(0).unwrap());
+    assert_eq!(stack.len(), THREADS * OPS as usize / 2);
+    assert_eq!(stack.push_all(vec![7]), THREADS * OPS as usize / 2 + 1);
+    assert_eq!(stack.peek(), Some(7));
+}
+
+/// Unsupported `impl` blocks and methods are rejected with an error.
+#[test]
+fn replicated_compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/replicated_*.rs");
+}
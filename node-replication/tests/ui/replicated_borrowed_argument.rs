@@ -0,0 +1,13 @@
+use node_replication::replicated;
+
+#[derive(Default)]
+struct Counter(u64);
+
+#[replicated]
+impl Counter {
+    fn add(&mut self, v: &u64) {
+        self.0 += v;
+    }
+}
+
+fn main() {}
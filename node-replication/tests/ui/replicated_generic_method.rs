@@ -0,0 +1,13 @@
+use node_replication::replicated;
+
+#[derive(Default)]
+struct Counter(u64);
+
+#[replicated]
+impl Counter {
+    fn add<V: Into<u64>>(&mut self, v: V) {
+        self.0 += v.into();
+    }
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use node_replication::replicated;
+
+#[derive(Default)]
+struct Counter(u64);
+
+#[replicated]
+impl Counter {
+    fn new(&mut self) -> u64 {
+        self.0 = 0;
+        self.0
+    }
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+use node_replication::replicated;
+
+#[derive(Default)]
+struct Counter(u64);
+
+#[replicated]
+impl Counter {
+    fn take(self) -> u64 {
+        self.0
+    }
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use node_replication::replicated;
+
+struct Counter(u64);
+
+#[replicated]
+impl Default for Counter {
+    fn default() -> Self {
+        Counter(0)
+    }
+}
+
+fn main() {}